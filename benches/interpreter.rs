@@ -0,0 +1,99 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+use zyde::instruction::Instruction;
+use zyde::passes::PassManager;
+use zyde::passes::fusion::Fusion;
+use zyde::vm::VM;
+use zyde::workloads::{self, REGISTERS};
+
+fn interpreter(c: &mut Criterion) {
+    for (name, program) in workloads::standard()
+        .into_iter()
+        .chain(workloads::allocating())
+    {
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let mut vm = VM::new(program.clone(), REGISTERS);
+                vm.run().unwrap();
+                black_box(vm.registers[0])
+            })
+        });
+    }
+}
+
+/// `run` skips register bounds checks for programs that pass
+/// `verify_registers`. An unreachable out-of-range operand appended after the
+/// final `halt` keeps the same code on the checked path for comparison.
+fn register_checks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("register_checks");
+    for (name, verified) in workloads::standard() {
+        let mut checked = verified.clone();
+        checked
+            .instructions
+            .push(Instruction::Print { src: REGISTERS });
+
+        for (mode, program) in [("checked", checked), ("verified", verified)] {
+            group.bench_function(format!("{}/{}", name, mode), |b| {
+                b.iter(|| {
+                    let mut vm = VM::new(program.clone(), REGISTERS);
+                    vm.run().unwrap();
+                    black_box(vm.registers[0])
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The same workloads before and after superinstruction fusion
+fn fusion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fusion");
+    for (name, plain) in workloads::standard() {
+        let mut fused = plain.clone();
+        PassManager::empty().add(Fusion).run(&mut fused);
+
+        for (mode, program) in [("plain", plain), ("fused", fused)] {
+            group.bench_function(format!("{}/{}", name, mode), |b| {
+                b.iter(|| {
+                    let mut vm = VM::new(program.clone(), REGISTERS);
+                    vm.run().unwrap();
+                    black_box(vm.registers[0])
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The same workloads interpreted and compiled with Cranelift; compilation
+/// happens once, outside the measured loop
+#[cfg(feature = "jit")]
+fn jit(c: &mut Criterion) {
+    use zyde::jit::CompiledProgram;
+
+    let mut group = c.benchmark_group("jit");
+    for (name, program) in workloads::standard() {
+        group.bench_function(format!("{}/interpreted", name), |b| {
+            b.iter(|| {
+                let mut vm = VM::new(program.clone(), REGISTERS);
+                vm.run().unwrap();
+                black_box(vm.registers[0])
+            })
+        });
+        let compiled = CompiledProgram::compile(&program, REGISTERS).unwrap();
+        group.bench_function(format!("{}/jit", name), |b| {
+            b.iter(|| {
+                let mut vm = VM::new(program.clone(), REGISTERS);
+                vm.run_compiled(&compiled).unwrap();
+                black_box(vm.registers[0])
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "jit"))]
+fn jit(_: &mut Criterion) {}
+
+criterion_group!(benches, interpreter, register_checks, fusion, jit);
+criterion_main!(benches);
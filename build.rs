@@ -0,0 +1,19 @@
+fn main() {
+    #[cfg(feature = "capi")]
+    capi_header();
+}
+
+/// Generate the C header for the `capi` module
+#[cfg(feature = "capi")]
+fn capi_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/capi.rs");
+
+    cbindgen::Builder::new()
+        .with_src(format!("{}/src/capi.rs", crate_dir))
+        .with_language(cbindgen::Language::C)
+        .with_include_guard("ZYDE_H")
+        .generate()
+        .expect("failed to generate C header")
+        .write_to_file(format!("{}/include/zyde.h", crate_dir));
+}
@@ -0,0 +1,46 @@
+; Ackermann's function: prints A(m, n) for m from 0 to 2 and n from 0
+; to 3. It recurses about as deep as its result, so it is run under a
+; call depth limit.
+
+        loadimm r3, 0           ; m
+outer:
+        loadimm r4, 0           ; n
+inner:
+        mov r0, r3
+        mov r1, r4
+        call ack
+        print r0
+        addimm r4, r4, r5, 1
+        loadimm r5, 3
+        gt r6, r4, r5
+        jz r6, inner
+        addimm r3, r3, r5, 1
+        loadimm r5, 2
+        gt r6, r3, r5
+        jz r6, outer
+        halt
+
+; A(r0, r1) in r0, using r1 and r2
+.func ack(int, int) -> int
+        loadimm r2, 0
+        eq r2, r0, r2
+        jz r2, m_positive
+        addimm r0, r1, r2, 1    ; A(0, n) = n + 1
+        ret
+m_positive:
+        loadimm r2, 0
+        eq r2, r1, r2
+        jz r2, both_positive
+        addimm r0, r0, r2, -1   ; A(m, 0) = A(m - 1, 1)
+        loadimm r1, 1
+        call ack
+        ret
+both_positive:
+        push r0                 ; A(m, n) = A(m - 1, A(m, n - 1))
+        addimm r1, r1, r2, -1
+        call ack
+        mov r1, r0
+        pop r0
+        addimm r0, r0, r2, -1
+        call ack
+        ret
@@ -0,0 +1,36 @@
+; Recursive Fibonacci: prints fib(n) for n from 0 to LAST.
+; Registers are shared by every call, so a caller pushes what it needs
+; after a call onto the operand stack and pops it back.
+
+.const LAST = 10
+
+        loadimm r1, 0           ; n
+next:
+        mov r0, r1
+        push r1
+        call fib
+        pop r1
+        print r0
+        addimm r1, r1, r2, 1
+        loadimm r2, LAST
+        gt r3, r1, r2
+        jz r3, next
+        halt
+
+; fib(r0) in r0, using r1 and r2
+.func fib(int) -> int
+        loadimm r1, 2
+        lt r2, r0, r1
+        jz r2, recurse
+        ret                     ; fib(n) = n below 2
+recurse:
+        push r0
+        addimm r0, r0, r1, -1
+        call fib
+        pop r1                  ; n
+        push r0                 ; fib(n - 1)
+        addimm r0, r1, r2, -2
+        call fib
+        pop r1
+        add r0, r0, r1
+        ret
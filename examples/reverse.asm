@@ -0,0 +1,52 @@
+; Reverses a string in place, a string being a map from index to
+; character code, and prints its codes: "stressed" becomes "desserts".
+
+        map r0                  ; the string
+        loadimm r1, 0           ; its length
+        loadimm r7, 1
+        loadimm r2, 115         ; s
+        call append
+        loadimm r2, 116         ; t
+        call append
+        loadimm r2, 114         ; r
+        call append
+        loadimm r2, 101         ; e
+        call append
+        loadimm r2, 115         ; s
+        call append
+        call append
+        loadimm r2, 101         ; e
+        call append
+        loadimm r2, 100         ; d
+        call append
+
+        loadimm r3, 0           ; i, from the front
+        sub r4, r1, r7          ; j, from the back
+swap:
+        lt r5, r3, r4
+        jz r5, output
+        mapget r5, r0, r3
+        mapget r6, r0, r4
+        mapset r0, r3, r6
+        mapset r0, r4, r5
+        add r3, r3, r7
+        sub r4, r4, r7
+        jmp swap
+
+output:
+        loadimm r3, 0
+each:
+        lt r5, r3, r1
+        jz r5, done
+        mapget r5, r0, r3
+        print r5
+        add r3, r3, r7
+        jmp each
+done:
+        halt
+
+; Append the character r2 to the string r0 of length r1
+append:
+        mapset r0, r1, r2
+        add r1, r1, r7
+        ret
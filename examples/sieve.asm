@@ -0,0 +1,29 @@
+; Sieve of Eratosthenes: prints the primes below LIMIT, crossing off
+; the multiples of each in a map of composites.
+
+.const LIMIT = 30
+
+        map r0                  ; composites
+        loadimm r1, 2           ; i
+        loadimm r5, LIMIT
+        loadimm r6, 1
+next:
+        maphas r2, r0, r1
+        jz r2, prime
+        jmp advance
+prime:
+        print r1
+        mul r3, r1, r1          ; multiples below i * i are crossed off
+cross:
+        lt r4, r3, r5
+        jz r4, advance
+        mapset r0, r3, r6
+        add r3, r3, r1
+        jmp cross
+advance:
+        add r1, r1, r6
+        lt r4, r1, r5
+        jz r4, done
+        jmp next
+done:
+        halt
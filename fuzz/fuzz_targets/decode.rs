@@ -0,0 +1,26 @@
+//! Feed arbitrary bytes to the bytecode decoder. It must never panic, and
+//! anything it accepts must survive being encoded and decoded again.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zyde::bytecode::BytecodeView;
+use zyde::program::Program;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(view) = BytecodeView::parse(data) {
+        for i in 0..view.len() {
+            let _ = view.instruction(i);
+        }
+        let _ = view.exports();
+        let _ = view.features();
+    }
+
+    let Ok(program) = Program::from_bytecode(data) else {
+        return;
+    };
+    // The first encoding may merge duplicate strings, so compare the second
+    let bytes = program.to_bytecode().expect("decoded program must encode");
+    let again = Program::from_bytecode(&bytes).expect("encoded program must decode");
+    assert_eq!(again.to_bytecode().unwrap(), bytes);
+});
@@ -0,0 +1,18 @@
+//! Run arbitrary valid programs on the interpreter. It must never panic,
+//! and programs from the default generator must halt or trap.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zyde::program::Program;
+use zyde::testing::Generator;
+use zyde::vm::{VM, VmConfig, VmError};
+
+fuzz_target!(|program: Program| {
+    let config = VmConfig {
+        max_steps: Some(1_000_000),
+        ..VmConfig::default()
+    };
+    let result = VM::with_config(program, Generator::default().registers, config).run();
+    assert!(!matches!(result, Err(VmError::StepLimitExceeded)));
+});
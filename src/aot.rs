@@ -0,0 +1,237 @@
+//! Ahead-of-time translation of a verified program into Rust source.
+//!
+//! Each basic block becomes one straight-line arm of a `loop { match }` keyed
+//! by the block's start address, so jumps and calls only dispatch at block
+//! boundaries. The generated function has no dependency on this crate.
+
+use crate::cfg::Cfg;
+use crate::instruction::{self, Comparison, Instruction};
+use crate::prelude::*;
+use crate::program::{Program, ProgramError};
+use core::fmt::Write;
+
+/// Translate `program` into a Rust function called `name` that runs it from
+/// instruction 0:
+///
+/// ```text
+/// pub fn name(registers: &mut [f64; N], variables: &mut HashMap<String, f64>) -> Result<(), String>
+/// ```
+///
+/// `N` is `num_registers`, and errors carry the interpreter's messages. The
+/// program must pass `verify` and `verify_registers(num_registers)`, and may
+/// not use error handling.
+pub fn to_rust(
+    program: &Program,
+    num_registers: usize,
+    name: &str,
+) -> Result<String, ProgramError> {
+    program.verify()?;
+    program.verify_registers(num_registers)?;
+    check_supported(program)?;
+
+    let code = &program.instructions;
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "#[allow(unreachable_code, unused_mut, unused_variables, clippy::all)]"
+    );
+    let _ = writeln!(
+        out,
+        "pub fn {}(\n    registers: &mut [f64; {}],\n    variables: &mut std::collections::HashMap<String, f64>,\n) -> Result<(), String> {{",
+        name, num_registers
+    );
+    let _ = writeln!(out, "    let r = registers;");
+    let _ = writeln!(out, "    let mut stack: Vec<usize> = Vec::new();");
+    let _ = writeln!(out, "    let mut block = 0usize;");
+    let _ = writeln!(out, "    loop {{");
+    let _ = writeln!(out, "        match block {{");
+
+    for block in Cfg::build(code).blocks {
+        let _ = writeln!(out, "            {} => {{", block.start);
+        for (pc, instr) in code.iter().enumerate().take(block.end).skip(block.start) {
+            let _ = writeln!(out, "                // {}: {}", pc, instr);
+            translate(&mut out, pc, instr, code.len());
+        }
+        let last = &code[block.end - 1];
+        if !last.is_terminator() && !last.is_call() {
+            let _ = writeln!(out, "                block = {};", block.end);
+        }
+        let _ = writeln!(out, "            }}");
+    }
+
+    let _ = writeln!(out, "            _ => return Ok(()),");
+    let _ = writeln!(out, "        }}");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "}}");
+    Ok(out)
+}
+
+fn translate(out: &mut String, pc: usize, instr: &Instruction, len: usize) {
+    use Instruction::*;
+
+    let line = match instr {
+        LoadImm { dest, value } => format!("r[{}] = {};", dest, literal(*value)),
+        Add { dest, src1, src2 } => format!("r[{}] = r[{}] + r[{}];", dest, src1, src2),
+        Sub { dest, src1, src2 } => format!("r[{}] = r[{}] - r[{}];", dest, src1, src2),
+        Mul { dest, src1, src2 } => format!("r[{}] = r[{}] * r[{}];", dest, src1, src2),
+        Div { dest, src1, src2 } => format!("r[{}] = r[{}] / r[{}];", dest, src1, src2),
+        Equal { dest, src1, src2 } => compare(*dest, *src1, "==", *src2),
+        LessThan { dest, src1, src2 } => compare(*dest, *src1, "<", *src2),
+        GreaterThan { dest, src1, src2 } => compare(*dest, *src1, ">", *src2),
+        Mov { dest, src } => format!("r[{}] = r[{}];", dest, src),
+        Not { dest, src } => format!(
+            "r[{}] = if r[{}] == 0.0 {{ 1.0 }} else {{ 0.0 }};",
+            dest, src
+        ),
+        Print { src } => format!("println!(\"{{}}\", r[{}]);", src),
+        Store { src, var } => format!(
+            "match variables.get_mut({var:?}) {{ Some(v) => *v = r[{src}], None => {{ variables.insert({var:?}.to_string(), r[{src}]); }} }}",
+        ),
+        Load { dest, var } => format!(
+            "r[{}] = *variables.get({:?}).ok_or_else(|| {:?}.to_string())?;",
+            dest,
+            var,
+            format!("Variable '{}' not found", var)
+        ),
+        Jump(target) => format!("block = {};", target),
+        Call { addr } => format!("stack.push({}); block = {};", pc + 1, addr),
+        JumpRel(offset) => format!("block = {};", instruction::relative(pc, *offset)),
+        CallRel(offset) => format!(
+            "stack.push({}); block = {};",
+            pc + 1,
+            instruction::relative(pc, *offset)
+        ),
+        ConditionalJump { cond, target } => format!(
+            "if r[{}] == 0.0 {{ block = {}; continue; }}",
+            cond, target
+        ),
+        Return => "block = stack.pop().ok_or_else(|| \"Call stack is empty, cannot return\".to_string())?;".to_string(),
+        Halt => "return Ok(());".to_string(),
+        TryBegin { .. }
+        | TryEnd
+        | Throw { .. }
+        | MakeClosure { .. }
+        | CallClosure { .. }
+        | GetUpvalue { .. }
+        | SetUpvalue { .. }
+        | NewRecord { .. }
+        | GetField { .. }
+        | SetField { .. }
+        | MapNew { .. }
+        | MapGet { .. }
+        | MapSet { .. }
+        | MapHas { .. }
+        | MapDelete { .. }
+        | Spawn { .. }
+        | Yield
+        | Resume { .. }
+        | CallHost { .. }
+        | Send { .. }
+        | Recv { .. }
+        | Rand { .. }
+        | Push { .. }
+        | Pop { .. }
+        | CallArgs { .. }
+        | Arity { .. } => unreachable!("rejected by check_supported"),
+        // Every instruction starts a block in a program with a computed jump
+        JumpIndirect { src } => format!(
+            "let v = r[{}]; if !(v >= 0.0 && v.fract() == 0.0 && v < {}.0) {{ return Err(\"Program counter out of bounds\".to_string()); }} block = v as usize;",
+            src, len
+        ),
+        CallIndirect { src } => format!(
+            "let v = r[{}]; if !(v >= 0.0 && v.fract() == 0.0 && v < {}.0) {{ return Err(\"Program counter out of bounds\".to_string()); }} stack.push({}); block = v as usize;",
+            src,
+            len,
+            pc + 1
+        ),
+        LoadAddr { dest, addr } => format!("r[{}] = {}.0;", dest, addr),
+        Switch {
+            src,
+            table,
+            default,
+        } => format!(
+            "let v = r[{}]; let table: &[usize] = &{:?}; block = if v >= 0.0 && v.fract() == 0.0 && v < table.len() as f64 {{ table[v as usize] }} else {{ {} }};",
+            src, table, default
+        ),
+        AddImm {
+            dest,
+            src,
+            imm,
+            value,
+        } => format!(
+            "r[{}] = {}; r[{}] = r[{}] + {};",
+            imm,
+            literal(*value),
+            dest,
+            src,
+            literal(*value)
+        ),
+        CompareJump {
+            cmp,
+            dest,
+            src1,
+            src2,
+            target,
+        } => {
+            let op = match cmp {
+                Comparison::Equal => "==",
+                Comparison::LessThan => "<",
+                Comparison::GreaterThan => ">",
+            };
+            format!(
+                "{} if r[{}] == 0.0 {{ block = {}; continue; }}",
+                compare(*dest, *src1, op, *src2),
+                dest,
+                target
+            )
+        }
+    };
+    let _ = writeln!(out, "                {}", line);
+}
+
+fn compare(dest: usize, src1: usize, op: &str, src2: usize) -> String {
+    format!(
+        "r[{}] = if r[{}] {} r[{}] {{ 1.0 }} else {{ 0.0 }};",
+        dest, src1, op, src2
+    )
+}
+
+/// `value` as a Rust expression of type `f64`
+fn literal(value: f64) -> String {
+    if value.is_nan() {
+        "f64::NAN".to_string()
+    } else if value.is_infinite() {
+        let sign = if value < 0.0 { "-" } else { "" };
+        format!("{}f64::INFINITY", sign)
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Error handling, heap objects, coroutines, host calls, message channels,
+/// the random generator and the operand stack need the interpreter's
+/// runtime state
+fn check_supported(program: &Program) -> Result<(), ProgramError> {
+    use Instruction::*;
+
+    if program.entry() != 0 {
+        return Err(ProgramError::UnsupportedEntry(program.entry()));
+    }
+
+    program.check_supported(|i| {
+        !matches!(i, TryBegin { .. } | TryEnd | Throw { .. })
+            && !i.uses_heap()
+            && !i.uses_coroutines()
+            && !matches!(
+                i,
+                CallHost { .. }
+                    | Send { .. }
+                    | Recv { .. }
+                    | Rand { .. }
+                    | Push { .. }
+                    | Pop { .. }
+                    | CallArgs { .. }
+                    | Arity { .. }
+            )
+    })
+}
@@ -0,0 +1,1371 @@
+//! Assembler for register-machine text.
+//!
+//! Each line holds at most one instruction, written as `Instruction`'s
+//! `Display` prints it: `loadimm r0, 10`, `add r2, r0, r1`, `jz r0, 7`. A
+//! jump, call or address operand may name a label instead of giving an
+//! address, and `rjmp`/`rcall` take either a signed offset such as `+3` or a
+//! label. Operands may also be separated by spaces alone, and a comma
+//! after the last one is ignored. Other lines are:
+//!
+//! - `name:`, a label for the next instruction, which may follow it on the
+//!   same line
+//! - `.export name/arity`, exporting the next instruction
+//! - `.entry`, starting execution at the next instruction
+//! - `.const NAME = expr`, naming a number
+//! - `.func name(int, float) -> int`, labelling and exporting the next
+//!   instruction as a function with that signature, for `types::check`
+//!
+//! `loadimm.i`, `loadimm.f` and `loadimm.b` load a constant declared to be
+//! an int, a float or a bool.
+//!
+//! `call` may give an argument count after its target, for the callee to
+//! check with `arity rN, min, max`; leaving out `max` allows any number
+//! from `min` up.
+//!
+//! Wherever a number or address goes, an expression such as
+//! `(WIDTH * 2 + 1)` may be used instead. It is evaluated when assembling,
+//! with `+ - * / %`, unary minus, parentheses and the usual precedence, and
+//! may use constants and labels, a label standing for its address. A
+//! constant may only use the constants and labels above it.
+//!
+//! Mnemonics, directives and registers are read in any case; names are
+//! case-sensitive. A label, constant or export name is an identifier: a
+//! letter or `_`, then letters, digits, `_` and `.`. Mnemonics, registers
+//! and words that read as numbers, such as `inf`, are reserved in any case.
+//! Any name at all can be written in double quotes instead, with `\"` and
+//! `\\` standing for `"` and `\`; so can the variable, channel and host
+//! function names instructions take.
+//!
+//! Everything after a `;` outside quotes is a comment. Labels and exports
+//! are checked as `Program::label` and `Program::export` check them, and
+//! every error carries the line it was found on. Hand-written or generated
+//! code can then be checked with `Program::verify` and
+//! `Program::verify_handlers` before it runs.
+//!
+//! `assemble_full` also returns warnings about code that assembles but is
+//! probably a mistake; `Lints` decides which of them matter.
+
+use crate::instruction::{Comparison, Instruction};
+use crate::prelude::*;
+use crate::program::{Program, ProgramError};
+use crate::types::{Annotations, Signature, Type};
+use crate::{HashMap, HashSet};
+use core::cell::RefCell;
+use core::error::Error;
+use core::fmt;
+use core::ops::Range;
+use core::str::FromStr;
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    /// Line of the source the error is on, from 1
+    pub line: usize,
+    /// The columns on that line of the text at fault, from 1
+    pub columns: Range<usize>,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    MissingOperand,
+    ExtraOperand(String),
+    /// Expected a register such as `r3`
+    ExpectedRegister(String),
+    ExpectedNumber(String),
+    /// Expected a list such as `[r1, r2]`
+    ExpectedList(String),
+    UnknownType(String),
+    /// A `.func` line not of the form `name(type, ...) -> type`
+    BadSignature(String),
+    /// A constant named like another constant or a label
+    DuplicateSymbol(String),
+    /// An expression that does not parse, with the text left unparsed
+    BadExpression(String),
+    DivideByZero,
+    /// Text that is neither an identifier nor a quoted name where a name
+    /// is defined
+    BadName(String),
+    /// A mnemonic, register or number used unquoted as a name
+    ReservedName(String),
+    Program(ProgramError),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::UnknownMnemonic(op) => write!(f, "Unknown instruction '{}'", op),
+            AsmErrorKind::UnknownDirective(d) => write!(f, "Unknown directive '{}'", d),
+            AsmErrorKind::MissingOperand => write!(f, "Missing operand"),
+            AsmErrorKind::ExtraOperand(op) => write!(f, "Unexpected operand '{}'", op),
+            AsmErrorKind::ExpectedRegister(op) => write!(f, "Expected a register, found '{}'", op),
+            AsmErrorKind::ExpectedNumber(op) => write!(f, "Expected a number, found '{}'", op),
+            AsmErrorKind::ExpectedList(op) => write!(f, "Expected a list, found '{}'", op),
+            AsmErrorKind::UnknownType(name) => write!(f, "Unknown type '{}'", name),
+            AsmErrorKind::BadSignature(sig) => write!(f, "Malformed signature '{}'", sig),
+            AsmErrorKind::DuplicateSymbol(name) => {
+                write!(f, "Symbol '{}' is defined more than once", name)
+            }
+            AsmErrorKind::BadExpression(rest) => {
+                write!(f, "Malformed expression at '{}'", rest)
+            }
+            AsmErrorKind::DivideByZero => write!(f, "Division by zero in expression"),
+            AsmErrorKind::BadName(name) => write!(f, "Invalid name '{}'", name),
+            AsmErrorKind::ReservedName(name) => {
+                write!(f, "'{}' is reserved and cannot be a name", name)
+            }
+            AsmErrorKind::Program(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+impl AsmErrorKind {
+    /// A short name for the kind of error, stable across releases
+    pub fn code(&self) -> &'static str {
+        match self {
+            AsmErrorKind::UnknownMnemonic(_) => "unknown-mnemonic",
+            AsmErrorKind::UnknownDirective(_) => "unknown-directive",
+            AsmErrorKind::MissingOperand => "missing-operand",
+            AsmErrorKind::ExtraOperand(_) => "extra-operand",
+            AsmErrorKind::ExpectedRegister(_) => "expected-register",
+            AsmErrorKind::ExpectedNumber(_) => "expected-number",
+            AsmErrorKind::ExpectedList(_) => "expected-list",
+            AsmErrorKind::UnknownType(_) => "unknown-type",
+            AsmErrorKind::BadSignature(_) => "bad-signature",
+            AsmErrorKind::DuplicateSymbol(_) => "duplicate-symbol",
+            AsmErrorKind::BadExpression(_) => "bad-expression",
+            AsmErrorKind::DivideByZero => "divide-by-zero",
+            AsmErrorKind::BadName(_) => "bad-name",
+            AsmErrorKind::ReservedName(_) => "reserved-name",
+            AsmErrorKind::Program(ProgramError::UnknownSymbol(_)) => "unknown-symbol",
+            AsmErrorKind::Program(ProgramError::DuplicateLabel(_)) => "duplicate-label",
+            AsmErrorKind::Program(ProgramError::DuplicateExport(_)) => "duplicate-export",
+            AsmErrorKind::Program(_) => "invalid-program",
+        }
+    }
+}
+
+/// Every instruction `assemble` knows, for suggesting one in place of a
+/// misspelling
+pub const MNEMONICS: [&str; 56] = [
+    "loadimm",
+    "loadimm.i",
+    "loadimm.f",
+    "loadimm.b",
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "eq",
+    "lt",
+    "gt",
+    "print",
+    "throw",
+    "jmpr",
+    "callr",
+    "callc",
+    "map",
+    "recv",
+    "rand",
+    "push",
+    "pop",
+    "switch",
+    "jmp",
+    "call",
+    "rjmp",
+    "rcall",
+    "jz",
+    "lea",
+    "getupval",
+    "setupval",
+    "record",
+    "spawn",
+    "getfield",
+    "setfield",
+    "mapget",
+    "maphas",
+    "mapset",
+    "mapdel",
+    "resume",
+    "closure",
+    "callhost",
+    "try",
+    "store",
+    "send",
+    "load",
+    "mov",
+    "not",
+    "ret",
+    "halt",
+    "endtry",
+    "yield",
+    "addimm",
+    "eqjz",
+    "ltjz",
+    "gtjz",
+    "arity",
+];
+
+/// Something that assembles but is probably a mistake
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// Line of the source the warning is about, from 1
+    pub line: usize,
+    /// The columns on that line of the text it is about, from 1
+    pub columns: Range<usize>,
+    pub kind: WarningKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WarningKind {
+    /// A label nothing refers to
+    UnusedLabel(String),
+    /// Code nothing jumps to after an instruction that never falls
+    /// through, named by its mnemonic
+    Unreachable(&'static str),
+    /// A variable stored to but never loaded
+    UnusedVariable(String),
+    /// A literal that overflows, underflows or rounds as an `f64`, and the
+    /// value it becomes
+    InexactLiteral(String, f64),
+}
+
+/// The name of each kind of warning, as `Lints::apply` takes them
+pub const WARNINGS: [&str; 4] = [
+    "unused-label",
+    "unreachable",
+    "unused-variable",
+    "inexact-literal",
+];
+
+impl WarningKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::UnusedLabel(_) => WARNINGS[0],
+            WarningKind::Unreachable(_) => WARNINGS[1],
+            WarningKind::UnusedVariable(_) => WARNINGS[2],
+            WarningKind::InexactLiteral(..) => WARNINGS[3],
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: ", self.line)?;
+        match &self.kind {
+            WarningKind::UnusedLabel(name) => write!(f, "Label '{}' is never used", name)?,
+            WarningKind::Unreachable(after) => write!(f, "Unreachable code after '{}'", after)?,
+            WarningKind::UnusedVariable(var) => {
+                write!(f, "Variable '{}' is stored but never loaded", var)?
+            }
+            WarningKind::InexactLiteral(text, value) => {
+                write!(f, "Literal '{}' becomes {}", text, value)?
+            }
+        }
+        write!(f, " ({})", self.kind.name())
+    }
+}
+
+/// What to do about a kind of warning
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Level {
+    Allow,
+    #[default]
+    Warn,
+    /// Treat it as an error
+    Deny,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Level::Allow),
+            "warn" => Ok(Level::Warn),
+            "deny" => Ok(Level::Deny),
+            _ => Err(format!("invalid warning level '{}'", s)),
+        }
+    }
+}
+
+/// The level of each kind of warning; every kind warns by default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Lints {
+    all: Level,
+    levels: HashMap<&'static str, Level>,
+}
+
+impl Lints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn level(&self, kind: &WarningKind) -> Level {
+        self.levels.get(kind.name()).copied().unwrap_or(self.all)
+    }
+
+    /// Set every kind to a level such as `deny`, or one kind with
+    /// `name=level`, e.g. `unused-label=allow`. Later settings win.
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        match spec.split_once('=') {
+            Some((name, level)) => {
+                let name = WARNINGS
+                    .iter()
+                    .find(|w| **w == name.trim())
+                    .ok_or_else(|| format!("unknown warning '{}'", name.trim()))?;
+                self.levels.insert(name, level.trim().parse()?);
+            }
+            None => {
+                self.all = spec.trim().parse()?;
+                self.levels.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a line other than an instruction says about the next instruction
+enum Marker {
+    Label(String),
+    Export(String, usize),
+    Entry,
+    Func(String, Signature),
+}
+
+/// A program with what the assembler learned about its source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assembly {
+    pub program: Program,
+    /// The line each instruction is on, from 1
+    pub lines: Vec<usize>,
+    /// Types from `.func` lines and typed `loadimm`s
+    pub types: Annotations,
+    /// In line order
+    pub warnings: Vec<Warning>,
+}
+
+/// Assemble `source` into a program
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    assemble_full(source).map(|assembly| assembly.program)
+}
+
+/// As `assemble`, also returning the line each instruction is on, from 1
+pub fn assemble_with_lines(source: &str) -> Result<(Program, Vec<usize>), AsmError> {
+    assemble_full(source).map(|assembly| (assembly.program, assembly.lines))
+}
+
+/// As `assemble`, also returning source lines, type annotations and
+/// warnings
+pub fn assemble_full(source: &str) -> Result<Assembly, AsmError> {
+    assemble_all(source).map_err(|mut errors| errors.swap_remove(0))
+}
+
+/// Most errors `assemble_all` returns
+pub const MAX_ERRORS: usize = 20;
+
+/// As `assemble_full`, carrying on past errors to return, in line order, up
+/// to `MAX_ERRORS` of them.
+///
+/// Errors that only follow from earlier ones are left out: uses of a name
+/// whose definition failed, and uses of an unknown name after the first.
+pub fn assemble_all(source: &str) -> Result<Assembly, Vec<AsmError>> {
+    let raw: Vec<&str> = source.lines().collect();
+    // Every slice of a line's text knows where on the line it came from
+    let error = |line: usize, part: &str, kind: AsmErrorKind| AsmError {
+        line,
+        columns: columns(raw[line - 1], part),
+        kind,
+    };
+    let mut errors = Vec::new();
+
+    // First pass: find each label's address, so operands can refer forward
+    let mut symbols = Symbols::default();
+    let mut markers = Vec::new();
+    let mut lines = Vec::new();
+    let mut types = Annotations::default();
+    for (i, text) in raw.iter().enumerate() {
+        let line = i + 1;
+        let (label, text) = split_label(strip_comment(text).trim());
+        if let Some(part) = label {
+            match name(part) {
+                Err(kind) => errors.push(error(line, part, kind)),
+                Ok(name) if symbols.constants.contains_key(&name) => {
+                    let kind = AsmErrorKind::DuplicateSymbol(name);
+                    errors.push(error(line, part, kind));
+                }
+                Ok(name) if symbols.labels.contains_key(&name) => {
+                    let e = ProgramError::DuplicateLabel(name);
+                    errors.push(error(line, part, AsmErrorKind::Program(e)));
+                }
+                Ok(name) => {
+                    symbols.labels.insert(name.clone(), lines.len());
+                    markers.push((line, part, lines.len(), Marker::Label(name)));
+                }
+            }
+        }
+        if text.is_empty() {
+            continue;
+        } else if let Some(directive) = text.strip_prefix('.') {
+            match directive_marker(directive, lines.len(), &mut symbols) {
+                Ok(Some((marker, part))) => markers.push((line, part, lines.len(), marker)),
+                Ok(None) => {}
+                Err((kind, part)) if !symbols.follows_failure(&kind) => {
+                    errors.push(error(line, part, kind))
+                }
+                Err(_) => {}
+            }
+        } else {
+            lines.push((line, text));
+        }
+    }
+
+    // Second pass: parse each instruction now that every label is known
+    let mut warnings = Vec::new();
+    let mut unknown = HashSet::new();
+    let mut instructions = Vec::with_capacity(lines.len());
+    for (pc, &(line, text)) in lines.iter().enumerate() {
+        let (op, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mut found = Vec::new();
+        let typed = op.get(..8).filter(|p| p.eq_ignore_ascii_case("loadimm."));
+        let parsed = match typed {
+            None => instruction(op, rest, pc, &symbols, &mut found),
+            Some(_) => match &op[8..] {
+                "i" | "I" => Ok(Type::Int),
+                "f" | "F" => Ok(Type::Float),
+                "b" | "B" => Ok(Type::Bool),
+                ty => Err((AsmErrorKind::UnknownType(ty.to_string()), ty)),
+            }
+            .and_then(|ty| {
+                types.immediates.insert(pc, ty);
+                instruction("loadimm", rest, pc, &symbols, &mut found)
+            }),
+        };
+        warnings.extend(found.into_iter().map(|(kind, part)| Warning {
+            line,
+            columns: columns(raw[line - 1], part),
+            kind,
+        }));
+        match parsed {
+            Ok(instr) => instructions.push(instr),
+            Err((kind, part)) => {
+                let repeated = match &kind {
+                    AsmErrorKind::Program(ProgramError::UnknownSymbol(name)) => {
+                        !unknown.insert(name.clone())
+                    }
+                    _ => false,
+                };
+                if !repeated && !symbols.follows_failure(&kind) {
+                    errors.push(error(line, part, kind));
+                }
+                // Keep later addresses where their labels say
+                instructions.push(Instruction::Halt);
+            }
+        }
+    }
+
+    let mut program = Program::new(instructions);
+    let mut unused = Vec::new();
+    for (line, part, addr, marker) in markers {
+        if let Marker::Label(name) = &marker
+            && !symbols.used.borrow().contains(name)
+        {
+            unused.push((line, part, addr, name.clone()));
+        }
+        let result = match marker {
+            Marker::Label(name) => program.label(name, addr),
+            Marker::Export(name, arity) => program.export(name, addr, arity),
+            Marker::Entry => program.set_entry_addr(addr),
+            Marker::Func(name, signature) => {
+                let arity = signature.params.len();
+                types.functions.insert(addr, signature);
+                program
+                    .label(name.clone(), addr)
+                    .and_then(|()| program.export(name, addr, arity))
+            }
+        };
+        if let Err(e) = result {
+            errors.push(error(line, part, AsmErrorKind::Program(e)));
+        }
+    }
+    if !errors.is_empty() {
+        errors.sort_by_key(|e| (e.line, e.columns.start));
+        errors.truncate(MAX_ERRORS);
+        return Err(errors);
+    }
+
+    // A label where an export or the program starts names it for the host
+    let named =
+        |addr: usize| addr == program.entry() || program.exports().iter().any(|e| e.addr == addr);
+    warnings.extend(
+        unused
+            .into_iter()
+            .filter(|&(_, _, addr, _)| !named(addr))
+            .map(|(line, part, _, name)| Warning {
+                line,
+                columns: columns(raw[line - 1], part),
+                kind: WarningKind::UnusedLabel(name),
+            }),
+    );
+    let spans: Vec<(usize, Range<usize>)> = lines
+        .iter()
+        .map(|&(line, text)| (line, columns(raw[line - 1], text)))
+        .collect();
+    warnings.extend(lint(&program, &spans));
+    warnings.sort_by_key(|w| (w.line, w.columns.start));
+    Ok(Assembly {
+        program,
+        lines: spans.into_iter().map(|(line, _)| line).collect(),
+        types,
+        warnings,
+    })
+}
+
+/// What the directive `directive`, without its `.`, says about the next
+/// instruction, at `addr`, and the text naming it, defining any constant or
+/// function it names in `symbols`. A name whose definition fails is marked
+/// as such.
+fn directive_marker<'a>(
+    directive: &'a str,
+    addr: usize,
+    symbols: &mut Symbols,
+) -> Result<Option<(Marker, &'a str)>, (AsmErrorKind, &'a str)> {
+    let (keyword, arg) = directive
+        .split_once(char::is_whitespace)
+        .unwrap_or((directive, ""));
+    let arg = arg.trim();
+    let marker = match keyword.to_ascii_lowercase().as_str() {
+        "export" => {
+            let (export, arity) = arg
+                .split_once('/')
+                .ok_or_else(|| (AsmErrorKind::ExpectedNumber(arg.to_string()), arg))?;
+            let (export, arity) = (export.trim(), arity.trim());
+            let name = name(export).map_err(|kind| (kind, export))?;
+            let count = symbols.integer(arity).map_err(|kind| (kind, arity))?;
+            (Marker::Export(name, count), export)
+        }
+        "const" => {
+            let (constant, expr) = arg
+                .split_once('=')
+                .ok_or_else(|| (AsmErrorKind::BadExpression(arg.to_string()), arg))?;
+            let (constant, expr) = (constant.trim(), expr.trim());
+            let defined = name(constant)
+                .map_err(|kind| (kind, constant))
+                .and_then(|name| {
+                    let value = symbols.eval(expr).map_err(|kind| (kind, expr))?;
+                    Ok((name, value))
+                });
+            let (name, value) = defined.inspect_err(|_| symbols.fail(constant))?;
+            if symbols.get(&name).is_some() {
+                return Err((AsmErrorKind::DuplicateSymbol(name), constant));
+            }
+            symbols.constants.insert(name, value);
+            return Ok(None);
+        }
+        "func" => {
+            let defined =
+                signature(arg)
+                    .map_err(|kind| (kind, arg))
+                    .and_then(|(func, signature)| {
+                        Ok((name(func).map_err(|kind| (kind, func))?, func, signature))
+                    });
+            let (name, func, signature) = defined
+                .inspect_err(|_| symbols.fail(arg.split('(').next().unwrap_or("").trim()))?;
+            if symbols.constants.contains_key(&name) {
+                return Err((AsmErrorKind::DuplicateSymbol(name), func));
+            }
+            if symbols.labels.contains_key(&name) {
+                let e = ProgramError::DuplicateLabel(name);
+                return Err((AsmErrorKind::Program(e), func));
+            }
+            symbols.labels.insert(name.clone(), addr);
+            (Marker::Func(name, signature), func)
+        }
+        "entry" if arg.is_empty() => (Marker::Entry, directive),
+        "entry" => return Err((AsmErrorKind::ExtraOperand(arg.to_string()), arg)),
+        _ => {
+            let kind = AsmErrorKind::UnknownDirective(keyword.to_string());
+            return Err((kind, keyword));
+        }
+    };
+    Ok(Some(marker))
+}
+
+/// The columns, from 1, that `part` takes up in `line`, which it must be a
+/// slice of; anything else is taken to be the whole line
+fn columns(line: &str, part: &str) -> Range<usize> {
+    let start = (part.as_ptr() as usize).wrapping_sub(line.as_ptr() as usize);
+    if start
+        .checked_add(part.len())
+        .is_some_and(|end| end <= line.len())
+    {
+        start + 1..start + 1 + part.len()
+    } else {
+        1..line.len() + 1
+    }
+}
+
+/// Warnings about unreachable code and unused variables in `program`, each
+/// of whose instructions is at the line and columns in `spans`
+fn lint(program: &Program, spans: &[(usize, Range<usize>)]) -> Vec<Warning> {
+    let code = &program.instructions;
+    let at = |pc: usize, kind| Warning {
+        line: spans[pc].0,
+        columns: spans[pc].1.clone(),
+        kind,
+    };
+    let mut warnings = Vec::new();
+    // A computed jump could land anywhere
+    if !code.iter().any(Instruction::is_computed_jump) {
+        let mut referred: HashSet<usize> = code
+            .iter()
+            .enumerate()
+            .flat_map(|(pc, instr)| instr.addresses(pc))
+            .collect();
+        referred.insert(program.entry());
+        referred.extend(program.exports().iter().map(|e| e.addr));
+        referred.extend(program.labels().iter().map(|l| l.addr));
+        for (pc, pair) in code.windows(2).enumerate() {
+            if pair[0].is_terminator() && !referred.contains(&(pc + 1)) {
+                warnings.push(at(pc + 1, WarningKind::Unreachable(pair[0].mnemonic())));
+            }
+        }
+    }
+
+    let loaded: HashSet<&str> = code
+        .iter()
+        .filter_map(|instr| match instr {
+            Instruction::Load { var, .. } => Some(var.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut reported = HashSet::new();
+    for (pc, instr) in code.iter().enumerate() {
+        if let Instruction::Store { var, .. } = instr
+            && !loaded.contains(var.as_str())
+            && reported.insert(var.as_str())
+        {
+            warnings.push(at(pc, WarningKind::UnusedVariable(var.clone())));
+        }
+    }
+    warnings
+}
+
+/// A warning if the literal `text` does not survive becoming `value`
+fn inexact(text: &str, value: f64) -> Option<WarningKind> {
+    let digits = text.trim_start_matches(['-', '+']);
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    let mantissa = digits.split(['e', 'E']).next().unwrap_or("");
+    let nonzero = mantissa.chars().any(|c| matches!(c, '1'..='9'));
+    let whole = mantissa.chars().all(|c| c.is_ascii_digit()) && !digits.contains(['e', 'E']);
+    let significant = match digits.trim_start_matches('0') {
+        "" => "0",
+        significant => significant,
+    };
+    let rounded = whole && format!("{:.0}", value.abs()) != significant;
+    (value.is_infinite() || (value == 0.0 && nonzero) || rounded)
+        .then(|| WarningKind::InexactLiteral(text.to_string(), value))
+}
+
+/// The name and signature in `name(type, ...) -> type`; the return type
+/// may be left out
+fn signature(text: &str) -> Result<(&str, Signature), AsmErrorKind> {
+    let bad = || AsmErrorKind::BadSignature(text.to_string());
+    let (name, rest) = text.split_once('(').ok_or_else(bad)?;
+    let (params, ret) = rest.split_once(')').ok_or_else(bad)?;
+    let parse = |ty: &str| {
+        ty.parse::<Type>()
+            .map_err(|_| AsmErrorKind::UnknownType(ty.to_string()))
+    };
+    let params = params
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(parse)
+        .collect::<Result<_, _>>()?;
+    let ret = match ret.trim() {
+        "" => None,
+        ret => Some(parse(ret.strip_prefix("->").ok_or_else(bad)?.trim())?),
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(bad());
+    }
+    Ok((name, Signature { params, ret }))
+}
+
+/// Parse the instruction `op` with operands `rest`, adding any warnings
+/// about it to `warnings`. Errors and warnings come with the text they are
+/// about.
+fn instruction<'a>(
+    op: &'a str,
+    rest: &'a str,
+    pc: usize,
+    symbols: &Symbols,
+    warnings: &mut Vec<(WarningKind, &'a str)>,
+) -> Result<Instruction, (AsmErrorKind, &'a str)> {
+    let mut ops = Operands {
+        items: split_operands(rest),
+        pos: 0,
+        rest,
+        current: op,
+        pc,
+        symbols,
+        warnings: Vec::new(),
+    };
+    let parsed = operands(&op.to_ascii_lowercase(), &mut ops).and_then(|instr| {
+        ops.finish()?;
+        Ok(instr)
+    });
+    warnings.append(&mut ops.warnings);
+    parsed.map_err(|kind| (kind, ops.current))
+}
+
+fn operands(op: &str, ops: &mut Operands) -> Result<Instruction, AsmErrorKind> {
+    use Instruction::*;
+    let instr = match op {
+        "loadimm" => LoadImm {
+            dest: ops.reg()?,
+            value: ops.value()?,
+        },
+        "add" => Add {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "sub" => Sub {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "mul" => Mul {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "div" => Div {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "eq" => Equal {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "lt" => LessThan {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "gt" => GreaterThan {
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+        },
+        "print" => Print { src: ops.reg()? },
+        "throw" => Throw { src: ops.reg()? },
+        "jmpr" => JumpIndirect { src: ops.reg()? },
+        "callr" => CallIndirect { src: ops.reg()? },
+        "callc" => CallClosure { src: ops.reg()? },
+        "map" => MapNew { dest: ops.reg()? },
+        "recv" => Recv { dest: ops.reg()? },
+        "rand" => Rand { dest: ops.reg()? },
+        "push" => Push { src: ops.reg()? },
+        "pop" => Pop { dest: ops.reg()? },
+        "switch" => Switch {
+            src: ops.reg()?,
+            table: ops.targets()?,
+            default: ops.target()?,
+        },
+        "jmp" => Jump(ops.target()?),
+        "call" => {
+            let addr = ops.target()?;
+            match ops.optional(Operands::number)? {
+                Some(argc) => CallArgs { addr, argc },
+                None => Call { addr },
+            }
+        }
+        "arity" => Arity {
+            dest: ops.reg()?,
+            min: ops.number()?,
+            max: ops.optional(Operands::number)?,
+        },
+        "rjmp" => JumpRel(ops.offset()?),
+        "rcall" => CallRel(ops.offset()?),
+        "jz" => ConditionalJump {
+            cond: ops.reg()?,
+            target: ops.target()?,
+        },
+        "lea" => LoadAddr {
+            dest: ops.reg()?,
+            addr: ops.target()?,
+        },
+        "getupval" => GetUpvalue {
+            dest: ops.reg()?,
+            index: ops.number()?,
+        },
+        "setupval" => SetUpvalue {
+            src: ops.reg()?,
+            index: ops.number()?,
+        },
+        "record" => NewRecord {
+            dest: ops.reg()?,
+            fields: ops.number()?,
+        },
+        "spawn" => Spawn {
+            dest: ops.reg()?,
+            addr: ops.target()?,
+        },
+        "getfield" => GetField {
+            dest: ops.reg()?,
+            record: ops.reg()?,
+            index: ops.number()?,
+        },
+        "setfield" => SetField {
+            record: ops.reg()?,
+            index: ops.number()?,
+            src: ops.reg()?,
+        },
+        "mapget" => MapGet {
+            dest: ops.reg()?,
+            map: ops.reg()?,
+            key: ops.reg()?,
+        },
+        "maphas" => MapHas {
+            dest: ops.reg()?,
+            map: ops.reg()?,
+            key: ops.reg()?,
+        },
+        "mapset" => MapSet {
+            map: ops.reg()?,
+            key: ops.reg()?,
+            src: ops.reg()?,
+        },
+        "mapdel" => MapDelete {
+            map: ops.reg()?,
+            key: ops.reg()?,
+        },
+        "resume" => Resume {
+            dest: ops.reg()?,
+            id: ops.reg()?,
+        },
+        "closure" => MakeClosure {
+            dest: ops.reg()?,
+            addr: ops.target()?,
+            captures: ops.regs()?,
+        },
+        "callhost" => CallHost {
+            dest: ops.reg()?,
+            name: ops.name()?,
+            args: ops.regs()?,
+        },
+        "try" => TryBegin {
+            dest: ops.reg()?,
+            handler: ops.target()?,
+        },
+        "store" => Store {
+            src: ops.reg()?,
+            var: ops.name()?,
+        },
+        "send" => Send {
+            src: ops.reg()?,
+            to: ops.name()?,
+        },
+        "load" => Load {
+            dest: ops.reg()?,
+            var: ops.name()?,
+        },
+        "mov" => Mov {
+            dest: ops.reg()?,
+            src: ops.reg()?,
+        },
+        "not" => Not {
+            dest: ops.reg()?,
+            src: ops.reg()?,
+        },
+        "ret" => Return,
+        "halt" => Halt,
+        "endtry" => TryEnd,
+        "yield" => Yield,
+        "addimm" => AddImm {
+            dest: ops.reg()?,
+            src: ops.reg()?,
+            imm: ops.reg()?,
+            value: ops.value()?,
+        },
+        "eqjz" | "ltjz" | "gtjz" => CompareJump {
+            cmp: match op {
+                "eqjz" => Comparison::Equal,
+                "ltjz" => Comparison::LessThan,
+                _ => Comparison::GreaterThan,
+            },
+            dest: ops.reg()?,
+            src1: ops.reg()?,
+            src2: ops.reg()?,
+            target: ops.target()?,
+        },
+        _ => return Err(AsmErrorKind::UnknownMnemonic(ops.current.to_string())),
+    };
+    Ok(instr)
+}
+
+/// The operands of one instruction, taken front to back
+struct Operands<'a, 's> {
+    items: Vec<&'a str>,
+    pos: usize,
+    /// All of the operands' text
+    rest: &'a str,
+    /// The text being parsed, for errors to point at
+    current: &'a str,
+    /// Address of the instruction, for offsets to labels
+    pc: usize,
+    symbols: &'s Symbols,
+    warnings: Vec<(WarningKind, &'a str)>,
+}
+
+impl<'a> Operands<'a, '_> {
+    fn next(&mut self) -> Result<&'a str, AsmErrorKind> {
+        let Some(&item) = self.items.get(self.pos) else {
+            // Point just past the last operand
+            self.current = &self.rest[self.rest.trim_end().len()..][..0];
+            return Err(AsmErrorKind::MissingOperand);
+        };
+        self.pos += 1;
+        self.current = item;
+        Ok(item)
+    }
+
+    fn finish(&mut self) -> Result<(), AsmErrorKind> {
+        match self.items.get(self.pos) {
+            Some(&extra) => {
+                self.current = extra;
+                Err(AsmErrorKind::ExtraOperand(extra.to_string()))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// An operand that may be left out at the end of the line
+    fn optional<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, AsmErrorKind>,
+    ) -> Result<Option<T>, AsmErrorKind> {
+        if self.pos == self.items.len() {
+            return Ok(None);
+        }
+        parse(self).map(Some)
+    }
+
+    fn reg(&mut self) -> Result<usize, AsmErrorKind> {
+        register(self.next()?)
+    }
+
+    fn number(&mut self) -> Result<usize, AsmErrorKind> {
+        self.symbols.integer(self.next()?)
+    }
+
+    /// A constant, including `inf` and `NaN`, or an expression
+    fn value(&mut self) -> Result<f64, AsmErrorKind> {
+        let item = self.next()?;
+        match item.parse() {
+            Ok(value) => {
+                if let Some(warning) = inexact(item, value) {
+                    self.warnings.push((warning, item));
+                }
+                Ok(value)
+            }
+            Err(_) => self.symbols.eval(item),
+        }
+    }
+
+    /// A variable or host function: a quoted name, or any other text
+    fn name(&mut self) -> Result<String, AsmErrorKind> {
+        let item = self.next()?;
+        if item.starts_with('"') {
+            return name(item);
+        }
+        Ok(item.to_string())
+    }
+
+    fn target(&mut self) -> Result<usize, AsmErrorKind> {
+        self.symbols.integer(self.next()?)
+    }
+
+    /// The offset to a label, or else a signed offset
+    fn offset(&mut self) -> Result<i32, AsmErrorKind> {
+        let item = self.next()?;
+        if let Some(target) = name(item).ok().and_then(|name| self.symbols.label(&name)) {
+            return Ok(target as i32 - self.pc as i32);
+        }
+        let offset = item.parse().or_else(|_| self.symbols.eval(item))?;
+        if offset as i32 as f64 != offset {
+            return Err(AsmErrorKind::ExpectedNumber(item.to_string()));
+        }
+        Ok(offset as i32)
+    }
+
+    fn regs(&mut self) -> Result<Vec<usize>, AsmErrorKind> {
+        list(self.next()?)?.into_iter().map(register).collect()
+    }
+
+    fn targets(&mut self) -> Result<Vec<usize>, AsmErrorKind> {
+        let items = list(self.next()?)?;
+        items
+            .into_iter()
+            .map(|item| self.symbols.integer(item))
+            .collect()
+    }
+}
+
+/// Names an expression can use
+#[derive(Default)]
+struct Symbols {
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    /// Labels looked up so far
+    used: RefCell<HashSet<String>>,
+    /// Names whose definitions had errors
+    failed: HashSet<String>,
+}
+
+impl Symbols {
+    /// Note that the definition of the name written `text` had an error
+    fn fail(&mut self, text: &str) {
+        self.failed
+            .insert(name(text).unwrap_or_else(|_| text.to_string()));
+    }
+
+    /// Whether `kind` is only about a name whose definition had an error
+    fn follows_failure(&self, kind: &AsmErrorKind) -> bool {
+        matches!(kind, AsmErrorKind::Program(ProgramError::UnknownSymbol(name))
+            if self.failed.contains(name))
+    }
+
+    fn get(&self, name: &str) -> Option<f64> {
+        self.constants
+            .get(name)
+            .copied()
+            .or_else(|| self.label(name).map(|addr| addr as f64))
+    }
+
+    fn label(&self, name: &str) -> Option<usize> {
+        let addr = *self.labels.get(name)?;
+        self.used.borrow_mut().insert(name.to_string());
+        Some(addr)
+    }
+
+    /// A count or address: a literal, a name, or an expression with a
+    /// whole, non-negative value
+    fn integer(&self, item: &str) -> Result<usize, AsmErrorKind> {
+        if let Ok(n) = item.parse() {
+            return Ok(n);
+        }
+        let value = self.eval(item)?;
+        if value < 0.0 || value as usize as f64 != value {
+            return Err(AsmErrorKind::ExpectedNumber(item.to_string()));
+        }
+        Ok(value as usize)
+    }
+
+    fn eval(&self, text: &str) -> Result<f64, AsmErrorKind> {
+        let mut expr = Expr {
+            symbols: self,
+            rest: text,
+        };
+        let value = expr.sum()?;
+        expr.finish()?;
+        Ok(value)
+    }
+}
+
+/// Recursive descent over an expression, evaluating as it goes
+struct Expr<'s, 't> {
+    symbols: &'s Symbols,
+    /// Text not yet parsed
+    rest: &'t str,
+}
+
+impl Expr<'_, '_> {
+    /// Consume `c` if it is next
+    fn eat(&mut self, c: char) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), AsmErrorKind> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            Ok(())
+        } else {
+            Err(AsmErrorKind::BadExpression(self.rest.to_string()))
+        }
+    }
+
+    /// sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<f64, AsmErrorKind> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                value += self.product()?;
+            } else if self.eat('-') {
+                value -= self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// product := unary (('*' | '/' | '%') unary)*
+    fn product(&mut self) -> Result<f64, AsmErrorKind> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') {
+                value *= self.unary()?;
+            } else if self.eat('/') {
+                value /= nonzero(self.unary()?)?;
+            } else if self.eat('%') {
+                value %= nonzero(self.unary()?)?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// unary := '-' unary | atom
+    fn unary(&mut self) -> Result<f64, AsmErrorKind> {
+        if self.eat('-') {
+            Ok(-self.unary()?)
+        } else {
+            self.atom()
+        }
+    }
+
+    /// atom := number | name | '(' sum ')'
+    fn atom(&mut self) -> Result<f64, AsmErrorKind> {
+        if self.eat('(') {
+            let value = self.sum()?;
+            if !self.eat(')') {
+                return Err(AsmErrorKind::BadExpression(self.rest.to_string()));
+            }
+            return Ok(value);
+        }
+        if self.rest.starts_with('"') {
+            let (name, rest) = unquote(self.rest)
+                .ok_or_else(|| AsmErrorKind::BadExpression(self.rest.to_string()))?;
+            self.rest = rest;
+            return self
+                .symbols
+                .get(&name)
+                .ok_or(AsmErrorKind::Program(ProgramError::UnknownSymbol(name)));
+        }
+        let len = self
+            .rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_' && c != '.')
+            .unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(len);
+        if word.is_empty() {
+            return Err(AsmErrorKind::BadExpression(self.rest.to_string()));
+        }
+        self.rest = rest;
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return word
+                .parse()
+                .map_err(|_| AsmErrorKind::ExpectedNumber(word.to_string()));
+        }
+        self.symbols
+            .get(word)
+            .ok_or_else(|| AsmErrorKind::Program(ProgramError::UnknownSymbol(word.to_string())))
+    }
+}
+
+fn nonzero(value: f64) -> Result<f64, AsmErrorKind> {
+    if value == 0.0 {
+        Err(AsmErrorKind::DivideByZero)
+    } else {
+        Ok(value)
+    }
+}
+
+/// The characters of `text` outside quoted names, with their offsets
+fn unquoted(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let (mut quoted, mut escaped) = (false, false);
+    text.char_indices().filter(move |&(_, c)| {
+        if escaped {
+            escaped = false;
+            return false;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ => return !quoted,
+        }
+        false
+    })
+}
+
+/// `line` without its comment
+pub(crate) fn strip_comment(line: &str) -> &str {
+    match unquoted(line).find(|&(_, c)| c == ';') {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+/// The label written at the start of `text`, if any, and the rest of it
+fn split_label(text: &str) -> (Option<&str>, &str) {
+    let Some((colon, _)) = unquoted(text).find(|&(_, c)| c == ':') else {
+        return (None, text);
+    };
+    let label = text[..colon].trim_end();
+    // Only a quoted name has spaces; anything else is an instruction
+    let single = label.starts_with('"') || !label.contains(char::is_whitespace);
+    if label.is_empty() || label.starts_with('.') || !single {
+        return (None, text);
+    }
+    (Some(label), text[colon + 1..].trim_start())
+}
+
+/// The name `text` stands for: a quoted name, or an identifier that is not
+/// reserved
+fn name(text: &str) -> Result<String, AsmErrorKind> {
+    if text.starts_with('"') {
+        return match unquote(text) {
+            Some((name, "")) => Ok(name),
+            _ => Err(AsmErrorKind::BadName(text.to_string())),
+        };
+    }
+    if !is_identifier(text) {
+        Err(AsmErrorKind::BadName(text.to_string()))
+    } else if is_reserved(text) {
+        Err(AsmErrorKind::ReservedName(text.to_string()))
+    } else {
+        Ok(text.to_string())
+    }
+}
+
+/// Split the quoted name at the start of `text` off the rest, returning
+/// the name it stands for
+fn unquote(text: &str) -> Option<(String, &str)> {
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut name = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((name, &text[i + 2..])),
+            '\\' => name.push(chars.next()?.1),
+            c => name.push(c),
+        }
+    }
+    None
+}
+
+/// Whether `word` is shaped like a name: a letter or `_`, then letters,
+/// digits, `_` and `.`
+pub fn is_identifier(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && word
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Whether `word` means something else where a name could go: a mnemonic,
+/// a register or a number such as `inf`, in any case
+pub fn is_reserved(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    MNEMONICS.contains(&word.as_str()) || register(&word).is_ok() || word.parse::<f64>().is_ok()
+}
+
+/// `name` as a label or constant is written: bare if it can be, quoted
+/// otherwise
+pub fn quote(name: &str) -> String {
+    if is_identifier(name) && !is_reserved(name) {
+        return name.to_string();
+    }
+    let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+/// Split at the commas outside brackets, parentheses and quotes, allowing
+/// one after the last operand. Without such commas, operands are separated
+/// by spaces instead, except on either side of a binary operator: `end - 1`
+/// is one operand, `r0 -1` two.
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut items = split_outside(text, |c| c == ',');
+    if items.len() > 1 && items.last() == Some(&"") {
+        items.pop();
+    }
+    let [text] = items[..] else {
+        return items;
+    };
+    let mut spaced: Vec<&str> = Vec::new();
+    let words = split_outside(text, char::is_whitespace);
+    for word in words.into_iter().filter(|w| !w.is_empty()) {
+        match spaced.last_mut() {
+            Some(last)
+                if last.ends_with(['+', '-', '*', '/', '%'])
+                    || word.starts_with(['*', '/', '%'])
+                    || word == "+"
+                    || word == "-" =>
+            {
+                let start = last.as_ptr() as usize - text.as_ptr() as usize;
+                let end = word.as_ptr() as usize - text.as_ptr() as usize + word.len();
+                *last = &text[start..end];
+            }
+            _ => spaced.push(word),
+        }
+    }
+    spaced
+}
+
+/// Split `text` at the characters `at` matches outside brackets,
+/// parentheses and quotes, trimming each part
+fn split_outside(text: &str, at: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in unquoted(text) {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' => depth -= 1,
+            c if depth == 0 && at(c) => {
+                items.push(text[start..i].trim());
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    let last = text[start..].trim();
+    if !last.is_empty() || !items.is_empty() {
+        items.push(last);
+    }
+    items
+}
+
+/// The items of a bracketed list such as `[r1, r2]`
+fn list(item: &str) -> Result<Vec<&str>, AsmErrorKind> {
+    let inner = item
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .ok_or_else(|| AsmErrorKind::ExpectedList(item.to_string()))?;
+    Ok(split_operands(inner)
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .collect())
+}
+
+fn register(item: &str) -> Result<usize, AsmErrorKind> {
+    item.strip_prefix(['r', 'R'])
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| AsmErrorKind::ExpectedRegister(item.to_string()))
+}
@@ -0,0 +1,4 @@
+//! Code generators that compile programs for other targets
+
+#[cfg(feature = "wasm-backend")]
+pub mod wasm;
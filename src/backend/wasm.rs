@@ -0,0 +1,578 @@
+//! Compile verified programs to WebAssembly modules.
+//!
+//! The module imports `env.print: (f64) -> ()` and exports its `memory` and a
+//! `run: () -> i32` function that executes the program from instruction 0.
+//! Registers, variables and the call stack live in memory, laid out as
+//! reported by [`WasmModule`], so the host can seed and inspect them around a
+//! call. Registers are cached in wasm locals while running.
+//!
+//! Each basic block is one arm of a `br_table` dispatch loop; straight-line
+//! code inside a block and fall-through between blocks need no dispatch.
+
+use crate::cfg::Cfg;
+use crate::instruction::{self, Comparison, Instruction};
+use crate::program::{Program, ProgramError};
+use crate::vm::VmError;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    ImportSection, Instruction as Wasm, MemArg, MemorySection, MemoryType, Module, TypeSection,
+    ValType,
+};
+
+const PAGE_SIZE: usize = 65536;
+
+/// Call stack entries reserved up front; the stack grows the memory when full
+const INITIAL_STACK: usize = 4096;
+
+/// Status `run` returns when the program halts
+pub const STATUS_HALTED: i32 = 0;
+
+/// Status `run` returns when a `ret` finds the call stack empty
+pub const STATUS_CALL_STACK_EMPTY: i32 = -1;
+
+/// Status `run` returns when a `jmpr` register holds no valid address
+pub const STATUS_PC_OUT_OF_BOUNDS: i32 = -2;
+
+/// An encoded module and the memory layout its `run` export uses
+#[derive(Debug, Clone)]
+pub struct WasmModule {
+    bytes: Vec<u8>,
+    num_registers: usize,
+    variables: Vec<String>,
+}
+
+impl WasmModule {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Variable name for each slot
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    /// Byte offset of register `reg`, an `f64`
+    pub fn register_offset(&self, reg: usize) -> usize {
+        reg * 8
+    }
+
+    /// Byte offset of the value of variable `slot`, an `f64`
+    pub fn variable_offset(&self, slot: usize) -> usize {
+        (self.num_registers + slot) * 8
+    }
+
+    /// Byte offset of the flag that is non-zero once variable `slot` is set
+    pub fn variable_set_offset(&self, slot: usize) -> usize {
+        self.variable_offset(self.variables.len()) + slot
+    }
+
+    fn stack_offset(&self) -> usize {
+        self.variable_set_offset(self.variables.len())
+            .next_multiple_of(4)
+    }
+
+    /// Translate a status returned by `run` into the interpreter's result.
+    /// Positive statuses are `1 + slot` of a variable loaded before being set.
+    pub fn status(&self, status: i32) -> Result<(), VmError> {
+        match status {
+            STATUS_HALTED => Ok(()),
+            STATUS_CALL_STACK_EMPTY => Err(VmError::CallStackEmpty),
+            STATUS_PC_OUT_OF_BOUNDS => Err(VmError::ProgramCounterOutOfBounds),
+            slot => Err(VmError::VariableNotFound(
+                self.variables[slot as usize - 1].clone(),
+            )),
+        }
+    }
+}
+
+/// Compile `program` for a register file of `num_registers`. The program
+/// must pass `verify` and `verify_registers(num_registers)`, and may not use
+/// error handling.
+pub fn compile(program: &Program, num_registers: usize) -> Result<WasmModule, ProgramError> {
+    program.verify()?;
+    program.verify_registers(num_registers)?;
+    check_supported(program)?;
+
+    let mut variables: Vec<String> = Vec::new();
+    let mut slots: HashMap<&str, usize> = HashMap::new();
+    for instr in &program.instructions {
+        if let Instruction::Store { var, .. } | Instruction::Load { var, .. } = instr {
+            slots.entry(var).or_insert_with(|| {
+                variables.push(var.clone());
+                variables.len() - 1
+            });
+        }
+    }
+
+    let mut module = WasmModule {
+        bytes: Vec::new(),
+        num_registers,
+        variables,
+    };
+    let pages = (module.stack_offset() + INITIAL_STACK * 4).div_ceil(PAGE_SIZE);
+    let function = Translator::new(&module, program, &slots).translate();
+
+    let mut types = TypeSection::new();
+    types.ty().function([ValType::F64], []);
+    types.ty().function([], [ValType::I32]);
+    let mut imports = ImportSection::new();
+    imports.import("env", "print", EntityType::Function(0));
+    let mut functions = FunctionSection::new();
+    functions.function(1);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: pages as u64,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+    let mut exports = ExportSection::new();
+    exports.export("memory", ExportKind::Memory, 0);
+    exports.export("run", ExportKind::Func, 1);
+    let mut code = CodeSection::new();
+    code.function(&function);
+
+    let mut encoded = Module::new();
+    encoded
+        .section(&types)
+        .section(&imports)
+        .section(&functions)
+        .section(&memories)
+        .section(&exports)
+        .section(&code);
+    module.bytes = encoded.finish();
+    Ok(module)
+}
+
+/// Local holding the start address of the next block to run
+const BLOCK: u32 = 0;
+/// Local holding the call stack depth
+const SP: u32 = 1;
+/// First register local
+const REGISTERS: u32 = 2;
+
+struct Translator<'a> {
+    module: &'a WasmModule,
+    program: &'a Program,
+    slots: &'a HashMap<&'a str, usize>,
+    f: Function,
+    /// Registers the program uses, loaded on entry and stored on exit
+    used: BTreeSet<usize>,
+    /// Labels between the current code and the dispatch loop
+    depth: u32,
+}
+
+impl<'a> Translator<'a> {
+    fn new(
+        module: &'a WasmModule,
+        program: &'a Program,
+        slots: &'a HashMap<&'a str, usize>,
+    ) -> Self {
+        let used = program
+            .instructions
+            .iter()
+            .flat_map(|i| i.writes().into_iter().chain(i.sources()))
+            .collect();
+        let locals = [
+            (2, ValType::I32),
+            (module.num_registers as u32, ValType::F64),
+        ];
+        Self {
+            module,
+            program,
+            slots,
+            f: Function::new(locals),
+            used,
+            depth: 0,
+        }
+    }
+
+    fn translate(mut self) -> Function {
+        let program = self.program;
+        let code = &program.instructions;
+        let blocks = Cfg::build(code).blocks;
+
+        for reg in self.used.clone() {
+            self.emit(Wasm::I32Const(0));
+            self.emit(Wasm::F64Load(mem(self.module.register_offset(reg))));
+            self.emit(Wasm::LocalSet(REGISTERS + reg as u32));
+        }
+
+        // One wrapper block per basic block, innermost first; branching to a
+        // wrapper's label continues right after its `end`, where the basic
+        // block's code is. Anything else, i.e. the end of the program, exits.
+        let exit = blocks.len() as u32;
+        let mut labels = vec![exit; code.len() + 1];
+        for (i, block) in blocks.iter().enumerate() {
+            labels[block.start] = i as u32;
+        }
+        self.emit(Wasm::Loop(BlockType::Empty));
+        self.emit(Wasm::Block(BlockType::Empty));
+        for _ in &blocks {
+            self.emit(Wasm::Block(BlockType::Empty));
+        }
+        self.emit(Wasm::LocalGet(BLOCK));
+        self.emit(Wasm::BrTable(Cow::Owned(labels), exit));
+
+        for (i, block) in blocks.iter().enumerate() {
+            self.emit(Wasm::End);
+            self.depth = (blocks.len() - i) as u32;
+            for (pc, instr) in code.iter().enumerate().take(block.end).skip(block.start) {
+                self.translate_instruction(pc, instr);
+            }
+        }
+        self.emit(Wasm::End);
+        self.emit(Wasm::End);
+
+        self.exit(STATUS_HALTED);
+        self.emit(Wasm::End);
+        self.f
+    }
+
+    fn translate_instruction(&mut self, pc: usize, instr: &Instruction) {
+        use Instruction::*;
+
+        match *instr {
+            LoadImm { dest, value } => {
+                self.emit(Wasm::F64Const(value));
+                self.set(dest);
+            }
+            Add { dest, src1, src2 } => self.binary(dest, src1, src2, Wasm::F64Add),
+            Sub { dest, src1, src2 } => self.binary(dest, src1, src2, Wasm::F64Sub),
+            Mul { dest, src1, src2 } => self.binary(dest, src1, src2, Wasm::F64Mul),
+            Div { dest, src1, src2 } => self.binary(dest, src1, src2, Wasm::F64Div),
+            Equal { dest, src1, src2 } => self.compare(Comparison::Equal, dest, src1, src2),
+            LessThan { dest, src1, src2 } => self.compare(Comparison::LessThan, dest, src1, src2),
+            GreaterThan { dest, src1, src2 } => {
+                self.compare(Comparison::GreaterThan, dest, src1, src2)
+            }
+            Mov { dest, src } => {
+                self.get(src);
+                self.set(dest);
+            }
+            Not { dest, src } => {
+                self.get(src);
+                self.emit(Wasm::F64Const(0.0));
+                self.emit(Wasm::F64Eq);
+                self.emit(Wasm::F64ConvertI32U);
+                self.set(dest);
+            }
+            Print { src } => {
+                self.get(src);
+                self.emit(Wasm::Call(0));
+            }
+            Store { src, ref var } => {
+                let slot = self.slots[var.as_str()];
+                self.emit(Wasm::I32Const(0));
+                self.get(src);
+                self.emit(Wasm::F64Store(mem(self.module.variable_offset(slot))));
+                self.emit(Wasm::I32Const(0));
+                self.emit(Wasm::I32Const(1));
+                self.emit(Wasm::I32Store8(mem1(self.module.variable_set_offset(slot))));
+            }
+            Load { dest, ref var } => {
+                let slot = self.slots[var.as_str()];
+                self.emit(Wasm::I32Const(0));
+                self.emit(Wasm::I32Load8U(mem1(self.module.variable_set_offset(slot))));
+                self.emit(Wasm::I32Eqz);
+                self.emit(Wasm::If(BlockType::Empty));
+                self.exit(slot as i32 + 1);
+                self.emit(Wasm::End);
+                self.emit(Wasm::I32Const(0));
+                self.emit(Wasm::F64Load(mem(self.module.variable_offset(slot))));
+                self.set(dest);
+            }
+            Jump(target) => self.goto(target),
+            ConditionalJump { cond, target } => self.jump_if_zero(cond, target),
+            Call { addr } => self.translate_call(pc, addr),
+            JumpRel(offset) => self.goto(instruction::relative(pc, offset)),
+            CallRel(offset) => self.translate_call(pc, instruction::relative(pc, offset)),
+            Return => self.translate_return(),
+            Halt => self.exit(STATUS_HALTED),
+            JumpIndirect { src } => {
+                self.check_address(src);
+                self.goto_register(src);
+            }
+            CallIndirect { src } => {
+                self.check_address(src);
+                self.push_frame(pc);
+                self.goto_register(src);
+            }
+            LoadAddr { dest, addr } => {
+                self.emit(Wasm::F64Const(addr as f64));
+                self.set(dest);
+            }
+            Switch {
+                src,
+                ref table,
+                default,
+            } => {
+                for (i, &target) in table.iter().enumerate() {
+                    self.get(src);
+                    self.emit(Wasm::F64Const(i as f64));
+                    self.emit(Wasm::F64Eq);
+                    self.emit(Wasm::If(BlockType::Empty));
+                    self.depth += 1;
+                    self.goto(target);
+                    self.depth -= 1;
+                    self.emit(Wasm::End);
+                }
+                self.goto(default);
+            }
+            TryBegin { .. }
+            | TryEnd
+            | Throw { .. }
+            | MakeClosure { .. }
+            | CallClosure { .. }
+            | GetUpvalue { .. }
+            | SetUpvalue { .. }
+            | NewRecord { .. }
+            | GetField { .. }
+            | SetField { .. }
+            | MapNew { .. }
+            | MapGet { .. }
+            | MapSet { .. }
+            | MapHas { .. }
+            | MapDelete { .. }
+            | Spawn { .. }
+            | Yield
+            | Resume { .. }
+            | CallHost { .. }
+            | Send { .. }
+            | Recv { .. }
+            | Rand { .. }
+            | Push { .. }
+            | Pop { .. }
+            | CallArgs { .. }
+            | Arity { .. } => unreachable!("rejected by check_supported"),
+            AddImm {
+                dest,
+                src,
+                imm,
+                value,
+            } => {
+                self.emit(Wasm::F64Const(value));
+                self.set(imm);
+                self.get(src);
+                self.emit(Wasm::F64Const(value));
+                self.emit(Wasm::F64Add);
+                self.set(dest);
+            }
+            CompareJump {
+                cmp,
+                dest,
+                src1,
+                src2,
+                target,
+            } => {
+                self.compare(cmp, dest, src1, src2);
+                self.jump_if_zero(dest, target);
+            }
+        }
+    }
+
+    fn translate_call(&mut self, pc: usize, addr: usize) {
+        self.push_frame(pc);
+        self.goto(addr);
+    }
+
+    /// Push `pc + 1` onto the call stack
+    fn push_frame(&mut self, pc: usize) {
+        let stack = self.module.stack_offset();
+
+        // Grow the memory by a page when the next frame would not fit
+        self.emit(Wasm::LocalGet(SP));
+        self.emit(Wasm::I32Const(1));
+        self.emit(Wasm::I32Add);
+        self.emit(Wasm::I32Const(2));
+        self.emit(Wasm::I32Shl);
+        self.emit(Wasm::I32Const(stack as i32));
+        self.emit(Wasm::I32Add);
+        self.emit(Wasm::MemorySize(0));
+        self.emit(Wasm::I32Const(16));
+        self.emit(Wasm::I32Shl);
+        self.emit(Wasm::I32GtU);
+        self.emit(Wasm::If(BlockType::Empty));
+        self.emit(Wasm::I32Const(1));
+        self.emit(Wasm::MemoryGrow(0));
+        self.emit(Wasm::I32Const(-1));
+        self.emit(Wasm::I32Eq);
+        self.emit(Wasm::If(BlockType::Empty));
+        self.emit(Wasm::Unreachable);
+        self.emit(Wasm::End);
+        self.emit(Wasm::End);
+
+        self.emit(Wasm::LocalGet(SP));
+        self.emit(Wasm::I32Const(2));
+        self.emit(Wasm::I32Shl);
+        self.emit(Wasm::I32Const(pc as i32 + 1));
+        self.emit(Wasm::I32Store(mem4(stack)));
+        self.emit(Wasm::LocalGet(SP));
+        self.emit(Wasm::I32Const(1));
+        self.emit(Wasm::I32Add);
+        self.emit(Wasm::LocalSet(SP));
+    }
+
+    /// Exit unless register `src` holds an in-bounds instruction address.
+    /// Every instruction starts a block in a program with a computed jump, so
+    /// any such address is a valid dispatch index.
+    fn check_address(&mut self, src: usize) {
+        let len = self.program.len() as f64;
+        self.get(src);
+        self.emit(Wasm::F64Const(0.0));
+        self.emit(Wasm::F64Ge);
+        self.get(src);
+        self.emit(Wasm::F64Const(len));
+        self.emit(Wasm::F64Lt);
+        self.emit(Wasm::I32And);
+        self.get(src);
+        self.emit(Wasm::F64Trunc);
+        self.get(src);
+        self.emit(Wasm::F64Eq);
+        self.emit(Wasm::I32And);
+        self.emit(Wasm::I32Eqz);
+        self.emit(Wasm::If(BlockType::Empty));
+        self.exit(STATUS_PC_OUT_OF_BOUNDS);
+        self.emit(Wasm::End);
+    }
+
+    fn goto_register(&mut self, src: usize) {
+        self.get(src);
+        self.emit(Wasm::I32TruncF64U);
+        self.emit(Wasm::LocalSet(BLOCK));
+        self.emit(Wasm::Br(self.depth));
+    }
+
+    fn translate_return(&mut self) {
+        self.emit(Wasm::LocalGet(SP));
+        self.emit(Wasm::I32Eqz);
+        self.emit(Wasm::If(BlockType::Empty));
+        self.exit(STATUS_CALL_STACK_EMPTY);
+        self.emit(Wasm::End);
+
+        self.emit(Wasm::LocalGet(SP));
+        self.emit(Wasm::I32Const(1));
+        self.emit(Wasm::I32Sub);
+        self.emit(Wasm::LocalTee(SP));
+        self.emit(Wasm::I32Const(2));
+        self.emit(Wasm::I32Shl);
+        self.emit(Wasm::I32Load(mem4(self.module.stack_offset())));
+        self.emit(Wasm::LocalSet(BLOCK));
+        self.emit(Wasm::Br(self.depth));
+    }
+
+    fn binary(&mut self, dest: usize, src1: usize, src2: usize, op: Wasm<'static>) {
+        self.get(src1);
+        self.get(src2);
+        self.emit(op);
+        self.set(dest);
+    }
+
+    fn compare(&mut self, cmp: Comparison, dest: usize, src1: usize, src2: usize) {
+        let op = match cmp {
+            Comparison::Equal => Wasm::F64Eq,
+            Comparison::LessThan => Wasm::F64Lt,
+            Comparison::GreaterThan => Wasm::F64Gt,
+        };
+        self.get(src1);
+        self.get(src2);
+        self.emit(op);
+        self.emit(Wasm::F64ConvertI32U);
+        self.set(dest);
+    }
+
+    fn jump_if_zero(&mut self, cond: usize, target: usize) {
+        self.get(cond);
+        self.emit(Wasm::F64Const(0.0));
+        self.emit(Wasm::F64Eq);
+        self.emit(Wasm::If(BlockType::Empty));
+        self.depth += 1;
+        self.goto(target);
+        self.depth -= 1;
+        self.emit(Wasm::End);
+    }
+
+    /// Continue at the block starting at `target`
+    fn goto(&mut self, target: usize) {
+        self.emit(Wasm::I32Const(target as i32));
+        self.emit(Wasm::LocalSet(BLOCK));
+        self.emit(Wasm::Br(self.depth));
+    }
+
+    /// Store the registers back to memory and return `status`
+    fn exit(&mut self, status: i32) {
+        for reg in self.used.clone() {
+            self.emit(Wasm::I32Const(0));
+            self.emit(Wasm::LocalGet(REGISTERS + reg as u32));
+            self.emit(Wasm::F64Store(mem(self.module.register_offset(reg))));
+        }
+        self.emit(Wasm::I32Const(status));
+        self.emit(Wasm::Return);
+    }
+
+    fn get(&mut self, reg: usize) {
+        self.emit(Wasm::LocalGet(REGISTERS + reg as u32));
+    }
+
+    fn set(&mut self, reg: usize) {
+        self.emit(Wasm::LocalSet(REGISTERS + reg as u32));
+    }
+
+    fn emit(&mut self, instr: Wasm) {
+        self.f.instruction(&instr);
+    }
+}
+
+/// Absolute address `offset` of an `f64`
+fn mem(offset: usize) -> MemArg {
+    mem_aligned(offset, 3)
+}
+
+/// `offset` from the address on the stack, of an `i32`
+fn mem4(offset: usize) -> MemArg {
+    mem_aligned(offset, 2)
+}
+
+/// Absolute address `offset` of a byte
+fn mem1(offset: usize) -> MemArg {
+    mem_aligned(offset, 0)
+}
+
+fn mem_aligned(offset: usize, align: u32) -> MemArg {
+    MemArg {
+        offset: offset as u64,
+        align,
+        memory_index: 0,
+    }
+}
+
+/// Error handling needs the interpreter's handler stack, there is no heap,
+/// second register file, operand stack or random state in linear memory,
+/// and neither a host call nor a blocked channel can suspend the module
+fn check_supported(program: &Program) -> Result<(), ProgramError> {
+    use Instruction::*;
+
+    if program.entry() != 0 {
+        return Err(ProgramError::UnsupportedEntry(program.entry()));
+    }
+
+    program.check_supported(|i| {
+        !matches!(i, TryBegin { .. } | TryEnd | Throw { .. })
+            && !i.uses_heap()
+            && !i.uses_coroutines()
+            && !matches!(
+                i,
+                CallHost { .. }
+                    | Send { .. }
+                    | Recv { .. }
+                    | Rand { .. }
+                    | Push { .. }
+                    | Pop { .. }
+                    | CallArgs { .. }
+                    | Arity { .. }
+            )
+    })
+}
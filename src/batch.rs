@@ -0,0 +1,70 @@
+//! Running one program over many inputs.
+//!
+//! `run_batch` gives each input its own VM on the rayon thread pool. Every
+//! VM shares the one `SharedProgram`, starts from the input's registers and
+//! variables, and captures what it prints, so a script can be applied to
+//! thousands of data rows at once. Results come back in input order.
+
+use crate::HashMap;
+use crate::program::SharedProgram;
+use crate::vm::{VM, VmConfig, VmError};
+use rayon::prelude::*;
+
+/// The values one run starts from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inputs {
+    /// Loaded into `r0` upward; registers past the end start at zero
+    pub registers: Vec<f64>,
+    pub variables: HashMap<String, f64>,
+}
+
+/// What one run left behind
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outputs {
+    pub registers: Vec<f64>,
+    pub variables: HashMap<String, f64>,
+    /// Values the program printed, in order
+    pub printed: Vec<f64>,
+}
+
+/// Run `program` once per input, each on its own VM with `registers`
+/// registers
+pub fn run_batch(
+    program: impl Into<SharedProgram>,
+    registers: usize,
+    inputs: Vec<Inputs>,
+) -> Vec<Result<Outputs, VmError>> {
+    run_batch_with_config(program, registers, &VmConfig::default(), inputs)
+}
+
+/// As `run_batch`, with every VM created with `config`
+pub fn run_batch_with_config(
+    program: impl Into<SharedProgram>,
+    registers: usize,
+    config: &VmConfig,
+    inputs: Vec<Inputs>,
+) -> Vec<Result<Outputs, VmError>> {
+    let program = program.into();
+    inputs
+        .into_par_iter()
+        .map(|input| run_one(program.clone(), registers, config.clone(), input))
+        .collect()
+}
+
+fn run_one(
+    program: SharedProgram,
+    registers: usize,
+    config: VmConfig,
+    input: Inputs,
+) -> Result<Outputs, VmError> {
+    let mut vm = VM::with_config(program, registers.max(input.registers.len()), config);
+    vm.registers[..input.registers.len()].copy_from_slice(&input.registers);
+    vm.variables = input.variables;
+    vm.capture_output();
+    vm.run()?;
+    Ok(Outputs {
+        printed: vm.take_output(),
+        registers: vm.registers,
+        variables: vm.variables,
+    })
+}
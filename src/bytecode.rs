@@ -0,0 +1,753 @@
+//! Binary program format.
+//!
+//! The layout is designed to be read in place from a borrowed (e.g. memory-mapped)
+//! slice without copying it first:
+//!
+//! ```text
+//! header    24 bytes   magic "ZYDE", version: u16, features: u16,
+//!                      instruction count: u32, export count: u32,
+//!                      string count: u32, entry address: u32
+//! code      16 bytes per instruction
+//! exports   16 bytes per export: name string: u32, addr: u32, arity: u32, reserved: u32
+//! strings   u32 offset per string (relative to the string data), then the data:
+//!           each entry is a u32 byte length followed by its bytes
+//! ```
+//!
+//! String entries hold UTF-8 names, except for a `callhost`'s argument list,
+//! which is stored as an entry of little-endian u32 register indices.
+//!
+//! Every instruction is `opcode: u8, 3 reserved bytes, a: u32, b: u32, c: u32`,
+//! except `LoadImm`, which stores its `f64` in the `b`/`c` slot. All integers are
+//! little-endian, and the fixed width lets instruction `i` be decoded on its own.
+//!
+//! Decoding never trusts the input: every count, offset and length is checked
+//! against the bytes actually present before it is used, so a truncated or
+//! hostile file yields a `DecodeError` rather than a panic or a huge
+//! allocation. Operands that size an allocation at run time are capped too.
+//!
+//! The version is that of the instruction set, and is bumped whenever an
+//! opcode is added or changes meaning; loaders reject files newer than they
+//! are. `features` lists the optional parts of the VM the code needs, so a
+//! host without them refuses the file up front rather than failing part way
+//! through a run. Version 1 files left it zero, and their features are found
+//! by scanning the code instead.
+
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+use core::error::Error;
+use core::fmt;
+use core::ops::BitOr;
+
+pub const MAGIC: [u8; 4] = *b"ZYDE";
+/// Instruction set version written to the header
+pub const VERSION: u16 = 5;
+
+const HEADER_SIZE: usize = 24;
+const INSTRUCTION_SIZE: usize = 16;
+const EXPORT_SIZE: usize = 16;
+
+/// Largest record a `record` instruction may allocate
+pub const MAX_RECORD_FIELDS: usize = u16::MAX as usize;
+
+mod opcode {
+    pub const LOAD_IMM: u8 = 0x01;
+    pub const ADD: u8 = 0x02;
+    pub const SUB: u8 = 0x03;
+    pub const MUL: u8 = 0x04;
+    pub const DIV: u8 = 0x05;
+    pub const PRINT: u8 = 0x06;
+    pub const JUMP: u8 = 0x07;
+    pub const CALL: u8 = 0x08;
+    pub const CONDITIONAL_JUMP: u8 = 0x09;
+    pub const RETURN: u8 = 0x0a;
+    pub const STORE: u8 = 0x0b;
+    pub const LOAD: u8 = 0x0c;
+    pub const MOV: u8 = 0x0d;
+    pub const EQUAL: u8 = 0x0e;
+    pub const LESS_THAN: u8 = 0x0f;
+    pub const GREATER_THAN: u8 = 0x10;
+    pub const NOT: u8 = 0x11;
+    pub const HALT: u8 = 0x12;
+    pub const TRY_BEGIN: u8 = 0x13;
+    pub const TRY_END: u8 = 0x14;
+    pub const THROW: u8 = 0x15;
+    pub const JUMP_INDIRECT: u8 = 0x16;
+    pub const CALL_INDIRECT: u8 = 0x17;
+    pub const LOAD_ADDR: u8 = 0x18;
+    pub const CALL_CLOSURE: u8 = 0x19;
+    pub const GET_UPVALUE: u8 = 0x1a;
+    pub const SET_UPVALUE: u8 = 0x1b;
+    pub const NEW_RECORD: u8 = 0x1c;
+    pub const GET_FIELD: u8 = 0x1d;
+    pub const SET_FIELD: u8 = 0x1e;
+    pub const MAP_NEW: u8 = 0x1f;
+    pub const MAP_GET: u8 = 0x20;
+    pub const MAP_SET: u8 = 0x21;
+    pub const MAP_HAS: u8 = 0x22;
+    pub const MAP_DELETE: u8 = 0x23;
+    pub const SPAWN: u8 = 0x24;
+    pub const YIELD: u8 = 0x25;
+    pub const RESUME: u8 = 0x26;
+    pub const SEND: u8 = 0x27;
+    pub const RECV: u8 = 0x28;
+    pub const RAND: u8 = 0x29;
+    pub const JUMP_REL: u8 = 0x2a;
+    pub const CALL_REL: u8 = 0x2b;
+    pub const PUSH: u8 = 0x2c;
+    pub const POP: u8 = 0x2d;
+    pub const CALL_ARGS: u8 = 0x2e;
+    pub const ARITY: u8 = 0x2f;
+    pub const CALL_HOST: u8 = 0x30;
+}
+
+/// Optional parts of the VM that a program's code relies on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(u16);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Closures, records and maps
+    pub const HEAP: Features = Features(1);
+    /// Variables and message channels, named through the string table
+    pub const STRINGS: Features = Features(1 << 1);
+    /// Host function calls
+    pub const HOST_CALLS: Features = Features(1 << 2);
+    /// Every feature this version knows of
+    pub const ALL: Features = Features(0b111);
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Whether every feature in `other` is also in `self`
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features `instr` needs
+    pub fn of(instr: &Instruction) -> Features {
+        match instr {
+            _ if instr.uses_heap() => Features::HEAP,
+            Instruction::Store { .. } | Instruction::Load { .. } | Instruction::Send { .. } => {
+                Features::STRINGS
+            }
+            Instruction::CallHost { .. } => Features::HOST_CALLS,
+            _ => Features::NONE,
+        }
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    /// A register, address, or count does not fit in 32 bits, or a record
+    /// has more than `MAX_RECORD_FIELDS` fields
+    OperandTooLarge(usize),
+    /// A fused superinstruction, which only exists in memory, or a switch or
+    /// closure, whose operand list does not fit the fixed-width layout
+    Unencodable(&'static str),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::OperandTooLarge(v) => {
+                write!(f, "Operand {} does not fit in the bytecode format", v)
+            }
+            EncodeError::Unencodable(op) => {
+                write!(f, "Instruction '{}' has no bytecode form", op)
+            }
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// Feature bits the file requires and the loader does not support
+    UnsupportedFeatures(u16),
+    Truncated,
+    UnknownOpcode {
+        index: usize,
+        opcode: u8,
+    },
+    /// A `record` with more than `MAX_RECORD_FIELDS` fields
+    TooManyFields {
+        index: usize,
+        fields: usize,
+    },
+    InstructionOutOfBounds(usize),
+    StringOutOfBounds(u32),
+    InvalidUtf8(u32),
+    /// A string entry used as a register list whose length is not a
+    /// multiple of four bytes
+    InvalidRegisterList(u32),
+    InvalidExport(String),
+    EntryOutOfBounds(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "Not a zyde bytecode file"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "Unsupported bytecode version {}", v),
+            DecodeError::UnsupportedFeatures(bits) => {
+                write!(f, "Bytecode requires unsupported features {:#06x}", bits)
+            }
+            DecodeError::Truncated => write!(f, "Bytecode is truncated"),
+            DecodeError::UnknownOpcode { index, opcode } => {
+                write!(f, "Unknown opcode {:#04x} at instruction {}", opcode, index)
+            }
+            DecodeError::TooManyFields { index, fields } => write!(
+                f,
+                "Record of {} fields at instruction {} exceeds the limit",
+                fields, index
+            ),
+            DecodeError::InstructionOutOfBounds(i) => {
+                write!(f, "Instruction index {} is out of bounds", i)
+            }
+            DecodeError::StringOutOfBounds(i) => write!(f, "String index {} is out of bounds", i),
+            DecodeError::InvalidUtf8(i) => write!(f, "String {} is not valid UTF-8", i),
+            DecodeError::InvalidRegisterList(i) => {
+                write!(f, "String {} is not a list of registers", i)
+            }
+            DecodeError::InvalidExport(name) => write!(f, "Export '{}' is invalid", name),
+            DecodeError::EntryOutOfBounds(addr) => {
+                write!(f, "Entry point {} is out of bounds", addr)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Serialize `program` into the binary format
+pub fn encode(program: &Program) -> Result<Vec<u8>, EncodeError> {
+    let mut strings = StringTable::default();
+    let mut code = Vec::with_capacity(program.len() * INSTRUCTION_SIZE);
+    for instr in &program.instructions {
+        encode_instruction(instr, &mut strings, &mut code)?;
+    }
+
+    let mut exports = Vec::with_capacity(program.exports().len() * EXPORT_SIZE);
+    for export in program.exports() {
+        let name = strings.intern(&export.name);
+        put_u32(&mut exports, name);
+        put_u32(&mut exports, to_u32(export.addr)?);
+        put_u32(&mut exports, to_u32(export.arity)?);
+        put_u32(&mut exports, 0);
+    }
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + code.len() + exports.len());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&program.features().bits().to_le_bytes());
+    put_u32(&mut out, to_u32(program.len())?);
+    put_u32(&mut out, to_u32(program.exports().len())?);
+    put_u32(&mut out, to_u32(strings.entries.len())?);
+    put_u32(&mut out, to_u32(program.entry())?);
+    out.extend_from_slice(&code);
+    out.extend_from_slice(&exports);
+    strings.write(&mut out)?;
+    Ok(out)
+}
+
+fn encode_instruction(
+    instr: &Instruction,
+    strings: &mut StringTable,
+    out: &mut Vec<u8>,
+) -> Result<(), EncodeError> {
+    use Instruction::*;
+
+    let (op, a, b, c) = match instr {
+        LoadImm { dest, value } => {
+            out.extend_from_slice(&[opcode::LOAD_IMM, 0, 0, 0]);
+            put_u32(out, to_u32(*dest)?);
+            out.extend_from_slice(&value.to_le_bytes());
+            return Ok(());
+        }
+        Add { dest, src1, src2 } => (opcode::ADD, *dest, *src1, *src2),
+        Sub { dest, src1, src2 } => (opcode::SUB, *dest, *src1, *src2),
+        Mul { dest, src1, src2 } => (opcode::MUL, *dest, *src1, *src2),
+        Div { dest, src1, src2 } => (opcode::DIV, *dest, *src1, *src2),
+        Print { src } => (opcode::PRINT, *src, 0, 0),
+        Jump(addr) => (opcode::JUMP, *addr, 0, 0),
+        Call { addr } => (opcode::CALL, *addr, 0, 0),
+        ConditionalJump { cond, target } => (opcode::CONDITIONAL_JUMP, *cond, *target, 0),
+        Return => (opcode::RETURN, 0, 0, 0),
+        Store { src, var } => (opcode::STORE, *src, strings.intern(var) as usize, 0),
+        Load { dest, var } => (opcode::LOAD, *dest, strings.intern(var) as usize, 0),
+        Mov { dest, src } => (opcode::MOV, *dest, *src, 0),
+        Equal { dest, src1, src2 } => (opcode::EQUAL, *dest, *src1, *src2),
+        LessThan { dest, src1, src2 } => (opcode::LESS_THAN, *dest, *src1, *src2),
+        GreaterThan { dest, src1, src2 } => (opcode::GREATER_THAN, *dest, *src1, *src2),
+        Not { dest, src } => (opcode::NOT, *dest, *src, 0),
+        Halt => (opcode::HALT, 0, 0, 0),
+        TryBegin { handler, dest } => (opcode::TRY_BEGIN, *handler, *dest, 0),
+        TryEnd => (opcode::TRY_END, 0, 0, 0),
+        Throw { src } => (opcode::THROW, *src, 0, 0),
+        JumpIndirect { src } => (opcode::JUMP_INDIRECT, *src, 0, 0),
+        CallIndirect { src } => (opcode::CALL_INDIRECT, *src, 0, 0),
+        LoadAddr { dest, addr } => (opcode::LOAD_ADDR, *dest, *addr, 0),
+        CallClosure { src } => (opcode::CALL_CLOSURE, *src, 0, 0),
+        GetUpvalue { dest, index } => (opcode::GET_UPVALUE, *dest, *index, 0),
+        SetUpvalue { src, index } => (opcode::SET_UPVALUE, *src, *index, 0),
+        NewRecord { fields, .. } if *fields > MAX_RECORD_FIELDS => {
+            return Err(EncodeError::OperandTooLarge(*fields));
+        }
+        NewRecord { dest, fields } => (opcode::NEW_RECORD, *dest, *fields, 0),
+        GetField {
+            dest,
+            record,
+            index,
+        } => (opcode::GET_FIELD, *dest, *record, *index),
+        SetField { record, index, src } => (opcode::SET_FIELD, *record, *index, *src),
+        MapNew { dest } => (opcode::MAP_NEW, *dest, 0, 0),
+        MapGet { dest, map, key } => (opcode::MAP_GET, *dest, *map, *key),
+        MapSet { map, key, src } => (opcode::MAP_SET, *map, *key, *src),
+        MapHas { dest, map, key } => (opcode::MAP_HAS, *dest, *map, *key),
+        MapDelete { map, key } => (opcode::MAP_DELETE, *map, *key, 0),
+        Spawn { dest, addr } => (opcode::SPAWN, *dest, *addr, 0),
+        Yield => (opcode::YIELD, 0, 0, 0),
+        Resume { dest, id } => (opcode::RESUME, *dest, *id, 0),
+        Send { src, to } => (opcode::SEND, *src, strings.intern(to) as usize, 0),
+        Recv { dest } => (opcode::RECV, *dest, 0, 0),
+        Rand { dest } => (opcode::RAND, *dest, 0, 0),
+        // Offsets are stored as their two's complement bits
+        JumpRel(offset) => (opcode::JUMP_REL, *offset as u32 as usize, 0, 0),
+        CallRel(offset) => (opcode::CALL_REL, *offset as u32 as usize, 0, 0),
+        Push { src } => (opcode::PUSH, *src, 0, 0),
+        Pop { dest } => (opcode::POP, *dest, 0, 0),
+        CallArgs { addr, argc } => (opcode::CALL_ARGS, *addr, *argc, 0),
+        // One more than the upper bound, or zero for none
+        Arity { dest, min, max } => (
+            opcode::ARITY,
+            *dest,
+            *min,
+            max.map_or(0, |max| max.saturating_add(1)),
+        ),
+        CallHost { dest, name, args } => {
+            let mut list = Vec::with_capacity(args.len() * 4);
+            for &reg in args {
+                put_u32(&mut list, to_u32(reg)?);
+            }
+            (
+                opcode::CALL_HOST,
+                *dest,
+                strings.intern(name) as usize,
+                strings.intern_bytes(&list) as usize,
+            )
+        }
+        AddImm { .. } | CompareJump { .. } | Switch { .. } | MakeClosure { .. } => {
+            return Err(EncodeError::Unencodable(instr.mnemonic()));
+        }
+    };
+
+    out.extend_from_slice(&[op, 0, 0, 0]);
+    put_u32(out, to_u32(a)?);
+    put_u32(out, to_u32(b)?);
+    put_u32(out, to_u32(c)?);
+    Ok(())
+}
+
+fn to_u32(v: usize) -> Result<u32, EncodeError> {
+    u32::try_from(v).map_err(|_| EncodeError::OperandTooLarge(v))
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+#[derive(Default)]
+struct StringTable {
+    entries: Vec<Vec<u8>>,
+}
+
+impl StringTable {
+    fn intern(&mut self, s: &str) -> u32 {
+        self.intern_bytes(s.as_bytes())
+    }
+
+    fn intern_bytes(&mut self, bytes: &[u8]) -> u32 {
+        match self.entries.iter().position(|e| e == bytes) {
+            Some(i) => i as u32,
+            None => {
+                self.entries.push(bytes.to_vec());
+                (self.entries.len() - 1) as u32
+            }
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let mut offset = 0;
+        for entry in &self.entries {
+            put_u32(out, to_u32(offset)?);
+            offset += 4 + entry.len();
+        }
+        for entry in &self.entries {
+            put_u32(out, to_u32(entry.len())?);
+            out.extend_from_slice(entry);
+        }
+        Ok(())
+    }
+}
+
+/// A validated, borrowed view over encoded bytecode.
+///
+/// Parsing only checks the header and section sizes; instructions and strings
+/// are decoded individually when asked for, so large programs can be used
+/// straight from a memory map without an upfront copy.
+#[derive(Debug, Clone, Copy)]
+pub struct BytecodeView<'a> {
+    bytes: &'a [u8],
+    version: u16,
+    instruction_count: usize,
+    export_count: usize,
+    string_count: usize,
+}
+
+impl<'a> BytecodeView<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, DecodeError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(if bytes.starts_with(&MAGIC) || bytes.len() < 4 {
+                DecodeError::Truncated
+            } else {
+                DecodeError::BadMagic
+            });
+        }
+        if bytes[0..4] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = read_u16(bytes, 4)?;
+        if version == 0 || version > VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let features = read_u16(bytes, 6)?;
+        if !Features::ALL.contains(Features(features)) {
+            return Err(DecodeError::UnsupportedFeatures(
+                features & !Features::ALL.0,
+            ));
+        }
+
+        let view = Self {
+            bytes,
+            version,
+            instruction_count: read_u32(bytes, 8)? as usize,
+            export_count: read_u32(bytes, 12)? as usize,
+            string_count: read_u32(bytes, 16)? as usize,
+        };
+        // The fixed-size sections must fit; string data is checked lazily
+        let table_end = view
+            .strings_offset()
+            .and_then(|o| o.checked_add(view.string_count.checked_mul(4)?))
+            .ok_or(DecodeError::Truncated)?;
+        if table_end > bytes.len() {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(view)
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// Instruction set version the file was written for
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Features the code needs. Version 1 files do not record them, so their
+    /// code is decoded to find out.
+    pub fn features(&self) -> Result<Features, DecodeError> {
+        if self.version > 1 {
+            return Ok(Features(read_u16(self.bytes, 6)?));
+        }
+        (0..self.instruction_count).try_fold(Features::NONE, |features, i| {
+            Ok(features | Features::of(&self.instruction(i)?))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instruction_count == 0
+    }
+
+    /// Decode the instruction at `index`
+    pub fn instruction(&self, index: usize) -> Result<Instruction, DecodeError> {
+        use Instruction::*;
+
+        if index >= self.instruction_count {
+            return Err(DecodeError::InstructionOutOfBounds(index));
+        }
+        let at = HEADER_SIZE + index * INSTRUCTION_SIZE;
+        let [op] = read_array(self.bytes, at)?;
+        let a = read_u32(self.bytes, at + 4)? as usize;
+        let b = read_u32(self.bytes, at + 8)? as usize;
+        let c = read_u32(self.bytes, at + 12)? as usize;
+
+        Ok(match op {
+            opcode::LOAD_IMM => LoadImm {
+                dest: a,
+                value: f64::from_le_bytes(read_array(self.bytes, at + 8)?),
+            },
+            opcode::ADD => Add {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::SUB => Sub {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::MUL => Mul {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::DIV => Div {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::PRINT => Print { src: a },
+            opcode::JUMP => Jump(a),
+            opcode::CALL => Call { addr: a },
+            opcode::CONDITIONAL_JUMP => ConditionalJump { cond: a, target: b },
+            opcode::RETURN => Return,
+            opcode::STORE => Store {
+                src: a,
+                var: self.string(b as u32)?.to_string(),
+            },
+            opcode::LOAD => Load {
+                dest: a,
+                var: self.string(b as u32)?.to_string(),
+            },
+            opcode::MOV => Mov { dest: a, src: b },
+            opcode::EQUAL => Equal {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::LESS_THAN => LessThan {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::GREATER_THAN => GreaterThan {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            opcode::NOT => Not { dest: a, src: b },
+            opcode::HALT => Halt,
+            opcode::TRY_BEGIN => TryBegin {
+                handler: a,
+                dest: b,
+            },
+            opcode::TRY_END => TryEnd,
+            opcode::THROW => Throw { src: a },
+            opcode::JUMP_INDIRECT => JumpIndirect { src: a },
+            opcode::CALL_INDIRECT => CallIndirect { src: a },
+            opcode::LOAD_ADDR => LoadAddr { dest: a, addr: b },
+            opcode::CALL_CLOSURE => CallClosure { src: a },
+            opcode::GET_UPVALUE => GetUpvalue { dest: a, index: b },
+            opcode::SET_UPVALUE => SetUpvalue { src: a, index: b },
+            opcode::NEW_RECORD if b > MAX_RECORD_FIELDS => {
+                return Err(DecodeError::TooManyFields { index, fields: b });
+            }
+            opcode::NEW_RECORD => NewRecord { dest: a, fields: b },
+            opcode::GET_FIELD => GetField {
+                dest: a,
+                record: b,
+                index: c,
+            },
+            opcode::SET_FIELD => SetField {
+                record: a,
+                index: b,
+                src: c,
+            },
+            opcode::MAP_NEW => MapNew { dest: a },
+            opcode::MAP_GET => MapGet {
+                dest: a,
+                map: b,
+                key: c,
+            },
+            opcode::MAP_SET => MapSet {
+                map: a,
+                key: b,
+                src: c,
+            },
+            opcode::MAP_HAS => MapHas {
+                dest: a,
+                map: b,
+                key: c,
+            },
+            opcode::MAP_DELETE => MapDelete { map: a, key: b },
+            opcode::SPAWN => Spawn { dest: a, addr: b },
+            opcode::YIELD => Yield,
+            opcode::RESUME => Resume { dest: a, id: b },
+            opcode::SEND => Send {
+                src: a,
+                to: self.string(b as u32)?.to_string(),
+            },
+            opcode::RECV => Recv { dest: a },
+            opcode::RAND => Rand { dest: a },
+            opcode::JUMP_REL => JumpRel(a as u32 as i32),
+            opcode::CALL_REL => CallRel(a as u32 as i32),
+            opcode::PUSH => Push { src: a },
+            opcode::POP => Pop { dest: a },
+            opcode::CALL_ARGS => CallArgs { addr: a, argc: b },
+            opcode::ARITY => Arity {
+                dest: a,
+                min: b,
+                max: c.checked_sub(1),
+            },
+            opcode::CALL_HOST => CallHost {
+                dest: a,
+                name: self.string(b as u32)?.to_string(),
+                args: self.registers(c as u32)?,
+            },
+            opcode => return Err(DecodeError::UnknownOpcode { index, opcode }),
+        })
+    }
+
+    /// Borrow string `index` from the string table without copying it
+    pub fn string(&self, index: u32) -> Result<&'a str, DecodeError> {
+        core::str::from_utf8(self.entry(index)?).map_err(|_| DecodeError::InvalidUtf8(index))
+    }
+
+    /// Decode string `index` as a list of register indices
+    fn registers(&self, index: u32) -> Result<Vec<usize>, DecodeError> {
+        let bytes = self.entry(index)?;
+        if bytes.len() % 4 != 0 {
+            return Err(DecodeError::InvalidRegisterList(index));
+        }
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|reg| u32::from_le_bytes([reg[0], reg[1], reg[2], reg[3]]) as usize)
+            .collect())
+    }
+
+    /// The bytes of string table entry `index`
+    fn entry(&self, index: u32) -> Result<&'a [u8], DecodeError> {
+        if index as usize >= self.string_count {
+            return Err(DecodeError::StringOutOfBounds(index));
+        }
+        let table = self.strings_offset().ok_or(DecodeError::Truncated)?;
+        let data = table + self.string_count * 4;
+        let entry = data
+            .checked_add(read_u32(self.bytes, table + index as usize * 4)? as usize)
+            .ok_or(DecodeError::Truncated)?;
+        let len = read_u32(self.bytes, entry)? as usize;
+        entry
+            .checked_add(4 + len)
+            .and_then(|end| self.bytes.get(entry + 4..end))
+            .ok_or(DecodeError::Truncated)
+    }
+
+    /// Decode the export catalog as `(name, addr, arity)` entries
+    pub fn exports(&self) -> Result<Vec<(&'a str, usize, usize)>, DecodeError> {
+        let base = HEADER_SIZE + self.instruction_count * INSTRUCTION_SIZE;
+        (0..self.export_count)
+            .map(|i| {
+                let at = base + i * EXPORT_SIZE;
+                Ok((
+                    self.string(read_u32(self.bytes, at)?)?,
+                    read_u32(self.bytes, at + 4)? as usize,
+                    read_u32(self.bytes, at + 8)? as usize,
+                ))
+            })
+            .collect()
+    }
+
+    /// Decode everything into an owned `Program`
+    pub fn to_program(&self) -> Result<Program, DecodeError> {
+        self.to_program_with(Features::ALL)
+    }
+
+    /// Decode everything into an owned `Program`, if it needs no features
+    /// beyond `supported`
+    pub fn to_program_with(&self, supported: Features) -> Result<Program, DecodeError> {
+        let required = self.features()?;
+        if !supported.contains(required) {
+            return Err(DecodeError::UnsupportedFeatures(required.0 & !supported.0));
+        }
+        let instructions = (0..self.instruction_count)
+            .map(|i| self.instruction(i))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut program = Program::new(instructions);
+        for (name, addr, arity) in self.exports()? {
+            program
+                .export(name, addr, arity)
+                .map_err(|_| DecodeError::InvalidExport(name.to_string()))?;
+        }
+        // Files written before entry points were stored leave this zero
+        let entry = read_u32(self.bytes, 20)? as usize;
+        if entry != 0 {
+            program
+                .set_entry_addr(entry)
+                .map_err(|_| DecodeError::EntryOutOfBounds(entry))?;
+        }
+        Ok(program)
+    }
+
+    fn strings_offset(&self) -> Option<usize> {
+        self.instruction_count
+            .checked_mul(INSTRUCTION_SIZE)?
+            .checked_add(self.export_count.checked_mul(EXPORT_SIZE)?)?
+            .checked_add(HEADER_SIZE)
+    }
+}
+
+fn read_array<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N], DecodeError> {
+    bytes
+        .get(at..at.checked_add(N).ok_or(DecodeError::Truncated)?)
+        .and_then(|s| s.try_into().ok())
+        .ok_or(DecodeError::Truncated)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16, DecodeError> {
+    read_array(bytes, at).map(u16::from_le_bytes)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, DecodeError> {
+    read_array(bytes, at).map(u32::from_le_bytes)
+}
+
+impl Program {
+    /// Decode a program from the binary format
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        BytecodeView::parse(bytes)?.to_program()
+    }
+
+    /// Decode a program from the binary format on a host that only supports
+    /// the `supported` features
+    pub fn from_bytecode_with(bytes: &[u8], supported: Features) -> Result<Self, DecodeError> {
+        BytecodeView::parse(bytes)?.to_program_with(supported)
+    }
+
+    /// Features this program's code needs
+    pub fn features(&self) -> Features {
+        self.instructions
+            .iter()
+            .fold(Features::NONE, |features, instr| {
+                features | Features::of(instr)
+            })
+    }
+
+    /// Encode this program into the binary format
+    pub fn to_bytecode(&self) -> Result<Vec<u8>, EncodeError> {
+        encode(self)
+    }
+}
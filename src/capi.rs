@@ -0,0 +1,133 @@
+//! A C interface for embedding the VM behind the `capi` feature
+//!
+//! Build the shared library with
+//! `cargo rustc --lib --crate-type cdylib --release --features capi`.
+//!
+//! Functions return 0 on success and -1 on failure; the message for the
+//! most recent failure is available from [`zyde_vm_last_error`]. The
+//! matching header is generated into `include/zyde.h` by the build script.
+
+use std::ffi::{CString, c_char, c_int};
+use std::ptr;
+
+use crate::program::Program;
+use crate::vm::VM;
+
+/// An embedded VM and the program loaded into it
+pub struct ZydeVm {
+    registers: usize,
+    vm: Option<VM>,
+    error: Option<CString>,
+}
+
+impl ZydeVm {
+    fn status<E: ToString>(&mut self, result: Result<(), E>) -> c_int {
+        match result {
+            Ok(()) => {
+                self.error = None;
+                0
+            }
+            Err(e) => {
+                let message = e.to_string().replace('\0', " ");
+                self.error = CString::new(message).ok();
+                -1
+            }
+        }
+    }
+}
+
+/// Create a VM with `registers` registers and no program loaded
+///
+/// The result must be released with [`zyde_vm_free`].
+#[unsafe(no_mangle)]
+pub extern "C" fn zyde_vm_new(registers: usize) -> *mut ZydeVm {
+    Box::into_raw(Box::new(ZydeVm {
+        registers,
+        vm: None,
+        error: None,
+    }))
+}
+
+/// Release a VM created by [`zyde_vm_new`]
+///
+/// # Safety
+///
+/// `vm` must be null or a pointer returned by [`zyde_vm_new`] that has not been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn zyde_vm_free(vm: *mut ZydeVm) {
+    if !vm.is_null() {
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+/// Decode `len` bytes of bytecode and load them, replacing any previous program
+///
+/// # Safety
+///
+/// `vm` must be a live pointer from [`zyde_vm_new`] and `bytes` must point to
+/// `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn zyde_vm_load(vm: *mut ZydeVm, bytes: *const u8, len: usize) -> c_int {
+    let vm = unsafe { &mut *vm };
+    let bytes = if len == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(bytes, len) }
+    };
+    let result = Program::from_bytecode(bytes).map(|program| {
+        vm.vm = Some(VM::new(program, vm.registers));
+    });
+    vm.status(result)
+}
+
+/// Run the loaded program until it halts or fails
+///
+/// # Safety
+///
+/// `vm` must be a live pointer from [`zyde_vm_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn zyde_vm_run(vm: *mut ZydeVm) -> c_int {
+    let vm = unsafe { &mut *vm };
+    let result = match &mut vm.vm {
+        Some(inner) => inner.run().map_err(|e| e.to_string()),
+        None => Err("No program loaded".to_string()),
+    };
+    vm.status(result)
+}
+
+/// Read register `index` into `out`
+///
+/// # Safety
+///
+/// `vm` must be a live pointer from [`zyde_vm_new`] and `out` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn zyde_vm_get_register(
+    vm: *mut ZydeVm,
+    index: usize,
+    out: *mut f64,
+) -> c_int {
+    let vm = unsafe { &mut *vm };
+    let value = match &vm.vm {
+        Some(inner) => inner
+            .registers
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("Register r{} out of bounds", index)),
+        None => Err("No program loaded".to_string()),
+    };
+    let result = value.map(|value| unsafe { *out = value });
+    vm.status(result)
+}
+
+/// The message for the most recent failure, or null if the last call succeeded
+///
+/// The string is owned by the VM and stays valid until its next call.
+///
+/// # Safety
+///
+/// `vm` must be a live pointer from [`zyde_vm_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn zyde_vm_last_error(vm: *const ZydeVm) -> *const c_char {
+    let vm = unsafe { &*vm };
+    vm.error.as_ref().map_or(ptr::null(), |e| e.as_ptr())
+}
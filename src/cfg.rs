@@ -0,0 +1,247 @@
+use crate::dot;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+/// A maximal straight-line run of instructions, `start..end`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<usize>,
+}
+
+/// Control-flow graph over a program, with blocks indexed in address order
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    pub fn build(program: &[Instruction]) -> Self {
+        let leaders = block_leaders(program);
+        let starts: Vec<usize> = (0..program.len()).filter(|&pc| leaders[pc]).collect();
+
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| BasicBlock {
+                start,
+                end: starts.get(i + 1).copied().unwrap_or(program.len()),
+                successors: Vec::new(),
+            })
+            .collect();
+
+        let block_at = |addr: usize| starts.binary_search(&addr).ok();
+
+        for i in 0..blocks.len() {
+            let at = blocks[i].end - 1;
+            let last = &program[at];
+            let mut successors = Vec::new();
+
+            if last.is_computed_jump() {
+                successors.extend(0..blocks.len());
+            }
+            for target in last.targets(at).into_iter().filter_map(block_at) {
+                if !successors.contains(&target) {
+                    successors.push(target);
+                }
+            }
+            // A call resumes at the next instruction once the callee returns
+            if !last.is_terminator() && i + 1 < blocks.len() && !successors.contains(&(i + 1)) {
+                successors.push(i + 1);
+            }
+            blocks[i].successors = successors;
+        }
+
+        Self { blocks }
+    }
+
+    /// Index of the block containing instruction `addr`
+    pub fn block_of(&self, addr: usize) -> Option<usize> {
+        match self.blocks.binary_search_by(|b| b.start.cmp(&addr)) {
+            Ok(i) => Some(i),
+            Err(0) => None,
+            Err(i) if addr < self.blocks[i - 1].end => Some(i - 1),
+            Err(_) => None,
+        }
+    }
+
+    /// Which blocks can be reached from the entry block
+    pub fn reachable(&self) -> Vec<bool> {
+        self.reachable_from(&[0])
+    }
+
+    /// Which blocks can be reached from any of the instruction addresses in `roots`
+    pub fn reachable_from(&self, roots: &[usize]) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut worklist: Vec<usize> = roots.iter().filter_map(|&r| self.block_of(r)).collect();
+
+        while let Some(b) = worklist.pop() {
+            if seen[b] {
+                continue;
+            }
+            seen[b] = true;
+            worklist.extend(self.blocks[b].successors.iter().copied());
+        }
+        seen
+    }
+}
+
+/// Marks every instruction that starts a basic block. A computed jump may
+/// land anywhere, so in a program with one every instruction is a leader.
+pub fn block_leaders(program: &[Instruction]) -> Vec<bool> {
+    if program.iter().any(Instruction::is_computed_jump) {
+        return vec![true; program.len()];
+    }
+    let mut leaders = vec![false; program.len()];
+    let mut mark = |addr: usize| {
+        if let Some(l) = leaders.get_mut(addr) {
+            *l = true;
+        }
+    };
+
+    mark(0);
+    for (pc, instr) in program.iter().enumerate() {
+        let targets = instr.targets(pc);
+        for &target in &targets {
+            mark(target);
+        }
+        if !targets.is_empty() || instr.is_terminator() || instr.is_call() {
+            mark(pc + 1);
+        }
+    }
+    leaders
+}
+
+/// Render the control-flow graph of `program` as Graphviz DOT. Blocks are
+/// titled by the nearest label or export; when `pc` is given, its block is
+/// filled and the instruction marked, e.g. to show where a paused VM stopped.
+pub fn to_dot(program: &Program, pc: Option<usize>) -> String {
+    to_dot_with_lines(program, pc, &[])
+}
+
+/// As `to_dot`, with each instruction annotated with the source line in
+/// `lines` it was assembled from, as `asm_reg::assemble_with_lines` gives
+pub fn to_dot_with_lines(program: &Program, pc: Option<usize>, lines: &[usize]) -> String {
+    let cfg = Cfg::build(&program.instructions);
+    let current = pc.and_then(|pc| cfg.block_of(pc));
+
+    let mut s = String::from("digraph cfg {\n  node [shape=box, fontname=monospace];\n");
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        let label = dot::left_lines(block_text(program, i, block, pc, lines));
+        let style = if current == Some(i) {
+            ", style=filled, fillcolor=lightyellow"
+        } else {
+            ""
+        };
+        s.push_str(&format!("  b{} [label={}{}];\n", i, label, style));
+    }
+
+    for (i, succ, edge) in edges(program, &cfg) {
+        let attrs = match edge {
+            Edge::Fallthrough => "",
+            Edge::Call => " [style=dashed, label=call]",
+            Edge::Zero => " [label=zero]",
+            Edge::Catch => " [style=dotted, label=catch]",
+        };
+        s.push_str(&format!("  b{} -> b{}{};\n", i, succ, attrs));
+    }
+    s.push_str("}\n");
+    s
+}
+
+/// Render the control-flow graph of `program` as a Mermaid flowchart,
+/// annotated with source lines as `to_dot_with_lines` does
+pub fn to_mermaid(program: &Program, lines: &[usize]) -> String {
+    let cfg = Cfg::build(&program.instructions);
+    let mut s = String::from("flowchart TD\n");
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        let text: Vec<String> = block_text(program, i, block, None, lines)
+            .into_iter()
+            .map(|line| mermaid_escape(line.trim_start()))
+            .collect();
+        s.push_str(&format!("  b{}[\"{}\"]\n", i, text.join("<br/>")));
+    }
+    for (i, succ, edge) in edges(program, &cfg) {
+        let arrow = match edge {
+            Edge::Fallthrough => "-->",
+            Edge::Call => "-.->|call|",
+            Edge::Zero => "-->|zero|",
+            Edge::Catch => "-.->|catch|",
+        };
+        s.push_str(&format!("  b{} {} b{}\n", i, arrow, succ));
+    }
+    s
+}
+
+/// The title, address range and instructions of block `i`, with `pc`
+/// marked and source lines appended when `lines` covers them
+fn block_text(
+    program: &Program,
+    i: usize,
+    block: &BasicBlock,
+    pc: Option<usize>,
+    lines: &[usize],
+) -> Vec<String> {
+    let mut title = format!("block {}", i);
+    if let Some((name, offset)) = program.symbolize(block.start) {
+        title = match offset {
+            0 => format!("{}: {}", title, name),
+            _ => format!("{}: {} (+{})", title, name, offset),
+        };
+    }
+    let range = format!("addresses {}..={}", block.start, block.end - 1);
+    let code = (block.start..block.end).map(|addr| {
+        let marker = if pc == Some(addr) { ">" } else { " " };
+        let text = format!("{} {:>4}  {}", marker, addr, program.instructions[addr]);
+        match lines.get(addr) {
+            Some(line) => format!("{}  ; line {}", text, line),
+            None => text,
+        }
+    });
+    [title, range].into_iter().chain(code).collect()
+}
+
+enum Edge {
+    Fallthrough,
+    Call,
+    /// A `jz` taken because its register was zero
+    Zero,
+    /// A `try` handler, entered when an error is raised
+    Catch,
+}
+
+/// Every edge of `cfg` as (from, to, kind), in block order
+fn edges(program: &Program, cfg: &Cfg) -> Vec<(usize, usize, Edge)> {
+    let mut edges = Vec::new();
+    for (i, block) in cfg.blocks.iter().enumerate() {
+        let at = block.end - 1;
+        let last = &program.instructions[at];
+        for &succ in &block.successors {
+            let jumps_there = last.target(at) == Some(cfg.blocks[succ].start);
+            let edge = match last {
+                Instruction::Call { .. }
+                | Instruction::CallRel(_)
+                | Instruction::CallArgs { .. }
+                    if jumps_there =>
+                {
+                    Edge::Call
+                }
+                Instruction::ConditionalJump { .. } if jumps_there => Edge::Zero,
+                Instruction::TryBegin { .. } if jumps_there => Edge::Catch,
+                _ => Edge::Fallthrough,
+            };
+            edges.push((i, succ, edge));
+        }
+    }
+    edges
+}
+
+/// Mermaid labels are HTML: entities replace quotes and angle brackets
+fn mermaid_escape(s: &str) -> String {
+    s.replace('&', "#amp;")
+        .replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
@@ -0,0 +1,283 @@
+//! Programs every machine must run alike, as an executable specification
+//! of the instruction sets.
+//!
+//! Each `Case` is written twice: as register-machine assembly, kept in
+//! `examples/`, and as a `StackVM` program over any `Number`. `check`
+//! assembles the first and runs it on a `VM`, runs the second on a
+//! `StackVM` of each number type, and compares what each printed with what
+//! the case expects. Every run is limited to `MAX_STEPS` instructions and
+//! `MAX_CALL_DEPTH` call frames, so a recursive case that stops returning
+//! fails rather than hanging.
+//!
+//! The stack machine has no heap, so where a register program keeps a
+//! table in a map, its stack program unrolls the loops over it into code
+//! that names each entry.
+
+use crate::asm_reg::{self, AsmError};
+use crate::prelude::*;
+use crate::sandbox::SandboxPolicy;
+use crate::stack_vm::{Number, StackInstruction, StackVM};
+use crate::vm::{VM, VmConfig, VmError};
+use StackInstruction::*;
+use core::fmt;
+
+/// Instructions any one run may execute
+pub const MAX_STEPS: u64 = 1_000_000;
+/// Call frames any one run may have
+pub const MAX_CALL_DEPTH: usize = 64;
+/// Registers the register programs are given
+pub const REGISTERS: usize = 8;
+
+/// A program and what it must print
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: &'static str,
+    /// The program as register-machine assembly
+    pub source: &'static str,
+    pub output: &'static [f64],
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Fib,
+    Ackermann,
+    Sieve,
+    Reverse,
+}
+
+/// Every case, in order of the parts of the machines they exercise:
+/// recursion, deeper recursion, tables, then strings
+pub fn cases() -> Vec<Case> {
+    vec![
+        Case {
+            name: "fib",
+            source: include_str!("../examples/fib.asm"),
+            output: &[0.0, 1.0, 1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 34.0, 55.0],
+            kind: Kind::Fib,
+        },
+        Case {
+            name: "ackermann",
+            source: include_str!("../examples/ackermann.asm"),
+            output: &[1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 5.0, 3.0, 5.0, 7.0, 9.0],
+            kind: Kind::Ackermann,
+        },
+        Case {
+            name: "sieve",
+            source: include_str!("../examples/sieve.asm"),
+            output: &[2.0, 3.0, 5.0, 7.0, 11.0, 13.0, 17.0, 19.0, 23.0, 29.0],
+            kind: Kind::Sieve,
+        },
+        Case {
+            name: "reverse",
+            source: include_str!("../examples/reverse.asm"),
+            output: &[100.0, 101.0, 115.0, 115.0, 101.0, 114.0, 116.0, 115.0],
+            kind: Kind::Reverse,
+        },
+    ]
+}
+
+/// How a machine failed a case
+#[derive(Debug)]
+pub enum Failure {
+    /// The case's source did not assemble
+    Assembly(AsmError),
+    /// The named machine stopped with an error
+    Error { machine: String, error: VmError },
+    /// The named machine printed something other than the case expects
+    Output { machine: String, printed: Vec<f64> },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Assembly(e) => write!(f, "Does not assemble: {}", e),
+            Failure::Error { machine, error } => write!(f, "{} failed: {}", machine, error),
+            Failure::Output { machine, printed } => {
+                write!(f, "{} printed {:?}", machine, printed)
+            }
+        }
+    }
+}
+
+impl core::error::Error for Failure {}
+
+/// The limits every run is held to
+pub fn config() -> VmConfig {
+    VmConfig {
+        max_steps: Some(MAX_STEPS),
+        sandbox: SandboxPolicy {
+            max_call_depth: Some(MAX_CALL_DEPTH),
+            ..SandboxPolicy::default()
+        },
+        ..VmConfig::default()
+    }
+}
+
+/// Run `case` on the register machine and on a stack machine of each
+/// number type, stopping at the first that fails it
+pub fn check(case: &Case) -> Result<(), Failure> {
+    let program = asm_reg::assemble(case.source).map_err(Failure::Assembly)?;
+    let mut vm = VM::with_config(program, REGISTERS, config());
+    vm.capture_output();
+    let result = vm.run();
+    expect(case, "VM".to_string(), result, vm.take_output())?;
+
+    check_stack::<f64>(case)?;
+    check_stack::<f32>(case)?;
+    check_stack::<i64>(case)?;
+    check_stack::<i32>(case)
+}
+
+fn check_stack<T: Number>(case: &Case) -> Result<(), Failure> {
+    let mut vm = StackVM::with_config(case.stack_program::<T>(), config());
+    vm.capture_output();
+    let result = vm.run();
+    let machine = format!("StackVM<{}>", core::any::type_name::<T>());
+    expect(case, machine, result, vm.take_output())
+}
+
+fn expect(
+    case: &Case,
+    machine: String,
+    result: Result<(), VmError>,
+    printed: Vec<f64>,
+) -> Result<(), Failure> {
+    if let Err(error) = result {
+        return Err(Failure::Error { machine, error });
+    }
+    if printed != case.output {
+        return Err(Failure::Output { machine, printed });
+    }
+    Ok(())
+}
+
+impl Case {
+    /// The case as a stack machine program over `T`
+    pub fn stack_program<T: Number>(&self) -> Vec<StackInstruction<T>> {
+        match self.kind {
+            Kind::Fib => fib(),
+            Kind::Ackermann => ackermann(),
+            Kind::Sieve => sieve(),
+            Kind::Reverse => reverse(),
+        }
+    }
+}
+
+/// `n` as a `T`
+fn number<T: Number>(n: usize) -> T {
+    (0..n).fold(T::ZERO, |v, _| v.add(T::ONE))
+}
+
+/// fib(n) for n from 0 to 10, by a function at 1 that replaces the top of
+/// the stack with its Fibonacci number
+fn fib<T: Number>() -> Vec<StackInstruction<T>> {
+    let fib = 1;
+    let mut code = vec![
+        Jump(0),
+        // fib(n) = n below 2
+        Dup,
+        Push(number(2)),
+        LessThan,
+        JumpIfZero(fib + 5),
+        Return,
+        Dup,
+        Push(T::ONE),
+        Sub,
+        Call(fib),
+        Swap,
+        Push(number(2)),
+        Sub,
+        Call(fib),
+        Add,
+        Return,
+    ];
+    code[0] = Jump(code.len());
+    for n in 0..=10 {
+        code.extend([Push(number(n)), Call(fib), Print]);
+    }
+    code
+}
+
+/// A(m, n) for m from 0 to 2 and n from 0 to 3, by a function at 1 that
+/// replaces `m, n` on the stack with A(m, n)
+fn ackermann<T: Number>() -> Vec<StackInstruction<T>> {
+    let ack = 1;
+    let mut code = vec![
+        Jump(0),
+        Swap,
+        Dup,
+        JumpIfZero(ack + 17),
+        Swap,
+        Dup,
+        JumpIfZero(ack + 21),
+        // A(m - 1, A(m, n - 1)); `n` is set aside only until it is loaded
+        // back, before any call
+        Store("n".to_string()),
+        Dup,
+        Push(T::ONE),
+        Sub,
+        Swap,
+        Load("n".to_string()),
+        Push(T::ONE),
+        Sub,
+        Call(ack),
+        Call(ack),
+        Return,
+        // A(0, n) = n + 1
+        Pop,
+        Push(T::ONE),
+        Add,
+        Return,
+        // A(m, 0) = A(m - 1, 1)
+        Pop,
+        Push(T::ONE),
+        Sub,
+        Push(T::ONE),
+        Call(ack),
+        Return,
+    ];
+    code[0] = Jump(code.len());
+    for m in 0..=2 {
+        for n in 0..=3 {
+            code.extend([Push(number(m)), Push(number(n)), Call(ack), Print]);
+        }
+    }
+    code
+}
+
+/// The primes below 30, with a variable for each number saying whether it
+/// has been crossed off
+fn sieve<T: Number>() -> Vec<StackInstruction<T>> {
+    const LIMIT: usize = 30;
+    let composite = |n: usize| format!("composite{}", n);
+    let mut code = Vec::new();
+    for n in 2..LIMIT {
+        code.extend([Push(T::ZERO), Store(composite(n))]);
+    }
+    for n in 2..LIMIT {
+        let multiples: Vec<usize> = (n * n..LIMIT).step_by(n).collect();
+        let start = code.len();
+        let next = start + 5 + 2 * multiples.len();
+        code.extend([
+            Load(composite(n)),
+            JumpIfZero(start + 3),
+            Jump(next),
+            Push(number(n)),
+            Print,
+        ]);
+        for m in multiples {
+            code.extend([Push(T::ONE), Store(composite(m))]);
+        }
+    }
+    code
+}
+
+/// "stressed" reversed, by pushing its character codes and printing them
+/// as they come off the stack
+fn reverse<T: Number>() -> Vec<StackInstruction<T>> {
+    let text = "stressed";
+    let mut code: Vec<_> = text.bytes().map(|c| Push(number(c as usize))).collect();
+    code.extend(text.bytes().map(|_| Print));
+    code
+}
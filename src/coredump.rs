@@ -0,0 +1,91 @@
+//! Post-mortem state for runs that fail.
+//!
+//! With `VmConfig::dump_on_trap` set, a run that stops on an error writes a
+//! JSON core dump of the error, where it happened, the registers, call stack
+//! and variables, and a summary of the heap, so a failure can be studied
+//! without reproducing it. Programs have no source map, so a location is an
+//! address with the nearest label or export and the instruction there.
+
+use crate::json;
+use crate::vm::{VM, VmError};
+use std::fs;
+
+impl VM {
+    /// The core dump of this VM after a run stopped with `error`
+    pub fn core_dump(&self, error: &VmError) -> String {
+        let registers: Vec<String> = self.registers.iter().map(|&r| json::number(r)).collect();
+        // Call sites waiting for a return, innermost first; `null` for a
+        // frame the host entered
+        let call_stack: Vec<String> = self.frame_sites()[1..]
+            .iter()
+            .map(|site| site.map_or("null".to_string(), |addr| self.location(addr)))
+            .collect();
+        let mut names: Vec<&String> = self.variables.keys().collect();
+        names.sort();
+        let variables: Vec<String> = names
+            .into_iter()
+            .map(|name| {
+                format!(
+                    "{}:{}",
+                    json::string(name),
+                    json::number(self.variables[name])
+                )
+            })
+            .collect();
+        let stats = self.heap.stats();
+        format!(
+            "{{\"error\":{},\"pc\":{},\"location\":{},\"steps\":{},\"registers\":[{}],\
+             \"call_stack\":[{}],\"variables\":{{{}}},\
+             \"heap\":{{\"objects\":{},\"cells\":{},\"allocated\":{},\"collections\":{}}}}}\n",
+            json::string(&error.to_string()),
+            self.pc,
+            self.location(trap_address(self.pc, error)),
+            self.steps,
+            registers.join(","),
+            call_stack.join(","),
+            variables.join(","),
+            self.heap.len(),
+            self.heap.cells(),
+            stats.allocated,
+            stats.collections
+        )
+    }
+
+    /// Write the core dump to the configured path if `result` is a failure
+    /// rather than the run suspending. A dump that cannot be written is
+    /// skipped, so the run's own error is what the caller sees.
+    pub(crate) fn write_core_dump(&self, result: &Result<(), VmError>) {
+        if let (Some(path), Err(error)) = (&self.config.dump_on_trap, result)
+            && !matches!(
+                error,
+                VmError::Blocked | VmError::HostCallPending(_) | VmError::Paused
+            )
+        {
+            let _ = fs::write(path, self.core_dump(error));
+        }
+    }
+
+    fn location(&self, addr: usize) -> String {
+        let (label, offset) = match self.program.symbolize(addr) {
+            Some((name, offset)) => (json::string(name), offset.to_string()),
+            None => ("null".to_string(), "null".to_string()),
+        };
+        let instruction = match self.program.instructions.get(addr) {
+            Some(instr) => json::string(&instr.to_string()),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"addr\":{},\"label\":{},\"offset\":{},\"instruction\":{}}}",
+            addr, label, offset, instruction
+        )
+    }
+}
+
+/// The instruction that raised `error`. Limits stop the run before the
+/// instruction at `pc`; anything else is raised by the one just fetched.
+fn trap_address(pc: usize, error: &VmError) -> usize {
+    match error {
+        VmError::StepLimitExceeded | VmError::Timeout | VmError::Cancelled | VmError::Aborted => pc,
+        _ => pc.saturating_sub(1),
+    }
+}
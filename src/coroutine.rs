@@ -0,0 +1,197 @@
+//! Cooperative coroutines.
+//!
+//! A coroutine is an independent execution context with its own pc,
+//! registers, call stack and error handlers; variables and the heap are
+//! shared with the rest of the VM. Nothing preempts a coroutine: it runs from
+//! a `resume` until it executes `yield` or returns from the function it was
+//! spawned at, and control then goes back to whoever resumed it.
+
+use crate::prelude::*;
+use crate::vm::{self, Frame, Handler, VmError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Status {
+    /// Spawned or yielded, and waiting to be resumed
+    Suspended,
+    /// Resumed and not yet yielded; it may itself be resuming another
+    Running,
+    /// Returned from the function it was spawned at
+    Finished,
+}
+
+/// A coroutine's saved execution context. While the coroutine runs, its slot
+/// holds the context of whoever resumed it instead.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Coroutine {
+    pub(crate) pc: usize,
+    pub(crate) registers: Vec<f64>,
+    pub(crate) call_stack: Vec<Frame>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) stack: Vec<f64>,
+    pub(crate) handlers: Vec<Handler>,
+    pub(crate) status: Status,
+}
+
+impl Coroutine {
+    pub fn status(&self) -> Status {
+        self.status
+    }
+}
+
+/// The running context, borrowed from the VM so either interpreter can
+/// switch coroutines in place
+pub(crate) struct Context<'a> {
+    pub(crate) pc: &'a mut usize,
+    pub(crate) registers: &'a mut [f64],
+    pub(crate) call_stack: &'a mut Vec<Frame>,
+    pub(crate) stack: &'a mut Vec<f64>,
+    pub(crate) handlers: &'a mut Vec<Handler>,
+}
+
+impl Context<'_> {
+    fn swap(&mut self, coroutine: &mut Coroutine) {
+        // The host may have resized the register file since the spawn
+        coroutine.registers.resize(self.registers.len(), 0.0);
+        core::mem::swap(self.pc, &mut coroutine.pc);
+        self.registers.swap_with_slice(&mut coroutine.registers);
+        core::mem::swap(self.call_stack, &mut coroutine.call_stack);
+        core::mem::swap(self.stack, &mut coroutine.stack);
+        core::mem::swap(self.handlers, &mut coroutine.handlers);
+    }
+}
+
+/// Every coroutine a VM has spawned, indexed by id
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Coroutines {
+    all: Vec<Coroutine>,
+    /// Coroutines currently resumed, innermost last, each with the register
+    /// its resumer receives the outcome in, or `None` if the host resumed it
+    active: Vec<(usize, Option<usize>)>,
+}
+
+impl Coroutines {
+    /// The coroutine with id `id`, if one was spawned
+    pub fn get(&self, id: f64) -> Option<&Coroutine> {
+        self.all.get(vm::address(id)?)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Coroutine> {
+        self.all.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.all.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty()
+    }
+
+    /// The id of the coroutine running now, if any
+    pub fn current(&self) -> Option<usize> {
+        self.active.last().map(|&(id, _)| id)
+    }
+
+    /// Ids of the running coroutines, outermost first
+    pub fn resumed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.active.iter().map(|&(id, _)| id)
+    }
+
+    /// Number of coroutines resumed and not yet yielded or finished
+    pub(crate) fn depth(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Saved contexts that may still run
+    pub(crate) fn live_mut(&mut self) -> impl Iterator<Item = &mut Coroutine> {
+        self.all.iter_mut().filter(|c| c.status != Status::Finished)
+    }
+
+    /// Create a suspended coroutine that starts at `addr` with `registers`,
+    /// returning its id
+    pub(crate) fn spawn(&mut self, addr: usize, registers: Vec<f64>) -> f64 {
+        self.all.push(Coroutine {
+            pc: addr,
+            registers,
+            call_stack: Vec::new(),
+            stack: Vec::new(),
+            handlers: Vec::new(),
+            status: Status::Suspended,
+        });
+        (self.all.len() - 1) as f64
+    }
+
+    /// Switch from `context` to the suspended coroutine `id`. When it yields
+    /// or finishes, `dest` receives 1 or 0 respectively.
+    pub(crate) fn resume(
+        &mut self,
+        id: f64,
+        dest: Option<usize>,
+        mut context: Context,
+    ) -> Result<(), VmError> {
+        let index = vm::address(id)
+            .filter(|&i| self.all.get(i).map(Coroutine::status) == Some(Status::Suspended))
+            .ok_or(VmError::NotResumable(id))?;
+        let coroutine = &mut self.all[index];
+        context.swap(coroutine);
+        coroutine.status = Status::Running;
+        self.active.push((index, dest));
+        Ok(())
+    }
+
+    /// Suspend the running coroutine and switch back to its resumer,
+    /// returning the register the resumer waits on
+    pub(crate) fn suspend(&mut self, mut context: Context) -> Result<Option<usize>, VmError> {
+        let (index, dest) = self.active.pop().ok_or(VmError::YieldOutsideCoroutine)?;
+        let coroutine = &mut self.all[index];
+        context.swap(coroutine);
+        coroutine.status = Status::Suspended;
+        Ok(dest)
+    }
+
+    /// Finish the running coroutine and switch back to its resumer,
+    /// returning the register the resumer waits on and the values the
+    /// coroutine left in its registers and operand stack. `None` if no
+    /// coroutine is running.
+    pub(crate) fn finish(&mut self, mut context: Context) -> Option<(Option<usize>, Vec<f64>)> {
+        let (index, dest) = self.active.pop()?;
+        let coroutine = &mut self.all[index];
+        context.swap(coroutine);
+        coroutine.status = Status::Finished;
+        coroutine.call_stack.clear();
+        coroutine.handlers.clear();
+        let mut values = core::mem::take(&mut coroutine.registers);
+        values.append(&mut coroutine.stack);
+        Some((dest, values))
+    }
+
+    /// Registers a context switch may write that the program does not
+    /// name: each resumer's outcome register and the code registers of the
+    /// handlers saved with every context
+    pub(crate) fn saved_registers(&self) -> impl Iterator<Item = usize> + '_ {
+        let outcomes = self.active.iter().filter_map(|&(_, dest)| dest);
+        let handlers = self
+            .all
+            .iter()
+            .flat_map(|c| c.handlers.iter().map(|handler| handler.dest));
+        outcomes.chain(handlers)
+    }
+
+    /// Values in the registers and operand stack of every context that is
+    /// not running
+    pub(crate) fn values(&self) -> impl Iterator<Item = f64> + '_ {
+        self.all
+            .iter()
+            .flat_map(|c| c.registers.iter().chain(&c.stack).copied())
+    }
+
+    /// Heap indices of the closures those contexts' frames are running
+    pub(crate) fn closures(&self) -> impl Iterator<Item = usize> + '_ {
+        self.all
+            .iter()
+            .flat_map(|c| c.call_stack.iter().filter_map(|frame| frame.closure))
+    }
+}
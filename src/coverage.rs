@@ -0,0 +1,85 @@
+//! Which instructions a run executed.
+//!
+//! `Coverage` is a hook counting how often each instruction runs. Programs
+//! have no source map back to the IR text they were assembled from, so
+//! reports identify instructions by address: lcov line N is the
+//! instruction at address N - 1, and each label is reported as a function.
+
+use crate::hook::{Hook, HookAction};
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+/// Execution counts per instruction address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    hits: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many times the instruction at `addr` started executing
+    pub fn hits(&self, addr: usize) -> u64 {
+        self.hits.get(addr).copied().unwrap_or(0)
+    }
+
+    /// Instructions of `program` executed at least once, and its length
+    pub fn covered(&self, program: &Program) -> (usize, usize) {
+        let len = program.instructions.len();
+        let covered = (0..len).filter(|&addr| self.hits(addr) > 0).count();
+        (covered, len)
+    }
+
+    /// The lcov tracefile for `program`, naming `source` as its file
+    pub fn to_lcov(&self, program: &Program, source: &str) -> String {
+        let mut out = format!("TN:\nSF:{}\n", source);
+        for label in program.labels() {
+            out.push_str(&format!("FN:{},{}\n", label.addr + 1, label.name));
+        }
+        for label in program.labels() {
+            out.push_str(&format!("FNDA:{},{}\n", self.hits(label.addr), label.name));
+        }
+        let called = program
+            .labels()
+            .iter()
+            .filter(|label| self.hits(label.addr) > 0)
+            .count();
+        out.push_str(&format!("FNF:{}\nFNH:{}\n", program.labels().len(), called));
+        for addr in 0..program.instructions.len() {
+            out.push_str(&format!("DA:{},{}\n", addr + 1, self.hits(addr)));
+        }
+        let (covered, len) = self.covered(program);
+        out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", len, covered));
+        out
+    }
+
+    /// The disassembly listing of `program` with each instruction's count
+    /// beside it, and `#####` beside those never executed
+    pub fn annotate(&self, program: &Program) -> String {
+        let mut out = String::new();
+        for (addr, instr) in program.instructions.iter().enumerate() {
+            for label in program.labels().iter().filter(|l| l.addr == addr) {
+                out.push_str(&format!("{:>8}  {}:\n", "", label.name));
+            }
+            let hits = match self.hits(addr) {
+                0 => "#####".to_string(),
+                n => n.to_string(),
+            };
+            out.push_str(&format!("{:>8}  {:>4}  {}\n", hits, addr, instr));
+        }
+        out
+    }
+}
+
+impl Hook for Coverage {
+    fn before(&mut self, pc: usize, _: &Instruction) -> HookAction {
+        if pc >= self.hits.len() {
+            self.hits.resize(pc + 1, 0);
+        }
+        self.hits[pc] += 1;
+        HookAction::Continue
+    }
+}
@@ -0,0 +1,331 @@
+//! Recovering structured control flow from jumps.
+//!
+//! `to_structured` prints a program as nested `if`/`else`, `while` and
+//! `loop` blocks wherever its jumps form them, the way a compiler lowers
+//! them: a conditional jump over a block, optionally ending in a jump over
+//! an else block, or a condition jumping out of a body that ends by
+//! jumping back to it. A block must only be entered at its start and only
+//! jump within itself. Code that fits none of these shapes, such as an
+//! irreducible loop, is printed as plain instructions, with a label
+//! before every address that something still jumps to.
+
+use crate::HashSet;
+use crate::instruction::{Comparison, Instruction};
+use crate::prelude::*;
+use crate::program::Program;
+use core::fmt::Write;
+
+/// A run of code and the construct it was recovered as
+enum Node {
+    Instr(usize),
+    /// The conditional jump at `pc` skips `then`; an else block comes with
+    /// the address of the jump over it, which ends `then`
+    If {
+        pc: usize,
+        then: Vec<Node>,
+        otherwise: Option<(usize, Vec<Node>)>,
+    },
+    /// `header` computes the condition the jump at `pc` tests, and `body` is
+    /// followed by the jump back to the header at `back`
+    While {
+        header: Vec<usize>,
+        pc: usize,
+        body: Vec<Node>,
+        back: usize,
+    },
+}
+
+impl Node {
+    fn start(&self) -> usize {
+        match self {
+            Node::Instr(pc) | Node::If { pc, .. } => *pc,
+            Node::While { header, pc, .. } => header.first().copied().unwrap_or(*pc),
+        }
+    }
+}
+
+/// The program as indented pseudocode with if/else and while blocks
+pub fn to_structured(program: &Program) -> String {
+    let code = &program.instructions;
+    let mut s = Structurer {
+        code,
+        incoming: vec![Vec::new(); code.len() + 1],
+        structured: HashSet::new(),
+    };
+    let nodes = if code.iter().any(Instruction::is_computed_jump) {
+        // Any instruction may be jumped to, so no block has a single entry
+        (0..code.len()).map(Node::Instr).collect()
+    } else {
+        for (pc, instr) in code.iter().enumerate() {
+            if !instr.is_call() {
+                for target in instr.targets(pc) {
+                    if let Some(sources) = s.incoming.get_mut(target) {
+                        sources.push(pc);
+                    }
+                }
+            }
+        }
+        s.structure(0, code.len())
+    };
+
+    // Label whatever the remaining jumps, calls and address operands refer to
+    let mut targeted = HashSet::new();
+    for (pc, instr) in code.iter().enumerate() {
+        if !s.structured.contains(&pc) {
+            targeted.extend(instr.addresses(pc));
+        }
+    }
+    let mut out = String::new();
+    let printer = Printer {
+        program,
+        targeted,
+        entry_shown: program.entry() != 0,
+    };
+    printer.nodes(&mut out, &nodes, 0);
+    out
+}
+
+struct Structurer<'a> {
+    code: &'a [Instruction],
+    /// For each address, the instructions that jump to it; calls excluded
+    incoming: Vec<Vec<usize>>,
+    /// Jumps now expressed by the block structure
+    structured: HashSet<usize>,
+}
+
+impl Structurer<'_> {
+    fn structure(&mut self, lo: usize, hi: usize) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        let mut pc = lo;
+        while pc < hi {
+            let (node, next) = self
+                .while_at(pc, hi)
+                .or_else(|| self.if_at(pc, hi))
+                .unwrap_or((Node::Instr(pc), pc + 1));
+            nodes.push(node);
+            pc = next;
+        }
+        nodes
+    }
+
+    /// A loop whose header starts at `top`: straight-line code, then a
+    /// conditional jump to just past the jump back to `top`
+    fn while_at(&mut self, top: usize, hi: usize) -> Option<(Node, usize)> {
+        let mut pc = top;
+        while pc < hi && self.is_straight(pc) {
+            pc += 1;
+        }
+        let end = cond_target(self.code.get(pc)?)?;
+        if end <= pc + 1 || end > hi || goto(self.code, end - 1) != Some(top) {
+            return None;
+        }
+        if !self.closed(top, end) || !self.closed(pc + 1, end - 1) {
+            return None;
+        }
+        self.structured.extend([pc, end - 1]);
+        let body = self.structure(pc + 1, end - 1);
+        let header = (top..pc).collect();
+        Some((
+            Node::While {
+                header,
+                pc,
+                body,
+                back: end - 1,
+            },
+            end,
+        ))
+    }
+
+    /// A conditional jump at `pc` over a then block, which may end in a
+    /// jump over an else block
+    fn if_at(&mut self, pc: usize, hi: usize) -> Option<(Node, usize)> {
+        let skip = cond_target(&self.code[pc])?;
+        if skip <= pc || skip > hi {
+            return None;
+        }
+        let over = if skip > pc + 1 {
+            goto(self.code, skip - 1).filter(|&end| end > skip && end <= hi)
+        } else {
+            None
+        };
+        if let Some(end) = over
+            && self.closed(pc, end)
+            && self.closed(pc + 1, skip - 1)
+            && self.closed(skip, end)
+        {
+            self.structured.extend([pc, skip - 1]);
+            let then = self.structure(pc + 1, skip - 1);
+            let otherwise = Some((skip - 1, self.structure(skip, end)));
+            return Some((
+                Node::If {
+                    pc,
+                    then,
+                    otherwise,
+                },
+                end,
+            ));
+        }
+        if !self.closed(pc, skip) || !self.closed(pc + 1, skip) {
+            return None;
+        }
+        self.structured.insert(pc);
+        let then = self.structure(pc + 1, skip);
+        Some((
+            Node::If {
+                pc,
+                then,
+                otherwise: None,
+            },
+            skip,
+        ))
+    }
+
+    /// Whether the instruction at `pc` always continues to the next one
+    fn is_straight(&self, pc: usize) -> bool {
+        let instr = &self.code[pc];
+        !instr.is_terminator() && (instr.is_call() || instr.targets(pc).is_empty())
+    }
+
+    /// Whether `start..end` is only entered at `start` and only jumps to
+    /// addresses in `start..=end`
+    fn closed(&self, start: usize, end: usize) -> bool {
+        let inside = |addr: usize| (start..=end).contains(&addr);
+        let jumps_within = (start..end).all(|pc| {
+            let instr = &self.code[pc];
+            instr.is_call() || instr.targets(pc).into_iter().all(inside)
+        });
+        let entered_at_start = (start + 1..end).all(|addr| {
+            self.incoming[addr]
+                .iter()
+                .all(|&from| (start..end).contains(&from))
+        });
+        jumps_within && entered_at_start
+    }
+}
+
+/// The target of a conditional jump, taken when its condition is false
+fn cond_target(instr: &Instruction) -> Option<usize> {
+    match instr {
+        Instruction::ConditionalJump { target, .. } | Instruction::CompareJump { target, .. } => {
+            Some(*target)
+        }
+        _ => None,
+    }
+}
+
+/// The target of the unconditional jump at `pc`, if it is one
+fn goto(code: &[Instruction], pc: usize) -> Option<usize> {
+    match code.get(pc)? {
+        instr @ (Instruction::Jump(_) | Instruction::JumpRel(_)) => instr.target(pc),
+        _ => None,
+    }
+}
+
+/// The condition under which a conditional jump falls through
+fn condition(instr: &Instruction) -> String {
+    match instr {
+        Instruction::ConditionalJump { cond, .. } => format!("r{}", cond),
+        Instruction::CompareJump {
+            cmp,
+            dest,
+            src1,
+            src2,
+            ..
+        } => {
+            let op = match cmp {
+                Comparison::Equal => "==",
+                Comparison::LessThan => "<",
+                Comparison::GreaterThan => ">",
+            };
+            format!("(r{} = r{} {} r{})", dest, src1, op, src2)
+        }
+        _ => unreachable!("only conditional jumps have conditions"),
+    }
+}
+
+struct Printer<'a> {
+    program: &'a Program,
+    targeted: HashSet<usize>,
+    entry_shown: bool,
+}
+
+impl Printer<'_> {
+    fn nodes(&self, out: &mut String, nodes: &[Node], depth: usize) {
+        for node in nodes {
+            self.node(out, node, depth);
+        }
+    }
+
+    fn node(&self, out: &mut String, node: &Node, depth: usize) {
+        let pad = "    ".repeat(depth);
+        self.labels(out, node.start(), &pad);
+        let code = &self.program.instructions;
+        match node {
+            Node::Instr(pc) => {
+                let _ = writeln!(out, "{}{}", pad, code[*pc]);
+            }
+            Node::If {
+                pc,
+                then,
+                otherwise,
+            } => {
+                let _ = writeln!(out, "{}if {} {{", pad, condition(&code[*pc]));
+                self.nodes(out, then, depth + 1);
+                if let Some((over, otherwise)) = otherwise {
+                    self.labels(out, *over, &"    ".repeat(depth + 1));
+                    let _ = writeln!(out, "{}}} else {{", pad);
+                    self.nodes(out, otherwise, depth + 1);
+                }
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Node::While {
+                header,
+                pc,
+                body,
+                back,
+            } if header.is_empty() => {
+                let _ = writeln!(out, "{}while {} {{", pad, condition(&code[*pc]));
+                self.nodes(out, body, depth + 1);
+                self.labels(out, *back, &"    ".repeat(depth + 1));
+                let _ = writeln!(out, "{}}}", pad);
+            }
+            Node::While {
+                header,
+                pc,
+                body,
+                back,
+            } => {
+                let _ = writeln!(out, "{}loop {{", pad);
+                let inner = "    ".repeat(depth + 1);
+                for (i, &at) in header.iter().enumerate() {
+                    if i > 0 {
+                        self.labels(out, at, &inner);
+                    }
+                    let _ = writeln!(out, "{}{}", inner, code[at]);
+                }
+                self.labels(out, *pc, &inner);
+                let cond = condition(&code[*pc]);
+                let _ = writeln!(out, "{}if !{} {{ break }}", inner, cond);
+                self.nodes(out, body, depth + 1);
+                self.labels(out, *back, &inner);
+                let _ = writeln!(out, "{}}}", pad);
+            }
+        }
+    }
+
+    /// The labels, exports and jump targets at `addr`
+    fn labels(&self, out: &mut String, addr: usize, pad: &str) {
+        for label in self.program.labels().iter().filter(|l| l.addr == addr) {
+            let _ = writeln!(out, "{}{}:", pad, label.name);
+        }
+        for export in self.program.exports().iter().filter(|e| e.addr == addr) {
+            let _ = writeln!(out, "{}; export {}/{}", pad, export.name, export.arity);
+        }
+        if self.entry_shown && addr == self.program.entry() {
+            let _ = writeln!(out, "{}; entry", pad);
+        }
+        if self.targeted.contains(&addr) {
+            let _ = writeln!(out, "{}{}:", pad, addr);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Everything wrong with an assembly source at once, located by line and
+//! column, for `zyde check` to print for people or as JSON for editors and
+//! scripts.
+//!
+//! Every error `asm_reg::assemble_all` finds is reported, but nothing more
+//! is checked until a source assembles. Then its warnings and whatever
+//! `Program::verify`, `verify_handlers`, `verify_ranges` and `types::check`
+//! find are reported against the lines they came from.
+
+use crate::asm_reg::{self, AsmErrorKind, Level, Lints, WarningKind};
+use crate::json;
+use crate::prelude::*;
+use crate::program::ProgramError;
+use crate::types::{self, TypeError};
+use core::fmt;
+use core::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A short name for the problem, such as `unknown-mnemonic` or
+    /// `unused-label`
+    pub code: &'static str,
+    pub message: String,
+    /// Line of the source, from 1
+    pub line: usize,
+    /// The columns on that line of the text at fault, from 1
+    pub columns: Range<usize>,
+    /// A likely fix, if there is one
+    pub suggestion: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}[{}]: {}",
+            self.line,
+            self.columns.start,
+            self.severity.name(),
+            self.code,
+            self.message
+        )?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "\n  help: {}", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Assemble and verify `source`, reporting each warning at the level
+/// `lints` gives it; denied warnings become errors
+pub fn check(source: &str, lints: &Lints) -> Vec<Diagnostic> {
+    let assembly = match asm_reg::assemble_all(source) {
+        Ok(assembly) => assembly,
+        Err(errors) => {
+            let mut diagnostics: Vec<Diagnostic> = errors
+                .into_iter()
+                .map(|e| Diagnostic {
+                    severity: Severity::Error,
+                    code: e.kind.code(),
+                    message: message(&e.to_string()),
+                    suggestion: suggest_fix(&e.kind),
+                    line: e.line,
+                    columns: e.columns,
+                })
+                .collect();
+            if diagnostics.len() == asm_reg::MAX_ERRORS {
+                let last = &diagnostics[diagnostics.len() - 1];
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    code: "too-many-errors",
+                    message: "Too many errors; any after this are not reported".to_string(),
+                    line: last.line,
+                    columns: last.columns.clone(),
+                    suggestion: None,
+                });
+            }
+            return diagnostics;
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+    for warning in &assembly.warnings {
+        let severity = match lints.level(&warning.kind) {
+            Level::Allow => continue,
+            Level::Warn => Severity::Warning,
+            Level::Deny => Severity::Error,
+        };
+        diagnostics.push(Diagnostic {
+            severity,
+            code: warning.kind.name(),
+            message: message(&warning.to_string()),
+            suggestion: Some(suggest_warning_fix(&warning.kind)),
+            line: warning.line,
+            columns: warning.columns.clone(),
+        });
+    }
+
+    let program = &assembly.program;
+    let lines: Vec<&str> = source.lines().collect();
+    // Errors name the instruction at fault by address
+    let at = |addr: Option<usize>| match addr.and_then(|addr| assembly.lines.get(addr)) {
+        Some(&line) => (line, statement(lines[line - 1])),
+        None => (1, 1..1),
+    };
+    let invalid = |e: ProgramError| {
+        let (addr, message) = (e.addr(), e.to_string());
+        (AsmErrorKind::Program(e).code(), addr, message)
+    };
+    let mut found = Vec::new();
+    match program.verify() {
+        Err(e) => found.push(invalid(e)),
+        // The later checks expect every address to be in the program
+        Ok(()) => {
+            for e in [program.verify_handlers(), program.verify_ranges()]
+                .into_iter()
+                .filter_map(Result::err)
+            {
+                found.push(invalid(e));
+            }
+            if let Err(e) = types::check(program, &assembly.types) {
+                found.push((type_code(&e), Some(e.addr()), e.to_string()));
+            }
+        }
+    }
+    for (code, addr, message) in found {
+        let (line, columns) = at(addr);
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            line,
+            columns,
+            suggestion: None,
+        });
+    }
+    diagnostics.sort_by_key(|d| (d.line, d.columns.start));
+    diagnostics
+}
+
+/// `diagnostics` from the source file `file` as a JSON array
+pub fn to_json(diagnostics: &[Diagnostic], file: &str) -> String {
+    let entries: Vec<String> = diagnostics
+        .iter()
+        .map(|d| {
+            format!(
+                "{{\"file\":{},\"line\":{},\"column\":{},\"end_column\":{},\"severity\":{},\"code\":{},\"message\":{},\"suggestion\":{}}}",
+                json::string(file),
+                d.line,
+                d.columns.start,
+                d.columns.end,
+                json::string(d.severity.name()),
+                json::string(d.code),
+                json::string(&d.message),
+                d.suggestion
+                    .as_deref()
+                    .map_or_else(|| "null".to_string(), json::string),
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// A message without the `Line N: ` that errors and warnings start with,
+/// or the `(name)` that warnings end with
+fn message(text: &str) -> String {
+    let text = text.split_once(": ").map_or(text, |(_, rest)| rest);
+    let text = text
+        .strip_suffix(')')
+        .and_then(|t| t.rsplit_once(" ("))
+        .filter(|(_, name)| asm_reg::WARNINGS.contains(name))
+        .map_or(text, |(t, _)| t);
+    text.to_string()
+}
+
+/// The columns of the statement on `line`, without its comment
+fn statement(line: &str) -> Range<usize> {
+    let code = asm_reg::strip_comment(line);
+    let start = code.len() - code.trim_start().len();
+    start + 1..code.trim_end().len() + 1
+}
+
+/// The types a `.func` signature can name
+const TYPES: [&str; 7] = [
+    "float",
+    "int",
+    "bool",
+    "record",
+    "map",
+    "closure",
+    "coroutine",
+];
+
+fn type_code(e: &TypeError) -> &'static str {
+    match e {
+        TypeError::Mismatch { .. } => "type-mismatch",
+        TypeError::BadImmediate { .. } => "bad-immediate",
+    }
+}
+
+fn suggest_fix(kind: &AsmErrorKind) -> Option<String> {
+    match kind {
+        AsmErrorKind::UnknownMnemonic(op) => {
+            closest(op, &asm_reg::MNEMONICS).map(|m| format!("did you mean `{}`?", m))
+        }
+        AsmErrorKind::UnknownDirective(name) => {
+            closest(name, &["export", "const", "func", "entry"])
+                .map(|d| format!("did you mean `.{}`?", d))
+        }
+        AsmErrorKind::ExpectedRegister(_) => {
+            Some("registers are written `r0`, `r1` and so on".to_string())
+        }
+        AsmErrorKind::UnknownType(name) if name.len() == 1 => {
+            Some("`loadimm.` takes `i`, `f` or `b`".to_string())
+        }
+        AsmErrorKind::UnknownType(name) => {
+            closest(name, &TYPES).map(|t| format!("did you mean `{}`?", t))
+        }
+        AsmErrorKind::BadSignature(_) => {
+            Some("signatures are written `name(int, float) -> bool`".to_string())
+        }
+        AsmErrorKind::BadName(name) | AsmErrorKind::ReservedName(name)
+            if !name.starts_with('"') =>
+        {
+            Some(format!("quote it: `{}`", asm_reg::quote(name)))
+        }
+        AsmErrorKind::Program(ProgramError::UnknownSymbol(name)) => {
+            Some(format!("define `{}:` before an instruction", name))
+        }
+        _ => None,
+    }
+}
+
+fn suggest_warning_fix(kind: &WarningKind) -> String {
+    match kind {
+        WarningKind::UnusedLabel(_) => "remove the label".to_string(),
+        WarningKind::Unreachable(_) => "remove the code, or label it and jump there".to_string(),
+        WarningKind::UnusedVariable(var) => format!("remove the stores to `{}`", var),
+        WarningKind::InexactLiteral(_, value) if value.is_finite() => {
+            format!("write `{}` to say so", value)
+        }
+        WarningKind::InexactLiteral(..) => "use a literal an `f64` can hold".to_string(),
+    }
+}
+
+/// The entry of `candidates` a typo or two away from `word`, if any
+fn closest<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|&c| (distance(word, c), c))
+        .filter(|&(d, _)| d <= 2 && d < word.len())
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, c)| c)
+}
+
+/// Levenshtein distance between `a` and `b`
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
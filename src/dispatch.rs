@@ -0,0 +1,706 @@
+//! Pre-decoded execution for verified programs.
+//!
+//! `Instruction` stays the public representation. Before a run, a program that
+//! passed `Program::verify_registers` is lowered to flat `Op`s: a one-byte
+//! opcode plus packed operands, with variable names interned into slots. The
+//! hot loop dispatches on the dense opcode, which compiles to a jump table,
+//! without bounds-checking registers or hashing variable names.
+
+use crate::HashMap;
+use crate::coroutine::Context;
+use crate::heap::{Object, map_key};
+use crate::host;
+use crate::instruction::{self, Comparison, Instruction};
+use crate::isolate::Message;
+use crate::prelude::*;
+use crate::vm::{self, Frame, Handler, RunLimits, VM, VmError};
+use alloc::collections::BTreeMap;
+
+#[derive(Clone, Copy)]
+enum Opcode {
+    LoadImm,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Print,
+    Jump,
+    Call,
+    ConditionalJump,
+    Return,
+    Store,
+    Load,
+    Mov,
+    Equal,
+    LessThan,
+    GreaterThan,
+    Not,
+    Halt,
+    AddImm,
+    EqualJump,
+    LessThanJump,
+    GreaterThanJump,
+    TryBegin,
+    TryEnd,
+    Throw,
+    JumpIndirect,
+    Switch,
+    CallIndirect,
+    MakeClosure,
+    CallClosure,
+    GetUpvalue,
+    SetUpvalue,
+    NewRecord,
+    GetField,
+    SetField,
+    MapNew,
+    MapGet,
+    MapSet,
+    MapHas,
+    MapDelete,
+    Spawn,
+    Yield,
+    Resume,
+    CallHost,
+    Send,
+    Recv,
+    Rand,
+    Push,
+    Pop,
+    CallArgs,
+    Arity,
+}
+
+/// One decoded instruction. Registers, addresses and variable slots are packed
+/// into `a` to `d` in the order the instruction's fields are declared; a
+/// switch table or a closure's captured registers are packed as an index
+/// into the decoded tables.
+struct Op {
+    code: Opcode,
+    a: usize,
+    b: usize,
+    c: usize,
+    d: usize,
+    imm: f64,
+}
+
+/// Run `vm` to completion on the decoded fast path.
+///
+/// The caller must have checked `vm.program.verify_registers(vm.registers.len())`.
+pub(crate) fn run(vm: &mut VM, limits: &RunLimits) -> Result<(), VmError> {
+    let mut names: Vec<String> = Vec::new();
+    let mut slots: HashMap<&str, usize> = HashMap::new();
+    let mut tables: Vec<&[usize]> = Vec::new();
+    let code: Vec<Op> = vm
+        .program
+        .instructions
+        .iter()
+        .enumerate()
+        .map(|(pc, instr)| {
+            if let Instruction::Switch { table, .. }
+            | Instruction::MakeClosure {
+                captures: table, ..
+            } = instr
+            {
+                tables.push(table);
+            }
+            decode(instr, pc, tables.len().saturating_sub(1), |name| {
+                *slots.entry(name).or_insert_with(|| {
+                    names.push(name.to_string());
+                    names.len() - 1
+                })
+            })
+        })
+        .collect();
+    let mut variables: Vec<Option<f64>> =
+        names.iter().map(|n| vm.variables.get(n).copied()).collect();
+
+    let len = code.len();
+    let registers = vm.registers.as_mut_slice();
+    let get = |registers: &[f64], reg: usize| {
+        debug_assert!(reg < registers.len());
+        // SAFETY: only programs that passed `verify_registers` for this
+        // register file are decoded, and it cannot be resized during the run
+        unsafe { *registers.get_unchecked(reg) }
+    };
+    let set = |registers: &mut [f64], reg: usize, value: f64| {
+        debug_assert!(reg < registers.len());
+        // SAFETY: as in `get`
+        unsafe { *registers.get_unchecked_mut(reg) = value };
+    };
+
+    let mut pc = vm.pc;
+    let mut executed = 0;
+    let max_stack = vm.config.max_stack.unwrap_or(usize::MAX);
+
+    // Allocate on the heap, collecting first once it has grown enough.
+    // Variables are cached here until the run ends, so the cached values
+    // are roots alongside the VM's own.
+    macro_rules! alloc {
+        ($object:expr) => {{
+            if vm.heap.needs_collection() {
+                let values = variables.iter().flatten().chain(vm.variables.values());
+                let frames = vm.call_stack.iter().filter_map(|frame| frame.closure);
+                vm.heap.collect(
+                    registers
+                        .iter()
+                        .chain(&vm.stack)
+                        .chain(values)
+                        .copied()
+                        .chain(vm.coroutines.values()),
+                    frames.chain(vm.coroutines.closures()),
+                );
+            }
+            vm.heap.alloc($object)
+        }};
+    }
+
+    // The running context, for switching coroutines
+    macro_rules! context {
+        () => {
+            Context {
+                pc: &mut pc,
+                registers: &mut *registers,
+                call_stack: &mut vm.call_stack,
+                stack: &mut vm.stack,
+                handlers: &mut vm.handlers,
+            }
+        };
+    }
+
+    // Unwind to the innermost handler, or stop the run if nothing catches
+    macro_rules! trap {
+        ($error:expr) => {
+            match vm::unwind(&mut vm.handlers, $error) {
+                Ok((handler, code)) => {
+                    vm.call_stack.truncate(handler.call_depth);
+                    vm.stack.truncate(handler.stack_depth);
+                    set(registers, handler.dest, code);
+                    pc = handler.addr;
+                    continue;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+    }
+
+    let result = loop {
+        let Some(op) = code.get(pc) else {
+            break Ok(());
+        };
+        if let Err(e) = limits.check(executed) {
+            break Err(e);
+        }
+        pc += 1;
+        executed += 1;
+
+        let jump = match op.code {
+            Opcode::LoadImm => {
+                set(registers, op.a, op.imm);
+                continue;
+            }
+            Opcode::Add => {
+                set(registers, op.a, get(registers, op.b) + get(registers, op.c));
+                continue;
+            }
+            Opcode::Sub => {
+                set(registers, op.a, get(registers, op.b) - get(registers, op.c));
+                continue;
+            }
+            Opcode::Mul => {
+                set(registers, op.a, get(registers, op.b) * get(registers, op.c));
+                continue;
+            }
+            Opcode::Div => {
+                set(registers, op.a, get(registers, op.b) / get(registers, op.c));
+                continue;
+            }
+            Opcode::Print => {
+                vm.output.write(get(registers, op.a));
+                continue;
+            }
+            Opcode::Jump => op.a,
+            Opcode::Call => {
+                if op.a >= len {
+                    trap!(VmError::ProgramCounterOutOfBounds);
+                }
+                vm.call_stack.push(Frame::new(pc));
+                pc = op.a;
+                continue;
+            }
+            Opcode::ConditionalJump => {
+                if get(registers, op.a) != 0.0 {
+                    continue;
+                }
+                op.b
+            }
+            Opcode::Return => match vm.call_stack.pop() {
+                Some(frame) => {
+                    pc = frame.return_address;
+                    vm::prune_handlers(&mut vm.handlers, vm.call_stack.len());
+                    continue;
+                }
+                // Returning from the function a coroutine was spawned at
+                None => match vm.coroutines.finish(context!()) {
+                    Some((dest, _)) => {
+                        if let Some(dest) = dest {
+                            set(registers, dest, 0.0);
+                        }
+                        continue;
+                    }
+                    None => trap!(VmError::CallStackEmpty),
+                },
+            },
+            Opcode::Store => {
+                variables[op.b] = Some(get(registers, op.a));
+                continue;
+            }
+            Opcode::Load => match variables[op.b] {
+                Some(v) => {
+                    set(registers, op.a, v);
+                    continue;
+                }
+                None => trap!(VmError::VariableNotFound(names[op.b].clone())),
+            },
+            Opcode::Mov => {
+                set(registers, op.a, get(registers, op.b));
+                continue;
+            }
+            Opcode::Equal => {
+                set(
+                    registers,
+                    op.a,
+                    bool_val(get(registers, op.b) == get(registers, op.c)),
+                );
+                continue;
+            }
+            Opcode::LessThan => {
+                set(
+                    registers,
+                    op.a,
+                    bool_val(get(registers, op.b) < get(registers, op.c)),
+                );
+                continue;
+            }
+            Opcode::GreaterThan => {
+                set(
+                    registers,
+                    op.a,
+                    bool_val(get(registers, op.b) > get(registers, op.c)),
+                );
+                continue;
+            }
+            Opcode::Not => {
+                set(registers, op.a, bool_val(get(registers, op.b) == 0.0));
+                continue;
+            }
+            Opcode::Halt => {
+                pc = len;
+                continue;
+            }
+            Opcode::AddImm => {
+                set(registers, op.c, op.imm);
+                set(registers, op.a, get(registers, op.b) + op.imm);
+                continue;
+            }
+            Opcode::EqualJump | Opcode::LessThanJump | Opcode::GreaterThanJump => {
+                let (x, y) = (get(registers, op.b), get(registers, op.c));
+                let taken = match op.code {
+                    Opcode::EqualJump => x == y,
+                    Opcode::LessThanJump => x < y,
+                    _ => x > y,
+                };
+                set(registers, op.a, bool_val(taken));
+                if taken {
+                    continue;
+                }
+                op.d
+            }
+            Opcode::TryBegin => {
+                if op.a >= len {
+                    trap!(VmError::ProgramCounterOutOfBounds);
+                }
+                vm.handlers.push(Handler {
+                    addr: op.a,
+                    dest: op.b,
+                    call_depth: vm.call_stack.len(),
+                    stack_depth: vm.stack.len(),
+                });
+                continue;
+            }
+            Opcode::TryEnd => {
+                if vm.handlers.pop().is_none() {
+                    trap!(VmError::HandlerStackEmpty);
+                }
+                continue;
+            }
+            Opcode::Throw => trap!(VmError::Uncaught(get(registers, op.a))),
+            Opcode::JumpIndirect => match vm::address(get(registers, op.a)) {
+                Some(addr) => addr,
+                None => trap!(VmError::ProgramCounterOutOfBounds),
+            },
+            Opcode::CallIndirect => match vm::address(get(registers, op.a)) {
+                Some(addr) if addr < len => {
+                    vm.call_stack.push(Frame::new(pc));
+                    pc = addr;
+                    continue;
+                }
+                _ => trap!(VmError::ProgramCounterOutOfBounds),
+            },
+            Opcode::MakeClosure => {
+                let upvalues = tables[op.c].iter().map(|&r| get(registers, r)).collect();
+                let handle = alloc!(Object::Closure {
+                    addr: op.b,
+                    upvalues,
+                });
+                set(registers, op.a, handle);
+                continue;
+            }
+            Opcode::CallClosure => match vm.heap.closure(get(registers, op.a)) {
+                Ok((addr, closure)) if addr < len => {
+                    vm.call_stack.push(Frame {
+                        return_address: pc,
+                        closure: Some(closure),
+                    });
+                    pc = addr;
+                    continue;
+                }
+                Ok(_) => trap!(VmError::ProgramCounterOutOfBounds),
+                Err(e) => trap!(e),
+            },
+            Opcode::GetUpvalue | Opcode::SetUpvalue => {
+                let closure = vm.call_stack.last().and_then(|frame| frame.closure);
+                match vm.heap.upvalue(closure, op.b) {
+                    Ok(upvalue) if matches!(op.code, Opcode::GetUpvalue) => {
+                        set(registers, op.a, *upvalue);
+                        continue;
+                    }
+                    Ok(upvalue) => {
+                        *upvalue = get(registers, op.a);
+                        continue;
+                    }
+                    Err(e) => trap!(e),
+                }
+            }
+            Opcode::NewRecord => {
+                let handle = alloc!(Object::Record {
+                    fields: vec![0.0; op.b],
+                });
+                set(registers, op.a, handle);
+                continue;
+            }
+            Opcode::GetField => match vm.heap.field(get(registers, op.b), op.c) {
+                Ok(field) => {
+                    set(registers, op.a, *field);
+                    continue;
+                }
+                Err(e) => trap!(e),
+            },
+            Opcode::SetField => match vm.heap.field(get(registers, op.a), op.b) {
+                Ok(field) => {
+                    *field = get(registers, op.c);
+                    continue;
+                }
+                Err(e) => trap!(e),
+            },
+            Opcode::MapNew => {
+                let handle = alloc!(Object::Map {
+                    entries: BTreeMap::new(),
+                });
+                set(registers, op.a, handle);
+                continue;
+            }
+            Opcode::MapGet => {
+                let key = get(registers, op.c);
+                match vm.heap.map(get(registers, op.b)) {
+                    Ok(entries) => match entries.get(&map_key(key)) {
+                        Some(&v) => {
+                            set(registers, op.a, v);
+                            continue;
+                        }
+                        None => trap!(VmError::KeyNotFound(key)),
+                    },
+                    Err(e) => trap!(e),
+                }
+            }
+            Opcode::MapHas => match vm.heap.map(get(registers, op.b)) {
+                Ok(entries) => {
+                    let has = entries.contains_key(&map_key(get(registers, op.c)));
+                    set(registers, op.a, bool_val(has));
+                    continue;
+                }
+                Err(e) => trap!(e),
+            },
+            Opcode::MapSet => {
+                let key = map_key(get(registers, op.b));
+                match vm
+                    .heap
+                    .map_insert(get(registers, op.a), key, get(registers, op.c))
+                {
+                    Ok(_) => continue,
+                    Err(e) => trap!(e),
+                }
+            }
+            Opcode::MapDelete => {
+                let key = map_key(get(registers, op.b));
+                match vm.heap.map_remove(get(registers, op.a), key) {
+                    Ok(_) => continue,
+                    Err(e) => trap!(e),
+                }
+            }
+            Opcode::Spawn => {
+                if op.b >= len {
+                    trap!(VmError::ProgramCounterOutOfBounds);
+                }
+                let id = vm.coroutines.spawn(op.b, registers.to_vec());
+                set(registers, op.a, id);
+                continue;
+            }
+            Opcode::Yield => match vm.coroutines.suspend(context!()) {
+                Ok(dest) => {
+                    if let Some(dest) = dest {
+                        set(registers, dest, 1.0);
+                    }
+                    continue;
+                }
+                Err(e) => trap!(e),
+            },
+            Opcode::Resume => {
+                let id = get(registers, op.b);
+                match vm.coroutines.resume(id, Some(op.a), context!()) {
+                    Ok(()) => continue,
+                    Err(e) => trap!(e),
+                }
+            }
+            // Registered functions run in place; any other call stops the run
+            // until the host completes it
+            Opcode::CallHost => {
+                let Instruction::CallHost { name, args, .. } = &vm.program.instructions[pc - 1]
+                else {
+                    unreachable!("decoded from a callhost")
+                };
+                let pending = match host::pending(op.a, name, args, registers) {
+                    Ok(pending) => pending,
+                    Err(e) => trap!(e),
+                };
+                match vm.host_functions.get(name) {
+                    Some(function) => match host::call(function, &pending.call) {
+                        Ok(v) => {
+                            set(registers, op.a, v);
+                            continue;
+                        }
+                        Err(e) => trap!(e),
+                    },
+                    None => {
+                        vm.host_call = Some(pending);
+                        break Err(VmError::HostCallPending(name.clone()));
+                    }
+                }
+            }
+            // A blocked channel leaves the run before the instruction
+            Opcode::Send => {
+                if vm.mailbox.outbox.is_some() {
+                    pc -= 1;
+                    executed -= 1;
+                    break Err(VmError::Blocked);
+                }
+                let Instruction::Send { to, .. } = &vm.program.instructions[pc - 1] else {
+                    unreachable!("decoded from a send")
+                };
+                vm.mailbox.outbox = Some(Message {
+                    to: to.clone(),
+                    value: get(registers, op.a),
+                });
+                continue;
+            }
+            Opcode::Recv => match vm.mailbox.inbox.pop_front() {
+                Some(value) => {
+                    set(registers, op.a, value);
+                    continue;
+                }
+                None => {
+                    pc -= 1;
+                    executed -= 1;
+                    break Err(VmError::Blocked);
+                }
+            },
+            Opcode::Rand => {
+                set(registers, op.a, vm.rng.next_f64());
+                continue;
+            }
+            Opcode::Push => {
+                if vm.stack.len() >= max_stack {
+                    break Err(VmError::MemoryLimitExceeded("stack"));
+                }
+                vm.stack.push(get(registers, op.a));
+                continue;
+            }
+            Opcode::Pop => match vm.stack.pop() {
+                Some(value) => {
+                    set(registers, op.a, value);
+                    continue;
+                }
+                None => trap!(VmError::StackUnderflow),
+            },
+            Opcode::CallArgs => {
+                if vm.stack.len() >= max_stack {
+                    break Err(VmError::MemoryLimitExceeded("stack"));
+                }
+                if op.a >= len {
+                    trap!(VmError::ProgramCounterOutOfBounds);
+                }
+                vm.call_stack.push(Frame::new(pc));
+                vm.stack.push(op.b as f64);
+                pc = op.a;
+                continue;
+            }
+            // No upper bound is packed as `usize::MAX`
+            Opcode::Arity => {
+                let Some(&value) = vm.stack.last() else {
+                    trap!(VmError::StackUnderflow);
+                };
+                match vm::arity(value, op.b, Some(op.c)) {
+                    Ok(_) => {
+                        vm.stack.pop();
+                        set(registers, op.a, value);
+                        continue;
+                    }
+                    Err(e) => trap!(e),
+                }
+            }
+            Opcode::Switch => vm::address(get(registers, op.a))
+                .and_then(|i| tables[op.b].get(i).copied())
+                .unwrap_or(op.c),
+        };
+        if jump >= len {
+            trap!(VmError::ProgramCounterOutOfBounds);
+        }
+        pc = jump;
+    };
+
+    // Write the run's state back, whether or not it succeeded
+    vm.pc = pc;
+    vm.steps += executed;
+    for (name, value) in names.into_iter().zip(variables) {
+        if let Some(value) = value {
+            match vm.variables.get_mut(&name) {
+                Some(slot) => *slot = value,
+                None => {
+                    vm.variables.insert(name, value);
+                }
+            }
+        }
+    }
+    result
+}
+
+fn decode<'p>(
+    instr: &'p Instruction,
+    pc: usize,
+    table: usize,
+    mut slot: impl FnMut(&'p str) -> usize,
+) -> Op {
+    use Instruction::*;
+
+    let op = |code, a, b, c| Op {
+        code,
+        a,
+        b,
+        c,
+        d: 0,
+        imm: 0.0,
+    };
+    match *instr {
+        LoadImm { dest, value } => Op {
+            imm: value,
+            ..op(Opcode::LoadImm, dest, 0, 0)
+        },
+        Add { dest, src1, src2 } => op(Opcode::Add, dest, src1, src2),
+        Sub { dest, src1, src2 } => op(Opcode::Sub, dest, src1, src2),
+        Mul { dest, src1, src2 } => op(Opcode::Mul, dest, src1, src2),
+        Div { dest, src1, src2 } => op(Opcode::Div, dest, src1, src2),
+        Print { src } => op(Opcode::Print, src, 0, 0),
+        Jump(target) => op(Opcode::Jump, target, 0, 0),
+        Call { addr } => op(Opcode::Call, addr, 0, 0),
+        JumpRel(offset) => op(Opcode::Jump, instruction::relative(pc, offset), 0, 0),
+        CallRel(offset) => op(Opcode::Call, instruction::relative(pc, offset), 0, 0),
+        ConditionalJump { cond, target } => op(Opcode::ConditionalJump, cond, target, 0),
+        Return => op(Opcode::Return, 0, 0, 0),
+        Store { src, ref var } => op(Opcode::Store, src, slot(var), 0),
+        Load { dest, ref var } => op(Opcode::Load, dest, slot(var), 0),
+        Mov { dest, src } => op(Opcode::Mov, dest, src, 0),
+        Equal { dest, src1, src2 } => op(Opcode::Equal, dest, src1, src2),
+        LessThan { dest, src1, src2 } => op(Opcode::LessThan, dest, src1, src2),
+        GreaterThan { dest, src1, src2 } => op(Opcode::GreaterThan, dest, src1, src2),
+        Not { dest, src } => op(Opcode::Not, dest, src, 0),
+        Halt => op(Opcode::Halt, 0, 0, 0),
+        TryBegin { handler, dest } => op(Opcode::TryBegin, handler, dest, 0),
+        TryEnd => op(Opcode::TryEnd, 0, 0, 0),
+        Throw { src } => op(Opcode::Throw, src, 0, 0),
+        JumpIndirect { src } => op(Opcode::JumpIndirect, src, 0, 0),
+        Switch { src, default, .. } => op(Opcode::Switch, src, table, default),
+        CallIndirect { src } => op(Opcode::CallIndirect, src, 0, 0),
+        MakeClosure { dest, addr, .. } => op(Opcode::MakeClosure, dest, addr, table),
+        CallClosure { src } => op(Opcode::CallClosure, src, 0, 0),
+        GetUpvalue { dest, index } => op(Opcode::GetUpvalue, dest, index, 0),
+        SetUpvalue { src, index } => op(Opcode::SetUpvalue, src, index, 0),
+        NewRecord { dest, fields } => op(Opcode::NewRecord, dest, fields, 0),
+        GetField {
+            dest,
+            record,
+            index,
+        } => op(Opcode::GetField, dest, record, index),
+        SetField { record, index, src } => op(Opcode::SetField, record, index, src),
+        MapNew { dest } => op(Opcode::MapNew, dest, 0, 0),
+        MapGet { dest, map, key } => op(Opcode::MapGet, dest, map, key),
+        MapSet { map, key, src } => op(Opcode::MapSet, map, key, src),
+        MapHas { dest, map, key } => op(Opcode::MapHas, dest, map, key),
+        MapDelete { map, key } => op(Opcode::MapDelete, map, key, 0),
+        Spawn { dest, addr } => op(Opcode::Spawn, dest, addr, 0),
+        Yield => op(Opcode::Yield, 0, 0, 0),
+        Resume { dest, id } => op(Opcode::Resume, dest, id, 0),
+        CallHost { dest, .. } => op(Opcode::CallHost, dest, 0, 0),
+        Send { src, .. } => op(Opcode::Send, src, 0, 0),
+        Recv { dest } => op(Opcode::Recv, dest, 0, 0),
+        Rand { dest } => op(Opcode::Rand, dest, 0, 0),
+        Push { src } => op(Opcode::Push, src, 0, 0),
+        Pop { dest } => op(Opcode::Pop, dest, 0, 0),
+        CallArgs { addr, argc } => op(Opcode::CallArgs, addr, argc, 0),
+        Arity { dest, min, max } => op(Opcode::Arity, dest, min, max.unwrap_or(usize::MAX)),
+        // The decoded form is never relocated, so the address is just a value
+        LoadAddr { dest, addr } => Op {
+            imm: addr as f64,
+            ..op(Opcode::LoadImm, dest, 0, 0)
+        },
+        AddImm {
+            dest,
+            src,
+            imm,
+            value,
+        } => Op {
+            imm: value,
+            ..op(Opcode::AddImm, dest, src, imm)
+        },
+        CompareJump {
+            cmp,
+            dest,
+            src1,
+            src2,
+            target,
+        } => {
+            let code = match cmp {
+                Comparison::Equal => Opcode::EqualJump,
+                Comparison::LessThan => Opcode::LessThanJump,
+                Comparison::GreaterThan => Opcode::GreaterThanJump,
+            };
+            Op {
+                d: target,
+                ..op(code, dest, src1, src2)
+            }
+        }
+    }
+}
+
+fn bool_val(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
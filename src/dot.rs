@@ -0,0 +1,23 @@
+//! Minimal helpers for writing Graphviz DOT by hand.
+
+use crate::prelude::*;
+
+/// Quote and escape `s` as a DOT string; newlines become centered line breaks
+pub(crate) fn string(s: &str) -> String {
+    format!("\"{}\"", escape(s).replace('\n', "\\n"))
+}
+
+/// Quote `lines` as a single DOT label with every line left-justified
+pub(crate) fn left_lines<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> String {
+    let mut out = String::from("\"");
+    for line in lines {
+        out.push_str(&escape(line.as_ref()));
+        out.push_str("\\l");
+    }
+    out.push('"');
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,346 @@
+//! Differential execution.
+//!
+//! `verify` runs a program as written on the stepping interpreter, then runs
+//! its optimized form on the stepping interpreter, the pre-decoded
+//! interpreter and, with the `jit` feature, native code. Each run's printed
+//! values, result, registers, variables, operand stack and call depth are
+//! compared with the first run's, and the first difference is reported, so a
+//! pass or backend that changes what a program does is caught on a program
+//! that shows it.
+//!
+//! When two traced runs differ, their traces are compared to find where.
+//! Passes may reorder and drop instructions, so steps are not matched one to
+//! one: instead each register, variable and the output is followed through
+//! the values it takes on, and the first of those that differs, earliest in
+//! the optimized run, is reported with its step index and pc on both sides.
+//! A wrong value is then blamed on the step that computed it, even if it is
+//! overwritten before it reaches anything printed.
+
+use crate::passes::PassManager;
+use crate::prelude::*;
+use crate::program::Program;
+use crate::trace::Trace;
+use crate::vm::VM;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::error::Error;
+use core::fmt;
+
+/// How a program is executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// One instruction at a time, traced so printed values carry their step
+    Stepping,
+    /// The pre-decoded interpreter `VM::run` uses when it can
+    Decoded,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Stepping => write!(f, "stepping"),
+            Engine::Decoded => write!(f, "decoded"),
+            #[cfg(feature = "jit")]
+            Engine::Jit => write!(f, "jit"),
+        }
+    }
+}
+
+/// A step of a traced run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// Index of the step in the run
+    pub index: usize,
+    /// Address of the instruction it executed
+    pub pc: usize,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {} (pc {})", self.index, self.pc)
+    }
+}
+
+/// A value the program printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Printed {
+    pub value: f64,
+    /// Index of the step that printed it, if the run was traced
+    pub step: Option<usize>,
+}
+
+impl fmt::Display for Printed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.step {
+            Some(step) => write!(f, "{} at step {}", self.value, step),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+/// Everything observable about a finished run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub result: Result<(), String>,
+    pub output: Vec<Printed>,
+    pub registers: Vec<f64>,
+    pub variables: BTreeMap<String, f64>,
+    /// Values left on the operand stack, top last
+    pub stack: Vec<f64>,
+    /// Frames left on the call stack
+    pub depth: usize,
+    /// Every step the run executed, if it was traced
+    pub trace: Option<Trace>,
+}
+
+/// The first way a run differed from the reference run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The engine the optimized program differed on
+    pub engine: Engine,
+    /// The step of the optimized run that first differed, if both runs were
+    /// traced and it made the change the reference run did not
+    pub step: Option<Step>,
+    /// What differed, such as `print 2`, `r3` or `variable x`
+    pub what: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "optimized {} run diverged at {}: expected {}, found {}",
+            self.engine, self.what, self.expected, self.found
+        )
+    }
+}
+
+impl Error for Divergence {}
+
+/// Run `program` to completion on `engine` with `registers` registers,
+/// capturing what it prints
+pub fn execute(program: &Program, registers: usize, engine: Engine) -> Outcome {
+    let mut vm = VM::new(program.clone(), registers);
+    vm.capture_output();
+    let mut steps = Vec::new();
+    let mut traced = None;
+    let result = match engine {
+        Engine::Stepping => {
+            let mut trace = Trace::new();
+            let mut result = Ok(());
+            while !vm.is_halted() {
+                if let Err(e) = trace.step(&mut vm) {
+                    result = Err(e);
+                    break;
+                }
+            }
+            steps = trace
+                .steps
+                .iter()
+                .enumerate()
+                .filter(|(_, step)| step.opcode == "print" && step.error.is_none())
+                .map(|(i, _)| i)
+                .collect();
+            traced = Some(trace);
+            result
+        }
+        Engine::Decoded => vm.run(),
+        #[cfg(feature = "jit")]
+        Engine::Jit => vm.run_jit(),
+    };
+    let output = vm
+        .take_output()
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| Printed {
+            value,
+            step: steps.get(i).copied(),
+        })
+        .collect();
+    Outcome {
+        result: result.map_err(|e| e.to_string()),
+        output,
+        registers: vm.registers,
+        variables: vm.variables.into_iter().collect(),
+        stack: vm.stack,
+        depth: vm.call_stack.len(),
+        trace: traced,
+    }
+}
+
+/// Run `program` as written, then optimized by `passes` on every engine,
+/// and report the first difference from the unoptimized run
+pub fn verify(program: &Program, registers: usize, passes: &PassManager) -> Result<(), Divergence> {
+    let expected = execute(program, registers, Engine::Stepping);
+    let mut optimized = program.clone();
+    passes.run(&mut optimized);
+    let engines = [
+        Engine::Stepping,
+        Engine::Decoded,
+        #[cfg(feature = "jit")]
+        Engine::Jit,
+    ];
+    for engine in engines {
+        let found = execute(&optimized, registers, engine);
+        if let Some((what, shown, seen)) = compare(&expected, &found) {
+            let traced = match (&expected.trace, &found.trace) {
+                (Some(a), Some(b)) => first_difference(a, b),
+                _ => None,
+            };
+            let (step, what, expected, found) = traced.unwrap_or((None, what, shown, seen));
+            return Err(Divergence {
+                engine,
+                step,
+                what,
+                expected,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// What first differs between `a` and `b`, and its value in each
+fn compare(a: &Outcome, b: &Outcome) -> Option<(String, String, String)> {
+    let shown = |p: Option<&Printed>| p.map_or("nothing".to_string(), Printed::to_string);
+    for i in 0..a.output.len().max(b.output.len()) {
+        let (x, y) = (a.output.get(i), b.output.get(i));
+        if !matches!((x, y), (Some(x), Some(y)) if same(x.value, y.value)) {
+            return Some((format!("print {}", i), shown(x), shown(y)));
+        }
+    }
+    if a.result != b.result {
+        let shown = |r: &Result<(), String>| r.clone().err().unwrap_or("halt".to_string());
+        return Some(("result".to_string(), shown(&a.result), shown(&b.result)));
+    }
+    for (reg, (&x, &y)) in a.registers.iter().zip(&b.registers).enumerate() {
+        if !same(x, y) {
+            return Some((format!("r{}", reg), x.to_string(), y.to_string()));
+        }
+    }
+    let names: BTreeSet<&String> = a.variables.keys().chain(b.variables.keys()).collect();
+    for name in names {
+        let (x, y) = (a.variables.get(name), b.variables.get(name));
+        if !matches!((x, y), (Some(&x), Some(&y)) if same(x, y)) {
+            let shown = |v: Option<&f64>| v.map_or("unset".to_string(), f64::to_string);
+            return Some((format!("variable {}", name), shown(x), shown(y)));
+        }
+    }
+    let stacks_match =
+        a.stack.len() == b.stack.len() && a.stack.iter().zip(&b.stack).all(|(&x, &y)| same(x, y));
+    if !stacks_match {
+        return Some((
+            "stack".to_string(),
+            format!("{:?}", a.stack),
+            format!("{:?}", b.stack),
+        ));
+    }
+    if a.depth != b.depth {
+        return Some((
+            "call depth".to_string(),
+            a.depth.to_string(),
+            b.depth.to_string(),
+        ));
+    }
+    None
+}
+
+/// What a step left in a location: a value, or the error it stopped with
+#[derive(Debug, Clone)]
+enum Effect {
+    Value(f64),
+    Error(String),
+}
+
+impl Effect {
+    fn matches(&self, other: &Effect) -> bool {
+        match (self, other) {
+            (Effect::Value(a), Effect::Value(b)) => same(*a, *b),
+            (Effect::Error(a), Effect::Error(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::Value(value) => write!(f, "{}", value),
+            Effect::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Every location `trace` changed, with what each step left in it, in order.
+/// Printed values go to `output` and the error the run stopped with, if
+/// any, to `result`.
+fn histories(trace: &Trace) -> BTreeMap<String, Vec<(Effect, Step)>> {
+    let mut histories: BTreeMap<String, Vec<(Effect, Step)>> = BTreeMap::new();
+    for (index, traced) in trace.steps.iter().enumerate() {
+        let step = Step {
+            index,
+            pc: traced.pc,
+        };
+        let mut record = |location: String, effect| {
+            histories.entry(location).or_default().push((effect, step));
+        };
+        for write in &traced.writes {
+            record(format!("r{}", write.reg), Effect::Value(write.new));
+        }
+        if let Some((name, value)) = &traced.store {
+            record(format!("variable {}", name), Effect::Value(*value));
+        }
+        match &traced.error {
+            Some(e) => record("result".to_string(), Effect::Error(e.clone())),
+            None if traced.opcode == "print" => {
+                let value = traced.reads.first().map_or(f64::NAN, |&(_, value)| value);
+                record("output".to_string(), Effect::Value(value));
+            }
+            None => {}
+        }
+    }
+    histories
+}
+
+/// The earliest step of `b` whose effect on some location differs from the
+/// matching effect in `a`, what it changed, and the value and step on each
+/// side. A change `b` never made is placed after its last step.
+fn first_difference(a: &Trace, b: &Trace) -> Option<(Option<Step>, String, String, String)> {
+    let (a_histories, b_histories) = (histories(a), histories(b));
+    let empty = Vec::new();
+    let locations: BTreeSet<&String> = a_histories.keys().chain(b_histories.keys()).collect();
+    let shown = |entry: Option<&(Effect, Step)>| {
+        entry.map_or("nothing".to_string(), |(effect, step)| {
+            format!("{} at {}", effect, step)
+        })
+    };
+    let mut first: Option<(usize, Option<Step>, String, String, String)> = None;
+    for location in locations {
+        let xs = a_histories.get(location).unwrap_or(&empty);
+        let ys = b_histories.get(location).unwrap_or(&empty);
+        let Some(i) = (0..xs.len().max(ys.len())).find(
+            |&i| !matches!((xs.get(i), ys.get(i)), (Some((x, _)), Some((y, _))) if x.matches(y)),
+        ) else {
+            continue;
+        };
+        let step = ys.get(i).map(|&(_, step)| step);
+        let at = step.map_or(b.steps.len(), |step| step.index);
+        if first.as_ref().is_some_and(|&(earliest, ..)| earliest <= at) {
+            continue;
+        }
+        let what = match location.as_str() {
+            "output" => format!("print {}", i),
+            _ => location.clone(),
+        };
+        first = Some((at, step, what, shown(xs.get(i)), shown(ys.get(i))));
+    }
+    first.map(|(_, step, what, expected, found)| (step, what, expected, found))
+}
+
+/// Equal, counting every NaN as equal to every other
+fn same(a: f64, b: f64) -> bool {
+    a == b || (a.is_nan() && b.is_nan())
+}
@@ -0,0 +1,379 @@
+//! Objects a program allocates at run time.
+//!
+//! Registers and variables only hold `f64`s, so an object is referenced by a
+//! handle: a quiet NaN whose payload carries a tag and the object's index.
+//! Handles can be copied, stored and passed like any other value; arithmetic
+//! on them is meaningless, and only heap instructions look inside.
+
+use crate::prelude::*;
+use crate::vm::VmError;
+use alloc::collections::BTreeMap;
+
+/// Sign, exponent and top mantissa bits shared by every handle. `f64::NAN`
+/// and the NaNs arithmetic creates, such as `0.0 / 0.0`, leave the second
+/// mantissa bit clear.
+const TAG: u64 = 0x7ffc_0000_0000_0000;
+const TAG_MASK: u64 = 0xffff_0000_0000_0000;
+
+/// The handle for the object at `index`
+pub fn handle(index: usize) -> f64 {
+    f64::from_bits(TAG | index as u64)
+}
+
+/// The object index `value` refers to, if it is a handle
+pub fn index(value: f64) -> Option<usize> {
+    let bits = value.to_bits();
+    (bits & TAG_MASK == TAG).then_some((bits & !TAG_MASK) as usize)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Object {
+    /// A function address with the values it captured
+    Closure { addr: usize, upvalues: Vec<f64> },
+    /// A fixed number of fields addressed by index
+    Record { fields: Vec<f64> },
+    /// Values keyed by number or by object identity, in `map_key` order
+    Map { entries: BTreeMap<u64, f64> },
+}
+
+impl Object {
+    /// Values this object holds, keys included, which may be handles to
+    /// other objects
+    fn values(&self) -> impl Iterator<Item = f64> + '_ {
+        let (slice, entries) = match self {
+            Object::Closure { upvalues, .. } => (upvalues.as_slice(), None),
+            Object::Record { fields } => (fields.as_slice(), None),
+            Object::Map { entries } => (&[][..], Some(entries)),
+        };
+        let entries = entries
+            .into_iter()
+            .flatten()
+            .flat_map(|(&key, &value)| [f64::from_bits(key), value]);
+        slice.iter().copied().chain(entries)
+    }
+
+    /// Values this object holds, counting a map entry's key and value
+    pub fn cells(&self) -> usize {
+        match self {
+            Object::Closure { upvalues, .. } => upvalues.len(),
+            Object::Record { fields } => fields.len(),
+            Object::Map { entries } => entries.len() * 2,
+        }
+    }
+}
+
+/// The key a map stores `value` under. Zeros and NaNs that are not handles
+/// each collapse to one key; handles are keyed by identity.
+pub fn map_key(value: f64) -> u64 {
+    if value == 0.0 {
+        0
+    } else if value.is_nan() && index(value).is_none() {
+        f64::NAN.to_bits()
+    } else {
+        value.to_bits()
+    }
+}
+
+/// Live objects below which the heap is never collected
+const MIN_COLLECTION_THRESHOLD: usize = 1024;
+
+/// Counters describing the garbage collector's work so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GcStats {
+    pub collections: u64,
+    pub allocated: u64,
+    pub freed: u64,
+    /// Objects currently allocated
+    pub live: usize,
+}
+
+/// How a heap decides when to free objects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MemoryMode {
+    /// Mark-and-sweep collection, run before an allocation once the live
+    /// count reaches twice what survived the last collection
+    #[default]
+    Tracing,
+    /// Count the registers, variables, frames and objects referring to each
+    /// object, and free it the moment the count drops to zero. Teardown is
+    /// deterministic, but objects in a reference cycle are never freed
+    /// unless `VM::collect_garbage` is called. Runs only on the stepping
+    /// interpreter, and host writes to `VM::registers` or `VM::variables`
+    /// are not counted.
+    RefCounted,
+}
+
+/// Every object allocated by a VM, indexed by handle.
+///
+/// In either `MemoryMode` the VM supplies the roots or reference counts, so
+/// the heap never frees an object a register, variable or frame can still
+/// reach.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Heap {
+    mode: MemoryMode,
+    objects: Vec<Option<Object>>,
+    /// Reference count of each slot, kept only in `RefCounted` mode
+    counts: Vec<usize>,
+    /// Indices of freed slots, reused before the heap grows
+    free: Vec<usize>,
+    /// Live count at which the next allocation collects first
+    threshold: usize,
+    stats: GcStats,
+    /// Cells held by live objects
+    #[cfg_attr(feature = "serde", serde(default))]
+    cells: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mode(mode: MemoryMode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    pub fn mode(&self) -> MemoryMode {
+        self.mode
+    }
+
+    /// Store `object` and return its handle. In `RefCounted` mode the handle
+    /// starts with no references, and the object references its values.
+    pub fn alloc(&mut self, object: Object) -> f64 {
+        self.stats.allocated += 1;
+        self.cells += object.cells();
+        if self.mode == MemoryMode::RefCounted {
+            for value in object.values() {
+                self.retain(value);
+            }
+        }
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                index
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.objects.len() - 1
+            }
+        };
+        if self.mode == MemoryMode::RefCounted {
+            self.counts.resize(self.objects.len(), 0);
+            self.counts[index] = 0;
+        }
+        handle(index)
+    }
+
+    /// Count a new reference to `value`, if it is a handle
+    pub(crate) fn retain(&mut self, value: f64) {
+        if let Some(i) = index(value) {
+            self.retain_index(i);
+        }
+    }
+
+    pub(crate) fn retain_index(&mut self, slot: usize) {
+        if let Some(count) = self.counts.get_mut(slot) {
+            *count += 1;
+        }
+    }
+
+    /// Drop a reference to `value`, freeing it and whatever only it kept
+    /// alive once nothing refers to it
+    pub(crate) fn release(&mut self, value: f64) {
+        if let Some(i) = index(value) {
+            self.release_index(i);
+        }
+    }
+
+    pub(crate) fn release_index(&mut self, slot: usize) {
+        let mut pending = vec![slot];
+        while let Some(i) = pending.pop() {
+            let Some(count) = self.counts.get_mut(i).filter(|c| **c > 0) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0
+                && let Some(object) = self.objects[i].take()
+            {
+                self.free.push(i);
+                self.stats.freed += 1;
+                self.cells = self.cells.saturating_sub(object.cells());
+                pending.extend(object.values().filter_map(index));
+            }
+        }
+    }
+
+    /// The object `value` refers to, if it is a handle to a live object
+    pub fn get(&self, value: f64) -> Option<&Object> {
+        self.objects.get(index(value)?)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, value: f64) -> Option<&mut Object> {
+        self.objects.get_mut(index(value)?)?.as_mut()
+    }
+
+    /// Number of live objects
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Values held by live objects, as counted by `Object::cells`
+    pub fn cells(&self) -> usize {
+        self.cells
+    }
+
+    /// Every slot in index order, `None` where an object was freed
+    pub fn slots(&self) -> &[Option<Object>] {
+        &self.objects
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            live: self.len(),
+            ..self.stats
+        }
+    }
+
+    /// Whether the next allocation should collect first
+    pub(crate) fn needs_collection(&self) -> bool {
+        self.mode == MemoryMode::Tracing
+            && self.len() >= self.threshold.max(MIN_COLLECTION_THRESHOLD)
+    }
+
+    /// Free every object not reachable from `values` or the closures
+    /// running in `frames`, returning how many were freed
+    pub fn collect(
+        &mut self,
+        values: impl IntoIterator<Item = f64>,
+        frames: impl IntoIterator<Item = usize>,
+    ) -> usize {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<usize> = values.into_iter().filter_map(index).collect();
+        pending.extend(frames);
+        while let Some(i) = pending.pop() {
+            if marked.get(i) != Some(&false) {
+                continue;
+            }
+            if let Some(object) = &self.objects[i] {
+                marked[i] = true;
+                pending.extend(object.values().filter_map(index));
+            }
+        }
+
+        let mut freed = 0;
+        for i in 0..self.objects.len() {
+            if marked[i] {
+                continue;
+            }
+            let Some(object) = self.objects[i].take() else {
+                continue;
+            };
+            // Garbage no longer refers to the survivors it pointed at
+            for j in object.values().filter_map(index) {
+                if marked.get(j) == Some(&true)
+                    && let Some(count) = self.counts.get_mut(j)
+                {
+                    *count -= 1;
+                }
+            }
+            self.free.push(i);
+            self.cells = self.cells.saturating_sub(object.cells());
+            freed += 1;
+        }
+        self.stats.collections += 1;
+        self.stats.freed += freed as u64;
+        self.threshold = self.len() * 2;
+        freed
+    }
+
+    /// The address and index of the closure `value` refers to
+    pub(crate) fn closure(&self, value: f64) -> Result<(usize, usize), VmError> {
+        if let Some(i) = index(value)
+            && let Some(Some(Object::Closure { addr, .. })) = self.objects.get(i)
+        {
+            return Ok((*addr, i));
+        }
+        Err(VmError::WrongType("closure"))
+    }
+
+    /// The code address of every live closure
+    pub(crate) fn closure_addrs_mut(&mut self) -> impl Iterator<Item = &mut usize> {
+        self.objects.iter_mut().filter_map(|object| match object {
+            Some(Object::Closure { addr, .. }) => Some(addr),
+            _ => None,
+        })
+    }
+
+    /// Upvalue `index` of the closure at heap index `closure`
+    pub(crate) fn upvalue(
+        &mut self,
+        closure: Option<usize>,
+        index: usize,
+    ) -> Result<&mut f64, VmError> {
+        match closure.and_then(|c| self.objects.get_mut(c)?.as_mut()) {
+            Some(Object::Closure { upvalues, .. }) => upvalues
+                .get_mut(index)
+                .ok_or(VmError::UpvalueOutOfBounds(index)),
+            _ => Err(VmError::UpvalueOutOfBounds(index)),
+        }
+    }
+
+    /// Field `index` of the record `value` refers to
+    pub(crate) fn field(&mut self, value: f64, index: usize) -> Result<&mut f64, VmError> {
+        match self.get_mut(value) {
+            Some(Object::Record { fields }) => fields
+                .get_mut(index)
+                .ok_or(VmError::FieldOutOfBounds(index)),
+            _ => Err(VmError::WrongType("record")),
+        }
+    }
+
+    /// The entries of the map `value` refers to
+    pub(crate) fn map(&self, value: f64) -> Result<&BTreeMap<u64, f64>, VmError> {
+        match self.get(value) {
+            Some(Object::Map { entries }) => Ok(entries),
+            _ => Err(VmError::WrongType("map")),
+        }
+    }
+
+    /// Set `key` to `v` in the map `value` refers to, returning the value
+    /// it replaced
+    pub(crate) fn map_insert(
+        &mut self,
+        value: f64,
+        key: u64,
+        v: f64,
+    ) -> Result<Option<f64>, VmError> {
+        let old = match self.get_mut(value) {
+            Some(Object::Map { entries }) => entries.insert(key, v),
+            _ => return Err(VmError::WrongType("map")),
+        };
+        if old.is_none() {
+            self.cells += 2;
+        }
+        Ok(old)
+    }
+
+    /// Remove `key` from the map `value` refers to, returning its value
+    pub(crate) fn map_remove(&mut self, value: f64, key: u64) -> Result<Option<f64>, VmError> {
+        let old = match self.get_mut(value) {
+            Some(Object::Map { entries }) => entries.remove(&key),
+            _ => return Err(VmError::WrongType("map")),
+        };
+        if old.is_some() {
+            self.cells = self.cells.saturating_sub(2);
+        }
+        Ok(old)
+    }
+}
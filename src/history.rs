@@ -0,0 +1,69 @@
+use crate::machine::Machine;
+use crate::vm::{VM, VmError};
+use alloc::collections::VecDeque;
+
+/// Records the VM state before each step so execution can be rewound.
+///
+/// Only the most recent `capacity` states are kept, bounding memory use on
+/// long runs while still allowing a debugger to step back from a failure.
+/// Works with any `Machine`, the register `VM` by default.
+pub struct History<M: Machine = VM> {
+    capacity: usize,
+    states: VecDeque<M::Snapshot>,
+}
+
+impl<M: Machine> History<M> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            states: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Number of steps that can currently be undone
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+
+    /// Execute one instruction on `vm`, remembering the state it started from
+    pub fn step(&mut self, vm: &mut M) -> Result<(), VmError> {
+        if self.capacity > 0 {
+            if self.states.len() == self.capacity {
+                self.states.pop_front();
+            }
+            self.states.push_back(vm.snapshot());
+        }
+        vm.step()
+    }
+
+    /// Step `vm` until it halts or fails; on failure the history leading up to it is kept
+    pub fn run(&mut self, vm: &mut M) -> Result<(), VmError> {
+        while !vm.is_halted() {
+            self.step(vm)?;
+        }
+        Ok(())
+    }
+
+    /// Undo up to `n` recorded steps, returning how many were actually undone
+    pub fn step_back(&mut self, vm: &mut M, n: usize) -> usize {
+        let n = n.min(self.states.len());
+        if n == 0 {
+            return 0;
+        }
+        self.states.truncate(self.states.len() - (n - 1));
+        let state = self
+            .states
+            .pop_back()
+            .expect("history holds at least n states");
+        vm.restore(&state);
+        n
+    }
+}
@@ -0,0 +1,114 @@
+//! Callbacks around every executed instruction.
+//!
+//! Tracers, profilers, coverage tools and debuggers observe a run through
+//! hooks rather than being built into the interpreter. Hooks run in the
+//! order they were added, and any of them can pause or abort the run. A VM
+//! with hooks runs on the stepping interpreter.
+//!
+//! Hooks are for the register `VM` unless they name another `Machine`, such
+//! as a `StackVM`; one written for any `Machine` works with both.
+
+use crate::machine::Machine;
+use crate::prelude::*;
+use crate::vm::{VM, VmError};
+use core::any::Any;
+
+/// What the run should do after a hook returns, weakest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HookAction {
+    #[default]
+    Continue,
+    /// Stop with `VmError::Paused`; running again continues where it stopped
+    Pause,
+    /// Stop with `VmError::Aborted`
+    Abort,
+}
+
+/// Observer of the instructions a VM executes
+pub trait Hook<M: Machine = VM>: Any + Send {
+    /// Called before the instruction at `pc` runs. Pausing or aborting here
+    /// leaves the instruction unexecuted.
+    fn before(&mut self, pc: usize, instruction: &M::Instruction) -> HookAction {
+        let _ = (pc, instruction);
+        HookAction::Continue
+    }
+
+    /// Called once the instruction at `pc` has run, or raised an error a
+    /// handler caught. Not called when the step fails.
+    fn after(&mut self, pc: usize, vm: &M) -> HookAction {
+        let _ = (pc, vm);
+        HookAction::Continue
+    }
+}
+
+/// The first of `hooks` of type `H`
+pub(crate) fn find<M: Machine, H: Hook<M>>(hooks: &[Box<dyn Hook<M>>]) -> Option<&H> {
+    hooks
+        .iter()
+        .find_map(|hook| (&**hook as &dyn Any).downcast_ref())
+}
+
+/// Remove the first of `hooks` of type `H` and return it
+pub(crate) fn take<M: Machine, H: Hook<M>>(hooks: &mut Vec<Box<dyn Hook<M>>>) -> Option<H> {
+    let i = hooks
+        .iter()
+        .position(|hook| (&**hook as &dyn Any).is::<H>())?;
+    let hook: Box<dyn Any> = hooks.remove(i);
+    hook.downcast().ok().map(|hook| *hook)
+}
+
+impl VM {
+    pub fn add_hook(&mut self, hook: impl Hook) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// The first hook of type `H`, to read what it has collected
+    pub fn hook<H: Hook>(&self) -> Option<&H> {
+        find(&self.hooks)
+    }
+
+    /// Remove the first hook of type `H` and return it
+    pub fn take_hook<H: Hook>(&mut self) -> Option<H> {
+        take(&mut self.hooks)
+    }
+
+    /// Remove every hook
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// `step`, with every hook called around the instruction
+    pub(crate) fn step_hooked(&mut self) -> Result<(), VmError> {
+        step(self, |vm| &mut vm.hooks, VM::step_unhooked)
+    }
+}
+
+/// Run `execute` on the instruction at `pc`, with the hooks kept in `hooks`
+/// called around it
+pub(crate) fn step<M: Machine + 'static>(
+    vm: &mut M,
+    hooks: impl Fn(&mut M) -> &mut Vec<Box<dyn Hook<M>>>,
+    execute: impl FnOnce(&mut M) -> Result<(), VmError>,
+) -> Result<(), VmError> {
+    let pc = vm.pc();
+    // Taken out so the hooks can be handed the machine
+    let mut taken = core::mem::take(hooks(vm));
+    let result = (|| {
+        if let Some(instruction) = vm.instruction(pc) {
+            check(taken.iter_mut().map(|hook| hook.before(pc, instruction)))?;
+        }
+        execute(vm)?;
+        check(taken.iter_mut().map(|hook| hook.after(pc, vm)))
+    })();
+    *hooks(vm) = taken;
+    result
+}
+
+/// Call every hook, then stop as the strongest action any of them asked
+fn check(actions: impl Iterator<Item = HookAction>) -> Result<(), VmError> {
+    match actions.max().unwrap_or_default() {
+        HookAction::Continue => Ok(()),
+        HookAction::Pause => Err(VmError::Paused),
+        HookAction::Abort => Err(VmError::Aborted),
+    }
+}
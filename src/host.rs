@@ -0,0 +1,136 @@
+//! Calls from a program out to functions the host provides.
+//!
+//! A `callhost` to a function registered with `VM::register_host_function`
+//! runs it in place. Any other `callhost` suspends the VM just past the
+//! instruction and hands the call to whoever is driving it. The host
+//! services the call however it likes, blocking or not, and passes the
+//! result to `VM::complete_host_call`, after which the run can continue.
+
+use crate::prelude::*;
+use crate::vm::{VM, VmError};
+use alloc::sync::Arc;
+
+/// A function a program can call by name, given its arguments. An `Err`
+/// is raised in the program as `VmError::HostFunction`.
+pub type HostFunction = Arc<dyn Fn(&[f64]) -> Result<f64, String> + Send + Sync>;
+
+/// A host function call a program is waiting on
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HostCall {
+    pub name: String,
+    pub args: Vec<f64>,
+}
+
+/// A suspended `callhost` and the register its result goes to
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PendingHostCall {
+    pub call: HostCall,
+    pub(crate) dest: usize,
+}
+
+/// Why `VM::poll` stopped
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exit {
+    Halted,
+    HostCall(HostCall),
+}
+
+impl VM {
+    /// Make `function` run in place whenever the program calls `name`,
+    /// replacing any function registered under that name
+    pub fn register_host_function(
+        &mut self,
+        name: impl Into<String>,
+        function: impl Fn(&[f64]) -> Result<f64, String> + Send + Sync + 'static,
+    ) {
+        self.host_functions.insert(name.into(), Arc::new(function));
+    }
+
+    pub fn has_host_function(&self, name: &str) -> bool {
+        self.host_functions.contains_key(name)
+    }
+
+    /// Run until the program halts or calls a host function that is not
+    /// registered. The VM stays
+    /// suspended on a host call until `complete_host_call` is given its
+    /// result; polling again before then returns the same call.
+    pub fn poll(&mut self) -> Result<Exit, VmError> {
+        match self.run() {
+            Ok(()) => Ok(Exit::Halted),
+            Err(VmError::HostCallPending(_)) => match &self.host_call {
+                Some(pending) => Ok(Exit::HostCall(pending.call.clone())),
+                None => unreachable!("host call pending without a call"),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The host call the VM is suspended on, if any
+    pub fn pending_host_call(&self) -> Option<&HostCall> {
+        self.host_call.as_ref().map(|pending| &pending.call)
+    }
+
+    /// Write `result` to the register the suspended `callhost` names, so the
+    /// next run continues after it
+    pub fn complete_host_call(&mut self, result: f64) -> Result<(), VmError> {
+        let pending = self.host_call.take().ok_or(VmError::NoHostCallPending)?;
+        self.record_host_call(&pending.call, &Ok(result));
+        self.set_register(pending.dest, result)
+    }
+
+    /// Run to completion, awaiting `host` for every host function the
+    /// program calls. Needs no particular executor, so the interpreter
+    /// thread is free while a call is outstanding.
+    #[cfg(feature = "async")]
+    pub async fn run_async<F, Fut>(&mut self, mut host: F) -> Result<(), VmError>
+    where
+        F: FnMut(HostCall) -> Fut,
+        Fut: core::future::Future<Output = f64>,
+    {
+        loop {
+            match self.poll()? {
+                Exit::Halted => return Ok(()),
+                Exit::HostCall(call) => {
+                    let result = host(call).await;
+                    self.complete_host_call(result)?;
+                }
+            }
+        }
+    }
+}
+
+/// Run `function` for `call`
+pub(crate) fn call(function: &HostFunction, call: &HostCall) -> Result<f64, VmError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("host_call", name = %call.name).entered();
+    function(&call.args).map_err(|message| VmError::HostFunction {
+        name: call.name.clone(),
+        message,
+    })
+}
+
+/// The call a `callhost` with `dest`, `name` and `args` makes given `registers`
+pub(crate) fn pending(
+    dest: usize,
+    name: &str,
+    args: &[usize],
+    registers: &[f64],
+) -> Result<PendingHostCall, VmError> {
+    let args = args
+        .iter()
+        .map(|&r| {
+            registers.get(r).copied().ok_or_else(|| {
+                VmError::RegisterOutOfBounds(format!("invalid register index {}", r))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(PendingHostCall {
+        call: HostCall {
+            name: name.to_string(),
+            args,
+        },
+        dest,
+    })
+}
@@ -1,4 +1,8 @@
-#[derive(Debug, Clone)]
+use crate::prelude::*;
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     /// Load an immediate constant into register `dest`
     LoadImm { dest: usize, value: f64 },
@@ -81,4 +85,741 @@ pub enum Instruction {
 
     /// Stop execution
     Halt,
+
+    /// Fused `loadimm imm, value` + `add dest, src, imm`: sets `imm` to
+    /// `value`, then `dest` to reg[src] + `value`
+    AddImm {
+        dest: usize,
+        src: usize,
+        imm: usize,
+        value: f64,
+    },
+
+    /// Fused compare + `jz dest, target`: sets `dest` like the comparison
+    /// does, then jumps to `target` if it is false
+    CompareJump {
+        cmp: Comparison,
+        dest: usize,
+        src1: usize,
+        src2: usize,
+        target: usize,
+    },
+
+    /// Install a handler at `handler` for errors raised until the matching
+    /// `TryEnd`; a caught error's code is written to register `dest`
+    TryBegin { handler: usize, dest: usize },
+
+    /// Remove the most recently installed handler
+    TryEnd,
+
+    /// Raise the value in register `src` as an error code
+    Throw { src: usize },
+
+    /// Jump to the instruction address held in register `src`
+    JumpIndirect { src: usize },
+
+    /// Jump to `table[reg[src]]`, or to `default` if reg[src] is not an
+    /// index into `table`
+    Switch {
+        src: usize,
+        table: Vec<usize>,
+        default: usize,
+    },
+
+    /// Call the function whose address is held in register `src`
+    CallIndirect { src: usize },
+
+    /// Write the instruction address `addr` into register `dest`. Unlike a
+    /// `loadimm`, the address is relocated when code moves.
+    LoadAddr { dest: usize, addr: usize },
+
+    /// Allocate a closure over the function at `addr` that captures the
+    /// current values of the `captures` registers, and write its handle to
+    /// register `dest`
+    MakeClosure {
+        dest: usize,
+        addr: usize,
+        captures: Vec<usize>,
+    },
+
+    /// Call the closure whose handle is in register `src`
+    CallClosure { src: usize },
+
+    /// Read upvalue `index` of the running closure into register `dest`
+    GetUpvalue { dest: usize, index: usize },
+
+    /// Write register `src` to upvalue `index` of the running closure
+    SetUpvalue { src: usize, index: usize },
+
+    /// Allocate a record of `fields` fields, all 0, and write its handle to
+    /// register `dest`
+    NewRecord { dest: usize, fields: usize },
+
+    /// Read field `index` of the record in register `record` into `dest`
+    GetField {
+        dest: usize,
+        record: usize,
+        index: usize,
+    },
+
+    /// Write register `src` to field `index` of the record in register `record`
+    SetField {
+        record: usize,
+        index: usize,
+        src: usize,
+    },
+
+    /// Allocate an empty map and write its handle to register `dest`
+    MapNew { dest: usize },
+
+    /// Read the value stored under register `key` in the map in register
+    /// `map` into `dest`
+    MapGet { dest: usize, map: usize, key: usize },
+
+    /// Store register `src` under register `key` in the map in register `map`
+    MapSet { map: usize, key: usize, src: usize },
+
+    /// Write 1 to register `dest` if the map in register `map` has an entry
+    /// for register `key`, otherwise 0
+    MapHas { dest: usize, map: usize, key: usize },
+
+    /// Remove the entry for register `key`, if any, from the map in register `map`
+    MapDelete { map: usize, key: usize },
+
+    /// Create a suspended coroutine that starts at `addr` with a copy of the
+    /// registers, and write its id to register `dest`
+    Spawn { dest: usize, addr: usize },
+
+    /// Suspend the running coroutine and return control to its resumer
+    Yield,
+
+    /// Run the coroutine whose id is in register `id` until it yields, then
+    /// write 1 to register `dest`, or until it finishes, then write 0
+    Resume { dest: usize, id: usize },
+
+    /// Suspend the VM to have the host call its function `name` with the
+    /// values of the `args` registers; the result goes to register `dest`
+    CallHost {
+        dest: usize,
+        name: String,
+        args: Vec<usize>,
+    },
+
+    /// Send register `src` to the isolate named `to`
+    Send { src: usize, to: String },
+
+    /// Take the oldest message this isolate has received into register `dest`
+    Recv { dest: usize },
+
+    /// Write the VM's next pseudo-random value, uniform in `[0, 1)`, to
+    /// register `dest`
+    Rand { dest: usize },
+
+    /// Jump `offset` instructions from this one; `jmp` that moves with
+    /// the code around it
+    JumpRel(i32),
+
+    /// Call the subroutine `offset` instructions from this one
+    CallRel(i32),
+
+    /// Push register `src` onto the operand stack
+    Push { src: usize },
+
+    /// Pop the top of the operand stack into register `dest`
+    Pop { dest: usize },
+
+    /// Call a subroutine at instruction `addr` with `argc` arguments in
+    /// registers `0..argc`, pushing `argc` for the callee's `Arity`
+    CallArgs { addr: usize, argc: usize },
+
+    /// Pop the argument count a `CallArgs` pushed into register `dest`,
+    /// trapping unless it is at least `min` and, if there is a `max`, at
+    /// most `max`
+    Arity {
+        dest: usize,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+/// The address `offset` instructions from `pc`. One before the start of the
+/// program saturates to `usize::MAX`, which is out of bounds of any program.
+pub fn relative(pc: usize, offset: i32) -> usize {
+    pc.checked_add_signed(offset as isize).unwrap_or(usize::MAX)
+}
+
+/// The comparison performed by a `CompareJump`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Comparison {
+    Equal,
+    LessThan,
+    GreaterThan,
+}
+
+impl Comparison {
+    pub fn eval(self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Equal => a == b,
+            Comparison::LessThan => a < b,
+            Comparison::GreaterThan => a > b,
+        }
+    }
+}
+
+impl Instruction {
+    /// Lowercase assembly mnemonic for this instruction
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::LoadImm { .. } => "loadimm",
+            Instruction::Add { .. } => "add",
+            Instruction::Sub { .. } => "sub",
+            Instruction::Mul { .. } => "mul",
+            Instruction::Div { .. } => "div",
+            Instruction::Print { .. } => "print",
+            Instruction::Jump(_) => "jmp",
+            Instruction::Call { .. } => "call",
+            Instruction::ConditionalJump { .. } => "jz",
+            Instruction::Return => "ret",
+            Instruction::Store { .. } => "store",
+            Instruction::Load { .. } => "load",
+            Instruction::Mov { .. } => "mov",
+            Instruction::Equal { .. } => "eq",
+            Instruction::LessThan { .. } => "lt",
+            Instruction::GreaterThan { .. } => "gt",
+            Instruction::Not { .. } => "not",
+            Instruction::Halt => "halt",
+            Instruction::AddImm { .. } => "addimm",
+            Instruction::CompareJump { cmp, .. } => match cmp {
+                Comparison::Equal => "eqjz",
+                Comparison::LessThan => "ltjz",
+                Comparison::GreaterThan => "gtjz",
+            },
+            Instruction::TryBegin { .. } => "try",
+            Instruction::TryEnd => "endtry",
+            Instruction::Throw { .. } => "throw",
+            Instruction::JumpIndirect { .. } => "jmpr",
+            Instruction::Switch { .. } => "switch",
+            Instruction::CallIndirect { .. } => "callr",
+            Instruction::LoadAddr { .. } => "lea",
+            Instruction::MakeClosure { .. } => "closure",
+            Instruction::CallClosure { .. } => "callc",
+            Instruction::GetUpvalue { .. } => "getupval",
+            Instruction::SetUpvalue { .. } => "setupval",
+            Instruction::NewRecord { .. } => "record",
+            Instruction::GetField { .. } => "getfield",
+            Instruction::SetField { .. } => "setfield",
+            Instruction::MapNew { .. } => "map",
+            Instruction::MapGet { .. } => "mapget",
+            Instruction::MapSet { .. } => "mapset",
+            Instruction::MapHas { .. } => "maphas",
+            Instruction::MapDelete { .. } => "mapdel",
+            Instruction::Spawn { .. } => "spawn",
+            Instruction::Yield => "yield",
+            Instruction::Resume { .. } => "resume",
+            Instruction::CallHost { .. } => "callhost",
+            Instruction::Send { .. } => "send",
+            Instruction::Recv { .. } => "recv",
+            Instruction::Rand { .. } => "rand",
+            Instruction::JumpRel(_) => "rjmp",
+            Instruction::CallRel(_) => "rcall",
+            Instruction::Push { .. } => "push",
+            Instruction::Pop { .. } => "pop",
+            Instruction::CallArgs { .. } => "call",
+            Instruction::Arity { .. } => "arity",
+        }
+    }
+
+    /// Register this instruction writes, if any
+    pub fn dest(&self) -> Option<usize> {
+        use Instruction::*;
+
+        match self {
+            LoadImm { dest, .. }
+            | Add { dest, .. }
+            | Sub { dest, .. }
+            | Mul { dest, .. }
+            | Div { dest, .. }
+            | Load { dest, .. }
+            | Mov { dest, .. }
+            | Equal { dest, .. }
+            | LessThan { dest, .. }
+            | GreaterThan { dest, .. }
+            | Not { dest, .. }
+            | AddImm { dest, .. }
+            | CompareJump { dest, .. }
+            | LoadAddr { dest, .. }
+            | MakeClosure { dest, .. }
+            | GetUpvalue { dest, .. }
+            | NewRecord { dest, .. }
+            | GetField { dest, .. }
+            | MapNew { dest }
+            | MapGet { dest, .. }
+            | MapHas { dest, .. }
+            | Spawn { dest, .. }
+            | Resume { dest, .. }
+            | CallHost { dest, .. }
+            | Recv { dest }
+            | Rand { dest }
+            | Pop { dest }
+            | Arity { dest, .. } => Some(*dest),
+            _ => None,
+        }
+    }
+
+    /// Every register this instruction writes, in the order it writes them;
+    /// only fused instructions write more than `dest()`. A `try` counts the
+    /// register its handler receives the error code in.
+    pub fn writes(&self) -> Vec<usize> {
+        match self {
+            Instruction::AddImm { dest, imm, .. } => vec![*imm, *dest],
+            Instruction::TryBegin { dest, .. } => vec![*dest],
+            _ => self.dest().into_iter().collect(),
+        }
+    }
+
+    /// Registers this instruction reads, in operand order
+    pub fn sources(&self) -> Vec<usize> {
+        use Instruction::*;
+
+        match self {
+            Add { src1, src2, .. }
+            | Sub { src1, src2, .. }
+            | Mul { src1, src2, .. }
+            | Div { src1, src2, .. }
+            | Equal { src1, src2, .. }
+            | LessThan { src1, src2, .. }
+            | GreaterThan { src1, src2, .. }
+            | CompareJump { src1, src2, .. } => vec![*src1, *src2],
+            Print { src }
+            | Store { src, .. }
+            | Mov { src, .. }
+            | Not { src, .. }
+            | AddImm { src, .. }
+            | Throw { src }
+            | JumpIndirect { src }
+            | Switch { src, .. }
+            | CallIndirect { src }
+            | CallClosure { src }
+            | SetUpvalue { src, .. }
+            | Resume { id: src, .. }
+            | Send { src, .. }
+            | Push { src } => vec![*src],
+            GetField { record, .. } => vec![*record],
+            SetField { record, src, .. } => vec![*record, *src],
+            MapGet { map, key, .. } | MapHas { map, key, .. } | MapDelete { map, key } => {
+                vec![*map, *key]
+            }
+            MapSet { map, key, src } => vec![*map, *key, *src],
+            MakeClosure { captures, .. } | CallHost { args: captures, .. } => captures.clone(),
+            ConditionalJump { cond, .. } => vec![*cond],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replace every register this instruction reads or writes with `f` of it
+    pub fn rename_registers(&mut self, mut f: impl FnMut(usize) -> usize) {
+        use Instruction::*;
+
+        match self {
+            Add { dest, src1, src2 }
+            | Sub { dest, src1, src2 }
+            | Mul { dest, src1, src2 }
+            | Div { dest, src1, src2 }
+            | Equal { dest, src1, src2 }
+            | LessThan { dest, src1, src2 }
+            | GreaterThan { dest, src1, src2 }
+            | CompareJump {
+                dest, src1, src2, ..
+            }
+            | MapGet {
+                dest,
+                map: src1,
+                key: src2,
+            }
+            | MapHas {
+                dest,
+                map: src1,
+                key: src2,
+            }
+            | MapSet {
+                map: dest,
+                key: src1,
+                src: src2,
+            } => {
+                *dest = f(*dest);
+                *src1 = f(*src1);
+                *src2 = f(*src2);
+            }
+            Mov { dest, src }
+            | Not { dest, src }
+            | GetField {
+                dest, record: src, ..
+            }
+            | SetField {
+                record: dest, src, ..
+            }
+            | MapDelete {
+                map: dest,
+                key: src,
+            }
+            | Resume { dest, id: src } => {
+                *dest = f(*dest);
+                *src = f(*src);
+            }
+            AddImm { dest, src, imm, .. } => {
+                *dest = f(*dest);
+                *src = f(*src);
+                *imm = f(*imm);
+            }
+            MakeClosure { dest, captures, .. }
+            | CallHost {
+                dest,
+                args: captures,
+                ..
+            } => {
+                *dest = f(*dest);
+                for reg in captures {
+                    *reg = f(*reg);
+                }
+            }
+            Print { src }
+            | Store { src, .. }
+            | Throw { src }
+            | JumpIndirect { src }
+            | Switch { src, .. }
+            | CallIndirect { src }
+            | CallClosure { src }
+            | SetUpvalue { src, .. }
+            | Send { src, .. }
+            | ConditionalJump { cond: src, .. }
+            | LoadImm { dest: src, .. }
+            | Load { dest: src, .. }
+            | TryBegin { dest: src, .. }
+            | LoadAddr { dest: src, .. }
+            | GetUpvalue { dest: src, .. }
+            | NewRecord { dest: src, .. }
+            | MapNew { dest: src }
+            | Spawn { dest: src, .. }
+            | Recv { dest: src }
+            | Rand { dest: src }
+            | Push { src }
+            | Pop { dest: src }
+            | Arity { dest: src, .. } => *src = f(*src),
+            Jump(_)
+            | Call { .. }
+            | CallArgs { .. }
+            | Return
+            | Halt
+            | TryEnd
+            | Yield
+            | JumpRel(_)
+            | CallRel(_) => {}
+        }
+    }
+
+    /// The single instruction address this instruction, placed at `pc`, may
+    /// transfer control to, if it has one; see `targets` for switches
+    pub fn target(&self, pc: usize) -> Option<usize> {
+        match self {
+            Instruction::Jump(addr)
+            | Instruction::Call { addr }
+            | Instruction::CallArgs { addr, .. }
+            | Instruction::ConditionalJump { target: addr, .. }
+            | Instruction::CompareJump { target: addr, .. }
+            | Instruction::TryBegin { handler: addr, .. } => Some(*addr),
+            Instruction::JumpRel(offset) | Instruction::CallRel(offset) => {
+                Some(relative(pc, *offset))
+            }
+            _ => None,
+        }
+    }
+
+    /// Every instruction address this instruction, placed at `pc`, may
+    /// transfer control to. A `jmpr` or `callr` target is only known at run
+    /// time, so it has none.
+    pub fn targets(&self, pc: usize) -> Vec<usize> {
+        match self {
+            Instruction::Switch { table, default, .. } => {
+                table.iter().chain([default]).copied().collect()
+            }
+            _ => self.target(pc).into_iter().collect(),
+        }
+    }
+
+    /// Every instruction address this instruction, placed at `pc`, refers
+    /// to: its targets, or the address a `lea` loads, a `closure` captures or
+    /// a `spawn` starts at
+    pub fn addresses(&self, pc: usize) -> Vec<usize> {
+        match self {
+            Instruction::LoadAddr { addr, .. }
+            | Instruction::MakeClosure { addr, .. }
+            | Instruction::Spawn { addr, .. } => vec![*addr],
+            _ => self.targets(pc),
+        }
+    }
+
+    /// Whether this instruction's target is an offset from its own address
+    pub fn is_relative(&self) -> bool {
+        matches!(self, Instruction::JumpRel(_) | Instruction::CallRel(_))
+    }
+
+    /// This instruction, placed at `pc`, with a relative target turned into
+    /// an absolute one
+    pub fn to_absolute(&self, pc: usize) -> Instruction {
+        match *self {
+            Instruction::JumpRel(offset) => Instruction::Jump(relative(pc, offset)),
+            Instruction::CallRel(offset) => Instruction::Call {
+                addr: relative(pc, offset),
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// This instruction, placed at `pc`, with an absolute `jmp` or `call`
+    /// target turned into an offset, if it fits
+    pub fn to_relative(&self, pc: usize) -> Instruction {
+        let offset = |addr: usize| i32::try_from(addr as i64 - pc as i64).ok();
+        match *self {
+            Instruction::Jump(addr) => offset(addr).map_or(self.clone(), Instruction::JumpRel),
+            Instruction::Call { addr } => offset(addr).map_or(self.clone(), Instruction::CallRel),
+            _ => self.clone(),
+        }
+    }
+
+    /// Whether this instruction jumps to an address computed at run time
+    pub fn is_computed_jump(&self) -> bool {
+        matches!(
+            self,
+            Instruction::JumpIndirect { .. } | Instruction::CallIndirect { .. }
+        )
+    }
+
+    /// Rewrite the instruction addresses this instruction refers to using
+    /// `f`, as it moves from address `from` to `to`. A relative target is
+    /// rebased so it still reaches wherever `f` moved its old target.
+    pub fn relocate(&mut self, from: usize, to: usize, mut f: impl FnMut(usize) -> usize) {
+        match self {
+            Instruction::JumpRel(offset) | Instruction::CallRel(offset) => {
+                // A target below zero is out of bounds wherever the code moves
+                if let Some(target) = from.checked_add_signed(*offset as isize)
+                    && let Ok(rebased) = i32::try_from(f(target) as i64 - to as i64)
+                {
+                    *offset = rebased;
+                }
+            }
+            Instruction::Jump(addr)
+            | Instruction::Call { addr }
+            | Instruction::CallArgs { addr, .. }
+            | Instruction::ConditionalJump { target: addr, .. }
+            | Instruction::CompareJump { target: addr, .. }
+            | Instruction::TryBegin { handler: addr, .. }
+            | Instruction::LoadAddr { addr, .. }
+            | Instruction::MakeClosure { addr, .. }
+            | Instruction::Spawn { addr, .. } => *addr = f(*addr),
+            Instruction::Switch { table, default, .. } => {
+                for addr in table {
+                    *addr = f(*addr);
+                }
+                *default = f(*default);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether this instruction hands control to other code that comes back
+    /// to the next instruction: a function returning, a coroutine yielding or
+    /// being resumed, or the host completing a call
+    pub fn is_call(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Call { .. }
+                | Instruction::CallRel(_)
+                | Instruction::CallArgs { .. }
+                | Instruction::CallIndirect { .. }
+                | Instruction::CallClosure { .. }
+                | Instruction::Yield
+                | Instruction::Resume { .. }
+                | Instruction::CallHost { .. }
+        )
+    }
+
+    /// Whether this instruction spawns or switches between coroutines
+    pub fn uses_coroutines(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Spawn { .. } | Instruction::Yield | Instruction::Resume { .. }
+        )
+    }
+
+    /// Whether this instruction reads or writes heap objects
+    pub fn uses_heap(&self) -> bool {
+        matches!(
+            self,
+            Instruction::MakeClosure { .. }
+                | Instruction::CallClosure { .. }
+                | Instruction::GetUpvalue { .. }
+                | Instruction::SetUpvalue { .. }
+                | Instruction::NewRecord { .. }
+                | Instruction::GetField { .. }
+                | Instruction::SetField { .. }
+                | Instruction::MapNew { .. }
+                | Instruction::MapGet { .. }
+                | Instruction::MapSet { .. }
+                | Instruction::MapHas { .. }
+                | Instruction::MapDelete { .. }
+        )
+    }
+
+    /// Whether execution never falls through to the next instruction
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jump(_)
+                | Instruction::JumpRel(_)
+                | Instruction::Return
+                | Instruction::Halt
+                | Instruction::Throw { .. }
+                | Instruction::JumpIndirect { .. }
+                | Instruction::Switch { .. }
+        )
+    }
+}
+
+/// A variable, channel or host function name, quoted where the assembler
+/// would otherwise read it as something else
+struct Name<'a>(&'a str);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.0;
+        let bare = !name.is_empty()
+            && name.trim() == name
+            && !name.starts_with('"')
+            && !name.contains([',', ';', '[', ']']);
+        if bare {
+            f.write_str(name)
+        } else {
+            let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+            write!(f, "\"{}\"", escaped)
+        }
+    }
+}
+
+/// Canonical assembly text, e.g. `add r2, r0, r1` or `jz r0, 7`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Instruction::*;
+        let op = self.mnemonic();
+        match self {
+            LoadImm { dest, value } => write!(f, "{} r{}, {}", op, dest, value),
+            Add { dest, src1, src2 }
+            | Sub { dest, src1, src2 }
+            | Mul { dest, src1, src2 }
+            | Div { dest, src1, src2 }
+            | Equal { dest, src1, src2 }
+            | LessThan { dest, src1, src2 }
+            | GreaterThan { dest, src1, src2 } => {
+                write!(f, "{} r{}, r{}, r{}", op, dest, src1, src2)
+            }
+            Print { src }
+            | Throw { src }
+            | JumpIndirect { src }
+            | CallIndirect { src }
+            | CallClosure { src }
+            | MapNew { dest: src }
+            | Recv { dest: src }
+            | Rand { dest: src }
+            | Push { src }
+            | Pop { dest: src } => {
+                write!(f, "{} r{}", op, src)
+            }
+            Switch {
+                src,
+                table,
+                default,
+            } => write!(f, "{} r{}, {:?}, {}", op, src, table, default),
+            Jump(target) | Call { addr: target } => write!(f, "{} {}", op, target),
+            CallArgs { addr, argc } => write!(f, "{} {}, {}", op, addr, argc),
+            JumpRel(offset) | CallRel(offset) => write!(f, "{} {:+}", op, offset),
+            ConditionalJump { cond, target }
+            | LoadAddr {
+                dest: cond,
+                addr: target,
+            }
+            | GetUpvalue {
+                dest: cond,
+                index: target,
+            }
+            | SetUpvalue {
+                src: cond,
+                index: target,
+            }
+            | NewRecord {
+                dest: cond,
+                fields: target,
+            }
+            | Spawn {
+                dest: cond,
+                addr: target,
+            } => write!(f, "{} r{}, {}", op, cond, target),
+            GetField {
+                dest,
+                record,
+                index,
+            } => write!(f, "{} r{}, r{}, {}", op, dest, record, index),
+            SetField { record, index, src } => {
+                write!(f, "{} r{}, {}, r{}", op, record, index, src)
+            }
+            MapGet { dest, map, key }
+            | MapHas { dest, map, key }
+            | MapSet {
+                map: dest,
+                key: map,
+                src: key,
+            } => write!(f, "{} r{}, r{}, r{}", op, dest, map, key),
+            MapDelete { map, key } | Resume { dest: map, id: key } => {
+                write!(f, "{} r{}, r{}", op, map, key)
+            }
+            MakeClosure {
+                dest,
+                addr,
+                captures,
+            } => {
+                let captures: Vec<String> = captures.iter().map(|r| format!("r{}", r)).collect();
+                write!(f, "{} r{}, {}, [{}]", op, dest, addr, captures.join(", "))
+            }
+            CallHost { dest, name, args } => {
+                let args: Vec<String> = args.iter().map(|r| format!("r{}", r)).collect();
+                write!(f, "{} r{}, {}, [{}]", op, dest, Name(name), args.join(", "))
+            }
+            TryBegin { handler, dest } => write!(f, "{} r{}, {}", op, dest, handler),
+            Store { src, var } | Send { src, to: var } => {
+                write!(f, "{} r{}, {}", op, src, Name(var))
+            }
+            Load { dest, var } => write!(f, "{} r{}, {}", op, dest, Name(var)),
+            Mov { dest, src } | Not { dest, src } => write!(f, "{} r{}, r{}", op, dest, src),
+            Arity { dest, min, max } => {
+                write!(f, "{} r{}, {}", op, dest, min)?;
+                match max {
+                    Some(max) => write!(f, ", {}", max),
+                    None => Ok(()),
+                }
+            }
+            Return | Halt | TryEnd | Yield => f.write_str(op),
+            AddImm {
+                dest,
+                src,
+                imm,
+                value,
+            } => write!(f, "{} r{}, r{}, r{}, {}", op, dest, src, imm, value),
+            CompareJump {
+                dest,
+                src1,
+                src2,
+                target,
+                ..
+            } => write!(f, "{} r{}, r{}, r{}, {}", op, dest, src1, src2, target),
+        }
+    }
 }
@@ -0,0 +1,222 @@
+//! Several VMs exchanging messages.
+//!
+//! Each isolate is a named `VM` with its own registers, variables and heap;
+//! the only thing isolates share is messages. `send` puts a value in the
+//! sending VM's outbox and `recv` takes one from its inbox, and `Isolates`
+//! moves messages from outboxes to the named inboxes, which hold a bounded
+//! number each. Messages are plain numbers: a heap handle means nothing to
+//! another isolate's heap.
+
+use crate::prelude::*;
+use crate::vm::{VM, VmError};
+use alloc::collections::VecDeque;
+use core::error::Error;
+use core::fmt;
+
+/// A value on its way to the isolate named `to`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Message {
+    pub to: String,
+    pub value: f64,
+}
+
+/// A VM's side of the message channels. A `send` blocks while the outbox
+/// still holds an undelivered message, and a `recv` while the inbox is empty.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mailbox {
+    pub inbox: VecDeque<f64>,
+    pub outbox: Option<Message>,
+}
+
+impl Mailbox {
+    pub fn is_empty(&self) -> bool {
+        self.inbox.is_empty() && self.outbox.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub enum IsolateError {
+    DuplicateName(String),
+    /// A message was sent to a name no isolate has
+    UnknownIsolate(String),
+    /// An isolate stopped with an error other than blocking
+    Failed {
+        name: String,
+        error: VmError,
+    },
+    /// Every isolate still running is blocked on a channel
+    Deadlock(Vec<String>),
+}
+
+impl fmt::Display for IsolateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsolateError::DuplicateName(name) => {
+                write!(f, "An isolate named '{}' already exists", name)
+            }
+            IsolateError::UnknownIsolate(name) => write!(f, "No isolate named '{}'", name),
+            IsolateError::Failed { name, error } => {
+                write!(f, "Isolate '{}' failed: {}", name, error)
+            }
+            IsolateError::Deadlock(names) => {
+                write!(f, "Deadlock: {} are all blocked", names.join(", "))
+            }
+        }
+    }
+}
+
+impl Error for IsolateError {}
+
+struct Isolate {
+    name: String,
+    vm: VM,
+}
+
+/// A set of named VMs connected by bounded channels
+pub struct Isolates {
+    isolates: Vec<Isolate>,
+    /// Messages an inbox holds before senders to it block
+    capacity: usize,
+}
+
+impl Isolates {
+    /// An empty set whose inboxes hold up to `capacity` messages, at least one
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            isolates: Vec::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn spawn(&mut self, name: impl Into<String>, vm: VM) -> Result<(), IsolateError> {
+        let name = name.into();
+        if self.index(&name).is_some() {
+            return Err(IsolateError::DuplicateName(name));
+        }
+        self.isolates.push(Isolate { name, vm });
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&VM> {
+        self.index(name).map(|i| &self.isolates[i].vm)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut VM> {
+        self.index(name).map(|i| &mut self.isolates[i].vm)
+    }
+
+    /// Run every isolate in turn until all have halted. A VM with
+    /// `max_steps` set is paused when it uses them up and run again next
+    /// round, so one that never blocks cannot starve the others.
+    pub fn run(&mut self) -> Result<(), IsolateError> {
+        self.run_rounds(|isolates| {
+            isolates
+                .iter_mut()
+                .map(|isolate| run_slice(&mut isolate.vm))
+                .collect()
+        })
+    }
+
+    /// As `run`, but each round spreads the isolates over up to `threads`
+    /// threads
+    #[cfg(feature = "std")]
+    pub fn run_parallel(&mut self, threads: usize) -> Result<(), IsolateError> {
+        self.run_rounds(|isolates| {
+            let chunk = isolates.len().div_ceil(threads.max(1)).max(1);
+            std::thread::scope(|scope| {
+                let workers: Vec<_> = isolates
+                    .chunks_mut(chunk)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter_mut()
+                                .map(|isolate| run_slice(&mut isolate.vm))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|worker| worker.join().expect("isolate thread panicked"))
+                    .collect()
+            })
+        })
+    }
+
+    /// Alternate running a round with `round` and delivering messages until
+    /// every isolate halts, one fails, or none can make progress
+    fn run_rounds(
+        &mut self,
+        mut round: impl FnMut(&mut [Isolate]) -> Vec<Result<u64, VmError>>,
+    ) -> Result<(), IsolateError> {
+        loop {
+            let results = round(&mut self.isolates);
+            let mut progress = false;
+            for (isolate, result) in self.isolates.iter_mut().zip(results) {
+                match result {
+                    Ok(steps) => progress |= steps > 0,
+                    Err(error) => {
+                        return Err(IsolateError::Failed {
+                            name: isolate.name.clone(),
+                            error,
+                        });
+                    }
+                }
+            }
+            progress |= self.deliver()?;
+
+            if self.isolates.iter().all(|isolate| isolate.vm.is_halted()) {
+                return Ok(());
+            }
+            if !progress {
+                let blocked = self
+                    .isolates
+                    .iter()
+                    .filter(|isolate| !isolate.vm.is_halted())
+                    .map(|isolate| isolate.name.clone())
+                    .collect();
+                return Err(IsolateError::Deadlock(blocked));
+            }
+        }
+    }
+
+    /// Move every outgoing message whose destination has room, returning
+    /// whether any moved
+    fn deliver(&mut self) -> Result<bool, IsolateError> {
+        let mut delivered = false;
+        for from in 0..self.isolates.len() {
+            let Some(message) = &self.isolates[from].vm.mailbox.outbox else {
+                continue;
+            };
+            let to = self
+                .index(&message.to)
+                .ok_or_else(|| IsolateError::UnknownIsolate(message.to.clone()))?;
+            if self.isolates[to].vm.mailbox.inbox.len() < self.capacity {
+                let message = self.isolates[from].vm.mailbox.outbox.take();
+                if let Some(message) = message {
+                    self.isolates[to].vm.mailbox.inbox.push_back(message.value);
+                    delivered = true;
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    fn index(&self, name: &str) -> Option<usize> {
+        self.isolates
+            .iter()
+            .position(|isolate| isolate.name == name)
+    }
+}
+
+/// Run `vm` until it halts, blocks or uses up its step budget, returning how
+/// many instructions it executed
+fn run_slice(vm: &mut VM) -> Result<u64, VmError> {
+    let start = vm.steps;
+    match vm.run() {
+        Ok(()) | Err(VmError::Blocked) | Err(VmError::StepLimitExceeded) => Ok(vm.steps - start),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,687 @@
+//! Native compilation of verified programs through Cranelift.
+//!
+//! The whole program becomes one function with a block per instruction and
+//! registers held in SSA variables. Execution can enter at any pc through a
+//! jump table, which is also how `Return` reaches its return address.
+//! Variables are interned into slots. Instructions the compiled code cannot
+//! run itself (`Print`, error handling, computed jumps and switches, loading
+//! an unset variable, targets outside the program, a full call stack buffer,
+//! returning with an empty stack or from a program that installs handlers)
+//! make it exit at that instruction so the interpreter can execute it, with
+//! exactly the interpreter's semantics, before re-entering. Programs that
+//! use heap objects, coroutines, host calls, message channels, `rand` or
+//! the operand stack are not compiled at all.
+
+use crate::instruction::{self, Comparison, Instruction};
+use crate::program::{Program, ProgramError};
+use crate::vm::{Frame, VM};
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    AbiParam, Block, BlockCall, InstBuilder, JumpTableData, MemFlags, Value, types,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{Linkage, Module, default_libcall_names};
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::mem::offset_of;
+
+#[derive(Debug)]
+pub enum JitError {
+    /// The program uses registers outside the register file
+    Unverified(ProgramError),
+    /// The program uses heap objects, whose frames the compiled call stack
+    /// cannot represent
+    Unsupported(ProgramError),
+    /// Only 64-bit hosts are supported
+    UnsupportedTarget,
+    /// Cranelift rejected the generated code or could not target this host
+    Codegen(String),
+}
+
+impl fmt::Display for JitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JitError::Unverified(e) => write!(f, "Cannot compile unverified program: {}", e),
+            JitError::Unsupported(e) => write!(f, "Cannot compile program: {}", e),
+            JitError::UnsupportedTarget => write!(f, "The JIT only supports 64-bit hosts"),
+            JitError::Codegen(msg) => write!(f, "Code generation failed: {}", msg),
+        }
+    }
+}
+
+impl Error for JitError {}
+
+fn codegen(e: impl fmt::Display) -> JitError {
+    JitError::Codegen(e.to_string())
+}
+
+/// Call-stack entries the compiled code can push before handing a `Call` to
+/// the interpreter, which grows the stack; the buffer is re-sized on re-entry
+const MIN_STACK_CAPACITY: usize = 1024;
+
+/// State shared with compiled code; field order is part of the generated code
+#[repr(C)]
+struct State {
+    pc: u64,
+    steps: u64,
+    stack: *mut u64,
+    stack_len: u64,
+    stack_capacity: u64,
+    values: *mut f64,
+    /// Non-zero for each slot in `values` that holds a variable's value
+    set: *mut u8,
+}
+
+const PC: i32 = offset_of!(State, pc) as i32;
+const STEPS: i32 = offset_of!(State, steps) as i32;
+const STACK: i32 = offset_of!(State, stack) as i32;
+const STACK_LEN: i32 = offset_of!(State, stack_len) as i32;
+const STACK_CAPACITY: i32 = offset_of!(State, stack_capacity) as i32;
+const VALUES: i32 = offset_of!(State, values) as i32;
+const SET: i32 = offset_of!(State, set) as i32;
+
+type Entry = unsafe extern "C" fn(*mut f64, *mut State);
+
+/// A program compiled to native code, reusable across runs with
+/// `VM::run_compiled`
+pub struct CompiledProgram {
+    module: Option<JITModule>,
+    entry: Entry,
+    program: Program,
+    num_registers: usize,
+    /// Variable name for each slot
+    names: Vec<String>,
+}
+
+/// Buffers reused across every entry into compiled code during one run
+pub(crate) struct Scratch {
+    stack: Vec<u64>,
+    values: Vec<f64>,
+    set: Vec<u8>,
+}
+
+impl CompiledProgram {
+    /// Compile `program` for VMs with at least `num_registers` registers
+    pub fn compile(program: &Program, num_registers: usize) -> Result<Self, JitError> {
+        program
+            .verify_registers(num_registers)
+            .map_err(JitError::Unverified)?;
+        program
+            .check_supported(|i| {
+                !i.uses_heap()
+                    && !i.uses_coroutines()
+                    && !matches!(
+                        i,
+                        Instruction::CallHost { .. }
+                            | Instruction::Send { .. }
+                            | Instruction::Recv { .. }
+                            | Instruction::Rand { .. }
+                            | Instruction::Push { .. }
+                            | Instruction::Pop { .. }
+                            | Instruction::CallArgs { .. }
+                            | Instruction::Arity { .. }
+                    )
+            })
+            .map_err(JitError::Unsupported)?;
+
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(codegen)?;
+        let isa = cranelift_native::builder()
+            .map_err(codegen)?
+            .finish(settings::Flags::new(flags))
+            .map_err(codegen)?;
+        let mut module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+
+        let ptr = module.target_config().pointer_type();
+        if ptr != types::I64 {
+            return Err(JitError::UnsupportedTarget);
+        }
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(ptr));
+        ctx.func.signature.params.push(AbiParam::new(ptr));
+
+        let mut names: Vec<String> = Vec::new();
+        let mut slots: HashMap<&str, usize> = HashMap::new();
+        for instr in &program.instructions {
+            if let Instruction::Store { var, .. } | Instruction::Load { var, .. } = instr {
+                slots.entry(var).or_insert_with(|| {
+                    names.push(var.clone());
+                    names.len() - 1
+                });
+            }
+        }
+
+        let mut fn_ctx = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+        Translator::new(builder, program, &slots).translate();
+
+        let id = module
+            .declare_function("zyde_program", Linkage::Local, &ctx.func.signature)
+            .map_err(codegen)?;
+        module.define_function(id, &mut ctx).map_err(codegen)?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().map_err(codegen)?;
+
+        let code = module.get_finalized_function(id);
+        // SAFETY: the function was declared with exactly this signature
+        let entry = unsafe { std::mem::transmute::<*const u8, Entry>(code) };
+        Ok(Self {
+            module: Some(module),
+            entry,
+            program: program.clone(),
+            num_registers,
+            names,
+        })
+    }
+
+    /// Whether this code was compiled from `vm`'s current program for a
+    /// register file no larger than `vm`'s
+    pub(crate) fn matches(&self, vm: &VM) -> bool {
+        vm.registers.len() >= self.num_registers && *vm.program == self.program
+    }
+
+    pub(crate) fn scratch(&self) -> Scratch {
+        Scratch {
+            stack: Vec::new(),
+            values: vec![0.0; self.names.len()],
+            set: vec![0; self.names.len()],
+        }
+    }
+
+    /// Run natively from `vm.pc` until the program halts or reaches an
+    /// instruction the interpreter has to execute. `vm` must `match`, and its
+    /// variables are synced in and out of `scratch` around the call.
+    pub(crate) fn enter(&self, vm: &mut VM, scratch: &mut Scratch) {
+        debug_assert!(self.matches(vm));
+
+        for (slot, name) in self.names.iter().enumerate() {
+            let value = vm.variables.get(name);
+            scratch.values[slot] = value.copied().unwrap_or(0.0);
+            scratch.set[slot] = value.is_some() as u8;
+        }
+        let stack = &mut scratch.stack;
+        stack.clear();
+        stack.extend(vm.call_stack.iter().map(|f| f.return_address as u64));
+        stack.reserve(stack.len().max(MIN_STACK_CAPACITY));
+        let mut state = State {
+            pc: vm.pc as u64,
+            steps: vm.steps,
+            stack: stack.as_mut_ptr(),
+            stack_len: stack.len() as u64,
+            stack_capacity: stack.capacity() as u64,
+            values: scratch.values.as_mut_ptr(),
+            set: scratch.set.as_mut_ptr(),
+        };
+
+        // SAFETY: the program was verified against this register file, the
+        // compiled code never writes the stack past `stack_capacity`, and
+        // every slot it uses is below `names.len()`
+        unsafe {
+            (self.entry)(vm.registers.as_mut_ptr(), &mut state);
+            stack.set_len(state.stack_len as usize);
+        }
+
+        vm.pc = state.pc as usize;
+        vm.steps = state.steps;
+        vm.call_stack.clear();
+        vm.call_stack
+            .extend(stack.iter().map(|&a| Frame::new(a as usize)));
+        for (slot, name) in self.names.iter().enumerate() {
+            if scratch.set[slot] == 0 {
+                continue;
+            }
+            match vm.variables.get_mut(name) {
+                Some(value) => *value = scratch.values[slot],
+                None => {
+                    vm.variables.insert(name.clone(), scratch.values[slot]);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for CompiledProgram {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `entry` points into this module and is never called again
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+struct Translator<'a> {
+    b: FunctionBuilder<'a>,
+    code: &'a [Instruction],
+    blocks: Vec<Block>,
+    /// Takes the pc to continue at; runs compiled code if it is in bounds
+    dispatch: Block,
+    /// Takes the pc to stop at; writes every variable back and returns
+    exit: Block,
+    used: BTreeSet<usize>,
+    slots: &'a HashMap<&'a str, usize>,
+    /// Whether the program installs error handlers, which returns must prune
+    has_handlers: bool,
+    steps: Variable,
+    stack_len: Variable,
+    regs_ptr: Value,
+    state_ptr: Value,
+}
+
+impl<'a> Translator<'a> {
+    fn new(
+        mut b: FunctionBuilder<'a>,
+        program: &'a Program,
+        slots: &'a HashMap<&'a str, usize>,
+    ) -> Self {
+        let code = program.instructions.as_slice();
+        let used = code
+            .iter()
+            .flat_map(|i| i.writes().into_iter().chain(i.sources()))
+            .collect();
+
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+        let regs_ptr = b.block_params(entry)[0];
+        let state_ptr = b.block_params(entry)[1];
+
+        let blocks = code.iter().map(|_| b.create_block()).collect();
+        let dispatch = b.create_block();
+        b.append_block_param(dispatch, types::I64);
+        let exit = b.create_block();
+        b.append_block_param(exit, types::I64);
+
+        let steps = Variable::new(0);
+        let stack_len = Variable::new(1);
+        b.declare_var(steps, types::I64);
+        b.declare_var(stack_len, types::I64);
+
+        Self {
+            b,
+            code,
+            blocks,
+            dispatch,
+            exit,
+            used,
+            slots,
+            has_handlers: code
+                .iter()
+                .any(|i| matches!(i, Instruction::TryBegin { .. })),
+            steps,
+            stack_len,
+            regs_ptr,
+            state_ptr,
+        }
+    }
+
+    fn reg(&self, reg: usize) -> Variable {
+        Variable::new(2 + reg)
+    }
+
+    fn translate(mut self) {
+        let flags = MemFlags::trusted();
+        let used: Vec<usize> = self.used.iter().copied().collect();
+        for &r in &used {
+            let var = self.reg(r);
+            self.b.declare_var(var, types::F64);
+            let v = self
+                .b
+                .ins()
+                .load(types::F64, flags, self.regs_ptr, (r * 8) as i32);
+            self.b.def_var(var, v);
+        }
+        let steps = self.b.ins().load(types::I64, flags, self.state_ptr, STEPS);
+        self.b.def_var(self.steps, steps);
+        let stack_len = self
+            .b
+            .ins()
+            .load(types::I64, flags, self.state_ptr, STACK_LEN);
+        self.b.def_var(self.stack_len, stack_len);
+        let pc = self.b.ins().load(types::I64, flags, self.state_ptr, PC);
+        self.b.ins().jump(self.dispatch, &[pc]);
+
+        self.translate_dispatch();
+        for pc in 0..self.code.len() {
+            self.b.switch_to_block(self.blocks[pc]);
+            self.translate_instruction(pc);
+        }
+
+        self.b.switch_to_block(self.exit);
+        let pc = self.b.block_params(self.exit)[0];
+        for &r in &used {
+            let v = self.b.use_var(self.reg(r));
+            self.b.ins().store(flags, v, self.regs_ptr, (r * 8) as i32);
+        }
+        let steps = self.b.use_var(self.steps);
+        self.b.ins().store(flags, steps, self.state_ptr, STEPS);
+        let stack_len = self.b.use_var(self.stack_len);
+        self.b
+            .ins()
+            .store(flags, stack_len, self.state_ptr, STACK_LEN);
+        self.b.ins().store(flags, pc, self.state_ptr, PC);
+        self.b.ins().return_(&[]);
+
+        self.b.seal_all_blocks();
+        self.b.finalize();
+    }
+
+    fn translate_dispatch(&mut self) {
+        self.b.switch_to_block(self.dispatch);
+        let pc = self.b.block_params(self.dispatch)[0];
+        let len = self.code.len() as i64;
+        let in_bounds = self.b.ins().icmp_imm(IntCC::UnsignedLessThan, pc, len);
+        let table = self.b.create_block();
+        self.b.ins().brif(in_bounds, table, &[], self.exit, &[pc]);
+
+        self.b.switch_to_block(table);
+        let index = self.b.ins().ireduce(types::I32, pc);
+        let exit = self.b.func.dfg.block_call(self.exit, &[pc]);
+        let targets: Vec<BlockCall> = self
+            .blocks
+            .iter()
+            .map(|&block| self.b.func.dfg.block_call(block, &[]))
+            .collect();
+        let jt = self.b.create_jump_table(JumpTableData::new(exit, &targets));
+        self.b.ins().br_table(index, jt);
+    }
+
+    /// Leave compiled code without executing instruction `pc`
+    fn bail(&mut self, pc: usize) {
+        let pc = self.b.ins().iconst(types::I64, pc as i64);
+        self.b.ins().jump(self.exit, &[pc]);
+    }
+
+    /// Continue at `pc`, which may be one past the end
+    fn goto(&mut self, pc: usize) {
+        match self.blocks.get(pc) {
+            Some(&block) => {
+                self.b.ins().jump(block, &[]);
+            }
+            None => {
+                let pc = self.b.ins().iconst(types::I64, pc as i64);
+                self.b.ins().jump(self.exit, &[pc]);
+            }
+        }
+    }
+
+    /// Branch to `then_pc` if `cond` is set, else to `else_pc`
+    fn branch(&mut self, cond: Value, then_pc: usize, else_pc: usize) {
+        let then_block = self.b.create_block();
+        let else_block = self.b.create_block();
+        self.b.ins().brif(cond, then_block, &[], else_block, &[]);
+        self.b.switch_to_block(then_block);
+        self.goto(then_pc);
+        self.b.switch_to_block(else_block);
+        self.goto(else_pc);
+    }
+
+    fn count_step(&mut self) {
+        let steps = self.b.use_var(self.steps);
+        let steps = self.b.ins().iadd_imm(steps, 1);
+        self.b.def_var(self.steps, steps);
+    }
+
+    fn get(&mut self, reg: usize) -> Value {
+        self.b.use_var(self.reg(reg))
+    }
+
+    fn set(&mut self, reg: usize, value: Value) {
+        self.b.def_var(self.reg(reg), value);
+    }
+
+    fn compare(&mut self, cc: FloatCC, src1: usize, src2: usize) -> Value {
+        let (x, y) = (self.get(src1), self.get(src2));
+        self.b.ins().fcmp(cc, x, y)
+    }
+
+    fn bool_val(&mut self, cond: Value) -> Value {
+        let one = self.b.ins().f64const(1.0);
+        let zero = self.b.ins().f64const(0.0);
+        self.b.ins().select(cond, one, zero)
+    }
+
+    fn translate_instruction(&mut self, pc: usize) {
+        use Instruction::*;
+
+        let len = self.code.len();
+        let next = pc + 1;
+        if self.code[pc].targets(pc).into_iter().any(|t| t >= len) {
+            return self.bail(pc);
+        }
+
+        match self.code[pc] {
+            Print { .. }
+            | TryBegin { .. }
+            | TryEnd
+            | Throw { .. }
+            | JumpIndirect { .. }
+            | Switch { .. }
+            | CallIndirect { .. } => return self.bail(pc),
+            Return if self.has_handlers => return self.bail(pc),
+            Load { dest, ref var } => {
+                return self.translate_load(pc, dest, self.slots[var.as_str()]);
+            }
+            Call { addr } => return self.translate_call(pc, addr),
+            CallRel(offset) => {
+                return self.translate_call(pc, instruction::relative(pc, offset));
+            }
+            Return => return self.translate_return(pc),
+            _ => {}
+        }
+
+        self.count_step();
+        match self.code[pc] {
+            LoadImm { dest, value } => {
+                let v = self.b.ins().f64const(value);
+                self.set(dest, v);
+            }
+            LoadAddr { dest, addr } => {
+                let v = self.b.ins().f64const(addr as f64);
+                self.set(dest, v);
+            }
+            Add { dest, src1, src2 } => {
+                let (x, y) = (self.get(src1), self.get(src2));
+                let v = self.b.ins().fadd(x, y);
+                self.set(dest, v);
+            }
+            Sub { dest, src1, src2 } => {
+                let (x, y) = (self.get(src1), self.get(src2));
+                let v = self.b.ins().fsub(x, y);
+                self.set(dest, v);
+            }
+            Mul { dest, src1, src2 } => {
+                let (x, y) = (self.get(src1), self.get(src2));
+                let v = self.b.ins().fmul(x, y);
+                self.set(dest, v);
+            }
+            Div { dest, src1, src2 } => {
+                let (x, y) = (self.get(src1), self.get(src2));
+                let v = self.b.ins().fdiv(x, y);
+                self.set(dest, v);
+            }
+            Jump(target) => return self.goto(target),
+            JumpRel(offset) => return self.goto(instruction::relative(pc, offset)),
+            ConditionalJump { cond, target } => {
+                let x = self.get(cond);
+                let zero = self.b.ins().f64const(0.0);
+                let is_zero = self.b.ins().fcmp(FloatCC::Equal, x, zero);
+                return self.branch(is_zero, target, next);
+            }
+            Mov { dest, src } => {
+                let v = self.get(src);
+                self.set(dest, v);
+            }
+            Equal { dest, src1, src2 } => {
+                let c = self.compare(FloatCC::Equal, src1, src2);
+                let v = self.bool_val(c);
+                self.set(dest, v);
+            }
+            LessThan { dest, src1, src2 } => {
+                let c = self.compare(FloatCC::LessThan, src1, src2);
+                let v = self.bool_val(c);
+                self.set(dest, v);
+            }
+            GreaterThan { dest, src1, src2 } => {
+                let c = self.compare(FloatCC::GreaterThan, src1, src2);
+                let v = self.bool_val(c);
+                self.set(dest, v);
+            }
+            Not { dest, src } => {
+                let x = self.get(src);
+                let zero = self.b.ins().f64const(0.0);
+                let c = self.b.ins().fcmp(FloatCC::Equal, x, zero);
+                let v = self.bool_val(c);
+                self.set(dest, v);
+            }
+            Halt => return self.goto(len),
+            AddImm {
+                dest,
+                src,
+                imm,
+                value,
+            } => {
+                let v = self.b.ins().f64const(value);
+                self.set(imm, v);
+                let x = self.get(src);
+                let sum = self.b.ins().fadd(x, v);
+                self.set(dest, sum);
+            }
+            CompareJump {
+                cmp,
+                dest,
+                src1,
+                src2,
+                target,
+            } => {
+                let cc = match cmp {
+                    Comparison::Equal => FloatCC::Equal,
+                    Comparison::LessThan => FloatCC::LessThan,
+                    Comparison::GreaterThan => FloatCC::GreaterThan,
+                };
+                let c = self.compare(cc, src1, src2);
+                let v = self.bool_val(c);
+                self.set(dest, v);
+                return self.branch(c, next, target);
+            }
+            Store { src, ref var } => {
+                let slot = self.slots[var.as_str()] as i32;
+                let flags = MemFlags::trusted();
+                let v = self.get(src);
+                let values = self.b.ins().load(types::I64, flags, self.state_ptr, VALUES);
+                self.b.ins().store(flags, v, values, slot * 8);
+                let set = self.b.ins().load(types::I64, flags, self.state_ptr, SET);
+                let one = self.b.ins().iconst(types::I8, 1);
+                self.b.ins().store(flags, one, set, slot);
+            }
+            Print { .. }
+            | Load { .. }
+            | Call { .. }
+            | CallRel(_)
+            | Return
+            | TryBegin { .. }
+            | TryEnd
+            | Throw { .. }
+            | JumpIndirect { .. }
+            | Switch { .. }
+            | CallIndirect { .. } => unreachable!(),
+            MakeClosure { .. }
+            | CallClosure { .. }
+            | GetUpvalue { .. }
+            | SetUpvalue { .. }
+            | NewRecord { .. }
+            | GetField { .. }
+            | SetField { .. }
+            | MapNew { .. }
+            | MapGet { .. }
+            | MapSet { .. }
+            | MapHas { .. }
+            | MapDelete { .. }
+            | Spawn { .. }
+            | Yield
+            | Resume { .. }
+            | CallHost { .. }
+            | Send { .. }
+            | Recv { .. }
+            | Rand { .. }
+            | Push { .. }
+            | Pop { .. }
+            | CallArgs { .. }
+            | Arity { .. } => unreachable!("rejected by compile"),
+        }
+        self.goto(next);
+    }
+
+    fn translate_call(&mut self, pc: usize, addr: usize) {
+        let flags = MemFlags::trusted();
+        let stack_len = self.b.use_var(self.stack_len);
+        let capacity = self
+            .b
+            .ins()
+            .load(types::I64, flags, self.state_ptr, STACK_CAPACITY);
+        let full = self
+            .b
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, stack_len, capacity);
+        let push = self.b.create_block();
+        let bail = self.b.create_block();
+        self.b.ins().brif(full, bail, &[], push, &[]);
+        self.b.switch_to_block(bail);
+        self.bail(pc);
+
+        self.b.switch_to_block(push);
+        self.count_step();
+        let stack = self.b.ins().load(types::I64, flags, self.state_ptr, STACK);
+        let offset = self.b.ins().imul_imm(stack_len, 8);
+        let slot = self.b.ins().iadd(stack, offset);
+        let return_address = self.b.ins().iconst(types::I64, (pc + 1) as i64);
+        self.b.ins().store(flags, return_address, slot, 0);
+        let stack_len = self.b.ins().iadd_imm(stack_len, 1);
+        self.b.def_var(self.stack_len, stack_len);
+        self.goto(addr);
+    }
+
+    fn translate_load(&mut self, pc: usize, dest: usize, slot: usize) {
+        let flags = MemFlags::trusted();
+        let slot = slot as i32;
+        let set = self.b.ins().load(types::I64, flags, self.state_ptr, SET);
+        let is_set = self.b.ins().load(types::I8, flags, set, slot);
+        let load = self.b.create_block();
+        let bail = self.b.create_block();
+        self.b.ins().brif(is_set, load, &[], bail, &[]);
+        self.b.switch_to_block(bail);
+        self.bail(pc);
+
+        self.b.switch_to_block(load);
+        self.count_step();
+        let values = self.b.ins().load(types::I64, flags, self.state_ptr, VALUES);
+        let v = self.b.ins().load(types::F64, flags, values, slot * 8);
+        self.set(dest, v);
+        self.goto(pc + 1);
+    }
+
+    fn translate_return(&mut self, pc: usize) {
+        let flags = MemFlags::trusted();
+        let stack_len = self.b.use_var(self.stack_len);
+        let empty = self.b.ins().icmp_imm(IntCC::Equal, stack_len, 0);
+        let pop = self.b.create_block();
+        let bail = self.b.create_block();
+        self.b.ins().brif(empty, bail, &[], pop, &[]);
+        self.b.switch_to_block(bail);
+        self.bail(pc);
+
+        self.b.switch_to_block(pop);
+        self.count_step();
+        let stack_len = self.b.ins().iadd_imm(stack_len, -1);
+        self.b.def_var(self.stack_len, stack_len);
+        let stack = self.b.ins().load(types::I64, flags, self.state_ptr, STACK);
+        let offset = self.b.ins().imul_imm(stack_len, 8);
+        let slot = self.b.ins().iadd(stack, offset);
+        let return_address = self.b.ins().load(types::I64, flags, slot, 0);
+        self.b.ins().jump(self.dispatch, &[return_address]);
+    }
+}
@@ -0,0 +1,32 @@
+//! Minimal helpers for writing JSON by hand, so machine-readable output does
+//! not require pulling in a serialization framework.
+
+use crate::prelude::*;
+
+/// Quote and escape `s` as a JSON string
+pub(crate) fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format `n` as a JSON number; NaN and infinities have no JSON form and become `null`
+pub(crate) fn number(n: f64) -> String {
+    if n.is_finite() {
+        format!("{}", n)
+    } else {
+        "null".to_string()
+    }
+}
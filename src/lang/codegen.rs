@@ -0,0 +1,214 @@
+use super::ast::{BinOp, Expr, Stmt, UnOp};
+use super::{Compiled, LangError, LangErrorKind};
+use crate::HashMap;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+pub(super) fn compile(stmts: &[Stmt]) -> Result<Compiled, LangError> {
+    let mut codegen = Codegen {
+        code: Vec::new(),
+        scopes: vec![Vec::new()],
+        next: 0,
+        max: 0,
+    };
+    for stmt in stmts {
+        codegen.stmt(stmt)?;
+    }
+    codegen.code.push(Instruction::Halt);
+    Ok(Compiled {
+        program: Program::new(codegen.code),
+        registers: codegen.max.max(1),
+        variables: codegen.scopes[0]
+            .iter()
+            .map(|(name, reg)| (name.clone(), *reg))
+            .collect(),
+        spilled: HashMap::new(),
+    })
+}
+
+struct Codegen {
+    code: Vec<Instruction>,
+    /// Variables declared in each enclosing block, innermost last
+    scopes: Vec<Vec<(String, usize)>>,
+    /// Lowest free register; everything above it is free too
+    next: usize,
+    /// Registers used so far
+    max: usize,
+}
+
+impl Codegen {
+    fn alloc(&mut self) -> usize {
+        let reg = self.next;
+        self.next += 1;
+        self.max = self.max.max(self.next);
+        reg
+    }
+
+    fn lookup(&self, name: &str, line: usize) -> Result<usize, LangError> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(n, _)| n == name)
+            .map(|&(_, reg)| reg)
+            .ok_or_else(|| LangError {
+                line,
+                kind: LangErrorKind::UndefinedVariable(name.to_string()),
+            })
+    }
+
+    /// Emit `instr`, returning its address
+    fn emit(&mut self, instr: Instruction) -> usize {
+        self.code.push(instr);
+        self.code.len() - 1
+    }
+
+    /// Point the jump at `at` to the next instruction emitted
+    fn patch(&mut self, at: usize) {
+        let here = self.code.len();
+        match &mut self.code[at] {
+            Instruction::Jump(target) | Instruction::ConditionalJump { target, .. } => {
+                *target = here
+            }
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<(), LangError> {
+        let base = self.next;
+        self.scopes.push(Vec::new());
+        for stmt in stmts {
+            self.stmt(stmt)?;
+        }
+        self.scopes.pop();
+        self.next = base;
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<(), LangError> {
+        match stmt {
+            Stmt::Let { name, value } => {
+                // Evaluated first, so `let x = x + 1` reads an outer `x`
+                let reg = self.alloc();
+                self.expr_into(value, reg)?;
+                self.scopes.last_mut().unwrap().push((name.clone(), reg));
+            }
+            Stmt::Assign { name, value, line } => {
+                let reg = self.lookup(name, *line)?;
+                self.expr_into(value, reg)?;
+            }
+            Stmt::Print(value) => {
+                let base = self.next;
+                let src = self.operand(value)?;
+                self.emit(Instruction::Print { src });
+                self.next = base;
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let to_else = self.branch(cond)?;
+                self.block(then)?;
+                if otherwise.is_empty() {
+                    self.patch(to_else);
+                } else {
+                    let to_end = self.emit(Instruction::Jump(0));
+                    self.patch(to_else);
+                    self.block(otherwise)?;
+                    self.patch(to_end);
+                }
+            }
+            Stmt::While { cond, body } => {
+                let top = self.code.len();
+                let to_end = self.branch(cond)?;
+                self.block(body)?;
+                self.emit(Instruction::Jump(top));
+                self.patch(to_end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate `cond` and emit a jump, to be patched, taken when it is false
+    fn branch(&mut self, cond: &Expr) -> Result<usize, LangError> {
+        let base = self.next;
+        let reg = self.operand(cond)?;
+        self.next = base;
+        Ok(self.emit(Instruction::ConditionalJump {
+            cond: reg,
+            target: 0,
+        }))
+    }
+
+    /// A register holding `e`: a variable's own, or a new temporary
+    fn operand(&mut self, e: &Expr) -> Result<usize, LangError> {
+        if let Expr::Var { name, line } = e {
+            return self.lookup(name, *line);
+        }
+        let reg = self.alloc();
+        self.expr_into(e, reg)?;
+        Ok(reg)
+    }
+
+    /// Evaluate `e` into register `dest`, freeing any temporaries after
+    fn expr_into(&mut self, e: &Expr, dest: usize) -> Result<(), LangError> {
+        let base = self.next;
+        match e {
+            Expr::Number(value) => {
+                self.emit(Instruction::LoadImm {
+                    dest,
+                    value: *value,
+                });
+            }
+            Expr::Var { name, line } => {
+                let src = self.lookup(name, *line)?;
+                if src != dest {
+                    self.emit(Instruction::Mov { dest, src });
+                }
+            }
+            Expr::Unary(UnOp::Neg, operand) => {
+                let src = self.operand(operand)?;
+                let zero = self.alloc();
+                self.emit(Instruction::LoadImm {
+                    dest: zero,
+                    value: 0.0,
+                });
+                self.emit(Instruction::Sub {
+                    dest,
+                    src1: zero,
+                    src2: src,
+                });
+            }
+            Expr::Unary(UnOp::Not, operand) => {
+                let src = self.operand(operand)?;
+                self.emit(Instruction::Not { dest, src });
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let src1 = self.operand(lhs)?;
+                let src2 = self.operand(rhs)?;
+                self.binary(*op, dest, src1, src2);
+            }
+        }
+        self.next = base;
+        Ok(())
+    }
+
+    fn binary(&mut self, op: BinOp, dest: usize, src1: usize, src2: usize) {
+        use Instruction::*;
+        let instr = match op {
+            BinOp::Add => Add { dest, src1, src2 },
+            BinOp::Sub => Sub { dest, src1, src2 },
+            BinOp::Mul => Mul { dest, src1, src2 },
+            BinOp::Div => Div { dest, src1, src2 },
+            BinOp::Equal | BinOp::NotEqual => Equal { dest, src1, src2 },
+            BinOp::Less | BinOp::GreaterEqual => LessThan { dest, src1, src2 },
+            BinOp::Greater | BinOp::LessEqual => GreaterThan { dest, src1, src2 },
+        };
+        self.emit(instr);
+        if matches!(op, BinOp::NotEqual | BinOp::GreaterEqual | BinOp::LessEqual) {
+            self.emit(Not { dest, src: dest });
+        }
+    }
+}
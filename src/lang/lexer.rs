@@ -0,0 +1,89 @@
+use super::{LangError, LangErrorKind};
+use crate::prelude::*;
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Token {
+    Number(f64),
+    Ident(String),
+    Let,
+    If,
+    Else,
+    While,
+    Print,
+    /// Punctuation and operators, such as `(`, `==` or `;`
+    Symbol(&'static str),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "'{}'", name),
+            Token::Let => write!(f, "'let'"),
+            Token::If => write!(f, "'if'"),
+            Token::Else => write!(f, "'else'"),
+            Token::While => write!(f, "'while'"),
+            Token::Print => write!(f, "'print'"),
+            Token::Symbol(s) => write!(f, "'{}'", s),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Longest first, so `<=` is not read as `<` then `=`
+const SYMBOLS: [&str; 17] = [
+    "==", "!=", "<=", ">=", "+", "-", "*", "/", "<", ">", "=", "!", "(", ")", "{", "}", ";",
+];
+
+/// Each token with its line, ending with `Token::End`
+pub(super) fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, LangError> {
+    let mut tokens = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let text = text.split("//").next().unwrap_or("");
+        let mut rest = text.trim_start();
+        while let Some(c) = rest.chars().next() {
+            let len = if c.is_ascii_digit() || c == '.' {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit() && c != '.')
+                    .unwrap_or(rest.len());
+                let number = rest[..len].parse().map_err(|_| LangError {
+                    line,
+                    kind: LangErrorKind::UnexpectedChar(c),
+                })?;
+                tokens.push((Token::Number(number), line));
+                len
+            } else if c.is_alphabetic() || c == '_' {
+                let len = rest
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                let token = match &rest[..len] {
+                    "let" => Token::Let,
+                    "if" => Token::If,
+                    "else" => Token::Else,
+                    "while" => Token::While,
+                    "print" => Token::Print,
+                    name => Token::Ident(name.to_string()),
+                };
+                tokens.push((token, line));
+                len
+            } else {
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|s| rest.starts_with(**s))
+                    .ok_or(LangError {
+                        line,
+                        kind: LangErrorKind::UnexpectedChar(c),
+                    })?;
+                tokens.push((Token::Symbol(symbol), line));
+                symbol.len()
+            };
+            rest = rest[len..].trim_start();
+        }
+    }
+    let last = source.lines().count().max(1);
+    tokens.push((Token::End, last));
+    Ok(tokens)
+}
@@ -0,0 +1,112 @@
+//! A small statement language that compiles to instructions.
+//!
+//! ```text
+//! let x = (a + b) * 3;
+//! if x < 10 { print x; } else { x = x - 1; }
+//! while x > 0 { x = x - 1; }
+//! ```
+//!
+//! Values are numbers. `let` declares a variable for the rest of its block,
+//! `=` assigns to one already declared, and `print` prints a value.
+//! Conditions are false when zero, and comparisons give 1 or 0. Variables
+//! and temporaries live in registers, allocated like a stack, so the
+//! compiled program needs no variables or heap, unless `Compiled::fit`
+//! squeezes it into fewer registers. `//` starts a comment.
+
+pub mod ast;
+mod codegen;
+mod lexer;
+mod parser;
+
+use crate::HashMap;
+use crate::prelude::*;
+use crate::program::Program;
+use crate::regalloc::{self, AllocError, Home};
+use core::error::Error;
+use core::fmt;
+
+#[derive(Debug, PartialEq)]
+pub struct LangError {
+    /// Line of the source the error is on, from 1
+    pub line: usize,
+    pub kind: LangErrorKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LangErrorKind {
+    UnexpectedChar(char),
+    UnexpectedToken {
+        expected: String,
+        found: String,
+    },
+    /// A name used or assigned before any `let` declares it
+    UndefinedVariable(String),
+}
+
+impl fmt::Display for LangError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Line {}: ", self.line)?;
+        match &self.kind {
+            LangErrorKind::UnexpectedChar(c) => write!(f, "Unexpected character '{}'", c),
+            LangErrorKind::UnexpectedToken { expected, found } => {
+                write!(f, "Expected {}, found {}", expected, found)
+            }
+            LangErrorKind::UndefinedVariable(name) => {
+                write!(f, "Variable '{}' is not declared", name)
+            }
+        }
+    }
+}
+
+impl Error for LangError {}
+
+/// A compiled script and what it takes to run it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    pub program: Program,
+    /// Registers the program uses, to create its VM with
+    pub registers: usize,
+    /// The register each top-level variable is left in when the program halts
+    pub variables: HashMap<String, usize>,
+    /// Top-level variables `fit` spilled, and the VM variable each is left in
+    pub spilled: HashMap<String, String>,
+}
+
+impl Compiled {
+    /// The same script renumbered to use at most `registers` registers,
+    /// spilling values to VM variables if it has to
+    pub fn fit(&self, registers: usize) -> Result<Compiled, AllocError> {
+        let exit: Vec<usize> = self.variables.values().copied().collect();
+        let allocation = regalloc::allocate(&self.program, registers, &exit)?;
+        let mut variables = HashMap::new();
+        let mut spilled = self.spilled.clone();
+        for (name, &reg) in &self.variables {
+            match &allocation.homes[reg] {
+                Some(Home::Register(reg)) => {
+                    variables.insert(name.clone(), *reg);
+                }
+                Some(Home::Variable(var)) => {
+                    spilled.insert(name.clone(), var.clone());
+                }
+                None => {}
+            }
+        }
+        Ok(Compiled {
+            program: allocation.program,
+            registers: allocation.registers.max(1),
+            variables,
+            spilled,
+        })
+    }
+}
+
+/// Parse `source` into statements
+pub fn parse(source: &str) -> Result<Vec<ast::Stmt>, LangError> {
+    let tokens = lexer::tokenize(source)?;
+    parser::Parser::new(tokens).program()
+}
+
+/// Parse and compile `source`
+pub fn compile(source: &str) -> Result<Compiled, LangError> {
+    codegen::compile(&parse(source)?)
+}
@@ -0,0 +1,217 @@
+use super::ast::{BinOp, Expr, Stmt, UnOp};
+use super::lexer::Token;
+use super::{LangError, LangErrorKind};
+use crate::prelude::*;
+
+/// Recursive descent over the token list, one method per grammar rule
+pub(super) struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    /// `tokens` must end with `Token::End`
+    pub(super) fn new(tokens: Vec<(Token, usize)>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    /// program := stmt* End
+    pub(super) fn program(&mut self) -> Result<Vec<Stmt>, LangError> {
+        let mut stmts = Vec::new();
+        while *self.peek() != Token::End {
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error(&self, expected: &str) -> LangError {
+        LangError {
+            line: self.line(),
+            kind: LangErrorKind::UnexpectedToken {
+                expected: expected.to_string(),
+                found: self.peek().to_string(),
+            },
+        }
+    }
+
+    /// Consume `symbol` if it is next
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Token::Symbol(s) if *s == symbol);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), LangError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}'", symbol)))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, LangError> {
+        match self.peek() {
+            Token::Ident(name) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            _ => Err(self.error("a name")),
+        }
+    }
+
+    /// stmt := let | assign | print | if | while
+    fn stmt(&mut self) -> Result<Stmt, LangError> {
+        let line = self.line();
+        match self.peek() {
+            Token::Let => {
+                self.advance();
+                let name = self.ident()?;
+                self.expect("=")?;
+                let value = self.expr()?;
+                self.expect(";")?;
+                Ok(Stmt::Let { name, value })
+            }
+            Token::Ident(_) => {
+                let name = self.ident()?;
+                self.expect("=")?;
+                let value = self.expr()?;
+                self.expect(";")?;
+                Ok(Stmt::Assign { name, value, line })
+            }
+            Token::Print => {
+                self.advance();
+                let value = self.expr()?;
+                self.expect(";")?;
+                Ok(Stmt::Print(value))
+            }
+            Token::If => {
+                self.advance();
+                let cond = self.expr()?;
+                let then = self.block()?;
+                let otherwise = if *self.peek() == Token::Else {
+                    self.advance();
+                    if *self.peek() == Token::If {
+                        vec![self.stmt()?]
+                    } else {
+                        self.block()?
+                    }
+                } else {
+                    Vec::new()
+                };
+                Ok(Stmt::If {
+                    cond,
+                    then,
+                    otherwise,
+                })
+            }
+            Token::While => {
+                self.advance();
+                let cond = self.expr()?;
+                let body = self.block()?;
+                Ok(Stmt::While { cond, body })
+            }
+            _ => Err(self.error("a statement")),
+        }
+    }
+
+    /// block := '{' stmt* '}'
+    fn block(&mut self) -> Result<Vec<Stmt>, LangError> {
+        self.expect("{")?;
+        let mut stmts = Vec::new();
+        while !self.eat("}") {
+            if *self.peek() == Token::End {
+                return Err(self.error("'}'"));
+            }
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    /// expr := sum (('==' | '!=' | '<' | '<=' | '>' | '>=') sum)*
+    fn expr(&mut self) -> Result<Expr, LangError> {
+        let ops = [
+            ("==", BinOp::Equal),
+            ("!=", BinOp::NotEqual),
+            ("<=", BinOp::LessEqual),
+            (">=", BinOp::GreaterEqual),
+            ("<", BinOp::Less),
+            (">", BinOp::Greater),
+        ];
+        self.binary(&ops, Self::sum)
+    }
+
+    /// sum := term (('+' | '-') term)*
+    fn sum(&mut self) -> Result<Expr, LangError> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::term)
+    }
+
+    /// term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Expr, LangError> {
+        self.binary(&[("*", BinOp::Mul), ("/", BinOp::Div)], Self::unary)
+    }
+
+    /// Left-associative operators from `ops` between operands parsed by `operand`
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        operand: fn(&mut Self) -> Result<Expr, LangError>,
+    ) -> Result<Expr, LangError> {
+        let mut lhs = operand(self)?;
+        while let Some(&(_, op)) = ops.iter().find(|(symbol, _)| self.eat(symbol)) {
+            let rhs = operand(self)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// unary := ('-' | '!') unary | primary
+    fn unary(&mut self) -> Result<Expr, LangError> {
+        if self.eat("-") {
+            Ok(Expr::Unary(UnOp::Neg, Box::new(self.unary()?)))
+        } else if self.eat("!") {
+            Ok(Expr::Unary(UnOp::Not, Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    /// primary := number | name | '(' expr ')'
+    fn primary(&mut self) -> Result<Expr, LangError> {
+        let line = self.line();
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.advance();
+                Ok(Expr::Number(n))
+            }
+            Token::Ident(name) => {
+                self.advance();
+                Ok(Expr::Var { name, line })
+            }
+            _ if self.eat("(") => {
+                let e = self.expr()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            _ => Err(self.error("an expression")),
+        }
+    }
+}
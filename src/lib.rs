@@ -1,2 +1,73 @@
+//! A register-based virtual machine.
+//!
+//! With the default `std` feature off, the crate is `no_std` and needs only
+//! `alloc`: programs, bytecode, the passes and the VM itself still build, so
+//! bytecode can run on targets without an operating system. `print` then
+//! goes to the sink set with `VM::set_output`, and the stdlib host
+//! functions, timeouts, core dump files and threads are unavailable.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+use hashbrown::{HashMap, HashSet};
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet};
+
+pub mod aot;
+pub mod asm_reg;
+#[cfg(feature = "wasm-backend")]
+pub mod backend;
+#[cfg(feature = "rayon")]
+pub mod batch;
+pub mod bytecode;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod cfg;
+pub mod conformance;
+#[cfg(feature = "std")]
+mod coredump;
+pub mod coroutine;
+pub mod coverage;
+pub mod decompile;
+pub mod diagnostics;
+mod dispatch;
+mod dot;
+pub mod equiv;
+pub mod heap;
+pub mod history;
+pub mod hook;
+pub mod host;
 pub mod instruction;
+pub mod isolate;
+#[cfg(feature = "jit")]
+pub mod jit;
+mod json;
+pub mod lang;
+pub mod link;
+pub mod machine;
+pub mod passes;
+mod prelude;
+#[cfg(feature = "std")]
+pub mod profile;
+pub mod program;
+pub mod ranges;
+pub mod regalloc;
+pub mod reload;
+pub mod replay;
+pub mod rng;
+pub mod sandbox;
+pub mod scheduler;
+pub mod stack_vm;
+#[cfg(feature = "std")]
+pub mod stdlib;
+#[cfg(feature = "tracing")]
+mod telemetry;
+pub mod testing;
+pub mod trace;
+pub mod types;
 pub mod vm;
+#[cfg(feature = "wasm-api")]
+pub mod wasm_api;
+pub mod workloads;
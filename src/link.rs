@@ -0,0 +1,172 @@
+//! Combining separately built programs.
+//!
+//! A `Module` is a program whose jumps and calls may refer by name to code
+//! in other modules. `link` lays the modules out one after another and
+//! points every such reference at the label or export it names, so a
+//! library of routines can be built once and shared between programs.
+
+use crate::HashMap;
+use crate::prelude::*;
+use crate::program::{Program, ProgramError};
+use core::error::Error;
+use core::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// Two modules define a label or export with the same name
+    DuplicateSymbol {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    UnresolvedSymbol {
+        module: String,
+        symbol: String,
+    },
+    /// The instruction does not refer to exactly one address
+    NotAReference {
+        module: String,
+        addr: usize,
+    },
+    Program(ProgramError),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateSymbol {
+                symbol,
+                first,
+                second,
+            } => write!(
+                f,
+                "Symbol '{}' is defined in both '{}' and '{}'",
+                symbol, first, second
+            ),
+            LinkError::UnresolvedSymbol { module, symbol } => {
+                write!(
+                    f,
+                    "Module '{}' refers to undefined symbol '{}'",
+                    module, symbol
+                )
+            }
+            LinkError::NotAReference { module, addr } => write!(
+                f,
+                "Instruction {} of module '{}' does not refer to one address",
+                addr, module
+            ),
+            LinkError::Program(e) => write!(f, "Linked program is invalid: {}", e),
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+impl From<ProgramError> for LinkError {
+    fn from(e: ProgramError) -> Self {
+        LinkError::Program(e)
+    }
+}
+
+/// A reference to a symbol, held by the instruction at `addr`
+#[derive(Debug, Clone, PartialEq)]
+struct Import {
+    addr: usize,
+    symbol: String,
+}
+
+/// A program built on its own, with references left for the linker
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub program: Program,
+    imports: Vec<Import>,
+}
+
+impl Module {
+    pub fn new(name: impl Into<String>, program: Program) -> Self {
+        Self {
+            name: name.into(),
+            program,
+            imports: Vec::new(),
+        }
+    }
+
+    /// Make the instruction at `addr`, such as a `call` or `jmp`, refer to
+    /// the label or export `symbol` once linked. Its own target is ignored.
+    pub fn import(&mut self, addr: usize, symbol: impl Into<String>) -> Result<(), LinkError> {
+        match self.program.instructions.get(addr) {
+            Some(instr) if instr.addresses(addr).len() == 1 => {
+                self.imports.push(Import {
+                    addr,
+                    symbol: symbol.into(),
+                });
+                Ok(())
+            }
+            _ => Err(LinkError::NotAReference {
+                module: self.name.clone(),
+                addr,
+            }),
+        }
+    }
+
+    /// Every label and export, with its address in this module
+    fn symbols(&self) -> impl Iterator<Item = (&str, usize)> {
+        let labels = self.program.labels().iter();
+        let exports = self.program.exports().iter();
+        labels
+            .map(|l| (l.name.as_str(), l.addr))
+            .chain(exports.map(|e| (e.name.as_str(), e.addr)))
+    }
+}
+
+/// Concatenate `modules` in order and resolve their imports. The result
+/// starts at the first module's entry point and keeps every label and
+/// export.
+pub fn link(modules: &[Module]) -> Result<Program, LinkError> {
+    let mut symbols: HashMap<&str, (usize, &str)> = HashMap::new();
+    let mut bases = Vec::with_capacity(modules.len());
+    let mut program = Program::default();
+    for module in modules {
+        let base = program.len();
+        for (symbol, addr) in module.symbols() {
+            match symbols.get(symbol) {
+                Some(&(_, first)) if first != module.name => {
+                    return Err(LinkError::DuplicateSymbol {
+                        symbol: symbol.to_string(),
+                        first: first.to_string(),
+                        second: module.name.clone(),
+                    });
+                }
+                _ => {
+                    symbols.insert(symbol, (base + addr, &module.name));
+                }
+            }
+        }
+        // Imported targets are placeholders, and need not be in bounds yet
+        let mut code = module.program.clone();
+        for import in &module.imports {
+            code.instructions[import.addr].relocate(import.addr, import.addr, |_| 0);
+        }
+        program.append(code)?;
+        bases.push(base);
+    }
+
+    for (module, base) in modules.iter().zip(bases) {
+        for import in &module.imports {
+            let Some(&(target, _)) = symbols.get(import.symbol.as_str()) else {
+                return Err(LinkError::UnresolvedSymbol {
+                    module: module.name.clone(),
+                    symbol: import.symbol.clone(),
+                });
+            };
+            let at = base + import.addr;
+            program.instructions[at].relocate(at, at, |_| target);
+        }
+    }
+    if let Some(first) = modules.first() {
+        program.set_entry_addr(first.program.entry())?;
+    }
+    program.verify()?;
+    Ok(program)
+}
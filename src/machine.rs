@@ -0,0 +1,110 @@
+//! What the register `VM` and the `StackVM` have in common, so tools that
+//! drive or inspect a run, such as `History` and hooks, are written once
+//! for both.
+
+use crate::hook::Hook;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::vm::{VM, VmError, VmSnapshot};
+
+/// A virtual machine, as tools see it
+pub trait Machine: Sized {
+    type Instruction;
+    /// What the machine computes with
+    type Value: Copy;
+    /// Its execution state, detached from its program and configuration
+    type Snapshot: Clone;
+
+    fn pc(&self) -> usize;
+    /// Total instructions executed over the lifetime of the machine
+    fn steps(&self) -> u64;
+    fn is_halted(&self) -> bool;
+    /// Execute the single instruction at `pc`
+    fn step(&mut self) -> Result<(), VmError>;
+    /// Run to completion, within the limits the machine is configured with
+    fn run(&mut self) -> Result<(), VmError>;
+
+    /// The instruction at `pc`, if there is one
+    fn instruction(&self, pc: usize) -> Option<&Self::Instruction>;
+    /// The registers of a register machine, or the stack of a stack machine
+    /// with its top last
+    fn values(&self) -> &[Self::Value];
+    fn variable(&self, name: &str) -> Option<Self::Value>;
+    /// Return addresses of the calls in progress, innermost last
+    fn return_addresses(&self) -> Vec<usize>;
+
+    fn snapshot(&self) -> Self::Snapshot;
+    /// Rewind or fast-forward to a previously captured state
+    fn restore(&mut self, snapshot: &Self::Snapshot);
+
+    fn add_hook(&mut self, hook: impl Hook<Self>);
+    /// The first hook of type `H`, to read what it has collected
+    fn hook<H: Hook<Self>>(&self) -> Option<&H>;
+    /// Remove the first hook of type `H` and return it
+    fn take_hook<H: Hook<Self>>(&mut self) -> Option<H>;
+}
+
+impl Machine for VM {
+    type Instruction = Instruction;
+    type Value = f64;
+    type Snapshot = VmSnapshot;
+
+    fn pc(&self) -> usize {
+        self.pc
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn is_halted(&self) -> bool {
+        VM::is_halted(self)
+    }
+
+    fn step(&mut self) -> Result<(), VmError> {
+        VM::step(self)
+    }
+
+    fn run(&mut self) -> Result<(), VmError> {
+        VM::run(self)
+    }
+
+    fn instruction(&self, pc: usize) -> Option<&Instruction> {
+        self.program.instructions.get(pc)
+    }
+
+    fn values(&self) -> &[f64] {
+        &self.registers
+    }
+
+    fn variable(&self, name: &str) -> Option<f64> {
+        self.variables.get(name).copied()
+    }
+
+    fn return_addresses(&self) -> Vec<usize> {
+        self.call_stack
+            .iter()
+            .map(|frame| frame.return_address)
+            .collect()
+    }
+
+    fn snapshot(&self) -> VmSnapshot {
+        VM::snapshot(self)
+    }
+
+    fn restore(&mut self, snapshot: &VmSnapshot) {
+        VM::restore(self, snapshot)
+    }
+
+    fn add_hook(&mut self, hook: impl Hook<Self>) {
+        VM::add_hook(self, hook)
+    }
+
+    fn hook<H: Hook<Self>>(&self) -> Option<&H> {
+        VM::hook(self)
+    }
+
+    fn take_hook<H: Hook<Self>>(&mut self) -> Option<H> {
+        VM::take_hook(self)
+    }
+}
@@ -1,15 +1,151 @@
-use clap::Parser;
-use zyde::{instruction::Instruction, vm::VM};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use zyde::{
+    aot, asm_reg, cfg,
+    coverage::Coverage,
+    decompile,
+    diagnostics::{self, Severity},
+    equiv,
+    instruction::Instruction,
+    passes::{OptLevel, PassManager},
+    profile::{Profiler, Unit},
+    program::Program,
+    trace::Trace,
+    types,
+    vm::{VM, VmError},
+    workloads,
+};
+
+const REGISTERS: usize = 8;
 
 #[derive(Parser)]
 #[command(author, version, about = "Assembles IR code into zyde instructions", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    input: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required = true)]
+    input: Option<String>,
+
+    /// Assemble the input file as register-machine text instead of running
+    /// the built-in demo program
+    #[arg(long)]
+    asm: bool,
+
+    /// How to treat assembler warnings: `allow`, `warn` or `deny` for all
+    /// of them, or `NAME=LEVEL` for one, e.g. `unused-label=allow`. Later
+    /// settings win.
+    #[arg(short = 'W', long = "warn", value_name = "LEVEL", global = true)]
+    warn: Vec<String>,
+
+    /// Fail to assemble on any warning, as `-W deny`
+    #[arg(long, global = true)]
+    deny_warnings: bool,
+
+    /// Optimization level applied before execution (0, 1, 2 or s)
+    #[arg(short = 'O', long, default_value_t = OptLevel::O0, global = true)]
+    opt_level: OptLevel,
+
+    /// Write a JSON trace of every executed instruction to this file
+    #[arg(long, value_name = "PATH")]
+    trace_json: Option<PathBuf>,
+
+    /// Write an lcov report of which instructions the run executed
+    #[arg(long, value_name = "PATH")]
+    coverage: Option<PathBuf>,
+
+    /// Write the time spent per call stack: a speedscope profile if the path
+    /// ends in `.json`, collapsed stacks for flamegraph tools otherwise
+    #[arg(long, value_name = "PATH")]
+    profile: Option<PathBuf>,
+
+    /// Write the optimized program's control-flow graph as Graphviz DOT.
+    /// With `--asm`, instructions show their source line unless the
+    /// optimizer changed the program.
+    #[arg(long, value_name = "PATH")]
+    cfg_dot: Option<PathBuf>,
+
+    /// As `--cfg-dot`, as a Mermaid flowchart
+    #[arg(long, value_name = "PATH")]
+    cfg_mermaid: Option<PathBuf>,
+
+    /// Write the optimized program as a standalone Rust function
+    #[arg(long, value_name = "PATH")]
+    emit_rust: Option<PathBuf>,
+
+    /// Write a disassembly listing of the optimized program
+    #[arg(long, value_name = "PATH")]
+    disasm: Option<PathBuf>,
+
+    /// Print the `--disasm` listing with if/else and while blocks recovered
+    /// from its jumps
+    #[arg(long, requires = "disasm")]
+    structured: bool,
+
+    /// Compile the program to native code before running it
+    #[cfg(feature = "jit")]
+    #[arg(long)]
+    jit: bool,
+
+    /// Run the program as written and optimized at the `-O` level on every
+    /// engine, report the first difference between the runs, and exit
+    #[arg(long)]
+    verify_equiv: bool,
+
+    /// Write a JSON core dump of the VM here if the run fails
+    #[arg(long, value_name = "PATH")]
+    dump_on_trap: Option<PathBuf>,
+
+    /// Checkpoint the VM state every N executed instructions
+    #[arg(long, value_name = "N")]
+    snapshot_every: Option<u64>,
+
+    /// Where checkpoints are written; each one overwrites the last
+    #[arg(long, value_name = "PATH", default_value = "zyde.snapshot.json")]
+    snapshot_file: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Report every problem in an assembly file without running it,
+    /// exiting with 1 if any is an error
+    Check {
+        file: String,
+
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Run the bundled workloads, optimized at the `-O` level, and print how
+    /// many instructions per second each executes
+    Bench,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// `file:line:column: severity[code]: message`, one per line
+    Text,
+    /// A JSON array of objects with file, line, column, end_column,
+    /// severity, code, message and suggestion fields
+    Json,
 }
 
 fn main() {
-    let program = vec![
+    let args = Args::parse();
+
+    match &args.command {
+        Some(Command::Check { file, format }) => check(file, *format, &args),
+        Some(Command::Bench) => {
+            bench(args.opt_level);
+            return;
+        }
+        None => {}
+    }
+    let input = args.input.as_deref().unwrap_or_default();
+
+    let demo = Program::new(vec![
         Instruction::Call { addr: 2 },
         Instruction::Halt, // should not halt here
         Instruction::LoadImm {
@@ -18,13 +154,236 @@ fn main() {
         },
         Instruction::Print { src: 0 },
         Instruction::Halt,
-    ];
+    ]);
+    let (source, lines) = if args.asm {
+        let assembled = fs::read_to_string(input)
+            .map_err(|e| e.to_string())
+            .and_then(|text| asm_reg::assemble_full(&text).map_err(|e| e.to_string()))
+            .and_then(|assembly| {
+                report_warnings(&assembly.warnings, &args)?;
+                let program = assembly.program;
+                program.verify().map_err(|e| e.to_string())?;
+                program.verify_handlers().map_err(|e| e.to_string())?;
+                program.verify_ranges().map_err(|e| e.to_string())?;
+                types::check(&program, &assembly.types).map_err(|e| e.to_string())?;
+                Ok((program, assembly.lines))
+            });
+        match assembled {
+            Ok(assembled) => assembled,
+            Err(e) => {
+                eprintln!("failed to assemble {}: {}", input, e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        (demo, Vec::new())
+    };
+
+    if args.verify_equiv {
+        match equiv::verify(&source, REGISTERS, &PassManager::with_level(args.opt_level)) {
+            Ok(()) => println!("all runs agree"),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut program = source.clone();
+    PassManager::with_level(args.opt_level).run(&mut program);
+    // Source lines only describe the program as assembled
+    let lines = if program == source { lines } else { Vec::new() };
+
+    if let Some(path) = &args.cfg_dot
+        && let Err(e) = fs::write(path, cfg::to_dot_with_lines(&program, None, &lines))
+    {
+        eprintln!("failed to write CFG to {}: {}", path.display(), e);
+    }
+    if let Some(path) = &args.cfg_mermaid
+        && let Err(e) = fs::write(path, cfg::to_mermaid(&program, &lines))
+    {
+        eprintln!("failed to write CFG to {}: {}", path.display(), e);
+    }
 
-    let mut vm = VM::new(program, 8);
-    if let Err(e) = vm.run() {
+    if let Some(path) = &args.disasm {
+        let listing = if args.structured {
+            decompile::to_structured(&program)
+        } else {
+            program.to_string()
+        };
+        if let Err(e) = fs::write(path, listing) {
+            eprintln!("failed to write disassembly to {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(path) = &args.emit_rust {
+        let written = aot::to_rust(&program, REGISTERS, "program")
+            .map_err(|e| e.to_string())
+            .and_then(|source| fs::write(path, source).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            eprintln!("failed to write Rust source to {}: {}", path.display(), e);
+        }
+    }
+
+    let mut vm = VM::new(program, REGISTERS);
+    if args.coverage.is_some() {
+        vm.add_hook(Coverage::new());
+    }
+    if args.profile.is_some() {
+        vm.add_hook(Profiler::new());
+    }
+    let mut trace = args.trace_json.as_ref().map(|_| Trace::new());
+    let result = run(&mut vm, trace.as_mut(), &args);
+
+    if let (Some(path), Some(trace)) = (&args.trace_json, &trace)
+        && let Err(e) = fs::write(path, trace.to_json())
+    {
+        eprintln!("failed to write trace to {}: {}", path.display(), e);
+    }
+    if let (Some(path), Some(coverage)) = (&args.coverage, vm.take_hook::<Coverage>())
+        && let Err(e) = fs::write(path, coverage.to_lcov(&vm.program, input))
+    {
+        eprintln!("failed to write coverage to {}: {}", path.display(), e);
+    }
+    if let (Some(path), Some(profiler)) = (&args.profile, vm.take_hook::<Profiler>()) {
+        let profile = if path.extension().is_some_and(|ext| ext == "json") {
+            profiler.to_speedscope(input, Unit::Nanoseconds)
+        } else {
+            profiler.to_collapsed(Unit::Nanoseconds)
+        };
+        if let Err(e) = fs::write(path, profile) {
+            eprintln!("failed to write profile to {}: {}", path.display(), e);
+        }
+    }
+    if let (Some(path), Err(e)) = (&args.dump_on_trap, &result)
+        && let Err(e) = fs::write(path, vm.core_dump(e))
+    {
+        eprintln!("failed to write core dump to {}: {}", path.display(), e);
+    }
+    if let Err(e) = result {
         eprintln!("VM error: {}", e);
+        eprint!("{}", vm.backtrace());
     }
 
     #[cfg(debug_assertions)]
-    println!("{}", vm.visualize_callstack());
+    print!("{}", vm.visualize());
+}
+
+/// The warning levels `args` sets
+fn lints(args: &Args) -> Result<asm_reg::Lints, String> {
+    let mut lints = asm_reg::Lints::new();
+    if args.deny_warnings {
+        lints.apply("deny")?;
+    }
+    for spec in &args.warn {
+        lints.apply(spec)?;
+    }
+    Ok(lints)
+}
+
+/// Print the warnings `args` does not allow, failing if it denies any
+fn report_warnings(warnings: &[asm_reg::Warning], args: &Args) -> Result<(), String> {
+    let lints = lints(args)?;
+    let mut denied = 0;
+    for warning in warnings {
+        match lints.level(&warning.kind) {
+            asm_reg::Level::Allow => {}
+            asm_reg::Level::Warn => eprintln!("warning: {}", warning),
+            asm_reg::Level::Deny => {
+                eprintln!("error: {}", warning);
+                denied += 1;
+            }
+        }
+    }
+    match denied {
+        0 => Ok(()),
+        n => Err(format!("{} denied warning(s)", n)),
+    }
+}
+
+/// Print the diagnostics for `file` and exit, with 1 if any is an error
+fn check(file: &str, format: Format, args: &Args) -> ! {
+    let found = fs::read_to_string(file)
+        .map_err(|e| e.to_string())
+        .and_then(|source| Ok(diagnostics::check(&source, &lints(args)?)));
+    let found = match found {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("failed to check {}: {}", file, e);
+            std::process::exit(2);
+        }
+    };
+    match format {
+        Format::Text => {
+            for diagnostic in &found {
+                println!("{}:{}", file, diagnostic);
+            }
+        }
+        Format::Json => println!("{}", diagnostics::to_json(&found, file)),
+    }
+    let failed = found.iter().any(|d| d.severity == Severity::Error);
+    std::process::exit(i32::from(failed));
+}
+
+/// How long each workload is run for
+const BENCH_TIME: Duration = Duration::from_millis(500);
+
+fn bench(level: OptLevel) {
+    let passes = PassManager::with_level(level);
+    let suite = workloads::standard()
+        .into_iter()
+        .chain(workloads::allocating());
+    for (name, mut program) in suite {
+        passes.run(&mut program);
+        let (mut runs, mut steps) = (0, 0);
+        let started = Instant::now();
+        while runs == 0 || started.elapsed() < BENCH_TIME {
+            let mut vm = VM::new(program.clone(), workloads::REGISTERS);
+            if let Err(e) = vm.run() {
+                eprintln!("{}: VM error: {}", name, e);
+                break;
+            }
+            runs += 1;
+            steps += vm.steps;
+        }
+        let rate = steps as f64 / started.elapsed().as_secs_f64();
+        println!(
+            "{:<14} {:>8} runs {:>14} instructions {:>10.1} M/s",
+            name,
+            runs,
+            steps,
+            rate / 1e6
+        );
+    }
+}
+
+fn run(vm: &mut VM, mut trace: Option<&mut Trace>, args: &Args) -> Result<(), VmError> {
+    if trace.is_none() && args.snapshot_every.is_none() {
+        #[cfg(feature = "jit")]
+        if args.jit {
+            return vm.run_jit();
+        }
+        return vm.run();
+    }
+
+    while !vm.is_halted() {
+        match trace.as_deref_mut() {
+            Some(trace) => trace.step(vm)?,
+            None => vm.step()?,
+        }
+
+        if let Some(every) = args.snapshot_every
+            && every > 0
+            && vm.steps.is_multiple_of(every)
+            && let Err(e) = fs::write(&args.snapshot_file, vm.snapshot().to_json())
+        {
+            eprintln!(
+                "failed to write snapshot to {}: {}",
+                args.snapshot_file.display(),
+                e
+            );
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,70 @@
+use super::Pass;
+use super::dce::DeadCodeElim;
+use crate::cfg::Cfg;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+/// Shortest shared tail worth replacing with a jump
+const MIN_TAIL: usize = 2;
+
+/// Shrinks code by merging identical block tails.
+///
+/// When a block ends with the same instructions (and terminator) as an earlier
+/// block, its copy of that tail is replaced by a jump into the earlier one.
+/// Whole duplicated blocks are the special case where the tail is the entire
+/// block. The leftover instructions are then removed as dead code.
+pub struct CodeCompaction;
+
+impl Pass for CodeCompaction {
+    fn name(&self) -> &'static str {
+        "compact"
+    }
+
+    fn run(&self, program: &mut Program) {
+        if !program.is_relocatable() {
+            return;
+        }
+        // Equal relative jumps in different places go to different code
+        program.make_absolute();
+        let cfg = Cfg::build(&program.instructions);
+        let mut canonical: Vec<(usize, usize)> = Vec::new();
+        let mut changed = false;
+
+        for block in &cfg.blocks {
+            let code = &program.instructions[block.start..block.end];
+            if !code.last().is_some_and(Instruction::is_terminator) {
+                continue;
+            }
+
+            let best = canonical
+                .iter()
+                .map(|&(start, end)| {
+                    let shared = common_suffix(&program.instructions[start..end], code);
+                    (end - shared, shared)
+                })
+                .max_by_key(|&(_, shared)| shared);
+
+            match best {
+                Some((tail_start, shared)) if shared >= MIN_TAIL => {
+                    let at = block.end - shared;
+                    program.instructions[at] = Instruction::Jump(tail_start);
+                    changed = true;
+                }
+                _ => canonical.push((block.start, block.end)),
+            }
+        }
+
+        if changed {
+            DeadCodeElim.run(program);
+        }
+    }
+}
+
+fn common_suffix(a: &[Instruction], b: &[Instruction]) -> usize {
+    a.iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+}
@@ -0,0 +1,159 @@
+use super::Pass;
+use crate::HashMap;
+use crate::cfg::block_leaders;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+use crate::vm;
+
+/// Evaluates arithmetic and comparisons whose operands are known constants and
+/// propagates stored variable values, all within a single basic block.
+///
+/// Folded instructions are rewritten in place, so instruction addresses never move.
+pub struct ConstFold;
+
+impl Pass for ConstFold {
+    fn name(&self) -> &'static str {
+        "const-fold"
+    }
+
+    fn run(&self, program: &mut Program) {
+        let mut leaders = block_leaders(&program.instructions);
+        // Execution can also begin at an entry point, knowing nothing
+        for addr in program.entry_points() {
+            if let Some(leader) = leaders.get_mut(addr) {
+                *leader = true;
+            }
+        }
+        let mut regs: HashMap<usize, f64> = HashMap::new();
+        let mut vars: HashMap<String, f64> = HashMap::new();
+
+        for (pc, instr) in program.instructions.iter_mut().enumerate() {
+            if leaders[pc] {
+                regs.clear();
+                vars.clear();
+            }
+            fold_instruction(instr, &mut regs, &mut vars);
+        }
+    }
+}
+
+fn fold_instruction(
+    instr: &mut Instruction,
+    regs: &mut HashMap<usize, f64>,
+    vars: &mut HashMap<String, f64>,
+) {
+    use Instruction::*;
+
+    let folded = match instr {
+        LoadImm { dest, value } => {
+            regs.insert(*dest, *value);
+            return;
+        }
+        Add { dest, src1, src2 } => binary(regs, *dest, *src1, *src2, |a, b| a + b),
+        Sub { dest, src1, src2 } => binary(regs, *dest, *src1, *src2, |a, b| a - b),
+        Mul { dest, src1, src2 } => binary(regs, *dest, *src1, *src2, |a, b| a * b),
+        Div { dest, src1, src2 } => binary(regs, *dest, *src1, *src2, |a, b| a / b),
+        Equal { dest, src1, src2 } => binary(regs, *dest, *src1, *src2, |a, b| bool_val(a == b)),
+        LessThan { dest, src1, src2 } => binary(regs, *dest, *src1, *src2, |a, b| bool_val(a < b)),
+        GreaterThan { dest, src1, src2 } => {
+            binary(regs, *dest, *src1, *src2, |a, b| bool_val(a > b))
+        }
+        Mov { dest, src } => regs.get(src).map(|&v| (*dest, v)),
+        Not { dest, src } => regs.get(src).map(|&v| (*dest, bool_val(v == 0.0))),
+        Load { dest, var } => vars.get(var).map(|&v| (*dest, v)),
+        Store { src, var } => {
+            match regs.get(src) {
+                Some(&v) => vars.insert(var.clone(), v),
+                None => vars.remove(var),
+            };
+            return;
+        }
+        ConditionalJump { cond, target } => {
+            if regs.get(cond) == Some(&0.0) {
+                *instr = Jump(*target);
+            }
+            return;
+        }
+        AddImm {
+            dest,
+            src,
+            imm,
+            value,
+        } => {
+            regs.insert(*imm, *value);
+            match regs.get(src) {
+                Some(&v) => regs.insert(*dest, v + *value),
+                None => regs.remove(dest),
+            };
+            return;
+        }
+        CompareJump {
+            cmp,
+            dest,
+            src1,
+            src2,
+            ..
+        } => {
+            let cmp = *cmp;
+            match binary(regs, *dest, *src1, *src2, |a, b| bool_val(cmp.eval(a, b))) {
+                Some((dest, v)) => regs.insert(dest, v),
+                None => regs.remove(dest),
+            };
+            return;
+        }
+        Print { .. } | Jump(_) | Call { .. } | Return | Halt => return,
+        JumpRel(_) | CallRel(_) => return,
+        TryBegin { .. } | TryEnd | Throw { .. } | JumpIndirect { .. } => return,
+        CallIndirect { .. } | CallClosure { .. } | SetUpvalue { .. } | SetField { .. } => return,
+        MapSet { .. } | MapDelete { .. } | Yield | Resume { .. } | CallHost { .. } => return,
+        Send { .. } | Push { .. } | CallArgs { .. } => return,
+        MakeClosure { .. } | GetUpvalue { .. } | NewRecord { .. } | GetField { .. } => None,
+        MapNew { .. } | MapGet { .. } | MapHas { .. } | Spawn { .. } | Recv { .. } => None,
+        Rand { .. } | Pop { .. } | Arity { .. } => None,
+        // Folding an address into a loadimm would stop it being relocated
+        LoadAddr { dest, .. } => {
+            regs.remove(dest);
+            return;
+        }
+        Switch {
+            src,
+            table,
+            default,
+        } => {
+            if let Some(&v) = regs.get(src) {
+                let target = vm::address(v).and_then(|i| table.get(i)).unwrap_or(default);
+                *instr = Jump(*target);
+            }
+            return;
+        }
+    };
+
+    match folded {
+        Some((dest, value)) => {
+            regs.insert(dest, value);
+            *instr = LoadImm { dest, value };
+        }
+        None => {
+            for reg in instr.writes() {
+                regs.remove(&reg);
+            }
+        }
+    }
+}
+
+fn binary(
+    regs: &HashMap<usize, f64>,
+    dest: usize,
+    src1: usize,
+    src2: usize,
+    op: impl Fn(f64, f64) -> f64,
+) -> Option<(usize, f64)> {
+    let a = *regs.get(&src1)?;
+    let b = *regs.get(&src2)?;
+    Some((dest, op(a, b)))
+}
+
+fn bool_val(b: bool) -> f64 {
+    if b { 1.0 } else { 0.0 }
+}
@@ -0,0 +1,70 @@
+use super::Pass;
+use crate::cfg::Cfg;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+/// Removes basic blocks that cannot be reached from instruction 0 or an export, along with
+/// jumps to the instruction that would run next anyway, and rewrites jump and
+/// call targets to the compacted addresses.
+pub struct DeadCodeElim;
+
+impl Pass for DeadCodeElim {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&self, program: &mut Program) {
+        // Removing code shifts addresses that computed jumps hold in registers
+        if !program.is_relocatable() {
+            return;
+        }
+        program.make_absolute();
+        let cfg = Cfg::build(&program.instructions);
+        let reachable = cfg.reachable_from(&program.entry_points());
+
+        let old_len = program.len();
+        let mut keep = vec![false; old_len];
+        for (block, _) in cfg.blocks.iter().zip(&reachable).filter(|(_, r)| **r) {
+            keep[block.start..block.end].fill(true);
+        }
+
+        // next_live[i] is the first kept instruction at or after i
+        let mut next_live = vec![old_len; old_len + 1];
+        for pc in (0..old_len).rev() {
+            if let Instruction::Jump(target) = program.instructions[pc]
+                && keep[pc]
+                && target > pc
+                && target < old_len
+                && next_live[target] == next_live[pc + 1]
+            {
+                keep[pc] = false;
+            }
+            next_live[pc] = if keep[pc] { pc } else { next_live[pc + 1] };
+        }
+        if keep.iter().all(|&k| k) {
+            return;
+        }
+
+        // new_addr[i] is the address instruction i moves to (or the next kept one)
+        let mut new_addr = Vec::with_capacity(old_len);
+        let mut next = 0;
+        for &k in &keep {
+            new_addr.push(next);
+            if k {
+                next += 1;
+            }
+        }
+        let removed = old_len - next;
+
+        let mut kept = keep.iter();
+        program.instructions.retain(|_| *kept.next().unwrap());
+        // Out-of-bounds targets stay out of bounds so they still trap
+        program.relocate(|addr| {
+            new_addr
+                .get(addr)
+                .copied()
+                .unwrap_or_else(|| addr - removed)
+        });
+    }
+}
@@ -0,0 +1,69 @@
+use super::Pass;
+use super::peephole::{Peephole, Rule};
+use crate::instruction::{Comparison, Instruction};
+use crate::prelude::*;
+use crate::program::Program;
+
+/// Fuses adjacent instruction pairs into superinstructions that do the work of
+/// both in one dispatch: an immediate load feeding an add becomes `AddImm`, and
+/// a comparison feeding a conditional jump becomes `CompareJump`.
+///
+/// Fused instructions still perform every register write of the pair, so no
+/// liveness information is needed. They have no bytecode form, so run this
+/// last, on programs that are about to execute.
+pub struct Fusion;
+
+impl Pass for Fusion {
+    fn name(&self) -> &'static str {
+        "fuse"
+    }
+
+    fn run(&self, program: &mut Program) {
+        Peephole::empty()
+            .with_rule(Rule::new("add-imm", 2, fuse_add_imm))
+            .with_rule(Rule::new("compare-jump", 2, fuse_compare_jump))
+            .run(program);
+    }
+}
+
+fn fuse_add_imm(window: &[Instruction]) -> Option<Vec<Instruction>> {
+    let (&Instruction::LoadImm { dest: imm, value }, &Instruction::Add { dest, src1, src2 }) =
+        (&window[0], &window[1])
+    else {
+        return None;
+    };
+    let src = match (src1 == imm, src2 == imm) {
+        (_, true) => src1,
+        (true, false) => src2,
+        (false, false) => return None,
+    };
+    Some(vec![Instruction::AddImm {
+        dest,
+        src,
+        imm,
+        value,
+    }])
+}
+
+fn fuse_compare_jump(window: &[Instruction]) -> Option<Vec<Instruction>> {
+    let (cmp, dest, src1, src2) = match window[0] {
+        Instruction::Equal { dest, src1, src2 } => (Comparison::Equal, dest, src1, src2),
+        Instruction::LessThan { dest, src1, src2 } => (Comparison::LessThan, dest, src1, src2),
+        Instruction::GreaterThan { dest, src1, src2 } => {
+            (Comparison::GreaterThan, dest, src1, src2)
+        }
+        _ => return None,
+    };
+    match window[1] {
+        Instruction::ConditionalJump { cond, target } if cond == dest => {
+            Some(vec![Instruction::CompareJump {
+                cmp,
+                dest,
+                src1,
+                src2,
+                target,
+            }])
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,197 @@
+use super::Pass;
+use crate::HashMap;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+/// Replaces calls to small functions with a copy of their body, each `ret`
+/// becoming a jump back to the instruction after the call.
+///
+/// Functions share the caller's registers, so nothing is renamed but the
+/// addresses. A function is inlined when the code reachable from its first
+/// instruction is a run of at most `threshold` instructions that cannot
+/// stop the program, since it would stop one frame shallower, and make no
+/// calls. Inlining bottom-up under `OptLevel::O2` turns callers of such
+/// leaves into leaves too. Labels inside a copy get a `.n` suffix.
+pub struct Inline {
+    pub threshold: usize,
+}
+
+impl Inline {
+    pub fn new(threshold: usize) -> Self {
+        Self { threshold }
+    }
+
+    /// Run the pass, returning the address in the original program each
+    /// instruction came from, to carry a source map such as
+    /// `asm_reg::assemble_with_lines` gives across
+    pub fn run_with_origins(&self, program: &mut Program) -> Vec<usize> {
+        let unchanged = (0..program.len()).collect();
+        // Copied code would not be where addresses held in registers point
+        if !program.is_relocatable() {
+            return unchanged;
+        }
+        program.make_absolute();
+        let code = &program.instructions;
+        let len = code.len();
+        let mut bodies: HashMap<usize, Option<usize>> = HashMap::new();
+        let mut inlined = vec![None; len];
+        for (pc, instr) in code.iter().enumerate() {
+            if let Instruction::Call { addr } = *instr
+                && let Some(end) = *bodies
+                    .entry(addr)
+                    .or_insert_with(|| self.body(code, addr))
+                && !(addr..end).contains(&pc)
+                // A `ret` there ends the program, which a jump cannot do
+                && pc + 1 < len
+            {
+                inlined[pc] = Some((addr, end));
+            }
+        }
+        if inlined.iter().all(Option::is_none) {
+            return unchanged;
+        }
+
+        // Where each original instruction, or the body replacing it, starts
+        let mut new_addr = Vec::with_capacity(len);
+        let mut at = 0;
+        for body in &inlined {
+            new_addr.push(at);
+            at += body.map_or(1, |(start, end)| end - start);
+        }
+        let new_len = at;
+        let map = |addr: usize| {
+            new_addr
+                .get(addr)
+                .copied()
+                .unwrap_or_else(|| (addr - len).saturating_add(new_len))
+        };
+
+        let original = program.clone();
+        program.relocate(map);
+        let mut code = Vec::with_capacity(new_len);
+        let mut origins = Vec::with_capacity(new_len);
+        let mut copies: HashMap<&str, usize> = HashMap::new();
+        let mut labels = Vec::new();
+        for (pc, body) in inlined.iter().enumerate() {
+            let Some((start, end)) = *body else {
+                code.push(program.instructions[pc].clone());
+                origins.push(pc);
+                continue;
+            };
+            let base = new_addr[pc];
+            let back = map(pc + 1);
+            for from in start..end {
+                let mut instr = original.instructions[from].clone();
+                if instr == Instruction::Return {
+                    instr = Instruction::Jump(back);
+                } else {
+                    instr.relocate(from, base + from - start, |t| {
+                        if (start..end).contains(&t) {
+                            base + t - start
+                        } else {
+                            map(t)
+                        }
+                    });
+                }
+                code.push(instr);
+                origins.push(from);
+            }
+            for label in original
+                .labels()
+                .iter()
+                .filter(|l| (start..end).contains(&l.addr))
+            {
+                let n = copies.entry(&label.name).or_insert(0);
+                *n += 1;
+                labels.push((format!("{}.{}", label.name, n), base + label.addr - start));
+            }
+        }
+        program.instructions = code;
+        for (name, addr) in labels {
+            // A name already taken by the program keeps its meaning
+            let _ = program.label(name, addr);
+        }
+        origins
+    }
+
+    /// The end of the function at `start`, if it can be inlined
+    fn body(&self, code: &[Instruction], start: usize) -> Option<usize> {
+        let mut seen = vec![false; code.len()];
+        let mut stack = vec![start];
+        let mut end = start;
+        let mut count = 0;
+        let mut addresses = Vec::new();
+        while let Some(pc) = stack.pop() {
+            let instr = code.get(pc).filter(|_| pc >= start)?;
+            if seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+            count += 1;
+            end = end.max(pc + 1);
+            if count > self.threshold || end - start > self.threshold {
+                return None;
+            }
+            if !cannot_fail(instr) {
+                return None;
+            }
+            if let Instruction::LoadAddr { addr, .. } = instr {
+                addresses.push(*addr);
+            }
+            stack.extend(instr.targets(pc));
+            if !instr.is_terminator() {
+                stack.push(pc + 1);
+            }
+        }
+        // Only a run with no gaps is copied whole, and a copy would take its
+        // own address rather than the function's
+        let whole = seen[start..end].iter().all(|&s| s);
+        let own = addresses.iter().any(|a| (start..end).contains(a));
+        (whole && !own).then_some(end)
+    }
+}
+
+/// Whether `instr` always runs to completion without calling anything
+pub(super) fn cannot_fail(instr: &Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        instr,
+        LoadImm { .. }
+            | Add { .. }
+            | Sub { .. }
+            | Mul { .. }
+            | Div { .. }
+            | Print { .. }
+            | Jump(_)
+            | ConditionalJump { .. }
+            | Return
+            | Store { .. }
+            | Mov { .. }
+            | Equal { .. }
+            | LessThan { .. }
+            | GreaterThan { .. }
+            | Not { .. }
+            | AddImm { .. }
+            | CompareJump { .. }
+            | Switch { .. }
+            | LoadAddr { .. }
+            | Rand { .. }
+    )
+}
+
+impl Default for Inline {
+    fn default() -> Self {
+        Self::new(8)
+    }
+}
+
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, program: &mut Program) {
+        self.run_with_origins(program);
+    }
+}
@@ -0,0 +1,259 @@
+use super::Pass;
+use crate::cfg::{BasicBlock, Cfg};
+use crate::coverage::Coverage;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+/// Orders basic blocks so each one's likeliest successor comes right after
+/// it, dropping the `jmp`s that now reach the next block.
+///
+/// Blocks that fall through keep their successor next; calls return there.
+/// A `jz` testing either side of the `not` just before it is inverted to
+/// test the other side when its target is laid out next, which is exact
+/// for every value. Without a profile, edges that stay inside a loop are
+/// taken to be the hot ones. The new order is only used when it takes
+/// fewer or colder jumps than the old one.
+pub struct BlockLayout {
+    profile: Option<(Program, Vec<u64>)>,
+}
+
+impl BlockLayout {
+    pub fn new() -> Self {
+        Self { profile: None }
+    }
+
+    /// Weigh each jump by how often `coverage` saw it run in `program`. The
+    /// counts only apply to `program` as it is, so this runs before passes
+    /// that change it, and falls back to guessing afterwards.
+    pub fn profiled(program: &Program, coverage: &Coverage) -> Self {
+        let mut program = program.clone();
+        program.make_absolute();
+        let hits = (0..program.len()).map(|addr| coverage.hits(addr)).collect();
+        Self {
+            profile: Some((program, hits)),
+        }
+    }
+}
+
+impl Default for BlockLayout {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pass for BlockLayout {
+    fn name(&self) -> &'static str {
+        "layout"
+    }
+
+    fn run(&self, program: &mut Program) {
+        // Moved code would not be where addresses held in registers point
+        if !program.is_relocatable() {
+            return;
+        }
+        program.make_absolute();
+        let hits = self
+            .profile
+            .as_ref()
+            .filter(|(profiled, _)| profiled == program)
+            .map(|(_, hits)| hits.as_slice());
+        if let Some(laid_out) = layout(program, hits) {
+            *program = laid_out;
+        }
+    }
+}
+
+/// How control leaves a block, by block index
+#[derive(Clone, Copy)]
+enum Exit {
+    /// Always on to the next block in address order, or off the end
+    Falls,
+    Jump(usize),
+    /// An invertible `jz`: to `taken`, or on to the next block, testing
+    /// `other` instead to swap the two
+    Branch {
+        taken: usize,
+        other: usize,
+    },
+    /// Anywhere else, whatever the order
+    Stops,
+}
+
+fn exit(code: &[Instruction], blocks: &[BasicBlock], i: usize) -> Exit {
+    let block = &blocks[i];
+    let at = block.end - 1;
+    let block_at = |addr: usize| blocks.binary_search_by_key(&addr, |b| b.start).ok();
+    match code[at] {
+        Instruction::Jump(target) => block_at(target).map_or(Exit::Stops, Exit::Jump),
+        Instruction::ConditionalJump { cond, target } if at > block.start => {
+            let other = match code[at - 1] {
+                Instruction::Not { dest, src } if dest != src && cond == dest => Some(src),
+                Instruction::Not { dest, src } if dest != src && cond == src => Some(dest),
+                _ => None,
+            };
+            match (other, block_at(target)) {
+                (Some(other), Some(taken)) if taken != i + 1 && i + 1 < blocks.len() => {
+                    Exit::Branch { taken, other }
+                }
+                _ => Exit::Falls,
+            }
+        }
+        ref last if last.is_terminator() => Exit::Stops,
+        _ => Exit::Falls,
+    }
+}
+
+/// `program` with its blocks reordered, if that saves anything
+fn layout(program: &Program, hits: Option<&[u64]>) -> Option<Program> {
+    let code = &program.instructions;
+    let cfg = Cfg::build(code);
+    let blocks = &cfg.blocks;
+    let n = blocks.len();
+    if n < 2 {
+        return None;
+    }
+    let exits: Vec<Exit> = (0..n).map(|i| exit(code, blocks, i)).collect();
+    // Which blocks each block can reach, to tell edges that stay in a loop
+    let reaches: Vec<Vec<bool>> = match hits {
+        Some(_) => Vec::new(),
+        None => blocks
+            .iter()
+            .map(|b| cfg.reachable_from(&[b.start]))
+            .collect(),
+    };
+    let weight = |from: usize, to: usize| match hits {
+        Some(hits) => hits[blocks[from].end - 1].min(hits[blocks[to].start]),
+        None if reaches[to][from] => 2,
+        None => 1,
+    };
+
+    // Join blocks into chains along the heaviest edges first
+    let mut edges = Vec::new();
+    for (i, exit) in exits.iter().enumerate() {
+        match *exit {
+            Exit::Falls if i + 1 < n => edges.push((u64::MAX, i, i + 1)),
+            Exit::Jump(to) => edges.push((weight(i, to), i, to)),
+            Exit::Branch { taken, .. } => {
+                edges.push((weight(i, i + 1), i, i + 1));
+                edges.push((weight(i, taken), i, taken));
+            }
+            _ => {}
+        }
+    }
+    edges.sort_by_key(|&(w, ..)| core::cmp::Reverse(w));
+    let mut next = vec![None; n];
+    let mut prev = vec![None; n];
+    for (_, from, to) in edges {
+        if next[from].is_some() || prev[to].is_some() {
+            continue;
+        }
+        let mut head = from;
+        while let Some(p) = prev[head] {
+            head = p;
+        }
+        if head != to {
+            next[from] = Some(to);
+            prev[to] = Some(from);
+        }
+    }
+
+    // Chains keep their original order, except that one falling off the
+    // end of the program must stay last
+    let mut order = Vec::with_capacity(n);
+    let mut last_chain = Vec::new();
+    for head in (0..n).filter(|&b| prev[b].is_none()) {
+        let mut chain = vec![head];
+        while let Some(b) = next[*chain.last().unwrap()] {
+            chain.push(b);
+        }
+        if chain.contains(&(n - 1)) && matches!(exits[n - 1], Exit::Falls) {
+            last_chain = chain;
+        } else {
+            order.extend(chain);
+        }
+    }
+    order.extend(last_chain);
+
+    let cost = |order: &[usize]| {
+        let mut following = vec![None; n];
+        for pair in order.windows(2) {
+            following[pair[0]] = Some(pair[1]);
+        }
+        (0..n)
+            .map(|i| match exits[i] {
+                Exit::Jump(to) if following[i] != Some(to) => weight(i, to),
+                Exit::Branch { taken, .. } if following[i] == Some(taken) => weight(i, i + 1),
+                Exit::Branch { taken, .. } if following[i] == Some(i + 1) => weight(i, taken),
+                Exit::Branch { taken, .. } => weight(i, taken).saturating_add(weight(i, i + 1)),
+                _ => 0,
+            })
+            .fold(0u64, u64::saturating_add)
+    };
+    let original: Vec<usize> = (0..n).collect();
+    if cost(&order) >= cost(&original) {
+        return None;
+    }
+    Some(emit(program, blocks, &exits, &order))
+}
+
+/// Lay out the blocks of `program` in `order`
+fn emit(program: &Program, blocks: &[BasicBlock], exits: &[Exit], order: &[usize]) -> Program {
+    let n = blocks.len();
+    let mut following = vec![None; n];
+    for pair in order.windows(2) {
+        following[pair[0]] = Some(pair[1]);
+    }
+    // How many instructions each block gains at its end
+    let change = |i: usize| -> isize {
+        match exits[i] {
+            Exit::Jump(to) if following[i] == Some(to) => -1,
+            Exit::Falls if i + 1 < n && following[i] != Some(i + 1) => 1,
+            Exit::Branch { taken, .. }
+                if following[i] != Some(taken) && following[i] != Some(i + 1) =>
+            {
+                1
+            }
+            _ => 0,
+        }
+    };
+    let mut new_start = vec![0; n];
+    let mut at = 0;
+    for &b in order {
+        new_start[b] = at;
+        at = (at as isize + (blocks[b].end - blocks[b].start) as isize + change(b)) as usize;
+    }
+    let new_len = at;
+    let map = |addr: usize| match blocks.binary_search_by(|b| b.start.cmp(&addr)) {
+        Ok(b) => new_start[b],
+        Err(0) => addr,
+        Err(b) if addr < blocks[b - 1].end => new_start[b - 1] + addr - blocks[b - 1].start,
+        Err(_) => addr - program.len() + new_len,
+    };
+
+    let mut relocated = program.clone();
+    relocated.relocate(map);
+    let mut code = Vec::with_capacity(new_len);
+    for &b in order {
+        let block = &blocks[b];
+        code.extend_from_slice(&relocated.instructions[block.start..block.end]);
+        let fallthrough = || Instruction::Jump(new_start[b + 1]);
+        match exits[b] {
+            Exit::Jump(to) if following[b] == Some(to) => {
+                code.pop();
+            }
+            Exit::Falls if b + 1 < n && following[b] != Some(b + 1) => code.push(fallthrough()),
+            Exit::Branch { taken, other } if following[b] == Some(taken) => {
+                let target = new_start[b + 1];
+                *code.last_mut().unwrap() = Instruction::ConditionalJump {
+                    cond: other,
+                    target,
+                };
+            }
+            Exit::Branch { .. } if following[b] != Some(b + 1) => code.push(fallthrough()),
+            _ => {}
+        }
+    }
+    relocated.instructions = code;
+    relocated
+}
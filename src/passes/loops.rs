@@ -0,0 +1,361 @@
+//! Loop optimizations: hoisting `load`s that read the same variable on every
+//! iteration, and turning multiplications by a loop counter into additions.
+//!
+//! A loop is a run of code `header..=back` whose last instruction jumps back
+//! to `header`, that is only entered at `header`, and that calls nothing, so
+//! no other code can change its registers or variables. Code moved out of a
+//! loop goes in front of its header, and only jumps from outside the loop
+//! are redirected to it. Both passes rewrite a loop only where the result is
+//! exactly the same at every point the program could stop.
+
+use super::Pass;
+use super::inline::cannot_fail;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::{Program, ProgramError};
+use crate::ranges;
+use alloc::collections::BTreeSet;
+use core::ops::{Range, RangeInclusive};
+
+/// Largest integer every smaller one of which an `f64` holds exactly
+const EXACT: f64 = 9_007_199_254_740_992.0;
+
+/// Moves a `load` out of a loop that never stores to its variable.
+///
+/// The `load` must run on every iteration before anything reads its
+/// register or branches, and be the only write to that register in the
+/// loop. Unless it starts the loop, its variable must also be stored on
+/// every path to the loop, so moving it cannot move where a missing
+/// variable stops the program.
+pub struct LoopInvariantMotion;
+
+impl Pass for LoopInvariantMotion {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+
+    fn run(&self, program: &mut Program) {
+        if !program.is_relocatable() {
+            return;
+        }
+        program.make_absolute();
+        // Every hoist takes one `load` out of a loop, so this always stops
+        while let Some((lp, addr)) = find_invariant_load(program) {
+            let load = program.instructions[addr].clone();
+            if program
+                .splice(addr..addr + 1, Program::new(vec![]))
+                .is_err()
+            {
+                return;
+            }
+            let lp = *lp.start()..=*lp.end() - 1;
+            if hoist(program, lp, vec![load]).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Replaces `mul j, i, k`, where `i` steps by one each iteration and `k`
+/// stays the same, with `add j, j, k`, or `sub` for a counter stepping down,
+/// and computes `j` once in front of the loop.
+///
+/// The counter must be stepped by an `addi` before the multiplication, and
+/// both must run on every iteration before the loop branches, with nothing
+/// in between that can stop the program or read `j`. `ranges::analyze` must
+/// show both operands are integers small enough for the sums to be exact.
+pub struct StrengthReduction;
+
+impl Pass for StrengthReduction {
+    fn name(&self) -> &'static str {
+        "strength-reduce"
+    }
+
+    fn run(&self, program: &mut Program) {
+        if !program.is_relocatable() {
+            return;
+        }
+        program.make_absolute();
+        // Every rewrite takes one `mul` out of a loop, so this always stops
+        while let Some((lp, addr, step)) = find_counter_product(program) {
+            let Instruction::Mul { dest, src1, src2 } = program.instructions[addr] else {
+                unreachable!()
+            };
+            let k = if src1 == step.counter { src2 } else { src1 };
+            program.instructions[addr] = if step.by == 1.0 {
+                Instruction::Add {
+                    dest,
+                    src1: dest,
+                    src2: k,
+                }
+            } else {
+                Instruction::Sub {
+                    dest,
+                    src1: dest,
+                    src2: k,
+                }
+            };
+            let first = Instruction::Mul {
+                dest,
+                src1: step.counter,
+                src2: k,
+            };
+            if hoist(program, lp, vec![first]).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Insert `code` in front of the loop `lp`, keeping its back edges
+fn hoist(
+    program: &mut Program,
+    lp: RangeInclusive<usize>,
+    code: Vec<Instruction>,
+) -> Result<(), ProgramError> {
+    let header = *lp.start();
+    let inserted = code.len();
+    program.splice(header..header, Program::new(code))?;
+    // Jumps to the header now land on the new code, which only those from
+    // outside the loop should run
+    let moved = header + inserted..=lp.end() + inserted;
+    for pc in moved {
+        program.instructions[pc].relocate(
+            pc,
+            pc,
+            |t| {
+                if t == header { header + inserted } else { t }
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Every loop in `code`, shortest first
+fn loops(program: &Program) -> Vec<RangeInclusive<usize>> {
+    let code = &program.instructions;
+    let entries = program.entry_points();
+    let mut found: Vec<RangeInclusive<usize>> = code
+        .iter()
+        .enumerate()
+        .flat_map(|(back, instr)| {
+            instr
+                .targets(back)
+                .into_iter()
+                .filter(move |&h| h <= back && !instr.is_call())
+                .map(move |h| h..=back)
+        })
+        .filter(|lp| {
+            let inner = *lp.start() + 1..=*lp.end();
+            !entries.iter().any(|a| inner.contains(a))
+                && code[lp.clone()].iter().all(stays_in_loop)
+                && code.iter().enumerate().all(|(pc, instr)| {
+                    let jumps = if lp.contains(&pc) {
+                        instr.targets(pc)
+                    } else {
+                        Vec::new()
+                    };
+                    instr
+                        .addresses(pc)
+                        .iter()
+                        .all(|a| !inner.contains(a) || jumps.contains(a))
+                })
+        })
+        .collect();
+    found.sort_by_key(|lp| lp.end() - lp.start());
+    found.dedup();
+    found
+}
+
+/// Whether `instr` leaves registers and variables to the loop it is in
+fn stays_in_loop(instr: &Instruction) -> bool {
+    !instr.is_call()
+        && !instr.uses_coroutines()
+        && !matches!(
+            instr,
+            Instruction::TryBegin { .. }
+                | Instruction::TryEnd
+                | Instruction::Send { .. }
+                | Instruction::Recv { .. }
+        )
+}
+
+/// The instructions from the header of `lp` that run, in order, on every
+/// iteration before anything branches
+fn prefix(code: &[Instruction], lp: &RangeInclusive<usize>) -> Range<usize> {
+    let header = *lp.start();
+    let end = (header..=*lp.end())
+        .find(|&pc| !code[pc].targets(pc).is_empty() || code[pc].is_terminator())
+        .unwrap_or(*lp.end() + 1);
+    header..end
+}
+
+fn writes_in(code: &[Instruction], lp: &RangeInclusive<usize>, reg: usize) -> usize {
+    code[lp.clone()]
+        .iter()
+        .filter(|i| i.writes().contains(&reg))
+        .count()
+}
+
+fn reads_in(code: &[Instruction], range: Range<usize>, reg: usize) -> bool {
+    code[range].iter().any(|i| i.sources().contains(&reg))
+}
+
+fn find_invariant_load(program: &Program) -> Option<(RangeInclusive<usize>, usize)> {
+    let code = &program.instructions;
+    let mut stored = None;
+    for lp in loops(program) {
+        let header = *lp.start();
+        for addr in prefix(code, &lp) {
+            let Instruction::Load { dest, ref var } = code[addr] else {
+                continue;
+            };
+            let invariant = !code[lp.clone()]
+                .iter()
+                .any(|i| matches!(i, Instruction::Store { var: v, .. } if v == var));
+            if !invariant || writes_in(code, &lp, dest) != 1 || reads_in(code, header..addr, dest) {
+                continue;
+            }
+            let cannot_miss = addr == header
+                || (code[header..addr].iter().all(cannot_fail)
+                    && stored
+                        .get_or_insert_with(|| stored_before(program))
+                        .get(header)
+                        .is_some_and(|vars: &Option<BTreeSet<&str>>| {
+                            vars.as_ref().is_some_and(|v| v.contains(var.as_str()))
+                        }));
+            if cannot_miss {
+                return Some((lp, addr));
+            }
+        }
+    }
+    None
+}
+
+/// An `addi` stepping `counter` by `by`
+struct Step {
+    counter: usize,
+    by: f64,
+}
+
+fn find_counter_product(program: &Program) -> Option<(RangeInclusive<usize>, usize, Step)> {
+    let code = &program.instructions;
+    let mut ranges = None;
+    for lp in loops(program) {
+        let header = *lp.start();
+        let prefix = prefix(code, &lp);
+        // Re-entering the prefix part way would skip the step or repeat the product
+        let reentered = code[lp.clone()].iter().enumerate().any(|(i, instr)| {
+            instr
+                .targets(header + i)
+                .iter()
+                .any(|&t| t > header && t <= prefix.end.min(*lp.end()))
+        });
+        if reentered {
+            continue;
+        }
+        for addr in prefix.clone() {
+            let Instruction::Mul { dest, src1, src2 } = code[addr] else {
+                continue;
+            };
+            for (counter, k) in [(src1, src2), (src2, src1)] {
+                let Some(by) = step_before(code, &lp, addr, counter) else {
+                    continue;
+                };
+                if dest == counter
+                    || dest == k
+                    || writes_in(code, &lp, k) != 0
+                    || writes_in(code, &lp, dest) != 1
+                    || reads_in(code, header..addr, dest)
+                    || !code[header..addr].iter().all(cannot_fail)
+                {
+                    continue;
+                }
+                let ranges = ranges.get_or_insert_with(|| ranges::analyze(program));
+                let (Some(i), Some(k)) = (ranges.get(addr, counter), ranges.get(addr, k)) else {
+                    continue;
+                };
+                // The counter starts one step back from its value here
+                let largest = |r: ranges::Range| r.lo.abs().max(r.hi.abs());
+                if i.integral && k.integral && (largest(i) + 1.0) * largest(k) < EXACT {
+                    return Some((lp, addr, Step { counter, by }));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// How much the only write to `counter` in `lp`, an `addi` in its prefix
+/// before `addr`, steps it by, if that is one either way
+fn step_before(
+    code: &[Instruction],
+    lp: &RangeInclusive<usize>,
+    addr: usize,
+    counter: usize,
+) -> Option<f64> {
+    if writes_in(code, lp, counter) != 1 {
+        return None;
+    }
+    code[*lp.start()..addr]
+        .iter()
+        .find_map(|instr| match *instr {
+            Instruction::AddImm {
+                dest, src, value, ..
+            } if dest == counter && src == counter && (value == 1.0 || value == -1.0) => {
+                Some(value)
+            }
+            _ => None,
+        })
+}
+
+/// The variables stored on every path to each instruction; `None` where no
+/// path reaches. Nothing removes a variable, so calls keep what was stored
+/// before them.
+fn stored_before(program: &Program) -> Vec<Option<BTreeSet<&str>>> {
+    let code = &program.instructions;
+    let mut states: Vec<Option<BTreeSet<&str>>> = vec![None; code.len()];
+    let mut worklist: Vec<(usize, BTreeSet<&str>)> = program
+        .entry_points()
+        .into_iter()
+        .map(|addr| (addr, BTreeSet::new()))
+        .collect();
+    for (pc, instr) in code.iter().enumerate() {
+        let start = match instr {
+            Instruction::Call { .. } | Instruction::CallArgs { .. } => instr.target(pc),
+            Instruction::TryBegin { handler, .. } => Some(*handler),
+            Instruction::MakeClosure { addr, .. } | Instruction::Spawn { addr, .. } => Some(*addr),
+            _ => None,
+        };
+        worklist.extend(start.map(|addr| (addr, BTreeSet::new())));
+    }
+    while let Some((addr, incoming)) = worklist.pop() {
+        let Some(instr) = code.get(addr) else {
+            continue;
+        };
+        let state = match &states[addr] {
+            Some(old) => {
+                let met: BTreeSet<&str> = old.intersection(&incoming).copied().collect();
+                if met.len() == old.len() {
+                    continue;
+                }
+                met
+            }
+            None => incoming,
+        };
+        states[addr] = Some(state.clone());
+        let mut out = state;
+        if let Instruction::Store { var, .. } = instr {
+            out.insert(var);
+        }
+        if !instr.is_call() {
+            for target in instr.targets(addr) {
+                worklist.push((target, out.clone()));
+            }
+        }
+        if !instr.is_terminator() {
+            worklist.push((addr + 1, out));
+        }
+    }
+    states
+}
@@ -0,0 +1,178 @@
+pub mod compact;
+pub mod const_fold;
+pub mod dce;
+pub mod fusion;
+pub mod inline;
+pub mod layout;
+pub mod loops;
+pub mod peephole;
+pub mod range_fold;
+
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+use core::fmt;
+use core::str::FromStr;
+
+/// A transformation applied to a program before it is executed
+pub trait Pass {
+    /// Short identifier used to toggle the pass in a `PassManager`
+    fn name(&self) -> &'static str;
+
+    fn run(&self, program: &mut Program);
+}
+
+/// Optimization presets, mirroring the usual `-O` compiler flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    /// No transforms at all
+    O0,
+    /// A single run of cheap local transforms
+    #[default]
+    O1,
+    /// Every pass, repeated until the program stops changing
+    O2,
+    /// Like `O2`, plus transforms that trade speed for smaller code
+    Os,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_start_matches(['O', 'o']) {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "s" => Ok(OptLevel::Os),
+            _ => Err(format!("invalid optimization level '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptLevel::O0 => write!(f, "O0"),
+            OptLevel::O1 => write!(f, "O1"),
+            OptLevel::O2 => write!(f, "O2"),
+            OptLevel::Os => write!(f, "Os"),
+        }
+    }
+}
+
+/// Upper bound on fixpoint iterations so a pair of oscillating passes cannot hang
+const MAX_ITERATIONS: usize = 8;
+
+struct Entry {
+    pass: Box<dyn Pass>,
+    enabled: bool,
+}
+
+/// An ordered list of passes that can be individually enabled or disabled
+pub struct PassManager {
+    passes: Vec<Entry>,
+    fixpoint: bool,
+}
+
+impl PassManager {
+    /// Create a manager with no passes registered
+    pub fn empty() -> Self {
+        Self {
+            passes: Vec::new(),
+            fixpoint: false,
+        }
+    }
+
+    /// Create a manager preloaded with the passes for `level`
+    pub fn with_level(level: OptLevel) -> Self {
+        let mut manager = Self::empty();
+        // Inlining grows the code, which `Os` is trying to shrink
+        if level == OptLevel::O2 {
+            manager.add(inline::Inline::default());
+        }
+        if level >= OptLevel::O1 {
+            manager.add(const_fold::ConstFold);
+        }
+        if level >= OptLevel::O2 {
+            manager.add(range_fold::RangeFold);
+            manager.add(loops::LoopInvariantMotion);
+            manager.add(loops::StrengthReduction);
+            manager.add(peephole::Peephole::new());
+        }
+        if level >= OptLevel::O1 {
+            manager.add(dce::DeadCodeElim);
+        }
+        if level >= OptLevel::O2 {
+            manager.add(layout::BlockLayout::new());
+        }
+        if level == OptLevel::Os {
+            manager.add(compact::CodeCompaction);
+        }
+        manager.fixpoint = level >= OptLevel::O2;
+        manager
+    }
+
+    /// Register a pass, to run after every pass already registered
+    pub fn add<P: Pass + 'static>(&mut self, pass: P) -> &mut Self {
+        self.passes.push(Entry {
+            pass: Box::new(pass),
+            enabled: true,
+        });
+        self
+    }
+
+    /// Enable or disable the pass called `name`, returning false if no such pass exists
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.passes.iter_mut().find(|e| e.pass.name() == name) {
+            Some(entry) => {
+                entry.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.passes
+            .iter()
+            .any(|e| e.enabled && e.pass.name() == name)
+    }
+
+    pub fn pass_names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|e| e.pass.name()).collect()
+    }
+
+    /// Run the enabled passes. Passes that move code work on absolute
+    /// targets, so a program with relative jumps comes back with every `jmp`
+    /// and `call` relative.
+    pub fn run(&self, program: &mut Program) {
+        let relative = program.instructions.iter().any(Instruction::is_relative);
+        if !self.fixpoint {
+            self.run_once(program);
+        } else {
+            for _ in 0..MAX_ITERATIONS {
+                let before = program.clone();
+                self.run_once(program);
+                if *program == before {
+                    break;
+                }
+            }
+        }
+        if relative {
+            program.make_relative();
+        }
+    }
+
+    fn run_once(&self, program: &mut Program) {
+        for entry in self.passes.iter().filter(|e| e.enabled) {
+            entry.pass.run(program);
+        }
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::with_level(OptLevel::default())
+    }
+}
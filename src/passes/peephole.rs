@@ -0,0 +1,186 @@
+use super::Pass;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+
+type Rewrite = dyn Fn(&[Instruction]) -> Option<Vec<Instruction>>;
+
+/// A rewrite over a fixed-size window of adjacent instructions.
+///
+/// The rewrite closure sees exactly `window` instructions and returns their
+/// replacement, or `None` to leave them alone. Replacements may be shorter or
+/// longer than the window; any jump targets they contain are addresses in the
+/// original program and are relocated along with everything else.
+pub struct Rule {
+    name: String,
+    window: usize,
+    rewrite: Box<Rewrite>,
+}
+
+impl Rule {
+    pub fn new(
+        name: impl Into<String>,
+        window: usize,
+        rewrite: impl Fn(&[Instruction]) -> Option<Vec<Instruction>> + 'static,
+    ) -> Self {
+        assert!(
+            window > 0,
+            "peephole rules must match at least one instruction"
+        );
+        Self {
+            name: name.into(),
+            window,
+            rewrite: Box::new(rewrite),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+}
+
+/// Applies local rewrite rules in a single left-to-right sweep.
+///
+/// A window never spans a jump target other than its first instruction, so
+/// rewrites cannot change what a branch lands on.
+pub struct Peephole {
+    rules: Vec<Rule>,
+}
+
+impl Peephole {
+    /// A peephole pass with the built-in rules
+    pub fn new() -> Self {
+        let mut peephole = Self::empty();
+        for rule in builtin_rules() {
+            peephole.add_rule(rule);
+        }
+        peephole
+    }
+
+    /// A peephole pass with no rules
+    pub fn empty() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    /// Register a rule; rules are tried in registration order
+    pub fn add_rule(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    pub fn rule_names(&self) -> Vec<&str> {
+        self.rules.iter().map(Rule::name).collect()
+    }
+}
+
+impl Default for Peephole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn run(&self, program: &mut Program) {
+        // Rules may change the instruction count, moving computed jump targets
+        if !program.is_relocatable() {
+            return;
+        }
+        program.make_absolute();
+        let code = &program.instructions;
+        let len = code.len();
+
+        let mut is_target = vec![false; len];
+        for addr in code.iter().enumerate().flat_map(|(pc, i)| i.addresses(pc)) {
+            if let Some(t) = is_target.get_mut(addr) {
+                *t = true;
+            }
+        }
+        for addr in program.entry_points() {
+            if let Some(t) = is_target.get_mut(addr) {
+                *t = true;
+            }
+        }
+
+        let mut out = Vec::with_capacity(len);
+        let mut new_addr = vec![0; len + 1];
+        let mut pc = 0;
+        while pc < len {
+            let rewritten = self.rules.iter().find_map(|rule| {
+                let end = pc + rule.window;
+                if end > len || is_target[pc + 1..end].iter().any(|&t| t) {
+                    return None;
+                }
+                (rule.rewrite)(&code[pc..end]).map(|replacement| (end, replacement))
+            });
+
+            match rewritten {
+                Some((end, replacement)) => {
+                    new_addr[pc..end].fill(out.len());
+                    out.extend(replacement);
+                    pc = end;
+                }
+                None => {
+                    new_addr[pc] = out.len();
+                    out.push(code[pc].clone());
+                    pc += 1;
+                }
+            }
+        }
+        new_addr[len] = out.len();
+
+        if out == program.instructions {
+            return;
+        }
+        let new_len = out.len();
+        program.instructions = out;
+        // Out-of-bounds targets stay out of bounds so they still trap
+        program.relocate(|addr| {
+            new_addr
+                .get(addr)
+                .copied()
+                .unwrap_or_else(|| addr - len + new_len)
+        });
+    }
+}
+
+fn builtin_rules() -> Vec<Rule> {
+    use Instruction::*;
+
+    vec![
+        Rule::new("self-move", 1, |w| match &w[0] {
+            Mov { dest, src } if dest == src => Some(vec![]),
+            _ => None,
+        }),
+        Rule::new("overwritten-immediate", 2, |w| match (&w[0], &w[1]) {
+            (LoadImm { dest: a, .. }, LoadImm { dest: b, .. }) if a == b => {
+                Some(vec![w[1].clone()])
+            }
+            _ => None,
+        }),
+        Rule::new("store-then-load", 2, |w| match (&w[0], &w[1]) {
+            (Store { src, var: v1 }, Load { dest, var: v2 }) if src == dest && v1 == v2 => {
+                Some(vec![w[0].clone()])
+            }
+            _ => None,
+        }),
+        Rule::new("move-back", 2, |w| match (&w[0], &w[1]) {
+            (Mov { dest: a, src: b }, Mov { dest: c, src: d }) if a == d && b == c => {
+                Some(vec![w[0].clone()])
+            }
+            _ => None,
+        }),
+    ]
+}
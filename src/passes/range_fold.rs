@@ -0,0 +1,93 @@
+use super::Pass;
+use crate::instruction::{Comparison, Instruction};
+use crate::program::Program;
+use crate::ranges::{self, Range};
+
+/// Drops checks that `ranges::analyze` shows always go one way: comparisons
+/// and conditional jumps with a known outcome, `switch`es whose index picks
+/// a single target, and computed jumps and calls to a known address.
+///
+/// Instructions are rewritten in place, so addresses never move; `dce`
+/// removes what is left unreachable.
+pub struct RangeFold;
+
+impl Pass for RangeFold {
+    fn name(&self) -> &'static str {
+        "range-fold"
+    }
+
+    fn run(&self, program: &mut Program) {
+        let ranges = ranges::analyze(program);
+        let len = program.len();
+        for (pc, instr) in program.instructions.iter_mut().enumerate() {
+            let get = |reg| ranges.get(pc, reg);
+            if let Some(folded) = fold(instr, pc, len, get) {
+                *instr = folded;
+            }
+        }
+    }
+}
+
+fn fold(
+    instr: &Instruction,
+    pc: usize,
+    len: usize,
+    get: impl Fn(usize) -> Option<Range>,
+) -> Option<Instruction> {
+    use Instruction::*;
+    match *instr {
+        CompareJump {
+            cmp,
+            dest,
+            src1,
+            src2,
+            ..
+        } => {
+            let (a, b) = (get(src1)?, get(src2)?);
+            let always = match cmp {
+                Comparison::Equal => a.constant().is_some() && a.constant() == b.constant(),
+                Comparison::LessThan => a.hi < b.lo,
+                Comparison::GreaterThan => a.lo > b.hi,
+            };
+            always.then_some(LoadImm { dest, value: 1.0 })
+        }
+        ConditionalJump { cond, target } => {
+            let cond = get(cond)?;
+            if cond.constant() == Some(0.0) {
+                Some(Jump(target))
+            } else {
+                (cond.lo > 0.0 || cond.hi < 0.0).then_some(Jump(pc + 1))
+            }
+        }
+        Switch {
+            src,
+            ref table,
+            default,
+        } => {
+            let index = get(src)?;
+            if index.misses(table.len()) {
+                return Some(Jump(default));
+            }
+            if !index.is_index(table.len()) {
+                return None;
+            }
+            let picked = &table[index.lo as usize..=index.hi as usize];
+            picked
+                .iter()
+                .all(|&t| t == picked[0])
+                .then_some(Jump(picked[0]))
+        }
+        JumpIndirect { src } | CallIndirect { src } => {
+            let addr = get(src)?;
+            if !addr.is_index(len) || addr.constant().is_none() {
+                return None;
+            }
+            let addr = addr.lo as usize;
+            Some(match instr {
+                JumpIndirect { .. } => Jump(addr),
+                _ => Call { addr },
+            })
+        }
+        _ => None,
+    }
+}
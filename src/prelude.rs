@@ -0,0 +1,7 @@
+//! The `alloc` items the standard prelude provides, for `no_std` builds.
+
+pub(crate) use alloc::boxed::Box;
+pub(crate) use alloc::format;
+pub(crate) use alloc::string::{String, ToString};
+pub(crate) use alloc::vec;
+pub(crate) use alloc::vec::Vec;
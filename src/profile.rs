@@ -0,0 +1,172 @@
+//! Aggregate call profiles.
+//!
+//! `Profiler` is a hook that follows calls and returns as the call stack
+//! grows and shrinks, and charges every executed instruction, and the time
+//! it took, to the stack of functions it ran in. A function is named by the
+//! label or export at its entry, or by its address if it has none. The
+//! totals export as collapsed stacks for flamegraph tools or as a speedscope
+//! profile.
+
+use crate::hook::{Hook, HookAction};
+use crate::instruction::Instruction;
+use crate::json;
+use crate::program::Program;
+use crate::vm::VM;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// What a profile's weights measure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Instructions,
+    Nanoseconds,
+}
+
+/// Cost charged to one stack of functions, excluding its callees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cost {
+    pub instructions: u64,
+    pub nanos: u64,
+}
+
+impl Cost {
+    fn get(&self, unit: Unit) -> u64 {
+        match unit {
+            Unit::Instructions => self.instructions,
+            Unit::Nanoseconds => self.nanos,
+        }
+    }
+}
+
+/// A weighted call tree, recorded as a hook
+#[derive(Debug, Default)]
+pub struct Profiler {
+    /// Functions running now, outermost first
+    stack: Vec<String>,
+    /// Call depth of the VM when the outermost function was entered
+    base: usize,
+    started: Option<Instant>,
+    costs: BTreeMap<Vec<String>, Cost>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cost of each stack, outermost function first
+    pub fn costs(&self) -> &BTreeMap<Vec<String>, Cost> {
+        &self.costs
+    }
+
+    /// Total cost of every stack that has `function` in it
+    pub fn inclusive(&self, function: &str) -> Cost {
+        self.costs
+            .iter()
+            .filter(|(stack, _)| stack.iter().any(|f| f == function))
+            .fold(Cost::default(), |total, (_, cost)| Cost {
+                instructions: total.instructions + cost.instructions,
+                nanos: total.nanos + cost.nanos,
+            })
+    }
+
+    /// One `outer;inner weight` line per stack, as flamegraph tools read
+    pub fn to_collapsed(&self, unit: Unit) -> String {
+        let mut out = String::new();
+        for (stack, cost) in &self.costs {
+            out.push_str(&format!("{} {}\n", stack.join(";"), cost.get(unit)));
+        }
+        out
+    }
+
+    /// A speedscope sampled profile with one weighted sample per stack
+    pub fn to_speedscope(&self, name: &str, unit: Unit) -> String {
+        let mut frames: Vec<&str> = Vec::new();
+        let mut samples = Vec::new();
+        let mut weights = Vec::new();
+        for (stack, cost) in &self.costs {
+            let indices: Vec<String> = stack
+                .iter()
+                .map(|function| {
+                    let index = match frames.iter().position(|f| f == function) {
+                        Some(index) => index,
+                        None => {
+                            frames.push(function);
+                            frames.len() - 1
+                        }
+                    };
+                    index.to_string()
+                })
+                .collect();
+            samples.push(format!("[{}]", indices.join(",")));
+            weights.push(cost.get(unit).to_string());
+        }
+        let frames: Vec<String> = frames
+            .iter()
+            .map(|f| format!("{{\"name\":{}}}", json::string(f)))
+            .collect();
+        let total: u64 = self.costs.values().map(|cost| cost.get(unit)).sum();
+        let unit = match unit {
+            Unit::Instructions => "none",
+            Unit::Nanoseconds => "nanoseconds",
+        };
+        format!(
+            "{{\"$schema\":\"https://www.speedscope.app/file-format-schema.json\",\
+             \"shared\":{{\"frames\":[{}]}},\
+             \"profiles\":[{{\"type\":\"sampled\",\"name\":{},\"unit\":\"{}\",\
+             \"startValue\":0,\"endValue\":{},\"samples\":[{}],\"weights\":[{}]}}]}}\n",
+            frames.join(","),
+            json::string(name),
+            unit,
+            total,
+            samples.join(","),
+            weights.join(",")
+        )
+    }
+}
+
+impl Hook for Profiler {
+    fn before(&mut self, _: usize, _: &Instruction) -> HookAction {
+        self.started = Some(Instant::now());
+        HookAction::Continue
+    }
+
+    fn after(&mut self, pc: usize, vm: &VM) -> HookAction {
+        let nanos = self
+            .started
+            .take()
+            .map_or(0, |t| t.elapsed().as_nanos() as u64);
+        if self.stack.is_empty() {
+            // The first instruction may itself have entered a function
+            let called = matches!(
+                vm.program.instructions.get(pc),
+                Some(
+                    Instruction::Call { .. }
+                        | Instruction::CallArgs { .. }
+                        | Instruction::CallClosure { .. }
+                )
+            ) && vm.pc != pc + 1;
+            self.base = vm.call_stack.len().saturating_sub(usize::from(called));
+            self.stack.push(function_name(&vm.program, pc));
+        }
+        let cost = self.costs.entry(self.stack.clone()).or_default();
+        cost.instructions += 1;
+        cost.nanos += nanos;
+
+        // A call enters the function at the new pc; returns and unwinding
+        // leave as many as the stack shrank by
+        let depth = vm.call_stack.len().saturating_sub(self.base) + 1;
+        if depth > self.stack.len() {
+            self.stack.push(function_name(&vm.program, vm.pc));
+        }
+        self.stack.truncate(depth);
+        HookAction::Continue
+    }
+}
+
+fn function_name(program: &Program, addr: usize) -> String {
+    match program.symbolize(addr) {
+        Some((name, 0)) => name.to_string(),
+        _ => format!("<{}>", addr),
+    }
+}
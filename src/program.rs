@@ -0,0 +1,667 @@
+use crate::HashMap;
+use crate::cfg::block_leaders;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::ranges;
+use alloc::sync::Arc;
+use core::error::Error;
+use core::fmt;
+use core::ops::{Deref, DerefMut, Range};
+
+#[derive(Debug, PartialEq)]
+pub enum ProgramError {
+    DuplicateExport(String),
+    ExportOutOfBounds {
+        name: String,
+        addr: usize,
+    },
+    DuplicateLabel(String),
+    LabelOutOfBounds {
+        name: String,
+        addr: usize,
+    },
+    TargetOutOfBounds {
+        addr: usize,
+        target: usize,
+    },
+    RegisterOutOfBounds {
+        addr: usize,
+        reg: usize,
+    },
+    /// A field access on a register that, within the same basic block, was
+    /// last written by a `record` with fewer fields
+    FieldOutOfBounds {
+        addr: usize,
+        index: usize,
+        fields: usize,
+    },
+    /// The instruction at `addr` cannot be compiled by a backend
+    Unsupported {
+        addr: usize,
+        mnemonic: &'static str,
+    },
+    /// No label or export has the name given as the entry point
+    UnknownSymbol(String),
+    EntryOutOfBounds(usize),
+    /// The backend only starts programs at instruction 0
+    UnsupportedEntry(usize),
+    /// An `endtry` at `addr` reached on a path where its function has no
+    /// handler installed
+    HandlerUnderflow(usize),
+    /// Paths reaching `addr` have installed different numbers of handlers
+    HandlerMismatch {
+        addr: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// The computed jump or call at `addr` goes through a register that
+    /// never holds an address inside the program
+    AddressOutOfBounds {
+        addr: usize,
+        reg: usize,
+    },
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgramError::DuplicateExport(name) => {
+                write!(f, "Export '{}' is defined more than once", name)
+            }
+            ProgramError::ExportOutOfBounds { name, addr } => {
+                write!(f, "Export '{}' points outside the program ({})", name, addr)
+            }
+            ProgramError::DuplicateLabel(name) => {
+                write!(f, "Label '{}' is defined more than once", name)
+            }
+            ProgramError::LabelOutOfBounds { name, addr } => {
+                write!(f, "Label '{}' points outside the program ({})", name, addr)
+            }
+            ProgramError::TargetOutOfBounds { addr, target } => write!(
+                f,
+                "Instruction {} targets {}, outside the program",
+                addr, target
+            ),
+            ProgramError::RegisterOutOfBounds { addr, reg } => write!(
+                f,
+                "Instruction {} uses register {}, outside the register file",
+                addr, reg
+            ),
+            ProgramError::FieldOutOfBounds {
+                addr,
+                index,
+                fields,
+            } => write!(
+                f,
+                "Instruction {} accesses field {} of a record with {} fields",
+                addr, index, fields
+            ),
+            ProgramError::Unsupported { addr, mnemonic } => write!(
+                f,
+                "Instruction {} ({}) is not supported by this backend",
+                addr, mnemonic
+            ),
+            ProgramError::UnknownSymbol(name) => write!(f, "No label or export named '{}'", name),
+            ProgramError::EntryOutOfBounds(addr) => {
+                write!(f, "Entry point {} is outside the program", addr)
+            }
+            ProgramError::UnsupportedEntry(addr) => write!(
+                f,
+                "Entry point {} is not supported by this backend, which starts at 0",
+                addr
+            ),
+            ProgramError::HandlerUnderflow(addr) => write!(
+                f,
+                "Instruction {} removes a handler on a path that installed none",
+                addr
+            ),
+            ProgramError::HandlerMismatch {
+                addr,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Instruction {} is reached with both {} and {} handlers installed",
+                addr, expected, found
+            ),
+            ProgramError::AddressOutOfBounds { addr, reg } => write!(
+                f,
+                "Instruction {} jumps through r{}, which never holds an address inside the program",
+                addr, reg
+            ),
+        }
+    }
+}
+
+impl Error for ProgramError {}
+
+impl ProgramError {
+    /// The address of the instruction at fault, if it is one instruction
+    pub fn addr(&self) -> Option<usize> {
+        match *self {
+            ProgramError::TargetOutOfBounds { addr, .. }
+            | ProgramError::RegisterOutOfBounds { addr, .. }
+            | ProgramError::FieldOutOfBounds { addr, .. }
+            | ProgramError::Unsupported { addr, .. }
+            | ProgramError::HandlerUnderflow(addr)
+            | ProgramError::HandlerMismatch { addr, .. }
+            | ProgramError::AddressOutOfBounds { addr, .. } => Some(addr),
+            _ => None,
+        }
+    }
+}
+
+/// A named entry point that an embedder can call into
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Export {
+    pub name: String,
+    pub addr: usize,
+    /// Number of arguments, passed in registers `0..arity`
+    pub arity: usize,
+}
+
+/// A symbolic name for an address, kept for diagnostics only
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Label {
+    pub name: String,
+    pub addr: usize,
+}
+
+/// Assembled instructions together with the metadata needed to run them
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    exports: Vec<Export>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    labels: Vec<Label>,
+    /// Where a new VM starts executing
+    #[cfg_attr(feature = "serde", serde(default))]
+    entry: usize,
+}
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Self {
+        Self {
+            instructions,
+            exports: Vec::new(),
+            labels: Vec::new(),
+            entry: 0,
+        }
+    }
+
+    /// Publish the function at `addr` under `name`
+    pub fn export(
+        &mut self,
+        name: impl Into<String>,
+        addr: usize,
+        arity: usize,
+    ) -> Result<(), ProgramError> {
+        let name = name.into();
+        if self.find_export(&name).is_some() {
+            return Err(ProgramError::DuplicateExport(name));
+        }
+        if addr >= self.instructions.len() {
+            return Err(ProgramError::ExportOutOfBounds { name, addr });
+        }
+        self.exports.push(Export { name, addr, arity });
+        Ok(())
+    }
+
+    pub fn exports(&self) -> &[Export] {
+        &self.exports
+    }
+
+    pub fn find_export(&self, name: &str) -> Option<&Export> {
+        self.exports.iter().find(|e| e.name == name)
+    }
+
+    /// Name the instruction at `addr`, e.g. the start of a function
+    pub fn label(&mut self, name: impl Into<String>, addr: usize) -> Result<(), ProgramError> {
+        let name = name.into();
+        if self.find_label(&name).is_some() {
+            return Err(ProgramError::DuplicateLabel(name));
+        }
+        if addr >= self.instructions.len() {
+            return Err(ProgramError::LabelOutOfBounds { name, addr });
+        }
+        self.labels.push(Label { name, addr });
+        Ok(())
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub fn find_label(&self, name: &str) -> Option<&Label> {
+        self.labels.iter().find(|l| l.name == name)
+    }
+
+    /// Address a new VM starts executing at, 0 unless set
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Address of the label called `name`, or else the export
+    pub fn find_symbol(&self, name: &str) -> Option<usize> {
+        match (self.find_label(name), self.find_export(name)) {
+            (Some(label), _) => Some(label.addr),
+            (None, Some(export)) => Some(export.addr),
+            (None, None) => None,
+        }
+    }
+
+    /// Start execution at the label or export called `name`, so functions
+    /// can be defined before the main code without a leading jump
+    pub fn set_entry(&mut self, name: &str) -> Result<(), ProgramError> {
+        let addr = self
+            .find_symbol(name)
+            .ok_or_else(|| ProgramError::UnknownSymbol(name.to_string()))?;
+        self.set_entry_addr(addr)
+    }
+
+    pub fn set_entry_addr(&mut self, addr: usize) -> Result<(), ProgramError> {
+        if addr >= self.instructions.len() {
+            return Err(ProgramError::EntryOutOfBounds(addr));
+        }
+        self.entry = addr;
+        Ok(())
+    }
+
+    /// The nearest label or export at or before `addr`, with the offset from it
+    pub fn symbolize(&self, addr: usize) -> Option<(&str, usize)> {
+        let labels = self.labels.iter().map(|l| (l.name.as_str(), l.addr));
+        let exports = self.exports.iter().map(|e| (e.name.as_str(), e.addr));
+        labels
+            .chain(exports)
+            .filter(|&(_, at)| at <= addr && addr < self.instructions.len())
+            .max_by_key(|&(_, at)| at)
+            .map(|(name, at)| (name, addr - at))
+    }
+
+    /// Addresses execution can start from: the entry point, every export
+    /// and every function a `lea`, `closure` or `spawn` refers to
+    pub fn entry_points(&self) -> Vec<usize> {
+        let mut entries = vec![self.entry];
+        entries.extend(self.exports.iter().map(|e| e.addr));
+        entries.extend(self.instructions.iter().filter_map(|instr| match instr {
+            Instruction::LoadAddr { addr, .. }
+            | Instruction::MakeClosure { addr, .. }
+            | Instruction::Spawn { addr, .. } => Some(*addr),
+            _ => None,
+        }));
+        entries
+    }
+
+    /// Rewrite every jump/call target, export and label address using `f`.
+    /// Relative targets are rebased as if each instruction moved to `f` of
+    /// its own address, so call this before moving the code.
+    pub fn relocate(&mut self, mut f: impl FnMut(usize) -> usize) {
+        for (pc, instr) in self.instructions.iter_mut().enumerate() {
+            let to = f(pc);
+            instr.relocate(pc, to, &mut f);
+        }
+        self.relocate_symbols(f);
+    }
+
+    fn relocate_symbols(&mut self, mut f: impl FnMut(usize) -> usize) {
+        for export in &mut self.exports {
+            export.addr = f(export.addr);
+        }
+        for label in &mut self.labels {
+            label.addr = f(label.addr);
+        }
+        self.entry = f(self.entry);
+    }
+
+    /// Turn every relative jump and call into its absolute form
+    pub fn make_absolute(&mut self) {
+        for (pc, instr) in self.instructions.iter_mut().enumerate() {
+            if instr.is_relative() {
+                *instr = instr.to_absolute(pc);
+            }
+        }
+    }
+
+    /// Turn every `jmp` and `call` into its relative form, so the code keeps
+    /// working wherever it is placed
+    pub fn make_relative(&mut self) {
+        for (pc, instr) in self.instructions.iter_mut().enumerate() {
+            *instr = instr.to_relative(pc);
+        }
+    }
+
+    /// Check that every jump/call target, export and label lies inside the
+    /// program, that no name is defined twice, and that field accesses fit
+    /// records whose shape is known
+    pub fn verify(&self) -> Result<(), ProgramError> {
+        let len = self.instructions.len();
+        if self.entry != 0 && self.entry >= len {
+            return Err(ProgramError::EntryOutOfBounds(self.entry));
+        }
+        for (addr, instr) in self.instructions.iter().enumerate() {
+            if let Some(target) = instr.addresses(addr).into_iter().find(|&t| t >= len) {
+                return Err(ProgramError::TargetOutOfBounds { addr, target });
+            }
+        }
+        self.verify_fields()?;
+        for (i, export) in self.exports.iter().enumerate() {
+            if self.exports[..i].iter().any(|e| e.name == export.name) {
+                return Err(ProgramError::DuplicateExport(export.name.clone()));
+            }
+            if export.addr >= len {
+                return Err(ProgramError::ExportOutOfBounds {
+                    name: export.name.clone(),
+                    addr: export.addr,
+                });
+            }
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if self.labels[..i].iter().any(|l| l.name == label.name) {
+                return Err(ProgramError::DuplicateLabel(label.name.clone()));
+            }
+            if label.addr >= len {
+                return Err(ProgramError::LabelOutOfBounds {
+                    name: label.name.clone(),
+                    addr: label.addr,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Track the field count of records allocated earlier in each basic
+    /// block; anything that crosses a block boundary is unknown
+    fn verify_fields(&self) -> Result<(), ProgramError> {
+        let leaders = block_leaders(&self.instructions);
+        let mut shapes: HashMap<usize, usize> = HashMap::new();
+        for (addr, instr) in self.instructions.iter().enumerate() {
+            if leaders[addr] {
+                shapes.clear();
+            }
+            if let Instruction::GetField { record, index, .. }
+            | Instruction::SetField { record, index, .. } = instr
+                && let Some(&fields) = shapes.get(record)
+                && *index >= fields
+            {
+                return Err(ProgramError::FieldOutOfBounds {
+                    addr,
+                    index: *index,
+                    fields,
+                });
+            }
+            for reg in instr.writes() {
+                shapes.remove(&reg);
+            }
+            if let Instruction::NewRecord { dest, fields } = instr {
+                shapes.insert(*dest, *fields);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every register operand is below `count`, so a VM with that
+    /// many registers can skip bounds checks while running this program
+    pub fn verify_registers(&self, count: usize) -> Result<(), ProgramError> {
+        for (addr, instr) in self.instructions.iter().enumerate() {
+            if let Some(reg) = instr
+                .writes()
+                .into_iter()
+                .chain(instr.sources())
+                .find(|&r| r >= count)
+            {
+                return Err(ProgramError::RegisterOutOfBounds { addr, reg });
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of handlers the running function has installed before
+    /// each instruction, `None` where no path reaches it. Functions start
+    /// with none, and a caught error resumes at its handler with one fewer
+    /// than the `try` left. A `jmpr` target is unknown, so it is not
+    /// followed.
+    pub fn handler_depths(&self) -> Result<Vec<Option<usize>>, ProgramError> {
+        let code = &self.instructions;
+        let mut depths = vec![None; code.len()];
+        let mut worklist: Vec<(usize, usize)> =
+            self.entry_points().into_iter().map(|a| (a, 0)).collect();
+        worklist.extend(
+            code.iter()
+                .enumerate()
+                .filter_map(|(pc, instr)| match instr {
+                    Instruction::Call { .. }
+                    | Instruction::CallRel(_)
+                    | Instruction::CallArgs { .. } => Some((instr.target(pc)?, 0)),
+                    _ => None,
+                }),
+        );
+        while let Some((addr, depth)) = worklist.pop() {
+            let Some(instr) = code.get(addr) else {
+                continue;
+            };
+            match depths[addr] {
+                Some(expected) if expected != depth => {
+                    return Err(ProgramError::HandlerMismatch {
+                        addr,
+                        expected,
+                        found: depth,
+                    });
+                }
+                Some(_) => continue,
+                None => depths[addr] = Some(depth),
+            }
+            let after = match instr {
+                Instruction::TryBegin { handler, .. } => {
+                    worklist.push((*handler, depth));
+                    depth + 1
+                }
+                Instruction::TryEnd => depth
+                    .checked_sub(1)
+                    .ok_or(ProgramError::HandlerUnderflow(addr))?,
+                _ if instr.is_call() => depth,
+                _ => {
+                    worklist.extend(instr.targets(addr).into_iter().map(|t| (t, depth)));
+                    depth
+                }
+            };
+            if !instr.is_terminator() {
+                worklist.push((addr + 1, after));
+            }
+        }
+        Ok(depths)
+    }
+
+    /// Check that every `endtry` has a handler of its own function to remove
+    /// and that paths merge with the same handlers installed; see
+    /// `handler_depths`
+    pub fn verify_handlers(&self) -> Result<(), ProgramError> {
+        self.handler_depths().map(|_| ())
+    }
+
+    /// Check that no reachable `jmpr` or `callr` always leaves the program,
+    /// by bounding register values with `ranges::analyze`
+    pub fn verify_ranges(&self) -> Result<(), ProgramError> {
+        let ranges = ranges::analyze(self);
+        for (addr, instr) in self.instructions.iter().enumerate() {
+            if let Instruction::JumpIndirect { src } | Instruction::CallIndirect { src } = instr
+                && let Some(range) = ranges.get(addr, *src)
+                && range.misses(self.len())
+            {
+                return Err(ProgramError::AddressOutOfBounds { addr, reg: *src });
+            }
+        }
+        Ok(())
+    }
+
+    /// Add `other` to the end of this program, shifting its targets, exports
+    /// and labels. Targets one past the end now reach the appended code. On
+    /// error `self` is left unchanged.
+    pub fn append(&mut self, other: Program) -> Result<(), ProgramError> {
+        let len = self.len();
+        self.splice(len..len, other)
+    }
+
+    /// Replace the instructions in `range` with `fragment`.
+    ///
+    /// Targets, exports and labels after the range move with the code; those
+    /// pointing at `start` or into the range land on the fragment, so with an
+    /// empty range the fragment is inserted in front of `start`. The
+    /// fragment's own addresses are relative to its first instruction. On
+    /// error `self` is left unchanged.
+    pub fn splice(&mut self, range: Range<usize>, fragment: Program) -> Result<(), ProgramError> {
+        let Range { start, end } = range;
+        assert!(
+            start <= end && end <= self.len(),
+            "splice range {}..{} out of bounds for program of length {}",
+            start,
+            end,
+            self.len()
+        );
+
+        let mut fragment = fragment;
+        let inserted = fragment.len();
+        fragment.relocate(|addr| addr + start);
+
+        let shift = move |addr: usize| {
+            if addr <= start {
+                addr
+            } else if addr < end {
+                start
+            } else {
+                addr - (end - start) + inserted
+            }
+        };
+        let mut result = self.clone();
+        for (pc, instr) in result.instructions.iter_mut().enumerate() {
+            // The instruction at `start` itself moves past the fragment
+            let to = match pc {
+                pc if pc < start => pc,
+                pc if pc < end => continue,
+                pc => pc - (end - start) + inserted,
+            };
+            instr.relocate(pc, to, shift);
+        }
+        result.relocate_symbols(shift);
+        result
+            .instructions
+            .splice(start..end, fragment.instructions);
+        result.exports.extend(fragment.exports);
+        result.labels.extend(fragment.labels);
+
+        result.verify()?;
+        *self = result;
+        Ok(())
+    }
+
+    /// Fail with `Unsupported` at the first instruction `supported` rejects
+    pub(crate) fn check_supported(
+        &self,
+        supported: impl Fn(&Instruction) -> bool,
+    ) -> Result<(), ProgramError> {
+        match self.instructions.iter().position(|i| !supported(i)) {
+            Some(addr) => Err(ProgramError::Unsupported {
+                addr,
+                mnemonic: self.instructions[addr].mnemonic(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether code can be moved without changing behaviour. Computed jumps
+    /// use addresses held in registers, which relocation cannot follow.
+    pub fn is_relocatable(&self) -> bool {
+        !self.instructions.iter().any(Instruction::is_computed_jump)
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Self::new(instructions)
+    }
+}
+
+/// A program any number of VMs, on any threads, can run without each
+/// having its own copy. Clones share one program; changing it through a
+/// clone, as hot reload or a pass does, copies it first unless nothing else
+/// shares it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SharedProgram(Arc<Program>);
+
+impl SharedProgram {
+    pub fn new(program: Program) -> Self {
+        Self(Arc::new(program))
+    }
+
+    /// Whether `a` and `b` share one program rather than holding equal ones
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        Arc::ptr_eq(&a.0, &b.0)
+    }
+}
+
+impl Deref for SharedProgram {
+    type Target = Program;
+
+    fn deref(&self) -> &Program {
+        &self.0
+    }
+}
+
+impl DerefMut for SharedProgram {
+    fn deref_mut(&mut self) -> &mut Program {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl From<Program> for SharedProgram {
+    fn from(program: Program) -> Self {
+        Self::new(program)
+    }
+}
+
+impl From<Vec<Instruction>> for SharedProgram {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Self::new(Program::new(instructions))
+    }
+}
+
+impl From<Arc<Program>> for SharedProgram {
+    fn from(program: Arc<Program>) -> Self {
+        Self(program)
+    }
+}
+
+/// Disassembly listing: one instruction per line, preceded by the labels and
+/// exports that point at it
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (addr, instr) in self.instructions.iter().enumerate() {
+            for label in self.labels.iter().filter(|l| l.addr == addr) {
+                writeln!(f, "{}:", label.name)?;
+            }
+            for export in self.exports.iter().filter(|e| e.addr == addr) {
+                writeln!(f, "; export {}/{}", export.name, export.arity)?;
+            }
+            if addr == self.entry && addr != 0 {
+                writeln!(f, "; entry")?;
+            }
+            match instr.target(addr) {
+                Some(target) if instr.is_relative() => {
+                    writeln!(f, "{:>4}  {}  ; {}", addr, instr, target)?
+                }
+                _ => writeln!(f, "{:>4}  {}", addr, instr)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,476 @@
+//! Value ranges for registers.
+//!
+//! `analyze` bounds the value of every register before every instruction,
+//! from the constants loaded into it, the arithmetic on it and the
+//! comparisons that branched on it, widening loops to an open bound after
+//! a few rounds and then narrowing them again from the loops' exit tests. A
+//! range never holds NaN, so a register that might is
+//! unknown. It is enough to show that a `switch` index is inside its table
+//! or that a computed jump lands inside the program, so the check can be
+//! dropped, and to catch one that never can.
+
+use crate::instruction::{Comparison, Instruction};
+use crate::prelude::*;
+use crate::program::Program;
+use crate::vm;
+
+/// Times an address may widen its state before bounds still moving are
+/// dropped
+const WIDEN_AFTER: usize = 3;
+
+/// Passes over the program recomputing each state from its predecessors,
+/// to win back bounds that widening dropped
+const NARROW_ROUNDS: usize = 2;
+
+/// Every value between `lo` and `hi`, inclusive; either may be infinite
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub lo: f64,
+    pub hi: f64,
+    /// Whether every value is a whole number
+    pub integral: bool,
+}
+
+impl Range {
+    /// Just `value`, or nothing known if it is NaN
+    pub fn point(value: f64) -> Option<Range> {
+        (!value.is_nan()).then_some(Range {
+            lo: value,
+            hi: value,
+            integral: is_integer(value),
+        })
+    }
+
+    /// The single value in this range, if there is one
+    pub fn constant(&self) -> Option<f64> {
+        (self.lo == self.hi).then_some(self.lo)
+    }
+
+    /// Whether every value is a whole number in `0..len`
+    pub fn is_index(&self, len: usize) -> bool {
+        self.integral && self.lo >= 0.0 && self.hi < len as f64
+    }
+
+    /// Whether no value is a whole number in `0..len`
+    pub fn misses(&self, len: usize) -> bool {
+        self.hi < 0.0
+            || self.lo >= len as f64
+            || self.constant().is_some_and(|v| vm::address(v).is_none())
+    }
+
+    fn join(self, other: Range) -> Range {
+        Range {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+            integral: self.integral && other.integral,
+        }
+    }
+
+    /// `self` joined with `other`, with any bound that moved made open
+    fn widen(self, other: Range) -> Range {
+        let joined = self.join(other);
+        Range {
+            lo: if joined.lo < self.lo {
+                f64::NEG_INFINITY
+            } else {
+                joined.lo
+            },
+            hi: if joined.hi > self.hi {
+                f64::INFINITY
+            } else {
+                joined.hi
+            },
+            integral: joined.integral,
+        }
+    }
+
+    /// The values in both, or `None` if there are none
+    fn meet(self, other: Range) -> Option<Range> {
+        let range = Range {
+            lo: self.lo.max(other.lo),
+            hi: self.hi.min(other.hi),
+            integral: self.integral || other.integral,
+        };
+        (range.lo <= range.hi).then_some(range)
+    }
+
+    fn add(self, other: Range) -> Option<Range> {
+        let (lo, hi) = (self.lo + other.lo, self.hi + other.hi);
+        // Opposite infinities make NaN
+        (!lo.is_nan() && !hi.is_nan()).then_some(Range {
+            lo,
+            hi,
+            integral: self.integral && other.integral,
+        })
+    }
+
+    fn neg(self) -> Range {
+        Range {
+            lo: -self.hi,
+            hi: -self.lo,
+            integral: self.integral,
+        }
+    }
+
+    fn mul(self, other: Range) -> Option<Range> {
+        let unbounded = |r: Range| r.lo.is_infinite() || r.hi.is_infinite();
+        let has_zero = |r: Range| r.lo <= 0.0 && r.hi >= 0.0;
+        // Zero times infinity makes NaN
+        if (has_zero(self) && unbounded(other)) || (has_zero(other) && unbounded(self)) {
+            return None;
+        }
+        let products = [
+            self.lo * other.lo,
+            self.lo * other.hi,
+            self.hi * other.lo,
+            self.hi * other.hi,
+        ];
+        Some(Range {
+            lo: products.into_iter().fold(f64::INFINITY, f64::min),
+            hi: products.into_iter().fold(f64::NEG_INFINITY, f64::max),
+            integral: self.integral && other.integral,
+        })
+    }
+
+    /// The values below every one of `other`'s, as far as they are known
+    fn below(self, other: Range) -> Option<Range> {
+        let hi = if self.integral && is_integer(other.hi) {
+            other.hi - 1.0
+        } else {
+            other.hi
+        };
+        self.meet(Range {
+            lo: f64::NEG_INFINITY,
+            hi,
+            integral: false,
+        })
+    }
+
+    fn above(self, other: Range) -> Option<Range> {
+        self.neg().below(other.neg()).map(Range::neg)
+    }
+
+    /// The values not below `other`'s smallest
+    fn at_least(self, other: Range) -> Option<Range> {
+        self.meet(Range {
+            lo: other.lo,
+            hi: f64::INFINITY,
+            integral: false,
+        })
+    }
+
+    fn at_most(self, other: Range) -> Option<Range> {
+        self.neg().at_least(other.neg()).map(Range::neg)
+    }
+}
+
+/// Whether `value` is finite and whole
+fn is_integer(value: f64) -> bool {
+    value.is_finite() && (value.abs() >= 9007199254740992.0 || value as i64 as f64 == value)
+}
+
+/// Any value that is not NaN
+const ANY: Range = Range {
+    lo: f64::NEG_INFINITY,
+    hi: f64::INFINITY,
+    integral: false,
+};
+
+/// Each register's range before an instruction
+type State = Vec<Option<Range>>;
+
+/// Register ranges before each instruction of a program
+#[derive(Debug, Clone)]
+pub struct Ranges {
+    states: Vec<Option<State>>,
+}
+
+impl Ranges {
+    /// The range of `reg` before the instruction at `addr`, if anything is
+    /// known about it
+    pub fn get(&self, addr: usize, reg: usize) -> Option<Range> {
+        self.states.get(addr)?.as_ref()?.get(reg).copied().flatten()
+    }
+
+    /// Whether any path reaches the instruction at `addr`
+    pub fn is_reachable(&self, addr: usize) -> bool {
+        self.states.get(addr).is_some_and(Option::is_some)
+    }
+
+    /// Whether the reachable instruction at `addr` is a `switch` whose
+    /// index is always inside its table, or a `jmpr` or `callr` that
+    /// always lands inside `program`
+    pub fn in_bounds(&self, program: &Program, addr: usize) -> bool {
+        let (src, len) = match program.instructions.get(addr) {
+            Some(Instruction::Switch { src, table, .. }) => (*src, table.len()),
+            Some(Instruction::JumpIndirect { src } | Instruction::CallIndirect { src }) => {
+                (*src, program.len())
+            }
+            _ => return false,
+        };
+        self.get(addr, src).is_some_and(|r| r.is_index(len))
+    }
+}
+
+/// Bound every register before every instruction of `program`
+pub fn analyze(program: &Program) -> Ranges {
+    let code = &program.instructions;
+    let registers = code
+        .iter()
+        .flat_map(|instr| instr.writes().into_iter().chain(instr.sources()))
+        .max()
+        .map_or(0, |r| r + 1);
+    let unknown = || vec![None; registers];
+
+    // Functions, handlers and coroutines start knowing nothing
+    let mut worklist: Vec<(usize, State)> = program
+        .entry_points()
+        .into_iter()
+        .map(|addr| (addr, unknown()))
+        .collect();
+    for (pc, instr) in code.iter().enumerate() {
+        let start = match instr {
+            Instruction::Call { .. } | Instruction::CallRel(_) | Instruction::CallArgs { .. } => {
+                instr.target(pc)
+            }
+            Instruction::TryBegin { handler, .. } => Some(*handler),
+            Instruction::MakeClosure { addr, .. } | Instruction::Spawn { addr, .. } => Some(*addr),
+            _ => None,
+        };
+        worklist.extend(start.map(|addr| (addr, unknown())));
+    }
+
+    let roots = worklist.clone();
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut visits = vec![0; code.len()];
+    while let Some((addr, state)) = worklist.pop() {
+        if addr >= code.len() {
+            continue;
+        }
+        match &mut states[addr] {
+            Some(known) => {
+                visits[addr] += 1;
+                if !join(known, &state, visits[addr] > WIDEN_AFTER) {
+                    continue;
+                }
+            }
+            slot @ None => *slot = Some(state),
+        }
+        let state = states[addr].clone().unwrap();
+        successors(code, addr, &state, &mut worklist, &unknown);
+    }
+    narrow(code, &roots, &mut states, &unknown);
+    Ranges { states }
+}
+
+/// Recompute each state from the states of its predecessors, in address
+/// order so a loop body sees its header's new state in the same round.
+/// Starting from a fixpoint, every round keeps the states sound and can
+/// only tighten them.
+fn narrow(
+    code: &[Instruction],
+    roots: &[(usize, State)],
+    states: &mut [Option<State>],
+    unknown: &dyn Fn() -> State,
+) {
+    let mut preds = vec![Vec::new(); code.len()];
+    for (addr, state) in states.iter().enumerate() {
+        if let Some(state) = state {
+            let mut edges = Vec::new();
+            successors(code, addr, state, &mut edges, unknown);
+            for (target, _) in edges {
+                if let Some(p) = preds.get_mut(target)
+                    && !p.contains(&addr)
+                {
+                    p.push(addr);
+                }
+            }
+        }
+    }
+    for _ in 0..NARROW_ROUNDS {
+        for addr in 0..code.len() {
+            if states[addr].is_none() {
+                continue;
+            }
+            let mut edges: Vec<(usize, State)> = roots
+                .iter()
+                .filter(|(root, _)| *root == addr)
+                .cloned()
+                .collect();
+            for &pred in &preds[addr] {
+                if let Some(state) = &states[pred] {
+                    successors(code, pred, state, &mut edges, unknown);
+                }
+            }
+            let mut incoming: Option<State> = None;
+            for (_, state) in edges.into_iter().filter(|(target, _)| *target == addr) {
+                match &mut incoming {
+                    Some(known) => {
+                        join(known, &state, false);
+                    }
+                    slot @ None => *slot = Some(state),
+                }
+            }
+            states[addr] = incoming;
+        }
+    }
+}
+
+/// Widen `known` to cover `state` too, reporting whether anything changed
+fn join(known: &mut State, state: &State, widen: bool) -> bool {
+    let mut changed = false;
+    for (a, &b) in known.iter_mut().zip(state) {
+        let joined = match (*a, b) {
+            (Some(a), Some(b)) if widen => Some(a.widen(b)),
+            (Some(a), Some(b)) => Some(a.join(b)),
+            _ => None,
+        };
+        changed |= joined != *a;
+        *a = joined;
+    }
+    changed
+}
+
+/// Queue where control goes after the instruction at `addr`, and in what
+/// state
+fn successors(
+    code: &[Instruction],
+    addr: usize,
+    state: &State,
+    worklist: &mut Vec<(usize, State)>,
+    unknown: &dyn Fn() -> State,
+) {
+    use Instruction::*;
+    let get = |reg: usize| state.get(reg).copied().flatten();
+    let mut after = state.clone();
+    let set = |after: &mut State, reg: usize, range: Option<Range>| {
+        if let Some(slot) = after.get_mut(reg) {
+            *slot = range;
+        }
+    };
+    let both = |a: usize, b: usize| get(a).zip(get(b));
+    let flag = Some(Range {
+        lo: 0.0,
+        hi: 1.0,
+        integral: true,
+    });
+
+    match code[addr] {
+        LoadImm { dest, value } => set(&mut after, dest, Range::point(value)),
+        LoadAddr { dest, addr } => set(&mut after, dest, Range::point(addr as f64)),
+        Mov { dest, src } => set(&mut after, dest, get(src)),
+        Add { dest, src1, src2 } => {
+            let range = both(src1, src2).and_then(|(a, b)| a.add(b));
+            set(&mut after, dest, range);
+        }
+        Sub { dest, src1, src2 } => {
+            let range = both(src1, src2).and_then(|(a, b)| a.add(b.neg()));
+            set(&mut after, dest, range);
+        }
+        Mul { dest, src1, src2 } => {
+            let range = both(src1, src2).and_then(|(a, b)| a.mul(b));
+            set(&mut after, dest, range);
+        }
+        AddImm {
+            dest,
+            src,
+            imm,
+            value,
+        } => {
+            let range = get(src)
+                .zip(Range::point(value))
+                .and_then(|(a, b)| a.add(b));
+            set(&mut after, imm, Range::point(value));
+            set(&mut after, dest, range);
+        }
+        Equal { dest, .. }
+        | LessThan { dest, .. }
+        | GreaterThan { dest, .. }
+        | Not { dest, .. }
+        | MapHas { dest, .. } => set(&mut after, dest, flag),
+        Rand { dest } => set(
+            &mut after,
+            dest,
+            Some(Range {
+                lo: 0.0,
+                hi: 1.0,
+                integral: false,
+            }),
+        ),
+        CompareJump {
+            cmp,
+            dest,
+            src1,
+            src2,
+            target,
+        } => {
+            let (a, b) = (get(src1), get(src2));
+            // Any comparison with NaN is false, so values that pass are
+            // never NaN
+            let (a_any, b_any) = (a.unwrap_or(ANY), b.unwrap_or(ANY));
+            let passed = match cmp {
+                Comparison::Equal => a_any.meet(b_any).map(|r| (r, r)),
+                Comparison::LessThan => a_any.below(b_any).zip(b_any.above(a_any)),
+                Comparison::GreaterThan => a_any.above(b_any).zip(b_any.below(a_any)),
+            };
+            if let Some((a, b)) = passed {
+                let mut next = after.clone();
+                set(&mut next, src1, Some(a));
+                set(&mut next, src2, Some(b));
+                set(&mut next, dest, Range::point(1.0));
+                worklist.push((addr + 1, next));
+            }
+            // Failing says nothing when either side may be NaN
+            let failed = match (cmp, a, b) {
+                (Comparison::LessThan, Some(a), Some(b)) => a.at_least(b).zip(b.at_most(a)),
+                (Comparison::GreaterThan, Some(a), Some(b)) => a.at_most(b).zip(b.at_least(a)),
+                (_, a, b) => Some((a.unwrap_or(ANY), b.unwrap_or(ANY))),
+            };
+            if let Some((a_failed, b_failed)) = failed {
+                let mut next = after;
+                let keep = |known: Option<Range>, failed| known.and(Some(failed));
+                set(&mut next, src1, keep(a, a_failed));
+                set(&mut next, src2, keep(b, b_failed));
+                set(&mut next, dest, Range::point(0.0));
+                worklist.push((target, next));
+            }
+            return;
+        }
+        JumpIndirect { src } | CallIndirect { src } => {
+            // The target is wherever the value says, or anywhere
+            let call = matches!(code[addr], CallIndirect { .. });
+            let entry = if call { unknown() } else { after.clone() };
+            let last = code.len() as f64 - 1.0;
+            let targets = match get(src) {
+                Some(r) if r.misses(code.len()) => 0..0,
+                Some(r) => r.lo.max(0.0) as usize..r.hi.min(last) as usize + 1,
+                None => 0..code.len(),
+            };
+            for target in targets {
+                worklist.push((target, entry.clone()));
+            }
+            if call && addr + 1 < code.len() {
+                worklist.push((addr + 1, unknown()));
+            }
+            return;
+        }
+        ref instr => {
+            for reg in instr.writes() {
+                set(&mut after, reg, None);
+            }
+        }
+    }
+
+    let instr = &code[addr];
+    if instr.is_call() {
+        // The callee may change any register
+        after = unknown();
+    } else if !matches!(instr, TryBegin { .. }) {
+        for target in instr.targets(addr) {
+            worklist.push((target, after.clone()));
+        }
+    }
+    if !instr.is_terminator() && addr + 1 < code.len() {
+        worklist.push((addr + 1, after));
+    }
+}
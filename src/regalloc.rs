@@ -0,0 +1,401 @@
+//! Register liveness and allocation.
+//!
+//! `liveness` finds the registers holding a value that some later
+//! instruction reads. `allocate` uses it to renumber a program into a
+//! smaller register file by linear scan: each register's live interval,
+//! from the first address it is live at to the last, gets a register that
+//! no overlapping interval has. When none is free, the interval ending last
+//! is spilled to a VM variable, loaded into a scratch register before each
+//! instruction that reads it and stored after each that writes it.
+//!
+//! Calls, coroutines, handlers and computed jumps are not followed, since
+//! they share the register file with code elsewhere.
+
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+use core::error::Error;
+use core::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum AllocError {
+    /// The instruction at `addr` passes control somewhere liveness cannot
+    /// follow
+    Unsupported { addr: usize, mnemonic: &'static str },
+    /// Too few registers to hold the values live at once, even spilling
+    TooFewRegisters(usize),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::Unsupported { addr, mnemonic } => write!(
+                f,
+                "Instruction {} ({}) is not supported by the register allocator",
+                addr, mnemonic
+            ),
+            AllocError::TooFewRegisters(registers) => {
+                write!(f, "The program does not fit in {} registers", registers)
+            }
+        }
+    }
+}
+
+impl Error for AllocError {}
+
+/// Which registers are live before each instruction
+#[derive(Debug, Clone)]
+pub struct Liveness {
+    live: Vec<Vec<bool>>,
+}
+
+impl Liveness {
+    /// Whether `reg` holds a value that may still be read when the
+    /// instruction at `addr` is about to run
+    pub fn is_live(&self, addr: usize, reg: usize) -> bool {
+        self.live
+            .get(addr)
+            .and_then(|regs| regs.get(reg))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// The registers live before the instruction at `addr`
+    pub fn live_at(&self, addr: usize) -> Vec<usize> {
+        self.live.get(addr).map_or(Vec::new(), |regs| {
+            (0..regs.len()).filter(|&reg| regs[reg]).collect()
+        })
+    }
+}
+
+/// Where a register of the original program ended up
+#[derive(Debug, Clone, PartialEq)]
+pub enum Home {
+    Register(usize),
+    /// Spilled to the VM variable with this name
+    Variable(String),
+}
+
+/// A program renumbered to fit a register file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub program: Program,
+    /// Registers the rewritten program uses
+    pub registers: usize,
+    /// Where each register of the original program lives now, `None` for
+    /// registers it never used
+    pub homes: Vec<Option<Home>>,
+}
+
+/// Find the registers live before each instruction of `program`, where
+/// `live_at_exit` are read after it halts
+pub fn liveness(program: &Program, live_at_exit: &[usize]) -> Liveness {
+    let code = &program.instructions;
+    let registers = register_count(program, live_at_exit);
+    let mut live = vec![vec![false; registers]; code.len()];
+    let mut at_exit = vec![false; registers];
+    for &reg in live_at_exit {
+        at_exit[reg] = true;
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..code.len()).rev() {
+            let instr = &code[pc];
+            let mut out = vec![false; registers];
+            let mut exits = matches!(instr, Instruction::Halt | Instruction::Return);
+            let mut next = instr.targets(pc);
+            if !instr.is_terminator() {
+                next.push(pc + 1);
+            }
+            for addr in next {
+                match live.get(addr) {
+                    Some(regs) => out.iter_mut().zip(regs).for_each(|(o, &l)| *o |= l),
+                    None => exits = true,
+                }
+            }
+            if exits {
+                out.iter_mut().zip(&at_exit).for_each(|(o, &l)| *o |= l);
+            }
+            for reg in instr.writes() {
+                out[reg] = false;
+            }
+            for reg in instr.sources() {
+                out[reg] = true;
+            }
+            if out != live[pc] {
+                live[pc] = out;
+                changed = true;
+            }
+        }
+    }
+    Liveness { live }
+}
+
+/// Renumber the registers of `program` to fit in `registers`, keeping
+/// those in `live_at_exit` readable after it halts, at their new homes.
+/// Registers live where the program starts keep their numbers, since they
+/// hold its inputs.
+pub fn allocate(
+    program: &Program,
+    registers: usize,
+    live_at_exit: &[usize],
+) -> Result<Allocation, AllocError> {
+    if let Some((addr, instr)) = program.instructions.iter().enumerate().find(|(_, i)| {
+        (i.is_call() && !matches!(i, Instruction::CallHost { .. }))
+            || i.is_computed_jump()
+            || matches!(
+                i,
+                Instruction::TryBegin { .. }
+                    | Instruction::Spawn { .. }
+                    | Instruction::MakeClosure { .. }
+            )
+    }) {
+        return Err(AllocError::Unsupported {
+            addr,
+            mnemonic: instr.mnemonic(),
+        });
+    }
+    let relative = program.instructions.iter().any(Instruction::is_relative);
+    let mut program = program.clone();
+    program.make_absolute();
+
+    let intervals = intervals(&program, live_at_exit);
+    let homes = match scan(&intervals, registers, false) {
+        Some(homes) => homes,
+        None => {
+            // Keep registers back to load spilled values into
+            let scratch = program
+                .instructions
+                .iter()
+                .map(|i| operands(i).len())
+                .max()
+                .unwrap_or(0);
+            registers
+                .checked_sub(scratch)
+                .and_then(|available| scan(&intervals, available, true))
+                .ok_or(AllocError::TooFewRegisters(registers))?
+        }
+    };
+    let homes: Vec<Option<Home>> = homes
+        .into_iter()
+        .enumerate()
+        .map(|(reg, home)| {
+            home.map(|home| home.map_or_else(|| Home::Variable(spill_name(reg)), Home::Register))
+        })
+        .collect();
+
+    let mut allocation = rewrite(program, &homes);
+    if relative {
+        allocation.program.make_relative();
+    }
+    Ok(allocation)
+}
+
+fn register_count(program: &Program, live_at_exit: &[usize]) -> usize {
+    program
+        .instructions
+        .iter()
+        .flat_map(operands)
+        .chain(live_at_exit.iter().copied())
+        .max()
+        .map_or(0, |r| r + 1)
+}
+
+/// The registers `instr` reads or writes, each once
+fn operands(instr: &Instruction) -> Vec<usize> {
+    let mut regs = instr.sources();
+    regs.extend(instr.writes());
+    distinct(regs)
+}
+
+fn distinct(mut regs: Vec<usize>) -> Vec<usize> {
+    regs.sort_unstable();
+    regs.dedup();
+    regs
+}
+
+fn spill_name(reg: usize) -> String {
+    format!("$spill{}", reg)
+}
+
+/// The addresses a register is live or written at, first to last
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    reg: usize,
+    start: usize,
+    end: usize,
+    /// Holds an input, so must keep its number
+    pinned: bool,
+    /// Written by a jump, so there is nowhere to store it after
+    spillable: bool,
+}
+
+fn intervals(program: &Program, live_at_exit: &[usize]) -> Vec<Interval> {
+    let code = &program.instructions;
+    let liveness = liveness(program, live_at_exit);
+    let entries = program.entry_points();
+    let mut intervals: Vec<Option<Interval>> = vec![None; register_count(program, live_at_exit)];
+    let mut extend = |reg: usize, addr: usize| {
+        let interval = intervals[reg].get_or_insert(Interval {
+            reg,
+            start: addr,
+            end: addr,
+            pinned: false,
+            spillable: true,
+        });
+        interval.start = interval.start.min(addr);
+        interval.end = interval.end.max(addr);
+    };
+    for (pc, instr) in code.iter().enumerate() {
+        for reg in liveness.live_at(pc).into_iter().chain(instr.writes()) {
+            extend(reg, pc);
+        }
+    }
+    for &reg in live_at_exit {
+        extend(reg, code.len());
+    }
+    for interval in intervals.iter_mut().flatten() {
+        let reg = interval.reg;
+        interval.pinned = entries.iter().any(|&e| liveness.is_live(e, reg));
+        interval.spillable = !interval.pinned
+            && !code
+                .iter()
+                .any(|i| matches!(i, Instruction::CompareJump { dest, .. } if *dest == reg));
+    }
+    let mut intervals: Vec<Interval> = intervals.into_iter().flatten().collect();
+    intervals.sort_by_key(|i| (i.start, !i.pinned));
+    intervals
+}
+
+/// Give each interval a register below `available`, or `Some(None)` if it
+/// is spilled; `None` if that fails
+fn scan(
+    intervals: &[Interval],
+    available: usize,
+    spill: bool,
+) -> Option<Vec<Option<Option<usize>>>> {
+    let count = intervals.iter().map(|i| i.reg + 1).max().unwrap_or(0);
+    let mut homes: Vec<Option<Option<usize>>> = vec![None; count];
+    // Intervals holding a register, with the register
+    let mut active: Vec<(Interval, usize)> = Vec::new();
+    for &interval in intervals {
+        active.retain(|(other, _)| other.end >= interval.start);
+        let overlaps =
+            |other: &Interval| other.start <= interval.end && interval.start <= other.end;
+        let taken = |reg: usize| {
+            active.iter().any(|&(_, r)| r == reg)
+                || intervals
+                    .iter()
+                    .any(|other| other.pinned && other.reg == reg && overlaps(other))
+        };
+        // No other interval takes a pinned one's register while it is live
+        let reg = if interval.pinned {
+            Some(interval.reg).filter(|&reg| reg < available)
+        } else {
+            (0..available).find(|&reg| !taken(reg))
+        };
+        if let Some(reg) = reg {
+            active.push((interval, reg));
+            homes[interval.reg] = Some(Some(reg));
+            continue;
+        }
+        if !spill || interval.pinned {
+            return None;
+        }
+        // Spill whichever of the active intervals and this one ends last. An
+        // active one ending later covers this one, so its register is free
+        // of pinned intervals here too.
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (other, _))| other.spillable)
+            .max_by_key(|(_, (other, _))| other.end)
+            .filter(|(_, (other, _))| other.end > interval.end || !interval.spillable)
+            .filter(|(_, (other, _))| other.end >= interval.end);
+        match victim {
+            Some((i, &(other, reg))) => {
+                homes[other.reg] = Some(None);
+                active[i] = (interval, reg);
+                homes[interval.reg] = Some(Some(reg));
+            }
+            _ if interval.spillable => homes[interval.reg] = Some(None),
+            _ => return None,
+        }
+    }
+    Some(homes)
+}
+
+/// `program` with every register moved to its home
+fn rewrite(program: Program, homes: &[Option<Home>]) -> Allocation {
+    let home = |reg: usize| homes.get(reg).cloned().flatten();
+    let mut registers = homes
+        .iter()
+        .filter_map(|h| match h {
+            Some(Home::Register(reg)) => Some(reg + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    // Spilled registers get scratch registers above every home
+    let scratch_base = registers;
+    let len = program.len();
+    let mut new_addr = Vec::with_capacity(len);
+    let mut at = 0;
+    for instr in &program.instructions {
+        new_addr.push(at);
+        let spilled = operands(instr)
+            .into_iter()
+            .filter(|&r| matches!(home(r), Some(Home::Variable(_))));
+        let loads = spilled
+            .clone()
+            .filter(|r| instr.sources().contains(r))
+            .count();
+        let stores = spilled.filter(|r| instr.writes().contains(r)).count();
+        at += 1 + loads + stores;
+    }
+    let new_len = at;
+
+    let mut program = program;
+    program.relocate(|addr| {
+        new_addr
+            .get(addr)
+            .copied()
+            .unwrap_or_else(|| (addr - len).saturating_add(new_len))
+    });
+    let mut code = Vec::with_capacity(new_len);
+    for instr in &program.instructions {
+        let mut scratch = Vec::new();
+        for reg in operands(instr) {
+            if let Some(Home::Variable(_)) = home(reg) {
+                scratch.push((reg, scratch_base + scratch.len()));
+            }
+        }
+        registers = registers.max(scratch_base + scratch.len());
+        let temp = |reg: usize| scratch.iter().find(|&&(r, _)| r == reg).map(|&(_, t)| t);
+        for reg in distinct(instr.sources()) {
+            if let (Some(Home::Variable(var)), Some(dest)) = (home(reg), temp(reg)) {
+                code.push(Instruction::Load { dest, var });
+            }
+        }
+        let mut renamed = instr.clone();
+        renamed.rename_registers(|reg| match home(reg) {
+            Some(Home::Register(r)) => r,
+            _ => temp(reg).unwrap_or(reg),
+        });
+        code.push(renamed);
+        for reg in distinct(instr.writes()) {
+            if let (Some(Home::Variable(var)), Some(src)) = (home(reg), temp(reg)) {
+                code.push(Instruction::Store { src, var });
+            }
+        }
+    }
+    program.instructions = code;
+    Allocation {
+        program,
+        registers,
+        homes: homes.to_vec(),
+    }
+}
@@ -0,0 +1,130 @@
+//! Swapping the code of a running VM.
+//!
+//! `VM::replace_program` installs a new program while keeping registers,
+//! variables, the heap and coroutines, so a script can be edited while it
+//! runs. Every code address the VM holds is carried over through an
+//! `AddressMap`: the pc, return addresses, handlers, the contexts of
+//! coroutines that may still run, and closures. Addresses a `lea` loaded
+//! into a register are plain numbers and keep their old value.
+
+use crate::program::{Program, ProgramError, SharedProgram};
+use crate::vm::VM;
+use alloc::collections::BTreeMap;
+use core::error::Error;
+use core::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum ReloadError {
+    Program(ProgramError),
+    /// The VM holds this old address, and the map has no place for it in
+    /// the new program
+    Unmapped(usize),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::Program(e) => write!(f, "New program is invalid: {}", e),
+            ReloadError::Unmapped(addr) => {
+                write!(f, "Address {} has no place in the new program", addr)
+            }
+        }
+    }
+}
+
+impl Error for ReloadError {}
+
+impl From<ProgramError> for ReloadError {
+    fn from(e: ProgramError) -> Self {
+        ReloadError::Program(e)
+    }
+}
+
+/// Where each address of an old program lands in its replacement
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddressMap {
+    map: BTreeMap<usize, usize>,
+}
+
+impl AddressMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map every address of `old` to the same offset from the same label or
+    /// export in `new`, where that is still inside `new`. The end of `old`,
+    /// where a finished VM stops, maps to the end of `new`.
+    pub fn by_symbol(old: &Program, new: &Program) -> Self {
+        let mut map = Self::new();
+        for addr in 0..old.len() {
+            if let Some((name, offset)) = old.symbolize(addr)
+                && let Some(base) = new.find_symbol(name)
+                && base + offset < new.len()
+            {
+                map.insert(addr, base + offset);
+            }
+        }
+        map.insert(old.len(), new.len());
+        map
+    }
+
+    pub fn insert(&mut self, old: usize, new: usize) -> &mut Self {
+        self.map.insert(old, new);
+        self
+    }
+
+    pub fn get(&self, old: usize) -> Option<usize> {
+        self.map.get(&old).copied()
+    }
+}
+
+impl VM {
+    /// Run `program` from now on, moving every code address the VM holds
+    /// through `remap`. Fails, leaving the VM unchanged, if `program` does not
+    /// verify or any of those addresses has no place in it.
+    pub fn replace_program(
+        &mut self,
+        program: impl Into<SharedProgram>,
+        remap: AddressMap,
+    ) -> Result<(), ReloadError> {
+        let program = program.into();
+        program.verify()?;
+        let len = program.len();
+        let map = |addr: usize| remap.get(addr).filter(|&new| new <= len);
+
+        let mut unmapped = None;
+        self.for_each_address(|addr| {
+            if map(*addr).is_none() {
+                unmapped.get_or_insert(*addr);
+            }
+        });
+        if let Some(addr) = unmapped {
+            return Err(ReloadError::Unmapped(addr));
+        }
+        self.for_each_address(|addr| *addr = map(*addr).unwrap_or(*addr));
+        self.program = program;
+        Ok(())
+    }
+
+    fn for_each_address(&mut self, mut f: impl FnMut(&mut usize)) {
+        f(&mut self.pc);
+        for frame in &mut self.call_stack {
+            f(&mut frame.return_address);
+        }
+        for handler in &mut self.handlers {
+            f(&mut handler.addr);
+        }
+        for coroutine in self.coroutines.live_mut() {
+            f(&mut coroutine.pc);
+            for frame in &mut coroutine.call_stack {
+                f(&mut frame.return_address);
+            }
+            for handler in &mut coroutine.handlers {
+                f(&mut handler.addr);
+            }
+        }
+        for addr in self.heap.closure_addrs_mut() {
+            f(addr);
+        }
+    }
+}
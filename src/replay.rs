@@ -0,0 +1,146 @@
+//! Deterministic replay of a recorded run.
+//!
+//! While recording, a VM logs every value that reaches the program from
+//! outside: host function results, whether from a registered function or
+//! `VM::complete_host_call`, and messages taken by `recv`. Time and console
+//! input reach programs as host calls, and `rand` draws from the generator
+//! whose state the starting snapshot holds, so the log and that snapshot
+//! are enough for `VM::replay` to run the program again exactly, with no
+//! host functions registered and no messages delivered.
+
+use crate::host::HostCall;
+use crate::prelude::*;
+use crate::vm::{VM, VmError, VmSnapshot};
+use alloc::collections::VecDeque;
+
+/// A value the program received from outside the VM
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Input {
+    /// A `callhost` and what the host answered; an `Err` is the message of
+    /// the `VmError::HostFunction` it raised
+    HostCall {
+        call: HostCall,
+        result: Result<f64, String>,
+    },
+    /// A value taken from the inbox by `recv`
+    Message(f64),
+}
+
+/// The state a recording started from and the inputs it received, in order
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReplayLog {
+    pub start: VmSnapshot,
+    pub inputs: Vec<Input>,
+}
+
+pub(crate) enum Replay {
+    Recording(Box<ReplayLog>),
+    /// Inputs not yet consumed
+    Replaying(VecDeque<Input>),
+}
+
+impl VM {
+    /// Start logging inputs from the current state, discarding any
+    /// recording already in progress
+    pub fn start_recording(&mut self) {
+        self.replay = Some(Replay::Recording(Box::new(ReplayLog {
+            start: self.snapshot(),
+            inputs: Vec::new(),
+        })));
+    }
+
+    /// Stop recording and return the log, if recording
+    pub fn take_recording(&mut self) -> Option<ReplayLog> {
+        match self.replay.take() {
+            Some(Replay::Recording(log)) => Some(*log),
+            other => {
+                self.replay = other;
+                None
+            }
+        }
+    }
+
+    /// Restore the state `log` started from and run, feeding the program
+    /// the recorded inputs instead of calling the host or reading the inbox.
+    /// Fails with `VmError::ReplayDiverged` if the program asks for an input
+    /// other than the next one recorded, or halts with inputs left over.
+    pub fn replay(&mut self, log: &ReplayLog) -> Result<(), VmError> {
+        self.restore(&log.start);
+        self.replay = Some(Replay::Replaying(log.inputs.iter().cloned().collect()));
+        let result = self.run();
+        let left = match self.replay.take() {
+            Some(Replay::Replaying(inputs)) => inputs.len(),
+            _ => 0,
+        };
+        result?;
+        if left > 0 {
+            return Err(VmError::ReplayDiverged(format!(
+                "{} recorded inputs were not used",
+                left
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_replaying(&self) -> bool {
+        matches!(self.replay, Some(Replay::Replaying(_)))
+    }
+
+    /// The recorded result of `call`, once the log runs out `None`
+    pub(crate) fn replay_host_call(&mut self, call: &HostCall) -> Option<Result<f64, VmError>> {
+        let Some(Replay::Replaying(inputs)) = &mut self.replay else {
+            return None;
+        };
+        Some(match inputs.pop_front()? {
+            Input::HostCall {
+                call: recorded,
+                result,
+            } if recorded == *call => result.map_err(|message| VmError::HostFunction {
+                name: call.name.clone(),
+                message,
+            }),
+            input => Err(diverged(&input, &format!("host call to '{}'", call.name))),
+        })
+    }
+
+    pub(crate) fn record_host_call(&mut self, call: &HostCall, result: &Result<f64, VmError>) {
+        if let Some(Replay::Recording(log)) = &mut self.replay {
+            let result = match result {
+                Ok(v) => Ok(*v),
+                Err(VmError::HostFunction { message, .. }) => Err(message.clone()),
+                Err(e) => Err(e.to_string()),
+            };
+            log.inputs.push(Input::HostCall {
+                call: call.clone(),
+                result,
+            });
+        }
+    }
+
+    /// The recorded message for a `recv`, once the log runs out `None`
+    pub(crate) fn replay_message(&mut self) -> Option<Result<f64, VmError>> {
+        let Some(Replay::Replaying(inputs)) = &mut self.replay else {
+            return None;
+        };
+        Some(match inputs.pop_front()? {
+            Input::Message(value) => Ok(value),
+            input => Err(diverged(&input, "message")),
+        })
+    }
+
+    pub(crate) fn record_message(&mut self, value: f64) {
+        if let Some(Replay::Recording(log)) = &mut self.replay {
+            log.inputs.push(Input::Message(value));
+        }
+    }
+}
+
+fn diverged(recorded: &Input, wanted: &str) -> VmError {
+    let recorded = match recorded {
+        Input::HostCall { call, .. } => format!("host call to '{}'", call.name),
+        Input::Message(_) => "message".to_string(),
+    };
+    VmError::ReplayDiverged(format!("expected a {}, recorded a {}", wanted, recorded))
+}
@@ -0,0 +1,50 @@
+//! The pseudo-random generator behind `rand` and the stdlib's `random`.
+//!
+//! xorshift64* seeded through splitmix64, so every seed, zero included,
+//! gives a well-mixed nonzero state. Not suitable for cryptography.
+
+/// A seedable generator whose sequence depends only on its seed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rng {
+    state: u64,
+    /// Values drawn so far
+    draws: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        Self {
+            state: if z == 0 { 1 } else { z },
+            draws: 0,
+        }
+    }
+
+    /// The next value, uniform in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.draws += 1;
+        let bits = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (bits >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn draws(&self) -> u64 {
+        self.draws
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
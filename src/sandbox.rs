@@ -0,0 +1,99 @@
+//! Capabilities a program is granted, for running code that is not trusted.
+//!
+//! A `SandboxPolicy` in `VmConfig` is checked as each instruction runs, on
+//! top of whatever verification the program passed when it was loaded. An
+//! instruction that needs a capability the policy withholds fails with
+//! `VmError::PermissionDenied` before it has any effect, and programs cannot
+//! catch the error.
+
+use crate::HashSet;
+use crate::prelude::*;
+#[cfg(feature = "std")]
+use crate::stdlib;
+use crate::vm::VmError;
+
+/// What a program may do. The default allows everything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    /// Whether `print` may write to stdout
+    pub stdout: bool,
+    /// Whether the stdlib functions that read stdin may be called
+    pub stdin: bool,
+    /// Names of the host functions `callhost` may call, or `None` for any
+    pub host_functions: Option<HashSet<String>>,
+    /// Live heap objects at which further allocations are refused
+    pub max_heap_objects: Option<usize>,
+    /// Call frames at which further calls are refused
+    pub max_call_depth: Option<usize>,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        Self {
+            stdout: true,
+            stdin: true,
+            host_functions: None,
+            max_heap_objects: None,
+            max_call_depth: None,
+        }
+    }
+}
+
+impl SandboxPolicy {
+    /// A policy granting no I/O and no host functions, leaving the caps unset
+    pub fn deny_all() -> Self {
+        Self {
+            stdout: false,
+            stdin: false,
+            host_functions: Some(HashSet::new()),
+            ..Self::default()
+        }
+    }
+
+    /// Whether the policy allows everything, so nothing needs checking
+    pub fn is_unrestricted(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(crate) fn check_stdout(&self) -> Result<(), VmError> {
+        if self.stdout {
+            Ok(())
+        } else {
+            Err(VmError::PermissionDenied("stdout".to_string()))
+        }
+    }
+
+    pub(crate) fn check_host_function(&self, name: &str) -> Result<(), VmError> {
+        #[cfg(feature = "std")]
+        if !self.stdin && stdlib::READS_STDIN.contains(&name) {
+            return Err(VmError::PermissionDenied("stdin".to_string()));
+        }
+        match &self.host_functions {
+            Some(allowed) if !allowed.contains(name) => Err(VmError::PermissionDenied(format!(
+                "host function '{}'",
+                name
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_heap(&self, live: usize) -> Result<(), VmError> {
+        match self.max_heap_objects {
+            Some(max) if live >= max => Err(VmError::PermissionDenied(format!(
+                "more than {} heap objects",
+                max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_call_depth(&self, depth: usize) -> Result<(), VmError> {
+        match self.max_call_depth {
+            Some(max) if depth >= max => Err(VmError::PermissionDenied(format!(
+                "more than {} call frames",
+                max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Time-sliced interleaving of several VMs on one thread.
+//!
+//! Each tick gives every runnable task one slice of at most `budget`
+//! instructions, in spawn order, by driving `VM::step`. A task is preempted
+//! when its budget runs out, so a script that never halts cannot hold up
+//! the others. The limits in a task's `VmConfig` do not apply.
+
+use crate::prelude::*;
+use crate::vm::{VM, VmError};
+use core::error::Error;
+use core::fmt;
+
+#[derive(Debug)]
+pub enum TaskState {
+    /// Runs in the next tick
+    Ready,
+    /// Skipped by ticks until resumed
+    Paused,
+    Halted,
+    Killed,
+    /// Stopped by an error its program did not catch
+    Failed(VmError),
+}
+
+/// How much time a task has had
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Instructions executed in this scheduler
+    pub steps: u64,
+    /// Slices the task was given
+    pub slices: u64,
+    /// Slices that ended because the budget ran out
+    pub preempted: u64,
+}
+
+#[derive(Debug)]
+pub enum SchedulerError {
+    UnknownTask(usize),
+    /// The task has halted, failed or been killed
+    Finished(usize),
+}
+
+impl fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedulerError::UnknownTask(id) => write!(f, "No task with id {}", id),
+            SchedulerError::Finished(id) => write!(f, "Task {} has already finished", id),
+        }
+    }
+}
+
+impl Error for SchedulerError {}
+
+struct Task {
+    vm: VM,
+    state: TaskState,
+    stats: TaskStats,
+}
+
+/// Round-robin scheduler for many small programs
+pub struct Scheduler {
+    tasks: Vec<Task>,
+    /// Instructions per slice
+    budget: u64,
+    ticks: u64,
+}
+
+impl Scheduler {
+    /// A scheduler giving each task up to `budget` instructions per tick, at
+    /// least one
+    pub fn new(budget: u64) -> Self {
+        Self {
+            tasks: Vec::new(),
+            budget: budget.max(1),
+            ticks: 0,
+        }
+    }
+
+    /// Add `vm` as a ready task, returning its id
+    pub fn spawn(&mut self, vm: VM) -> usize {
+        self.tasks.push(Task {
+            vm,
+            state: TaskState::Ready,
+            stats: TaskStats::default(),
+        });
+        self.tasks.len() - 1
+    }
+
+    pub fn vm(&self, id: usize) -> Option<&VM> {
+        self.tasks.get(id).map(|task| &task.vm)
+    }
+
+    pub fn vm_mut(&mut self, id: usize) -> Option<&mut VM> {
+        self.tasks.get_mut(id).map(|task| &mut task.vm)
+    }
+
+    pub fn state(&self, id: usize) -> Option<&TaskState> {
+        self.tasks.get(id).map(|task| &task.state)
+    }
+
+    pub fn stats(&self, id: usize) -> Option<TaskStats> {
+        self.tasks.get(id).map(|task| task.stats)
+    }
+
+    /// Ticks run so far
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn pause(&mut self, id: usize) -> Result<(), SchedulerError> {
+        self.set_state(id, TaskState::Paused)
+    }
+
+    pub fn resume(&mut self, id: usize) -> Result<(), SchedulerError> {
+        self.set_state(id, TaskState::Ready)
+    }
+
+    /// Stop a task for good; its VM stays available for inspection
+    pub fn kill(&mut self, id: usize) -> Result<(), SchedulerError> {
+        self.set_state(id, TaskState::Killed)
+    }
+
+    fn set_state(&mut self, id: usize, state: TaskState) -> Result<(), SchedulerError> {
+        let task = self
+            .tasks
+            .get_mut(id)
+            .ok_or(SchedulerError::UnknownTask(id))?;
+        match task.state {
+            TaskState::Ready | TaskState::Paused => {
+                task.state = state;
+                Ok(())
+            }
+            _ => Err(SchedulerError::Finished(id)),
+        }
+    }
+
+    /// Give every ready task one slice, returning how many ran
+    pub fn tick(&mut self) -> usize {
+        let budget = self.budget;
+        let mut ran = 0;
+        for task in &mut self.tasks {
+            if matches!(task.state, TaskState::Ready) {
+                task.run_slice(budget);
+                ran += 1;
+            }
+        }
+        self.ticks += 1;
+        ran
+    }
+
+    /// Tick until no task is ready, or every ready task is waiting on a
+    /// channel or a host call
+    pub fn run(&mut self) {
+        let total = |s: &Self| s.tasks.iter().map(|task| task.stats.steps).sum::<u64>();
+        loop {
+            let before = total(self);
+            if self.tick() == 0 || total(self) == before {
+                return;
+            }
+        }
+    }
+
+    /// Jain's fairness index over the steps of tasks that have run: 1 when
+    /// every task has had the same number of instructions, approaching
+    /// `1 / n` as one task takes all of them
+    pub fn fairness(&self) -> f64 {
+        let steps: Vec<f64> = self
+            .tasks
+            .iter()
+            .filter(|task| task.stats.slices > 0)
+            .map(|task| task.stats.steps as f64)
+            .collect();
+        let sum: f64 = steps.iter().sum();
+        let squares: f64 = steps.iter().map(|s| s * s).sum();
+        if squares == 0.0 {
+            1.0
+        } else {
+            sum * sum / (steps.len() as f64 * squares)
+        }
+    }
+}
+
+impl Task {
+    fn run_slice(&mut self, budget: u64) {
+        self.stats.slices += 1;
+        for _ in 0..budget {
+            if self.vm.is_halted() {
+                self.state = TaskState::Halted;
+                return;
+            }
+            let before = self.vm.steps;
+            let result = self.vm.step();
+            self.stats.steps += self.vm.steps - before;
+            match result {
+                Ok(()) => {}
+                // Waiting on something outside the VM; try again next tick
+                Err(VmError::Blocked | VmError::HostCallPending(_)) => return,
+                Err(error) => {
+                    self.state = TaskState::Failed(error);
+                    return;
+                }
+            }
+        }
+        if self.vm.is_halted() {
+            self.state = TaskState::Halted;
+        } else {
+            self.stats.preempted += 1;
+        }
+    }
+}
@@ -0,0 +1,464 @@
+//! A stack machine, as a second execution model beside the register `VM`.
+//!
+//! Instructions take their operands from the top of a value stack and push
+//! their results back, so programs need no register allocation. Values are
+//! any `Number`, such as `f64` or a wrapping `i64`. The two machines share
+//! `VmError`, the step limit, timeout and cancellation of `VmConfig` and
+//! the call depth cap of its sandbox, and the `Machine` trait: a
+//! `Hook<StackVM<T>>` observes a stack machine as a `Hook` does a `VM`.
+//! Other `VmConfig` settings do not apply here.
+
+use crate::HashMap;
+use crate::hook::{self, Hook};
+use crate::machine::Machine;
+use crate::prelude::*;
+use crate::vm::{Deadline, Output, RunLimits, VmConfig, VmError};
+use core::fmt;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+/// A value a `StackVM` computes with
+pub trait Number: Copy + PartialOrd + fmt::Debug + fmt::Display + Send + 'static {
+    const ZERO: Self;
+    const ONE: Self;
+
+    fn add(self, other: Self) -> Self;
+    fn sub(self, other: Self) -> Self;
+    fn mul(self, other: Self) -> Self;
+    /// `None` where the quotient does not exist, as for an integer divided
+    /// by zero
+    fn div(self, other: Self) -> Option<Self>;
+    /// The value as `print` writes it
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! float {
+    ($t:ty) => {
+        impl Number for $t {
+            const ZERO: Self = 0.0;
+            const ONE: Self = 1.0;
+
+            fn add(self, other: Self) -> Self {
+                self + other
+            }
+            fn sub(self, other: Self) -> Self {
+                self - other
+            }
+            fn mul(self, other: Self) -> Self {
+                self * other
+            }
+            fn div(self, other: Self) -> Option<Self> {
+                Some(self / other)
+            }
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        }
+    };
+}
+
+/// Integers wrap on overflow rather than stopping the program
+macro_rules! integer {
+    ($t:ty) => {
+        impl Number for $t {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+
+            fn add(self, other: Self) -> Self {
+                self.wrapping_add(other)
+            }
+            fn sub(self, other: Self) -> Self {
+                self.wrapping_sub(other)
+            }
+            fn mul(self, other: Self) -> Self {
+                self.wrapping_mul(other)
+            }
+            fn div(self, other: Self) -> Option<Self> {
+                self.checked_div(other)
+            }
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+        }
+    };
+}
+
+float!(f64);
+float!(f32);
+integer!(i64);
+integer!(i32);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StackInstruction<T> {
+    Push(T),
+    Pop,
+    /// Push a copy of the top value
+    Dup,
+    /// Exchange the top two values
+    Swap,
+    /// Pop `b`, then `a`, and push `a + b`; likewise for the other
+    /// arithmetic and comparisons
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// Push one if equal, zero otherwise
+    Equal,
+    LessThan,
+    GreaterThan,
+    /// Push one for zero, zero for anything else
+    Not,
+    Jump(usize),
+    /// Pop a value and jump if it is zero
+    JumpIfZero(usize),
+    Call(usize),
+    Return,
+    /// Push the value of a variable
+    Load(String),
+    /// Pop a value into a variable
+    Store(String),
+    /// Pop a value and write it to the output
+    Print,
+    Halt,
+}
+
+impl<T: fmt::Display> fmt::Display for StackInstruction<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackInstruction::Push(value) => write!(f, "push {}", value),
+            StackInstruction::Pop => write!(f, "pop"),
+            StackInstruction::Dup => write!(f, "dup"),
+            StackInstruction::Swap => write!(f, "swap"),
+            StackInstruction::Add => write!(f, "add"),
+            StackInstruction::Sub => write!(f, "sub"),
+            StackInstruction::Mul => write!(f, "mul"),
+            StackInstruction::Div => write!(f, "div"),
+            StackInstruction::Equal => write!(f, "eq"),
+            StackInstruction::LessThan => write!(f, "lt"),
+            StackInstruction::GreaterThan => write!(f, "gt"),
+            StackInstruction::Not => write!(f, "not"),
+            StackInstruction::Jump(target) => write!(f, "jmp {}", target),
+            StackInstruction::JumpIfZero(target) => write!(f, "jz {}", target),
+            StackInstruction::Call(addr) => write!(f, "call {}", addr),
+            StackInstruction::Return => write!(f, "ret"),
+            StackInstruction::Load(var) => write!(f, "load {}", var),
+            StackInstruction::Store(var) => write!(f, "store {}", var),
+            StackInstruction::Print => write!(f, "print"),
+            StackInstruction::Halt => write!(f, "halt"),
+        }
+    }
+}
+
+/// A stack-based virtual machine over values of type `T`
+pub struct StackVM<T: Number> {
+    pub pc: usize,
+    /// Operands, top last
+    pub stack: Vec<T>,
+    pub program: Vec<StackInstruction<T>>,
+    /// Return addresses, innermost last
+    pub call_stack: Vec<usize>,
+    pub variables: HashMap<String, T>,
+    pub config: VmConfig,
+    /// Total instructions executed over the lifetime of this VM
+    pub steps: u64,
+    hooks: Vec<Box<dyn Hook<StackVM<T>>>>,
+    output: Output,
+}
+
+/// Execution state of a `StackVM`, detached from its program and
+/// configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackSnapshot<T> {
+    pub pc: usize,
+    pub stack: Vec<T>,
+    pub call_stack: Vec<usize>,
+    pub variables: HashMap<String, T>,
+    pub steps: u64,
+}
+
+impl<T: Number> Machine for StackVM<T> {
+    type Instruction = StackInstruction<T>;
+    type Value = T;
+    type Snapshot = StackSnapshot<T>;
+
+    fn pc(&self) -> usize {
+        self.pc
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn is_halted(&self) -> bool {
+        StackVM::is_halted(self)
+    }
+
+    fn step(&mut self) -> Result<(), VmError> {
+        StackVM::step(self)
+    }
+
+    fn run(&mut self) -> Result<(), VmError> {
+        StackVM::run(self)
+    }
+
+    fn instruction(&self, pc: usize) -> Option<&StackInstruction<T>> {
+        self.program.get(pc)
+    }
+
+    fn values(&self) -> &[T] {
+        &self.stack
+    }
+
+    fn variable(&self, name: &str) -> Option<T> {
+        self.variables.get(name).copied()
+    }
+
+    fn return_addresses(&self) -> Vec<usize> {
+        self.call_stack.clone()
+    }
+
+    fn snapshot(&self) -> StackSnapshot<T> {
+        StackVM::snapshot(self)
+    }
+
+    fn restore(&mut self, snapshot: &StackSnapshot<T>) {
+        StackVM::restore(self, snapshot)
+    }
+
+    fn add_hook(&mut self, hook: impl Hook<Self>) {
+        StackVM::add_hook(self, hook)
+    }
+
+    fn hook<H: Hook<Self>>(&self) -> Option<&H> {
+        StackVM::hook(self)
+    }
+
+    fn take_hook<H: Hook<Self>>(&mut self) -> Option<H> {
+        StackVM::take_hook(self)
+    }
+}
+
+impl<T: Number> StackVM<T> {
+    pub fn new(program: Vec<StackInstruction<T>>) -> Self {
+        Self::with_config(program, VmConfig::default())
+    }
+
+    pub fn with_config(program: Vec<StackInstruction<T>>, config: VmConfig) -> Self {
+        Self {
+            pc: 0,
+            stack: Vec::new(),
+            program,
+            call_stack: Vec::new(),
+            variables: HashMap::new(),
+            config,
+            steps: 0,
+            hooks: Vec::new(),
+            output: Output::default(),
+        }
+    }
+
+    /// Run to completion
+    pub fn run(&mut self) -> Result<(), VmError> {
+        self.run_until(None)
+    }
+
+    /// Run to completion, failing with `VmError::Timeout` once `deadline` passes
+    #[cfg(feature = "std")]
+    pub fn run_with_deadline(&mut self, deadline: Instant) -> Result<(), VmError> {
+        self.run_until(Some(deadline))
+    }
+
+    /// When a limit trips the VM is left as it was, so the run can be resumed
+    fn run_until(&mut self, deadline: Deadline) -> Result<(), VmError> {
+        let start_steps = self.steps;
+        let limits = RunLimits::new(&self.config, deadline);
+        while !self.is_halted() {
+            limits.check(self.steps - start_steps)?;
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Execute the single instruction at `pc`
+    pub fn step(&mut self) -> Result<(), VmError> {
+        if self.hooks.is_empty() {
+            return self.execute();
+        }
+        hook::step(self, |vm| &mut vm.hooks, StackVM::execute)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// Capture the current execution state
+    pub fn snapshot(&self) -> StackSnapshot<T> {
+        StackSnapshot {
+            pc: self.pc,
+            stack: self.stack.clone(),
+            call_stack: self.call_stack.clone(),
+            variables: self.variables.clone(),
+            steps: self.steps,
+        }
+    }
+
+    /// Rewind or fast-forward to a previously captured state
+    pub fn restore(&mut self, snapshot: &StackSnapshot<T>) {
+        self.pc = snapshot.pc;
+        self.stack = snapshot.stack.clone();
+        self.call_stack = snapshot.call_stack.clone();
+        self.variables = snapshot.variables.clone();
+        self.steps = snapshot.steps;
+    }
+
+    pub fn add_hook(&mut self, hook: impl Hook<StackVM<T>>) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    /// The first hook of type `H`, to read what it has collected
+    pub fn hook<H: Hook<StackVM<T>>>(&self) -> Option<&H> {
+        hook::find(&self.hooks)
+    }
+
+    /// Remove the first hook of type `H` and return it
+    pub fn take_hook<H: Hook<StackVM<T>>>(&mut self) -> Option<H> {
+        hook::take(&mut self.hooks)
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// Collect printed values instead of writing them to stdout
+    pub fn capture_output(&mut self) {
+        if !matches!(self.output, Output::Captured(_)) {
+            self.output = Output::Captured(Vec::new());
+        }
+    }
+
+    /// Values printed since the last call, if output is captured
+    pub fn take_output(&mut self) -> Vec<f64> {
+        match &mut self.output {
+            Output::Captured(values) => core::mem::take(values),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Hand every printed value to `sink`
+    pub fn set_output(&mut self, sink: impl FnMut(f64) + Send + 'static) {
+        self.output = Output::Sink(Box::new(sink));
+    }
+
+    fn execute(&mut self) -> Result<(), VmError> {
+        let instruction = self
+            .program
+            .get(self.pc)
+            .ok_or(VmError::ProgramCounterOutOfBounds)?;
+        self.pc += 1;
+        self.steps += 1;
+        match instruction {
+            StackInstruction::Push(value) => self.stack.push(*value),
+            StackInstruction::Pop => {
+                self.pop()?;
+            }
+            StackInstruction::Dup => {
+                let top = *self.stack.last().ok_or(VmError::StackUnderflow)?;
+                self.stack.push(top);
+            }
+            StackInstruction::Swap => {
+                let [a, b] = self.pop_two()?;
+                self.stack.extend([b, a]);
+            }
+            StackInstruction::Add => self.binary(|a, b| Ok(a.add(b)))?,
+            StackInstruction::Sub => self.binary(|a, b| Ok(a.sub(b)))?,
+            StackInstruction::Mul => self.binary(|a, b| Ok(a.mul(b)))?,
+            StackInstruction::Div => self.binary(|a, b| a.div(b).ok_or(VmError::DivisionByZero))?,
+            StackInstruction::Equal => self.binary(|a, b| Ok(truth(a == b)))?,
+            StackInstruction::LessThan => self.binary(|a, b| Ok(truth(a < b)))?,
+            StackInstruction::GreaterThan => self.binary(|a, b| Ok(truth(a > b)))?,
+            StackInstruction::Not => {
+                let value = self.pop()?;
+                self.stack.push(truth(value == T::ZERO));
+            }
+            &StackInstruction::Jump(target) => self.jump(target)?,
+            &StackInstruction::JumpIfZero(target) => {
+                if self.pop()? == T::ZERO {
+                    self.jump(target)?;
+                }
+            }
+            &StackInstruction::Call(addr) => {
+                self.config
+                    .sandbox
+                    .check_call_depth(self.call_stack.len())?;
+                self.call_stack.push(self.pc);
+                self.jump(addr)?;
+            }
+            StackInstruction::Return => {
+                self.pc = self.call_stack.pop().ok_or(VmError::CallStackEmpty)?;
+            }
+            StackInstruction::Load(var) => {
+                let value = *self
+                    .variables
+                    .get(var)
+                    .ok_or_else(|| VmError::VariableNotFound(var.clone()))?;
+                self.stack.push(value);
+            }
+            StackInstruction::Store(var) => {
+                let var = var.clone();
+                let value = self.pop()?;
+                self.variables.insert(var, value);
+            }
+            StackInstruction::Print => {
+                let value = self.pop()?;
+                self.output.write(value.to_f64());
+            }
+            StackInstruction::Halt => self.pc = self.program.len(),
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<T, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    /// The top two values, the top one last. Neither is taken unless both
+    /// are there.
+    fn pop_two(&mut self) -> Result<[T; 2], VmError> {
+        let at = self
+            .stack
+            .len()
+            .checked_sub(2)
+            .ok_or(VmError::StackUnderflow)?;
+        let [a, b] = [self.stack[at], self.stack[at + 1]];
+        self.stack.truncate(at);
+        Ok([a, b])
+    }
+
+    fn binary(&mut self, op: impl FnOnce(T, T) -> Result<T, VmError>) -> Result<(), VmError> {
+        let [a, b] = self.pop_two()?;
+        match op(a, b) {
+            Ok(value) => {
+                self.stack.push(value);
+                Ok(())
+            }
+            Err(e) => {
+                // Left as it was, so the failed instruction can be inspected
+                self.stack.extend([a, b]);
+                Err(e)
+            }
+        }
+    }
+
+    /// Jump to `target`, which may be the end of the program to halt
+    fn jump(&mut self, target: usize) -> Result<(), VmError> {
+        if target > self.program.len() {
+            return Err(VmError::ProgramCounterOutOfBounds);
+        }
+        self.pc = target;
+        Ok(())
+    }
+}
+
+fn truth<T: Number>(condition: bool) -> T {
+    if condition { T::ONE } else { T::ZERO }
+}
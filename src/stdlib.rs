@@ -0,0 +1,99 @@
+//! Built-in host functions.
+//!
+//! `install` registers a default set of functions programs can `callhost`
+//! by name:
+//!
+//! | name        | arguments | result                              |
+//! |-------------|-----------|-------------------------------------|
+//! | `sin`       | x         | sine of x radians                   |
+//! | `cos`       | x         | cosine of x radians                 |
+//! | `pow`       | x, y      | x raised to y                       |
+//! | `log`       | x         | natural logarithm of x              |
+//! | `min`       | x, y      | the smaller of x and y              |
+//! | `max`       | x, y      | the larger of x and y               |
+//! | `clamp`     | x, lo, hi | x limited to `lo..=hi`              |
+//! | `random`    |           | a uniform value in `[0, 1)`         |
+//! | `time_ms`   |           | milliseconds since the Unix epoch   |
+//! | `read_line` |           | the next line of stdin, as a number |
+//!
+//! `read_line` touches the outside world, so it is only registered when
+//! I/O is allowed.
+
+use crate::rng::Rng;
+use crate::vm::VM;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Built-ins that read stdin, which `SandboxPolicy::stdin` governs
+pub const READS_STDIN: &[&str] = &["read_line"];
+
+/// Register every built-in with `vm`, leaving out those that do I/O unless
+/// `allow_io` is set
+pub fn install(vm: &mut VM, allow_io: bool) {
+    vm.register_host_function("sin", |args| unary(args, f64::sin));
+    vm.register_host_function("cos", |args| unary(args, f64::cos));
+    vm.register_host_function("log", |args| unary(args, f64::ln));
+    vm.register_host_function("pow", |args| binary(args, f64::powf));
+    vm.register_host_function("min", |args| binary(args, f64::min));
+    vm.register_host_function("max", |args| binary(args, f64::max));
+    vm.register_host_function("clamp", |args| {
+        let [x, lo, hi] = arguments(args)?;
+        if lo > hi {
+            return Err(format!("lower bound {} is above upper bound {}", lo, hi));
+        }
+        Ok(x.clamp(lo, hi))
+    });
+
+    // Seeded from the clock; the `rand` instruction gives repeatable values
+    let rng = Mutex::new(Rng::new(seed()));
+    vm.register_host_function("random", move |args| {
+        let [] = arguments(args)?;
+        let mut rng = rng.lock().map_err(|e| e.to_string())?;
+        Ok(rng.next_f64())
+    });
+    vm.register_host_function("time_ms", |args| {
+        let [] = arguments(args)?;
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?;
+        Ok(elapsed.as_millis() as f64)
+    });
+
+    if allow_io {
+        vm.register_host_function("read_line", |args| {
+            let [] = arguments(args)?;
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) => Err("end of input".to_string()),
+                Ok(_) => line
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a number", line.trim())),
+                Err(e) => Err(e.to_string()),
+            }
+        });
+    }
+}
+
+/// `args` as exactly `N` values
+fn arguments<const N: usize>(args: &[f64]) -> Result<[f64; N], String> {
+    args.try_into()
+        .map_err(|_| format!("expected {} arguments, found {}", N, args.len()))
+}
+
+fn unary(args: &[f64], f: fn(f64) -> f64) -> Result<f64, String> {
+    let [x] = arguments(args)?;
+    Ok(f(x))
+}
+
+fn binary(args: &[f64], f: fn(f64, f64) -> f64) -> Result<f64, String> {
+    let [x, y] = arguments(args)?;
+    Ok(f(x, y))
+}
+
+/// A seed that differs between runs
+fn seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
@@ -0,0 +1,65 @@
+//! Spans and events for the `tracing` crate.
+//!
+//! A run is an INFO span, and each call frame and host function call a
+//! DEBUG span inside it; traps are DEBUG events. Each carries the pc and
+//! the label it falls under. Frames and traps are only seen by the stepping
+//! interpreter, so a VM steps whenever DEBUG is enabled.
+
+use crate::vm::{VM, VmError};
+use tracing::{Level, Span};
+
+impl VM {
+    /// Whether a subscriber wants the frame and trap detail
+    pub(crate) fn traces_steps(&self) -> bool {
+        tracing::enabled!(Level::DEBUG)
+    }
+
+    pub(crate) fn run_span(&self) -> Span {
+        tracing::info_span!("run", pc = self.pc, label = self.label(self.pc))
+    }
+
+    /// Close the spans of frames that have returned or been unwound, and
+    /// open one for each frame pushed since the last step
+    pub(crate) fn trace_frames(&mut self) {
+        self.frame_spans.truncate(self.call_stack.len());
+        while self.frame_spans.len() < self.call_stack.len() {
+            let parent = self
+                .frame_spans
+                .last()
+                .cloned()
+                .unwrap_or_else(Span::current);
+            let span = tracing::debug_span!(
+                parent: &parent,
+                "call",
+                pc = self.pc,
+                label = self.label(self.pc)
+            );
+            self.frame_spans.push(span);
+        }
+    }
+
+    /// Record `error`, raised by the instruction at `pc`, unless it only
+    /// suspends the run
+    pub(crate) fn trace_trap(&self, pc: usize, error: &VmError) {
+        if matches!(error, VmError::Blocked | VmError::HostCallPending(_)) {
+            return;
+        }
+        let parent = self
+            .frame_spans
+            .last()
+            .cloned()
+            .unwrap_or_else(Span::current);
+        tracing::debug!(
+            parent: &parent,
+            pc,
+            label = self.label(pc),
+            caught = error.code().is_some() && !self.handlers.is_empty(),
+            error = %error,
+            "trap"
+        );
+    }
+
+    fn label(&self, pc: usize) -> &str {
+        self.program.symbolize(pc).map_or("", |(name, _)| name)
+    }
+}
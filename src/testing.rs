@@ -0,0 +1,263 @@
+//! Random valid programs for property tests and fuzzing.
+//!
+//! A `Generator` turns any byte string into a `Program` that passes
+//! `Program::verify` and uses only the registers it is configured with, so a
+//! fuzzer's or property-testing library's raw bytes can drive the
+//! interpreter directly: with proptest, map a `Vec<u8>` strategy through
+//! `Generator::program`, and with the `arbitrary` feature, `Program`
+//! implements `Arbitrary`. Similar bytes give similar programs, so shrinking
+//! the bytes shrinks the program.
+//!
+//! Generated code avoids instructions that print, call the host or wait on
+//! messages. Unless `loops` is set, every jump, call and handler points
+//! forward, so programs cannot loop.
+
+use crate::instruction::{Comparison, Instruction};
+use crate::prelude::*;
+use crate::program::Program;
+use crate::rng::Rng;
+
+/// Settings for the programs a generator makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Generator {
+    /// Registers a program may use, at least 1
+    pub registers: usize,
+    /// Longest program generated, counting the final `halt`
+    pub max_len: usize,
+    /// Allow backward jumps and calls, which can loop forever
+    pub loops: bool,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            registers: 8,
+            max_len: 64,
+            loops: false,
+        }
+    }
+}
+
+/// Constants worth trying more often than random bit patterns
+const VALUES: [f64; 12] = [
+    0.0,
+    -0.0,
+    1.0,
+    -1.0,
+    2.0,
+    0.5,
+    1e300,
+    f64::MIN_POSITIVE,
+    f64::MAX,
+    f64::INFINITY,
+    f64::NEG_INFINITY,
+    f64::NAN,
+];
+
+const VARIABLES: [&str; 3] = ["a", "b", "c"];
+
+/// Kinds of instruction `instruction` picks from
+const KINDS: usize = 37;
+
+impl Generator {
+    /// The program `data` describes. Running out of bytes reads as zeros,
+    /// so every input, the empty one included, gives a program.
+    pub fn program(&self, data: &[u8]) -> Program {
+        let mut bytes = Bytes { data, pos: 0 };
+        let max_len = self.max_len.max(1);
+        let len = bytes.below(max_len) + 1;
+        let mut instructions: Vec<Instruction> = (0..len - 1)
+            .map(|pc| self.instruction(&mut bytes, pc, len))
+            .collect();
+        instructions.push(Instruction::Halt);
+        Program::new(instructions)
+    }
+
+    /// The program a pseudo-random byte string from `seed` describes
+    pub fn seeded(&self, seed: u64) -> Program {
+        let mut rng = Rng::new(seed);
+        let data: Vec<u8> = (0..self.max_len.max(1) * 16)
+            .map(|_| (rng.next_f64() * 256.0) as u8)
+            .collect();
+        self.program(&data)
+    }
+
+    fn instruction(&self, bytes: &mut Bytes, pc: usize, len: usize) -> Instruction {
+        use Instruction::*;
+        let registers = self.registers.max(1);
+        let mut reg = || bytes.below(registers);
+        let (a, b, c) = (reg(), reg(), reg());
+        // Forward targets lie in pc + 1..len, which always has the `halt`
+        let target = if self.loops {
+            bytes.below(len)
+        } else {
+            pc + 1 + bytes.below(len - pc - 1)
+        };
+        let var = VARIABLES[bytes.below(VARIABLES.len())].to_string();
+        match bytes.below(KINDS) {
+            0 | 1 => LoadImm {
+                dest: a,
+                value: bytes.value(),
+            },
+            2 => Add {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            3 => Sub {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            4 => Mul {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            5 => Div {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            6 => Mov { dest: a, src: b },
+            7 => Equal {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            8 => LessThan {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            9 => GreaterThan {
+                dest: a,
+                src1: b,
+                src2: c,
+            },
+            10 => Not { dest: a, src: b },
+            11 => AddImm {
+                dest: a,
+                src: b,
+                imm: c,
+                value: bytes.value(),
+            },
+            12 => Jump(target),
+            13 => ConditionalJump { cond: a, target },
+            14 => CompareJump {
+                cmp: [
+                    Comparison::Equal,
+                    Comparison::LessThan,
+                    Comparison::GreaterThan,
+                ][bytes.below(3)],
+                dest: a,
+                src1: b,
+                src2: c,
+                target,
+            },
+            15 => Call { addr: target },
+            16 => Return,
+            17 => JumpRel(target as i32 - pc as i32),
+            18 => CallRel(target as i32 - pc as i32),
+            19 => Store { src: a, var },
+            20 => Load { dest: a, var },
+            21 => TryBegin {
+                handler: target,
+                dest: a,
+            },
+            22 => TryEnd,
+            23 => Throw { src: a },
+            // Field 0 exists in every record, so field accesses verify
+            24 => NewRecord {
+                dest: a,
+                fields: 1 + bytes.below(4),
+            },
+            25 => GetField {
+                dest: a,
+                record: b,
+                index: 0,
+            },
+            26 => SetField {
+                record: a,
+                index: 0,
+                src: b,
+            },
+            27 => MapNew { dest: a },
+            28 => MapGet {
+                dest: a,
+                map: b,
+                key: c,
+            },
+            29 => MapSet {
+                map: a,
+                key: b,
+                src: c,
+            },
+            30 => MapHas {
+                dest: a,
+                map: b,
+                key: c,
+            },
+            31 => Rand { dest: a },
+            32 => Push { src: a },
+            33 => Pop { dest: a },
+            34 => CallArgs {
+                addr: target,
+                argc: bytes.below(4),
+            },
+            35 => {
+                let min = bytes.below(3);
+                let max = (bytes.below(2) == 1).then(|| min + bytes.below(3));
+                Arity { dest: a, min, max }
+            }
+            _ => Halt,
+        }
+    }
+}
+
+/// Reads `data` front to back, as zeros once it runs out
+struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bytes<'_> {
+    fn byte(&mut self) -> u8 {
+        let byte = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    /// A number in `0..n`, reading two bytes when one cannot cover `n`
+    fn below(&mut self, n: usize) -> usize {
+        let x = if n <= 256 {
+            self.byte() as usize
+        } else {
+            u16::from_le_bytes([self.byte(), self.byte()]) as usize
+        };
+        x % n.max(1)
+    }
+
+    /// A constant from `VALUES`, a small integer or any bit pattern
+    fn value(&mut self) -> f64 {
+        let choice = self.byte() as usize;
+        if choice < VALUES.len() {
+            VALUES[choice]
+        } else if choice < 128 {
+            (choice - VALUES.len()) as f64
+        } else {
+            let mut bits = [0; 8];
+            bits.fill_with(|| self.byte());
+            f64::from_bits(u64::from_le_bytes(bits))
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Program {
+    /// A program from `Generator::default`
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let len = u.arbitrary_len::<u8>()?;
+        Ok(Generator::default().program(u.bytes(len)?))
+    }
+}
@@ -0,0 +1,132 @@
+use crate::instruction::Instruction;
+use crate::json;
+use crate::prelude::*;
+use crate::vm::{VM, VmError};
+
+/// A register whose value changed during a step
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterWrite {
+    pub reg: usize,
+    pub old: f64,
+    pub new: f64,
+}
+
+/// Everything observable about one executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    pub pc: usize,
+    pub opcode: &'static str,
+    /// The instruction as assembly text
+    pub text: String,
+    /// Source registers and the values they held before the step
+    pub reads: Vec<(usize, f64)>,
+    pub writes: Vec<RegisterWrite>,
+    /// Variable assigned by this step, if any
+    pub store: Option<(String, f64)>,
+    pub next_pc: usize,
+    pub error: Option<String>,
+}
+
+/// A recording of every instruction a VM executed, in order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Execute one instruction on `vm` and record what it did
+    pub fn step(&mut self, vm: &mut VM) -> Result<(), VmError> {
+        let pc = vm.pc;
+        let instr = vm
+            .program
+            .instructions
+            .get(pc)
+            .cloned()
+            .ok_or(VmError::ProgramCounterOutOfBounds)?;
+        let reads = instr
+            .sources()
+            .into_iter()
+            .filter_map(|r| vm.registers.get(r).map(|&v| (r, v)))
+            .collect();
+        let before = vm.registers.clone();
+
+        let result = vm.step();
+
+        let writes = before
+            .iter()
+            .zip(&vm.registers)
+            .enumerate()
+            .filter(|(_, (old, new))| old.to_bits() != new.to_bits())
+            .map(|(reg, (&old, &new))| RegisterWrite { reg, old, new })
+            .collect();
+        let store = match (&instr, &result) {
+            (Instruction::Store { var, .. }, Ok(())) => {
+                vm.variables.get(var).map(|&v| (var.clone(), v))
+            }
+            _ => None,
+        };
+
+        self.steps.push(TraceStep {
+            pc,
+            opcode: instr.mnemonic(),
+            text: instr.to_string(),
+            reads,
+            writes,
+            store,
+            next_pc: vm.pc,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        result
+    }
+
+    pub fn to_json(&self) -> String {
+        let steps: Vec<String> = self.steps.iter().map(step_json).collect();
+        format!("{{\"steps\":[\n{}\n]}}\n", steps.join(",\n"))
+    }
+}
+
+fn step_json(step: &TraceStep) -> String {
+    let reads: Vec<String> = step
+        .reads
+        .iter()
+        .map(|(reg, value)| format!("{{\"reg\":{},\"value\":{}}}", reg, json::number(*value)))
+        .collect();
+    let writes: Vec<String> = step
+        .writes
+        .iter()
+        .map(|w| {
+            format!(
+                "{{\"reg\":{},\"old\":{},\"new\":{}}}",
+                w.reg,
+                json::number(w.old),
+                json::number(w.new)
+            )
+        })
+        .collect();
+
+    let mut out = format!(
+        "{{\"pc\":{},\"opcode\":{},\"text\":{},\"reads\":[{}],\"writes\":[{}],\"next_pc\":{}",
+        step.pc,
+        json::string(step.opcode),
+        json::string(&step.text),
+        reads.join(","),
+        writes.join(","),
+        step.next_pc
+    );
+    if let Some((name, value)) = &step.store {
+        out.push_str(&format!(
+            ",\"store\":{{\"var\":{},\"value\":{}}}",
+            json::string(name),
+            json::number(*value)
+        ));
+    }
+    if let Some(error) = &step.error {
+        out.push_str(&format!(",\"error\":{}", json::string(error)));
+    }
+    out.push('}');
+    out
+}
@@ -0,0 +1,458 @@
+//! Static types for registers.
+//!
+//! Every value is an `f64` at run time, so a register holding a map handle
+//! where a record is expected, or a fraction where a whole number is, only
+//! shows up as a `VmError::WrongType` or a wrong result. `check` infers the
+//! type of each register before each instruction, from what wrote it and
+//! from optional annotations: function signatures and typed `loadimm`
+//! constants, as the `.func` directive and `loadimm.i`/`.f`/`.b` give them
+//! in `asm_reg`. Where paths disagree, or a value comes from a variable,
+//! the heap or the host, the type is unknown and is not checked.
+
+use crate::HashMap;
+use crate::instruction::Instruction;
+use crate::prelude::*;
+use crate::program::Program;
+use core::error::Error;
+use core::fmt;
+use core::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    /// Any number
+    Float,
+    /// A whole number
+    Int,
+    /// 0 or 1
+    Bool,
+    Record,
+    Map,
+    Closure,
+    Coroutine,
+}
+
+impl Type {
+    /// Whether every value of this type is also one of `other`
+    pub fn is_subtype(self, other: Type) -> bool {
+        use Type::*;
+        self == other || matches!((self, other), (Bool, Int | Float) | (Int, Float))
+    }
+
+    /// The most specific type both `a` and `b` belong to, if any
+    fn join(a: Type, b: Type) -> Option<Type> {
+        if a.is_subtype(b) {
+            Some(b)
+        } else if b.is_subtype(a) {
+            Some(a)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `value` belongs to this type, as a constant
+    fn admits(self, value: f64) -> bool {
+        match self {
+            Type::Float => true,
+            Type::Int => value as i64 as f64 == value,
+            Type::Bool => value == 0.0 || value == 1.0,
+            _ => false,
+        }
+    }
+
+    /// The narrowest number type `value` belongs to
+    fn of(value: f64) -> Type {
+        [Type::Bool, Type::Int]
+            .into_iter()
+            .find(|t| t.admits(value))
+            .unwrap_or(Type::Float)
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Float => "float",
+            Type::Int => "int",
+            Type::Bool => "bool",
+            Type::Record => "record",
+            Type::Map => "map",
+            Type::Closure => "closure",
+            Type::Coroutine => "coroutine",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Type {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "float" => Ok(Type::Float),
+            "int" => Ok(Type::Int),
+            "bool" => Ok(Type::Bool),
+            "record" => Ok(Type::Record),
+            "map" => Ok(Type::Map),
+            "closure" => Ok(Type::Closure),
+            "coroutine" => Ok(Type::Coroutine),
+            _ => Err(format!("unknown type '{}'", s)),
+        }
+    }
+}
+
+/// Argument types, passed in registers `0..params.len()`, and the type
+/// returned in `r0`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Option<Type>,
+}
+
+/// Types declared for parts of a program
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotations {
+    /// Signatures of the functions starting at each address
+    pub functions: HashMap<usize, Signature>,
+    /// Types of the `loadimm` constants at each address
+    pub immediates: HashMap<usize, Type>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TypeError {
+    /// Instruction `addr` needs register `reg` to hold a `expected`, but it
+    /// holds a `found`
+    Mismatch {
+        addr: usize,
+        reg: usize,
+        expected: Type,
+        found: Type,
+    },
+    /// The annotated `loadimm` at `addr` loads a value not of its type
+    BadImmediate {
+        addr: usize,
+        value: f64,
+        expected: Type,
+    },
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeError::Mismatch {
+                addr,
+                reg,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Instruction {} needs r{} to be {}, but it is {}",
+                addr, reg, expected, found
+            ),
+            TypeError::BadImmediate {
+                addr,
+                value,
+                expected,
+            } => write!(
+                f,
+                "Instruction {} loads {}, which is not {}",
+                addr, value, expected
+            ),
+        }
+    }
+}
+
+impl Error for TypeError {}
+
+impl TypeError {
+    /// The address of the instruction at fault
+    pub fn addr(&self) -> usize {
+        match *self {
+            TypeError::Mismatch { addr, .. } | TypeError::BadImmediate { addr, .. } => addr,
+        }
+    }
+}
+
+/// What is known before an instruction: each register's type, and the type
+/// the running function must return
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    regs: Vec<Option<Type>>,
+    ret: Option<Type>,
+}
+
+impl State {
+    fn unknown(registers: usize) -> Self {
+        Self {
+            regs: vec![None; registers],
+            ret: None,
+        }
+    }
+
+    /// The state on entry to a function with `signature`
+    fn entry(registers: usize, signature: Option<&Signature>) -> Self {
+        let mut state = Self::unknown(registers);
+        if let Some(signature) = signature {
+            for (reg, &ty) in signature.params.iter().enumerate() {
+                state.set(reg, Some(ty));
+            }
+            state.ret = signature.ret;
+        }
+        state
+    }
+
+    fn set(&mut self, reg: usize, ty: Option<Type>) {
+        if let Some(slot) = self.regs.get_mut(reg) {
+            *slot = ty;
+        }
+    }
+
+    fn get(&self, reg: usize) -> Option<Type> {
+        self.regs.get(reg).copied().flatten()
+    }
+
+    /// Widen to cover `other` too, reporting whether anything changed
+    fn join(&mut self, other: &State) -> bool {
+        let before = self.clone();
+        for (a, &b) in self.regs.iter_mut().zip(&other.regs) {
+            *a = a.zip(b).and_then(|(a, b)| Type::join(a, b));
+        }
+        if self.ret != other.ret {
+            self.ret = None;
+        }
+        *self != before
+    }
+}
+
+/// Infer register types through `program` and check every use against
+/// them and against `annotations`
+pub fn check(program: &Program, annotations: &Annotations) -> Result<(), TypeError> {
+    let code = &program.instructions;
+    for (&addr, &expected) in &annotations.immediates {
+        if let Some(Instruction::LoadImm { value, .. }) = code.get(addr)
+            && !expected.admits(*value)
+        {
+            return Err(TypeError::BadImmediate {
+                addr,
+                value: *value,
+                expected,
+            });
+        }
+    }
+    let registers = code
+        .iter()
+        .flat_map(|instr| instr.writes().into_iter().chain(instr.sources()))
+        .max()
+        .map_or(1, |r| r + 1)
+        .max(
+            annotations
+                .functions
+                .values()
+                .map(|s| s.params.len())
+                .max()
+                .unwrap_or(0),
+        );
+
+    let mut roots = program.entry_points();
+    roots.extend(
+        code.iter()
+            .enumerate()
+            .filter_map(|(pc, instr)| match instr {
+                Instruction::Call { .. }
+                | Instruction::CallRel(_)
+                | Instruction::CallArgs { .. } => instr.target(pc),
+                _ => None,
+            }),
+    );
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    let mut worklist = Vec::new();
+    for root in roots {
+        let state = State::entry(registers, annotations.functions.get(&root));
+        worklist.push((root, state));
+    }
+
+    while let Some((addr, state)) = worklist.pop() {
+        let Some(instr) = code.get(addr) else {
+            continue;
+        };
+        match &mut states[addr] {
+            Some(known) => {
+                if !known.join(&state) {
+                    continue;
+                }
+            }
+            slot @ None => *slot = Some(state),
+        }
+        let state = states[addr].clone().unwrap();
+        for (to, next) in successors(code, annotations, addr, instr, &state) {
+            worklist.push((to, next));
+        }
+    }
+
+    for (addr, state) in states.iter().enumerate() {
+        if let Some(state) = state {
+            uses(code, annotations, addr, state)?;
+        }
+    }
+    Ok(())
+}
+
+/// Where control goes after the instruction at `addr`, and in what state
+fn successors(
+    code: &[Instruction],
+    annotations: &Annotations,
+    addr: usize,
+    instr: &Instruction,
+    state: &State,
+) -> Vec<(usize, State)> {
+    use Instruction::*;
+    let mut after = state.clone();
+    let number = |reg: usize| state.get(reg).filter(|t| t.is_subtype(Type::Float));
+    match instr {
+        LoadImm { dest, value } => {
+            let ty = annotations
+                .immediates
+                .get(&addr)
+                .copied()
+                .unwrap_or(Type::of(*value));
+            after.set(*dest, Some(ty));
+        }
+        Add { dest, src1, src2 } | Sub { dest, src1, src2 } | Mul { dest, src1, src2 } => {
+            let ty = number(*src1).zip(number(*src2)).map(|(a, b)| {
+                if a.is_subtype(Type::Int) && b.is_subtype(Type::Int) {
+                    Type::Int
+                } else {
+                    Type::Float
+                }
+            });
+            after.set(*dest, ty);
+        }
+        Div { dest, .. } | Rand { dest } => after.set(*dest, Some(Type::Float)),
+        AddImm {
+            dest,
+            src,
+            imm,
+            value,
+        } => {
+            let ty = number(*src).map(|t| {
+                if t.is_subtype(Type::Int) && Type::Int.admits(*value) {
+                    Type::Int
+                } else {
+                    Type::Float
+                }
+            });
+            after.set(*imm, Some(Type::of(*value)));
+            after.set(*dest, ty);
+        }
+        Mov { dest, src } => after.set(*dest, state.get(*src)),
+        Equal { dest, .. }
+        | LessThan { dest, .. }
+        | GreaterThan { dest, .. }
+        | Not { dest, .. }
+        | MapHas { dest, .. }
+        | CompareJump { dest, .. }
+        | Resume { dest, .. } => after.set(*dest, Some(Type::Bool)),
+        LoadAddr { dest, .. } | Arity { dest, .. } => after.set(*dest, Some(Type::Int)),
+        NewRecord { dest, .. } => after.set(*dest, Some(Type::Record)),
+        MapNew { dest } => after.set(*dest, Some(Type::Map)),
+        MakeClosure { dest, .. } => after.set(*dest, Some(Type::Closure)),
+        Spawn { dest, .. } => after.set(*dest, Some(Type::Coroutine)),
+        Call { .. } | CallRel(_) | CallArgs { .. } => {
+            // The callee may write any register but the one it returns in
+            let signature = instr
+                .target(addr)
+                .and_then(|t| annotations.functions.get(&t));
+            let ret = after.ret;
+            after = State::unknown(state.regs.len());
+            after.ret = ret;
+            after.set(0, signature.and_then(|s| s.ret));
+        }
+        CallIndirect { .. } | CallClosure { .. } => {
+            let ret = after.ret;
+            after = State::unknown(state.regs.len());
+            after.ret = ret;
+        }
+        _ => {
+            for reg in instr.writes() {
+                after.set(reg, None);
+            }
+        }
+    }
+
+    let mut next = Vec::new();
+    match instr {
+        TryBegin { handler, dest } => {
+            // Registers may change anywhere before the error is raised
+            let mut caught = State::unknown(state.regs.len());
+            caught.ret = state.ret;
+            caught.set(*dest, Some(Type::Float));
+            next.push((*handler, caught));
+        }
+        _ if instr.is_call() => {}
+        _ => next.extend(instr.targets(addr).into_iter().map(|t| (t, after.clone()))),
+    }
+    if !instr.is_terminator() && addr + 1 < code.len() {
+        next.push((addr + 1, after));
+    }
+    next
+}
+
+/// Check that the instruction at `addr` gets the types it needs in `state`
+fn uses(
+    code: &[Instruction],
+    annotations: &Annotations,
+    addr: usize,
+    state: &State,
+) -> Result<(), TypeError> {
+    use Instruction::*;
+    let expect = |reg: usize, expected: Type| match state.get(reg) {
+        Some(found) if !found.is_subtype(expected) => Err(TypeError::Mismatch {
+            addr,
+            reg,
+            expected,
+            found,
+        }),
+        _ => Ok(()),
+    };
+    let instr = &code[addr];
+    match instr {
+        Add { src1, src2, .. }
+        | Sub { src1, src2, .. }
+        | Mul { src1, src2, .. }
+        | Div { src1, src2, .. }
+        | LessThan { src1, src2, .. }
+        | GreaterThan { src1, src2, .. }
+        | CompareJump { src1, src2, .. } => {
+            expect(*src1, Type::Float)?;
+            expect(*src2, Type::Float)?;
+        }
+        AddImm { src, .. } | Throw { src } | Switch { src, .. } => expect(*src, Type::Float)?,
+        JumpIndirect { src } | CallIndirect { src } => expect(*src, Type::Int)?,
+        CallClosure { src } => expect(*src, Type::Closure)?,
+        GetField { record, .. } | SetField { record, .. } => expect(*record, Type::Record)?,
+        MapGet { map, .. } | MapSet { map, .. } | MapHas { map, .. } | MapDelete { map, .. } => {
+            expect(*map, Type::Map)?
+        }
+        Resume { id, .. } => expect(*id, Type::Coroutine)?,
+        Return => {
+            if let Some(ret) = state.ret {
+                expect(0, ret)?;
+            }
+        }
+        Call { .. } | CallRel(_) | CallArgs { .. } => {
+            let signature = instr
+                .target(addr)
+                .and_then(|t| annotations.functions.get(&t));
+            // Parameters past those passed are optional
+            let passed = match instr {
+                CallArgs { argc, .. } => *argc,
+                _ => usize::MAX,
+            };
+            let params = signature.map_or(&[][..], |s| &s.params);
+            for (reg, &ty) in params.iter().enumerate().take(passed) {
+                expect(reg, ty)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
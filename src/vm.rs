@@ -1,7 +1,36 @@
-use crate::instruction::Instruction;
-use std::collections::HashMap;
-use std::error::Error;
-use std::fmt;
+use crate::HashMap;
+use crate::coroutine::{Context, Coroutines, Status};
+use crate::dispatch;
+use crate::dot;
+use crate::heap::{GcStats, Heap, MemoryMode, Object, map_key};
+use crate::hook::Hook;
+use crate::host::{self, HostFunction, PendingHostCall};
+use crate::instruction::{self, Instruction};
+use crate::isolate::{Mailbox, Message};
+#[cfg(feature = "jit")]
+use crate::jit;
+use crate::json;
+use crate::prelude::*;
+use crate::program::SharedProgram;
+use crate::replay::Replay;
+use crate::rng::Rng;
+use crate::sandbox::SandboxPolicy;
+use crate::trace::Trace;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::error::Error;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+/// When a run must stop; without `std` there is no clock to check one against
+#[cfg(feature = "std")]
+pub(crate) type Deadline = Option<Instant>;
+#[cfg(not(feature = "std"))]
+pub(crate) type Deadline = Option<core::convert::Infallible>;
 
 #[derive(Debug)]
 pub enum VmError {
@@ -9,6 +38,54 @@ pub enum VmError {
     ProgramCounterOutOfBounds,
     CallStackEmpty,
     VariableNotFound(String),
+    UnknownExport(String),
+    /// An export was called, or an `arity` reached, with the wrong number of
+    /// arguments. `expected` is the bound the count fell outside of.
+    ArityMismatch {
+        expected: usize,
+        found: usize,
+    },
+    StepLimitExceeded,
+    Timeout,
+    Cancelled,
+    /// A `throw` no handler caught, with its code
+    Uncaught(f64),
+    HandlerStackEmpty,
+    /// A heap instruction was given a value that is not the kind of object it needs
+    WrongType(&'static str),
+    UpvalueOutOfBounds(usize),
+    FieldOutOfBounds(usize),
+    /// A `mapget` for a key the map has no entry for
+    KeyNotFound(f64),
+    /// A `resume` of an id that is not a suspended coroutine
+    NotResumable(f64),
+    YieldOutsideCoroutine,
+    /// The program is suspended on a call to the named host function
+    HostCallPending(String),
+    /// A registered host function reported an error
+    HostFunction {
+        name: String,
+        message: String,
+    },
+    NoHostCallPending,
+    /// A `send` with a full outbox or a `recv` with an empty inbox. The VM is
+    /// left before the instruction, to retry it on the next run.
+    Blocked,
+    /// The program needed a capability its `SandboxPolicy` withholds
+    PermissionDenied(String),
+    /// An allocation would take the named kind of memory past its
+    /// configured limit
+    MemoryLimitExceeded(&'static str),
+    /// A hook asked for the run to pause; running again continues it
+    Paused,
+    /// A hook asked for the run to stop
+    Aborted,
+    /// A replayed run asked for an input the recording does not have next
+    ReplayDiverged(String),
+    /// A `StackVM` instruction needed more values than the stack held
+    StackUnderflow,
+    /// A `StackVM` division with no result, such as an integer one by zero
+    DivisionByZero,
 }
 
 impl fmt::Display for VmError {
@@ -18,55 +95,934 @@ impl fmt::Display for VmError {
             VmError::ProgramCounterOutOfBounds => write!(f, "Program counter out of bounds"),
             VmError::CallStackEmpty => write!(f, "Call stack is empty, cannot return"),
             VmError::VariableNotFound(name) => write!(f, "Variable '{}' not found", name),
+            VmError::UnknownExport(name) => write!(f, "No exported function named '{}'", name),
+            VmError::ArityMismatch { expected, found } => write!(
+                f,
+                "Arity mismatch: expected {} arguments, found {}",
+                expected, found
+            ),
+            VmError::StepLimitExceeded => write!(f, "Step limit exceeded"),
+            VmError::Timeout => write!(f, "Execution timed out"),
+            VmError::Cancelled => write!(f, "Execution was cancelled"),
+            VmError::Uncaught(code) => write!(f, "Uncaught error with code {}", code),
+            VmError::HandlerStackEmpty => write!(f, "No error handler to remove"),
+            VmError::WrongType(expected) => write!(f, "Expected a {}", expected),
+            VmError::UpvalueOutOfBounds(index) => {
+                write!(
+                    f,
+                    "Upvalue {} out of bounds for the running function",
+                    index
+                )
+            }
+            VmError::FieldOutOfBounds(index) => write!(f, "Record has no field {}", index),
+            VmError::KeyNotFound(key) => write!(f, "Map has no entry for key {}", key),
+            VmError::NotResumable(id) => write!(f, "No suspended coroutine with id {}", id),
+            VmError::YieldOutsideCoroutine => write!(f, "Cannot yield outside a coroutine"),
+            VmError::HostCallPending(name) => {
+                write!(f, "Waiting on a call to host function '{}'", name)
+            }
+            VmError::NoHostCallPending => write!(f, "No host call is pending"),
+            VmError::HostFunction { name, message } => {
+                write!(f, "Host function '{}' failed: {}", name, message)
+            }
+            VmError::Blocked => write!(f, "Blocked on a message channel"),
+            VmError::PermissionDenied(what) => write!(f, "Permission denied: {}", what),
+            VmError::MemoryLimitExceeded(what) => write!(f, "Limit on {} exceeded", what),
+            VmError::Paused => write!(f, "Paused by a hook"),
+            VmError::Aborted => write!(f, "Aborted by a hook"),
+            VmError::ReplayDiverged(why) => write!(f, "Replay diverged: {}", why),
+            VmError::StackUnderflow => write!(f, "Stack underflow"),
+            VmError::DivisionByZero => write!(f, "Division by zero"),
         }
     }
 }
 
 impl Error for VmError {}
 
-#[derive(Debug)]
+impl VmError {
+    /// The code a handler receives for this error, or `None` if programs
+    /// cannot catch it. Thrown codes are passed through; traps raised by the
+    /// VM itself have negative codes. Errors from limits the host imposed
+    /// (steps, time, memory, cancellation, sandboxing) are never catchable.
+    pub fn code(&self) -> Option<f64> {
+        match self {
+            VmError::Uncaught(code) => Some(*code),
+            VmError::RegisterOutOfBounds(_) => Some(-1.0),
+            VmError::ProgramCounterOutOfBounds => Some(-2.0),
+            VmError::CallStackEmpty => Some(-3.0),
+            VmError::VariableNotFound(_) => Some(-4.0),
+            VmError::HandlerStackEmpty => Some(-5.0),
+            VmError::WrongType(_) => Some(-6.0),
+            VmError::UpvalueOutOfBounds(_) => Some(-7.0),
+            VmError::FieldOutOfBounds(_) => Some(-8.0),
+            VmError::KeyNotFound(_) => Some(-9.0),
+            VmError::NotResumable(_) => Some(-10.0),
+            VmError::YieldOutsideCoroutine => Some(-11.0),
+            VmError::HostFunction { .. } => Some(-12.0),
+            VmError::StackUnderflow => Some(-13.0),
+            VmError::DivisionByZero => Some(-14.0),
+            VmError::ArityMismatch { .. } => Some(-15.0),
+            VmError::UnknownExport(_)
+            | VmError::StepLimitExceeded
+            | VmError::Timeout
+            | VmError::Cancelled
+            | VmError::HostCallPending(_)
+            | VmError::NoHostCallPending
+            | VmError::Blocked
+            | VmError::PermissionDenied(_)
+            | VmError::MemoryLimitExceeded(_)
+            | VmError::Paused
+            | VmError::Aborted
+            | VmError::ReplayDiverged(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Frame {
-    return_address: usize,
+    pub(crate) return_address: usize,
+    /// Heap index of the closure this frame is running, if any
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) closure: Option<usize>,
 }
 
 impl Frame {
     pub fn new(return_address: usize) -> Self {
-        Self { return_address }
+        Self {
+            return_address,
+            closure: None,
+        }
+    }
+}
+
+/// An error handler installed by `TryBegin`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Handler {
+    pub(crate) addr: usize,
+    pub(crate) dest: usize,
+    /// Call stack depth when the handler was installed
+    pub(crate) call_depth: usize,
+    /// Operand stack depth when the handler was installed
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) stack_depth: usize,
+}
+
+/// Pop the innermost handler able to catch `error` and return it with the
+/// error's code. The caller drops the frames entered and the values pushed
+/// since it was installed.
+pub(crate) fn unwind(
+    handlers: &mut Vec<Handler>,
+    error: VmError,
+) -> Result<(Handler, f64), VmError> {
+    // Uncatchable errors leave the handlers in place for a resumed run
+    let Some(code) = error.code() else {
+        return Err(error);
+    };
+    let Some(handler) = handlers.pop() else {
+        return Err(error);
+    };
+    Ok((handler, code))
+}
+
+/// `value` as an instruction address or table index, if it is a
+/// non-negative integer
+pub(crate) fn address(value: f64) -> Option<usize> {
+    (value >= 0.0 && value <= u32::MAX as f64 && value as u32 as f64 == value)
+        .then_some(value as usize)
+}
+
+/// The argument count `value` a `CallArgs` pushed, if `min..=max` allows it
+pub(crate) fn arity(value: f64, min: usize, max: Option<usize>) -> Result<usize, VmError> {
+    let found = address(value).ok_or(VmError::WrongType("argument count"))?;
+    let expected = match max {
+        _ if found < min => min,
+        Some(max) if found > max => max,
+        _ => return Ok(found),
+    };
+    Err(VmError::ArityMismatch { expected, found })
+}
+
+/// Drop the handlers installed by frames that have returned
+pub(crate) fn prune_handlers(handlers: &mut Vec<Handler>, call_depth: usize) {
+    while handlers.last().is_some_and(|h| h.call_depth > call_depth) {
+        handlers.pop();
+    }
+}
+
+/// Execution state of a VM, detached from its program and configuration
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VmSnapshot {
+    pub pc: usize,
+    pub registers: Vec<f64>,
+    pub variables: HashMap<String, f64>,
+    pub call_stack: Vec<Frame>,
+    pub steps: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub stack: Vec<f64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub handlers: Vec<Handler>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub heap: Heap,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub coroutines: Coroutines,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub host_call: Option<PendingHostCall>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mailbox: Mailbox,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rng: Rng,
+}
+
+impl VmSnapshot {
+    pub fn to_json(&self) -> String {
+        let registers: Vec<String> = self.registers.iter().map(|&r| json::number(r)).collect();
+        let mut names: Vec<&String> = self.variables.keys().collect();
+        names.sort();
+        let variables: Vec<String> = names
+            .into_iter()
+            .map(|name| {
+                format!(
+                    "{}:{}",
+                    json::string(name),
+                    json::number(self.variables[name])
+                )
+            })
+            .collect();
+        let call_stack: Vec<String> = self
+            .call_stack
+            .iter()
+            .map(|f| f.return_address.to_string())
+            .collect();
+
+        // Omitted when empty, so snapshots of programs without them keep
+        // their original shape
+        let stack = if self.stack.is_empty() {
+            String::new()
+        } else {
+            let values: Vec<String> = self.stack.iter().map(|&v| json::number(v)).collect();
+            format!(",\"stack\":[{}]", values.join(","))
+        };
+        let handlers = if self.handlers.is_empty() {
+            String::new()
+        } else {
+            let handlers: Vec<String> = self
+                .handlers
+                .iter()
+                .map(|h| {
+                    format!(
+                        "{{\"addr\":{},\"dest\":{},\"call_depth\":{},\"stack_depth\":{}}}",
+                        h.addr, h.dest, h.call_depth, h.stack_depth
+                    )
+                })
+                .collect();
+            format!(",\"handlers\":[{}]", handlers.join(","))
+        };
+        let heap = if self.heap.is_empty() {
+            String::new()
+        } else {
+            let objects: Vec<String> = self
+                .heap
+                .slots()
+                .iter()
+                .map(|slot| match slot {
+                    None => "null".to_string(),
+                    Some(Object::Closure { addr, upvalues }) => {
+                        let upvalues: Vec<String> =
+                            upvalues.iter().map(|&v| json::number(v)).collect();
+                        format!(
+                            "{{\"closure\":{},\"upvalues\":[{}]}}",
+                            addr,
+                            upvalues.join(",")
+                        )
+                    }
+                    Some(Object::Record { fields }) => {
+                        let fields: Vec<String> = fields.iter().map(|&v| json::number(v)).collect();
+                        format!("{{\"record\":[{}]}}", fields.join(","))
+                    }
+                    Some(Object::Map { entries }) => {
+                        let entries: Vec<String> = entries
+                            .iter()
+                            .map(|(&key, &value)| {
+                                format!(
+                                    "[{},{}]",
+                                    json::number(f64::from_bits(key)),
+                                    json::number(value)
+                                )
+                            })
+                            .collect();
+                        format!("{{\"map\":[{}]}}", entries.join(","))
+                    }
+                })
+                .collect();
+            format!(",\"heap\":[{}]", objects.join(","))
+        };
+        let coroutines = if self.coroutines.is_empty() {
+            String::new()
+        } else {
+            let contexts: Vec<String> = self
+                .coroutines
+                .iter()
+                .map(|c| {
+                    let status = match c.status() {
+                        Status::Suspended => "suspended",
+                        Status::Running => "running",
+                        Status::Finished => "finished",
+                    };
+                    let registers: Vec<String> =
+                        c.registers.iter().map(|&r| json::number(r)).collect();
+                    let stack: Vec<String> = c.stack.iter().map(|&v| json::number(v)).collect();
+                    let call_stack: Vec<String> = c
+                        .call_stack
+                        .iter()
+                        .map(|f| f.return_address.to_string())
+                        .collect();
+                    format!(
+                        "{{\"status\":\"{}\",\"pc\":{},\"registers\":[{}],\"call_stack\":[{}],\"stack\":[{}]}}",
+                        status,
+                        c.pc,
+                        registers.join(","),
+                        call_stack.join(","),
+                        stack.join(",")
+                    )
+                })
+                .collect();
+            let resumed: Vec<String> = self.coroutines.resumed().map(|id| id.to_string()).collect();
+            format!(
+                ",\"coroutines\":[{}],\"resumed\":[{}]",
+                contexts.join(","),
+                resumed.join(",")
+            )
+        };
+
+        let host_call = match &self.host_call {
+            None => String::new(),
+            Some(pending) => {
+                let args: Vec<String> =
+                    pending.call.args.iter().map(|&v| json::number(v)).collect();
+                format!(
+                    ",\"host_call\":{{\"name\":{},\"args\":[{}],\"dest\":{}}}",
+                    json::string(&pending.call.name),
+                    args.join(","),
+                    pending.dest
+                )
+            }
+        };
+
+        let mailbox = if self.mailbox.is_empty() {
+            String::new()
+        } else {
+            let inbox: Vec<String> = self
+                .mailbox
+                .inbox
+                .iter()
+                .map(|&v| json::number(v))
+                .collect();
+            let outbox = match &self.mailbox.outbox {
+                Some(message) => format!(
+                    "{{\"to\":{},\"value\":{}}}",
+                    json::string(&message.to),
+                    json::number(message.value)
+                ),
+                None => "null".to_string(),
+            };
+            format!(
+                ",\"mailbox\":{{\"inbox\":[{}],\"outbox\":{}}}",
+                inbox.join(","),
+                outbox
+            )
+        };
+
+        let rng = if self.rng.draws() == 0 {
+            String::new()
+        } else {
+            format!(
+                ",\"rng\":{{\"state\":{},\"draws\":{}}}",
+                self.rng.state(),
+                self.rng.draws()
+            )
+        };
+
+        format!(
+            "{{\"pc\":{},\"steps\":{},\"registers\":[{}],\"variables\":{{{}}},\"call_stack\":[{}]{}{}{}{}{}{}{}}}\n",
+            self.pc,
+            self.steps,
+            registers.join(","),
+            variables.join(","),
+            call_stack.join(","),
+            stack,
+            handlers,
+            heap,
+            coroutines,
+            host_call,
+            mailbox,
+            rng
+        )
     }
 }
 
-/// A register–based virtual machine using f64 for all values
+/// How many steps run between wall-clock and cancellation checks, so they stay cheap
+const LIMIT_CHECK_INTERVAL: u64 = 1024;
+
+/// The limits a single run enforces, resolved when it starts
+pub(crate) struct RunLimits {
+    max_steps: Option<u64>,
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    deadline: Deadline,
+    cancellation: Option<CancellationToken>,
+}
+
+impl RunLimits {
+    /// The limits `config` sets for a run starting now, stopping at
+    /// `deadline` too if it comes first
+    pub(crate) fn new(config: &VmConfig, deadline: Deadline) -> Self {
+        #[cfg(feature = "std")]
+        let deadline = match (deadline, config.timeout.map(|t| Instant::now() + t)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        RunLimits {
+            max_steps: config.max_steps,
+            deadline,
+            cancellation: config.cancellation.clone(),
+        }
+    }
+
+    /// Fail if a run that has executed `executed` instructions may not execute another
+    #[inline(always)]
+    pub(crate) fn check(&self, executed: u64) -> Result<(), VmError> {
+        if self.max_steps.is_some_and(|max| executed >= max) {
+            return Err(VmError::StepLimitExceeded);
+        }
+        if executed.is_multiple_of(LIMIT_CHECK_INTERVAL) {
+            if let Some(token) = &self.cancellation
+                && token.is_cancelled()
+            {
+                return Err(VmError::Cancelled);
+            }
+            if self.expired() {
+                return Err(VmError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "std")]
+    fn expired(&self) -> bool {
+        self.deadline.is_some_and(|d| Instant::now() >= d)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn expired(&self) -> bool {
+        false
+    }
+}
+
+/// A shareable flag a host can set to stop a running VM from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits and options applied to every `run`
+#[derive(Debug, Clone, Default)]
+pub struct VmConfig {
+    /// Maximum number of instructions a single run may execute
+    pub max_steps: Option<u64>,
+    /// Wall-clock budget for a single run, checked every thousand or so instructions
+    #[cfg(feature = "std")]
+    pub timeout: Option<Duration>,
+    /// Stops the run with `VmError::Cancelled` once cancelled, checked alongside `timeout`
+    pub cancellation: Option<CancellationToken>,
+    /// How heap objects are freed, fixed when the VM is created
+    pub memory: MemoryMode,
+    /// Seed for the values `rand` produces, applied when the VM is created.
+    /// The same seed always gives the same sequence.
+    pub seed: u64,
+    /// What the program may do; anything else fails with
+    /// `VmError::PermissionDenied`
+    pub sandbox: SandboxPolicy,
+    /// Values live heap objects may hold in total, as counted by
+    /// `Object::cells`. Garbage is collected before an allocation fails.
+    pub max_heap_cells: Option<usize>,
+    /// Distinct variables the program may store to
+    pub max_variables: Option<usize>,
+    /// Total bytes of variable names the program may create
+    pub max_string_bytes: Option<usize>,
+    /// Values the running context's operand stack may hold
+    pub max_stack: Option<usize>,
+    /// Where a run that fails writes a JSON core dump of the VM
+    #[cfg(feature = "std")]
+    pub dump_on_trap: Option<PathBuf>,
+}
+
+impl VmConfig {
+    fn limits_memory(&self) -> bool {
+        self.max_heap_cells.is_some()
+            || self.max_variables.is_some()
+            || self.max_string_bytes.is_some()
+    }
+}
+
+/// How much memory a VM's program is using, for comparing against the
+/// limits in `VmConfig`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub heap_objects: usize,
+    pub heap_cells: usize,
+    pub variables: usize,
+    /// Bytes of variable names
+    pub string_bytes: usize,
+    /// Values on the running context's operand stack
+    pub stack: usize,
+}
+
+/// Where `print` sends values
+pub(crate) enum Output {
+    #[cfg(feature = "std")]
+    Stdout,
+    Captured(Vec<f64>),
+    Sink(Box<dyn FnMut(f64) + Send>),
+}
+
+impl Default for Output {
+    /// Stdout, or without `std` nowhere until a sink is set
+    fn default() -> Self {
+        #[cfg(feature = "std")]
+        return Output::Stdout;
+        #[cfg(not(feature = "std"))]
+        Output::Sink(Box::new(|_| {}))
+    }
+}
+
+impl Output {
+    pub(crate) fn write(&mut self, value: f64) {
+        match self {
+            #[cfg(feature = "std")]
+            Output::Stdout => println!("{}", value),
+            Output::Captured(values) => values.push(value),
+            Output::Sink(sink) => sink(value),
+        }
+    }
+}
+
+/// A register–based virtual machine using f64 for all values.
+///
+/// A VM is `Send` but not `Sync`: it can move between threads, and one
+/// thread at a time runs it. Host functions are `Send + Sync` and hooks and
+/// output sinks `Send` to keep it so. VMs built from clones of one
+/// `SharedProgram` run it concurrently without copying it.
 pub struct VM {
     pub pc: usize,
     pub registers: Vec<f64>,
-    pub program: Vec<Instruction>,
+    pub program: SharedProgram,
     pub call_stack: Vec<Frame>,
+    /// Values `push` saved for `pop`, top last
+    pub stack: Vec<f64>,
     pub variables: HashMap<String, f64>,
+    /// Installed error handlers, innermost last
+    pub handlers: Vec<Handler>,
+    /// Objects allocated by the program, referenced from registers by handle
+    pub heap: Heap,
+    /// Contexts of the coroutines not running now; `pc`, `registers`,
+    /// `call_stack`, `stack` and `handlers` belong to whichever context is
+    /// running
+    pub coroutines: Coroutines,
+    /// The `callhost` the VM is suspended on, until the host completes it
+    pub(crate) host_call: Option<PendingHostCall>,
+    pub(crate) host_functions: HashMap<String, HostFunction>,
+    pub(crate) hooks: Vec<Box<dyn Hook>>,
+    /// Where `print` sends values
+    pub(crate) output: Output,
+    /// The inputs being recorded or replayed
+    pub(crate) replay: Option<Replay>,
+    /// A span per call frame, outermost first
+    #[cfg(feature = "tracing")]
+    pub(crate) frame_spans: Vec<tracing::Span>,
+    /// Messages to and from other isolates
+    pub mailbox: Mailbox,
+    pub rng: Rng,
+    pub config: VmConfig,
+    /// Total instructions executed over the lifetime of this VM
+    pub steps: u64,
 }
 
 impl VM {
-    pub fn new(program: Vec<Instruction>, num_registers: usize) -> Self {
+    pub fn new(program: impl Into<SharedProgram>, num_registers: usize) -> Self {
+        Self::with_config(program, num_registers, VmConfig::default())
+    }
+
+    pub fn with_config(
+        program: impl Into<SharedProgram>,
+        num_registers: usize,
+        config: VmConfig,
+    ) -> Self {
+        let program = program.into();
         Self {
-            pc: 0,
+            pc: program.entry(),
             registers: vec![0.0; num_registers],
             program,
             call_stack: Vec::new(),
+            stack: Vec::new(),
             variables: HashMap::new(),
+            handlers: Vec::new(),
+            heap: Heap::with_mode(config.memory),
+            coroutines: Coroutines::default(),
+            host_call: None,
+            host_functions: HashMap::new(),
+            hooks: Vec::new(),
+            output: Output::default(),
+            replay: None,
+            #[cfg(feature = "tracing")]
+            frame_spans: Vec::new(),
+            mailbox: Mailbox::default(),
+            rng: Rng::new(config.seed),
+            config,
+            steps: 0,
         }
     }
 
+    /// Run to completion. Programs whose register operands all fit the
+    /// register file are pre-decoded and run without per-access bounds checks.
     pub fn run(&mut self) -> Result<(), VmError> {
-        while self.pc < self.program.len() {
-            let instr = self.program[self.pc].clone();
-            self.pc += 1;
-            self.execute_instruction(instr)?;
+        self.run_fast(None)
+    }
+
+    /// Run to completion, failing with `VmError::Timeout` once `deadline` passes
+    #[cfg(feature = "std")]
+    pub fn run_with_deadline(&mut self, deadline: Instant) -> Result<(), VmError> {
+        self.run_fast(Some(deadline))
+    }
+
+    fn run_fast(&mut self, deadline: Deadline) -> Result<(), VmError> {
+        self.check_host_call()?;
+        #[cfg(feature = "tracing")]
+        let _span = self.run_span().entered();
+        // Verified per run, since `program` and `registers` are public and
+        // may have changed since the last one
+        let result = if !self.needs_stepping()
+            && self.program.verify_registers(self.registers.len()).is_ok()
+            && self.saved_registers_in_bounds()
+        {
+            let limits = self.run_limits(deadline);
+            dispatch::run(self, &limits)
+        } else {
+            self.run_with(deadline, VM::step)
+        };
+        #[cfg(feature = "std")]
+        self.write_core_dump(&result);
+        result
+    }
+
+    /// Compile the program to native code, then run it to completion with
+    /// `run_compiled`. Programs that cannot be compiled run on the interpreter.
+    #[cfg(feature = "jit")]
+    pub fn run_jit(&mut self) -> Result<(), VmError> {
+        match jit::CompiledProgram::compile(&self.program, self.registers.len()) {
+            Ok(compiled) => self.run_compiled(&compiled),
+            Err(_) => self.run(),
+        }
+    }
+
+    /// Run to completion on previously compiled code.
+    ///
+    /// Instructions the compiled code cannot execute are handed to the
+    /// interpreter one at a time. If `compiled` was built from a different
+    /// program or for more registers, or limits are configured, the whole run
+    /// uses the interpreter instead.
+    #[cfg(feature = "jit")]
+    pub fn run_compiled(&mut self, compiled: &jit::CompiledProgram) -> Result<(), VmError> {
+        let limited = self.config.max_steps.is_some()
+            || self.config.timeout.is_some()
+            || self.config.cancellation.is_some()
+            || self.needs_stepping();
+        if limited || !compiled.matches(self) {
+            return self.run();
+        }
+
+        let mut scratch = compiled.scratch();
+        while !self.is_halted() {
+            compiled.enter(self, &mut scratch);
+            if !self.is_halted()
+                && let Err(e) = self.step()
+            {
+                let result = Err(e);
+                self.write_core_dump(&result);
+                return result;
+            }
         }
         Ok(())
     }
 
-    fn execute_instruction(&mut self, instr: Instruction) -> Result<(), VmError> {
+    /// Run to completion, recording every executed instruction into `trace`
+    pub fn run_traced(&mut self, trace: &mut Trace) -> Result<(), VmError> {
+        let result = self.run_with(None, |vm| trace.step(vm));
+        #[cfg(feature = "std")]
+        self.write_core_dump(&result);
+        result
+    }
+
+    /// Drive `step` until the program halts, enforcing the configured limits.
+    ///
+    /// When a limit trips the VM is left as it was, so the run can be resumed.
+    fn run_with(
+        &mut self,
+        deadline: Deadline,
+        mut step: impl FnMut(&mut VM) -> Result<(), VmError>,
+    ) -> Result<(), VmError> {
+        let start_steps = self.steps;
+        let limits = self.run_limits(deadline);
+
+        while !self.is_halted() {
+            limits.check(self.steps - start_steps)?;
+            step(self)?;
+        }
+        Ok(())
+    }
+
+    /// Resolve the configured limits for a run starting now
+    fn run_limits(&self, deadline: Deadline) -> RunLimits {
+        RunLimits::new(&self.config, deadline)
+    }
+
+    /// Execute the single instruction at `pc`. A catchable error with a
+    /// handler installed unwinds to that handler instead of failing.
+    pub fn step(&mut self) -> Result<(), VmError> {
+        self.check_host_call()?;
+        if self.hooks.is_empty() {
+            self.step_unhooked()
+        } else {
+            self.step_hooked()
+        }
+    }
+
+    pub(crate) fn step_unhooked(&mut self) -> Result<(), VmError> {
+        #[cfg(feature = "tracing")]
+        let pc = self.pc;
+        let result = match self.execute_instruction() {
+            Err(error) => {
+                #[cfg(feature = "tracing")]
+                self.trace_trap(pc, &error);
+                self.catch(error)
+            }
+            ok => ok,
+        };
+        #[cfg(feature = "tracing")]
+        self.trace_frames();
+        result
+    }
+
+    /// Undo fetching the instruction that could not run, and stop
+    fn block(&mut self) -> Result<(), VmError> {
+        self.pc -= 1;
+        self.steps -= 1;
+        Err(VmError::Blocked)
+    }
+
+    /// Fail while a host call is waiting for its result
+    fn check_host_call(&self) -> Result<(), VmError> {
+        match &self.host_call {
+            Some(pending) => Err(VmError::HostCallPending(pending.call.name.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn catch(&mut self, error: VmError) -> Result<(), VmError> {
+        let (handler, code) = unwind(&mut self.handlers, error)?;
+        self.drop_frames(handler.call_depth);
+        self.drop_values(handler.stack_depth);
+        self.set_register(handler.dest, code)?;
+        self.pc = handler.addr;
+        Ok(())
+    }
+
+    /// Capture the current execution state
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            pc: self.pc,
+            registers: self.registers.clone(),
+            variables: self.variables.clone(),
+            call_stack: self.call_stack.clone(),
+            steps: self.steps,
+            stack: self.stack.clone(),
+            handlers: self.handlers.clone(),
+            heap: self.heap.clone(),
+            coroutines: self.coroutines.clone(),
+            host_call: self.host_call.clone(),
+            mailbox: self.mailbox.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    /// Rewind or fast-forward to a previously captured state
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.pc = snapshot.pc;
+        self.registers = snapshot.registers.clone();
+        self.variables = snapshot.variables.clone();
+        self.call_stack = snapshot.call_stack.clone();
+        self.steps = snapshot.steps;
+        self.stack = snapshot.stack.clone();
+        self.handlers = snapshot.handlers.clone();
+        self.heap = snapshot.heap.clone();
+        self.coroutines = snapshot.coroutines.clone();
+        self.host_call = snapshot.host_call.clone();
+        self.mailbox = snapshot.mailbox.clone();
+        self.rng = snapshot.rng.clone();
+    }
+
+    /// Whether the registers that state left by an earlier run may write,
+    /// such as a handler's code register or a resumer's outcome register,
+    /// fit the current register file
+    fn saved_registers_in_bounds(&self) -> bool {
+        let len = self.registers.len();
+        self.handlers.iter().all(|handler| handler.dest < len)
+            && self.coroutines.saved_registers().all(|reg| reg < len)
+    }
+
+    /// Reference counts, the sandbox, memory limits, hooks, traced frames
+    /// and replay are maintained by the stepping interpreter only
+    fn needs_stepping(&self) -> bool {
+        #[cfg(feature = "tracing")]
+        if self.traces_steps() {
+            return true;
+        }
+        !self.hooks.is_empty()
+            || self.heap.mode() == MemoryMode::RefCounted
+            || !self.config.sandbox.is_unrestricted()
+            || self.config.limits_memory()
+            || self.replay.is_some()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            heap_objects: self.heap.len(),
+            heap_cells: self.heap.cells(),
+            variables: self.variables.len(),
+            string_bytes: self.variables.keys().map(String::len).sum(),
+            stack: self.stack.len(),
+        }
+    }
+
+    /// Free every heap object the program can no longer reach from a
+    /// register, operand stack, variable or frame of any coroutine,
+    /// returning how many were freed. Runs automatically as the heap grows.
+    pub fn collect_garbage(&mut self) -> usize {
+        let values = self.registers.iter().chain(&self.stack);
+        let values = values.chain(self.variables.values());
+        let frames = self.call_stack.iter().filter_map(|frame| frame.closure);
+        self.heap.collect(
+            values.copied().chain(self.coroutines.values()),
+            frames.chain(self.coroutines.closures()),
+        )
+    }
+
+    /// Resume coroutine `id` from the host and run it until it yields or
+    /// finishes, returning whether it can be resumed again. If it fails or
+    /// halts, the VM is left in its context.
+    pub fn resume(&mut self, id: f64) -> Result<bool, VmError> {
+        let depth = self.coroutines.depth();
+        let (coroutines, context) = self.context();
+        coroutines.resume(id, None, context)?;
+
+        let start_steps = self.steps;
+        let limits = self.run_limits(None);
+        while self.coroutines.depth() > depth && !self.is_halted() {
+            limits.check(self.steps - start_steps)?;
+            self.step()?;
+        }
+        Ok(self
+            .coroutines
+            .get(id)
+            .is_some_and(|c| c.status() == Status::Suspended))
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    /// Collect printed values instead of writing them to stdout
+    pub fn capture_output(&mut self) {
+        if !matches!(self.output, Output::Captured(_)) {
+            self.output = Output::Captured(Vec::new());
+        }
+    }
+
+    /// Values printed since the last call, if output is captured
+    pub fn take_output(&mut self) -> Vec<f64> {
+        match &mut self.output {
+            Output::Captured(values) => core::mem::take(values),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Hand every printed value to `sink`, such as a serial port writer on a
+    /// target without stdout
+    pub fn set_output(&mut self, sink: impl FnMut(f64) + Send + 'static) {
+        self.output = Output::Sink(Box::new(sink));
+    }
+
+    /// Whether execution has run off the end of the program or hit `Halt`
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// Run the exported function `name` to completion with `args` in registers `0..arity`.
+    ///
+    /// Variables and registers persist between calls, so one VM can service
+    /// `init`, `update`, and `shutdown` style entry points over its lifetime.
+    pub fn call_export(&mut self, name: &str, args: &[f64]) -> Result<(), VmError> {
+        let export = self
+            .program
+            .find_export(name)
+            .ok_or_else(|| VmError::UnknownExport(name.to_string()))?;
+        if export.arity != args.len() {
+            return Err(VmError::ArityMismatch {
+                expected: export.arity,
+                found: args.len(),
+            });
+        }
+        let addr = export.addr;
+
+        for (i, &arg) in args.iter().enumerate() {
+            self.set_register(i, arg)?;
+        }
+        // Returning from the entry frame lands past the end of the program and stops the run
+        self.drop_frames(0);
+        self.call_stack.push(Frame::new(self.program.len()));
+        self.handlers.clear();
+        self.pc = addr;
+        self.run()
+    }
+
+    /// Fetch and execute the instruction at `pc`. The instruction is matched
+    /// in place rather than cloned: operands are copied out, so the borrow of
+    /// `program` ends before any arm mutates the VM.
+    fn execute_instruction(&mut self) -> Result<(), VmError> {
         use Instruction::*;
-        match instr {
+        let instr = self
+            .program
+            .instructions
+            .get(self.pc)
+            .ok_or(VmError::ProgramCounterOutOfBounds)?;
+        self.pc += 1;
+        self.steps += 1;
+        match *instr {
             LoadImm { dest, value } => self.set_register(dest, value)?,
             Add { dest, src1, src2 } => {
                 let v = self.get_register(src1)? + self.get_register(src2)?;
@@ -84,24 +1040,45 @@ impl VM {
                 let v = self.get_register(src1)? / self.get_register(src2)?;
                 self.set_register(dest, v)?;
             }
-            Print { src } => println!("{}", self.get_register(src)?),
+            Print { src } => {
+                self.config.sandbox.check_stdout()?;
+                let v = self.get_register(src)?;
+                self.output.write(v);
+            }
             Jump(addr) => self.jump(addr)?,
             Call { addr } => self.call(addr)?,
+            CallArgs { addr, argc } => {
+                self.check_stack()?;
+                self.call(addr)?;
+                self.stack.push(argc as f64);
+            }
+            JumpRel(offset) => self.jump(instruction::relative(self.pc - 1, offset))?,
+            CallRel(offset) => self.call(instruction::relative(self.pc - 1, offset))?,
             ConditionalJump { cond, target } => {
                 if self.get_register(cond)? == 0.0 {
                     self.jump(target)?;
                 }
             }
             Return => self.ret()?,
-            Store { src, var } => {
+            Store { src, ref var } => {
                 let val = self.get_register(src)?;
-                self.variables.insert(var, val);
+                if !self.variables.contains_key(var) {
+                    self.check_new_variable(var)?;
+                }
+                self.heap.retain(val);
+                // Only the first store to a variable allocates its name
+                match self.variables.get_mut(var) {
+                    Some(slot) => self.heap.release(core::mem::replace(slot, val)),
+                    None => {
+                        self.variables.insert(var.clone(), val);
+                    }
+                }
             }
-            Load { dest, var } => {
+            Load { dest, ref var } => {
                 let val = *self
                     .variables
-                    .get(&var)
-                    .ok_or(VmError::VariableNotFound(var))?;
+                    .get(var)
+                    .ok_or_else(|| VmError::VariableNotFound(var.clone()))?;
                 self.set_register(dest, val)?;
             }
             Mov { dest, src } => {
@@ -141,6 +1118,258 @@ impl VM {
                 self.set_register(dest, v)?;
             }
             Halt => self.pc = self.program.len(),
+            AddImm {
+                dest,
+                src,
+                imm,
+                value,
+            } => {
+                self.set_register(imm, value)?;
+                let v = self.get_register(src)? + value;
+                self.set_register(dest, v)?;
+            }
+            CompareJump {
+                cmp,
+                dest,
+                src1,
+                src2,
+                target,
+            } => {
+                let taken = cmp.eval(self.get_register(src1)?, self.get_register(src2)?);
+                self.set_register(dest, if taken { 1.0 } else { 0.0 })?;
+                if !taken {
+                    self.jump(target)?;
+                }
+            }
+            TryBegin { handler, dest } => {
+                if handler >= self.program.len() {
+                    return Err(VmError::ProgramCounterOutOfBounds);
+                }
+                self.handlers.push(Handler {
+                    addr: handler,
+                    dest,
+                    call_depth: self.call_stack.len(),
+                    stack_depth: self.stack.len(),
+                });
+            }
+            TryEnd => {
+                self.handlers.pop().ok_or(VmError::HandlerStackEmpty)?;
+            }
+            Throw { src } => return Err(VmError::Uncaught(self.get_register(src)?)),
+            JumpIndirect { src } => {
+                let addr =
+                    address(self.get_register(src)?).ok_or(VmError::ProgramCounterOutOfBounds)?;
+                self.jump(addr)?;
+            }
+            Switch {
+                src,
+                ref table,
+                default,
+            } => {
+                let target = address(self.get_register(src)?)
+                    .and_then(|i| table.get(i).copied())
+                    .unwrap_or(default);
+                self.jump(target)?;
+            }
+            CallIndirect { src } => {
+                let addr =
+                    address(self.get_register(src)?).ok_or(VmError::ProgramCounterOutOfBounds)?;
+                self.call(addr)?;
+            }
+            LoadAddr { dest, addr } => self.set_register(dest, addr as f64)?,
+            MakeClosure {
+                dest,
+                addr,
+                ref captures,
+            } => {
+                let upvalues = captures
+                    .iter()
+                    .map(|&reg| self.get_register(reg))
+                    .collect::<Result<_, _>>()?;
+                let handle = self.alloc(Object::Closure { addr, upvalues })?;
+                self.set_register(dest, handle)?;
+            }
+            CallClosure { src } => {
+                let (addr, closure) = self.heap.closure(self.get_register(src)?)?;
+                self.call(addr)?;
+                if let Some(frame) = self.call_stack.last_mut() {
+                    frame.closure = Some(closure);
+                    self.heap.retain_index(closure);
+                }
+            }
+            GetUpvalue { dest, index } => {
+                let v = *self.heap.upvalue(self.running_closure(), index)?;
+                self.set_register(dest, v)?;
+            }
+            SetUpvalue { src, index } => {
+                let v = self.get_register(src)?;
+                let old = core::mem::replace(self.heap.upvalue(self.running_closure(), index)?, v);
+                self.heap.retain(v);
+                self.heap.release(old);
+            }
+            NewRecord { dest, fields } => {
+                let handle = self.alloc(Object::Record {
+                    fields: vec![0.0; fields],
+                })?;
+                self.set_register(dest, handle)?;
+            }
+            GetField {
+                dest,
+                record,
+                index,
+            } => {
+                let v = *self.heap.field(self.get_register(record)?, index)?;
+                self.set_register(dest, v)?;
+            }
+            SetField { record, index, src } => {
+                let v = self.get_register(src)?;
+                let old =
+                    core::mem::replace(self.heap.field(self.get_register(record)?, index)?, v);
+                self.heap.retain(v);
+                self.heap.release(old);
+            }
+            MapNew { dest } => {
+                let handle = self.alloc(Object::Map {
+                    entries: BTreeMap::new(),
+                })?;
+                self.set_register(dest, handle)?;
+            }
+            MapGet { dest, map, key } => {
+                let key = self.get_register(key)?;
+                let v = *self
+                    .heap
+                    .map(self.get_register(map)?)?
+                    .get(&map_key(key))
+                    .ok_or(VmError::KeyNotFound(key))?;
+                self.set_register(dest, v)?;
+            }
+            MapSet { map, key, src } => {
+                let (key, v) = (self.get_register(key)?, self.get_register(src)?);
+                let map = self.get_register(map)?;
+                if !self.heap.map(map)?.contains_key(&map_key(key)) {
+                    self.reserve_cells(2)?;
+                }
+                let old = self.heap.map_insert(map, map_key(key), v)?;
+                self.heap.retain(v);
+                match old {
+                    Some(old) => self.heap.release(old),
+                    None => self.heap.retain(key),
+                }
+            }
+            MapHas { dest, map, key } => {
+                let key = self.get_register(key)?;
+                let has = self
+                    .heap
+                    .map(self.get_register(map)?)?
+                    .contains_key(&map_key(key));
+                self.set_register(dest, if has { 1.0 } else { 0.0 })?;
+            }
+            MapDelete { map, key } => {
+                let key = self.get_register(key)?;
+                if let Some(old) = self
+                    .heap
+                    .map_remove(self.get_register(map)?, map_key(key))?
+                {
+                    self.heap.release(key);
+                    self.heap.release(old);
+                }
+            }
+            Spawn { dest, addr } => {
+                if addr >= self.program.len() {
+                    return Err(VmError::ProgramCounterOutOfBounds);
+                }
+                for &value in &self.registers {
+                    self.heap.retain(value);
+                }
+                let id = self.coroutines.spawn(addr, self.registers.clone());
+                self.set_register(dest, id)?;
+            }
+            Yield => {
+                let (coroutines, context) = self.context();
+                if let Some(dest) = coroutines.suspend(context)? {
+                    self.set_register(dest, 1.0)?;
+                }
+            }
+            Resume { dest, id } => {
+                let id = self.get_register(id)?;
+                let (coroutines, context) = self.context();
+                coroutines.resume(id, Some(dest), context)?;
+            }
+            CallHost {
+                dest,
+                ref name,
+                ref args,
+            } => {
+                self.config.sandbox.check_host_function(name)?;
+                let pending = host::pending(dest, name, args, &self.registers)?;
+                if let Some(result) = self.replay_host_call(&pending.call) {
+                    self.set_register(dest, result?)?;
+                    return Ok(());
+                }
+                match self.host_functions.get(&pending.call.name) {
+                    Some(function) => {
+                        let result = host::call(function, &pending.call);
+                        self.record_host_call(&pending.call, &result);
+                        self.set_register(dest, result?)?;
+                    }
+                    None => {
+                        #[cfg(feature = "tracing")]
+                        tracing::debug!(name = %pending.call.name, "host call pending");
+                        let name = pending.call.name.clone();
+                        self.host_call = Some(pending);
+                        return Err(VmError::HostCallPending(name));
+                    }
+                }
+            }
+            Send { src, ref to } => {
+                let value = self.get_register(src)?;
+                // The recorded run already delivered it
+                if self.is_replaying() {
+                    return Ok(());
+                }
+                if self.mailbox.outbox.is_some() {
+                    return self.block();
+                }
+                self.mailbox.outbox = Some(Message {
+                    to: to.clone(),
+                    value,
+                });
+            }
+            Recv { dest } => {
+                if let Some(value) = self.replay_message() {
+                    self.set_register(dest, value?)?;
+                    return Ok(());
+                }
+                match self.mailbox.inbox.pop_front() {
+                    Some(value) => {
+                        self.record_message(value);
+                        self.set_register(dest, value)?;
+                    }
+                    None => return self.block(),
+                }
+            }
+            Rand { dest } => {
+                let v = self.rng.next_f64();
+                self.set_register(dest, v)?;
+            }
+            Push { src } => {
+                let v = self.get_register(src)?;
+                self.check_stack()?;
+                self.heap.retain(v);
+                self.stack.push(v);
+            }
+            Pop { dest } => {
+                let v = *self.stack.last().ok_or(VmError::StackUnderflow)?;
+                self.set_register(dest, v)?;
+                self.stack.pop();
+                self.heap.release(v);
+            }
+            Arity { dest, min, max } => {
+                let v = *self.stack.last().ok_or(VmError::StackUnderflow)?;
+                arity(v, min, max)?;
+                self.set_register(dest, v)?;
+                self.stack.pop();
+            }
         }
         Ok(())
     }
@@ -151,9 +1380,11 @@ impl VM {
         })
     }
 
-    fn set_register(&mut self, index: usize, value: f64) -> Result<(), VmError> {
+    pub(crate) fn set_register(&mut self, index: usize, value: f64) -> Result<(), VmError> {
         if let Some(reg) = self.registers.get_mut(index) {
-            *reg = value;
+            let old = core::mem::replace(reg, value);
+            self.heap.retain(value);
+            self.heap.release(old);
             Ok(())
         } else {
             Err(VmError::RegisterOutOfBounds(format!(
@@ -176,18 +1407,131 @@ impl VM {
         if addr >= self.program.len() {
             return Err(VmError::ProgramCounterOutOfBounds);
         }
+        self.config
+            .sandbox
+            .check_call_depth(self.call_stack.len())?;
         self.call_stack.push(Frame::new(self.pc));
         self.pc = addr;
         Ok(())
     }
 
+    /// Fail unless one more value fits on the operand stack
+    fn check_stack(&self) -> Result<(), VmError> {
+        if self
+            .config
+            .max_stack
+            .is_some_and(|max| self.stack.len() >= max)
+        {
+            return Err(VmError::MemoryLimitExceeded("stack"));
+        }
+        Ok(())
+    }
+
+    fn running_closure(&self) -> Option<usize> {
+        self.call_stack.last().and_then(|frame| frame.closure)
+    }
+
+    /// Allocate `object`, collecting first if the heap has grown enough or
+    /// is at the sandbox's cap
+    fn alloc(&mut self, object: Object) -> Result<f64, VmError> {
+        let sandbox = &self.config.sandbox;
+        if self.heap.needs_collection() || sandbox.check_heap(self.heap.stats().live).is_err() {
+            self.collect_garbage();
+        }
+        self.config.sandbox.check_heap(self.heap.stats().live)?;
+        self.reserve_cells(object.cells())?;
+        Ok(self.heap.alloc(object))
+    }
+
+    /// Fail unless `cells` more heap cells fit under `max_heap_cells`,
+    /// collecting garbage first if they would not
+    fn reserve_cells(&mut self, cells: usize) -> Result<(), VmError> {
+        let Some(max) = self.config.max_heap_cells else {
+            return Ok(());
+        };
+        if self.heap.cells() + cells > max {
+            self.collect_garbage();
+        }
+        if self.heap.cells() + cells > max {
+            return Err(VmError::MemoryLimitExceeded("heap cells"));
+        }
+        Ok(())
+    }
+
+    /// Fail if storing to a new variable named `name` would exceed a limit
+    fn check_new_variable(&self, name: &str) -> Result<(), VmError> {
+        if self
+            .config
+            .max_variables
+            .is_some_and(|max| self.variables.len() >= max)
+        {
+            return Err(VmError::MemoryLimitExceeded("variables"));
+        }
+        if let Some(max) = self.config.max_string_bytes {
+            let used: usize = self.variables.keys().map(String::len).sum();
+            if used + name.len() > max {
+                return Err(VmError::MemoryLimitExceeded("string bytes"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pop frames until `depth` remain, releasing the closures they ran
+    fn drop_frames(&mut self, depth: usize) {
+        let depth = depth.min(self.call_stack.len());
+        for frame in self.call_stack.drain(depth..) {
+            if let Some(closure) = frame.closure {
+                self.heap.release_index(closure);
+            }
+        }
+    }
+
+    /// Pop values until `depth` remain, releasing them
+    fn drop_values(&mut self, depth: usize) {
+        let depth = depth.min(self.stack.len());
+        for value in self.stack.drain(depth..) {
+            self.heap.release(value);
+        }
+    }
+
     fn ret(&mut self) -> Result<(), VmError> {
-        let frame = self.call_stack.pop().ok_or(VmError::CallStackEmpty)?;
+        let Some(frame) = self.call_stack.pop() else {
+            return self.finish_coroutine();
+        };
         self.pc = frame.return_address;
+        if let Some(closure) = frame.closure {
+            self.heap.release_index(closure);
+        }
+        prune_handlers(&mut self.handlers, self.call_stack.len());
         Ok(())
     }
 
-    #[cfg(debug_assertions)]
+    /// Return from the function a coroutine was spawned at, handing control
+    /// back to its resumer
+    fn finish_coroutine(&mut self) -> Result<(), VmError> {
+        let (coroutines, context) = self.context();
+        let (dest, values) = coroutines.finish(context).ok_or(VmError::CallStackEmpty)?;
+        for value in values {
+            self.heap.release(value);
+        }
+        if let Some(dest) = dest {
+            self.set_register(dest, 0.0)?;
+        }
+        Ok(())
+    }
+
+    /// The coroutines alongside the running context, to switch between them
+    fn context(&mut self) -> (&mut Coroutines, Context<'_>) {
+        let context = Context {
+            pc: &mut self.pc,
+            registers: &mut self.registers,
+            call_stack: &mut self.call_stack,
+            stack: &mut self.stack,
+            handlers: &mut self.handlers,
+        };
+        (&mut self.coroutines, context)
+    }
+
     pub fn visualize_callstack(&self) -> String {
         if self.call_stack.is_empty() {
             "(empty call stack)".to_string()
@@ -202,4 +1546,91 @@ impl VM {
             s
         }
     }
+
+    /// Render the whole machine state: pc, registers, operand stack,
+    /// variables and call stack
+    pub fn visualize(&self) -> String {
+        let mut s = format!("pc: {} (steps: {})\nregisters:\n", self.pc, self.steps);
+        for (i, value) in self.registers.iter().enumerate() {
+            s.push_str(&format!("  r{} = {}\n", i, value));
+        }
+        if !self.stack.is_empty() {
+            s.push_str(&format!("stack: {:?}\n", self.stack));
+        }
+        if self.variables.is_empty() {
+            s.push_str("(no variables)\n");
+        } else {
+            s.push_str("variables:\n");
+            let mut names: Vec<&String> = self.variables.keys().collect();
+            names.sort();
+            for name in names {
+                s.push_str(&format!("  {} = {}\n", name, self.variables[name]));
+            }
+        }
+        s.push_str(&self.visualize_callstack());
+        if self.call_stack.is_empty() {
+            s.push('\n');
+        }
+        s
+    }
+
+    /// Render the active frames innermost first, naming each by the nearest
+    /// preceding label or export. Frame 0 is the current pc; the others are
+    /// the call sites still waiting for a return.
+    pub fn backtrace(&self) -> String {
+        let mut s = String::from("backtrace:\n");
+        for (i, site) in self.frame_sites().into_iter().enumerate() {
+            s.push_str(&format!("  frame {}: {}\n", i, self.describe_site(site)));
+        }
+        s
+    }
+
+    /// Render the active frames as a Graphviz DOT chain, callee to caller,
+    /// with the current frame highlighted
+    pub fn export_callstack_dot(&self) -> String {
+        let mut s = String::from("digraph callstack {\n  node [shape=box, fontname=monospace];\n");
+        let sites = self.frame_sites();
+        for (i, &site) in sites.iter().enumerate() {
+            let mut label = format!("frame {}\n{}", i, self.describe_site(site));
+            if let Some(addr) = site {
+                label.push_str(&format!("\npc {}", addr));
+            }
+            let style = if i == 0 {
+                ", style=filled, fillcolor=lightyellow"
+            } else {
+                ""
+            };
+            s.push_str(&format!(
+                "  f{} [label={}{}];\n",
+                i,
+                dot::string(&label),
+                style
+            ));
+        }
+        for i in 1..sites.len() {
+            s.push_str(&format!("  f{} -> f{} [label=\"returns to\"];\n", i - 1, i));
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// The current pc followed by each pending call site, innermost first;
+    /// `None` marks the entry frame pushed by `call_export`
+    pub(crate) fn frame_sites(&self) -> Vec<Option<usize>> {
+        let callers = self.call_stack.iter().rev().map(|frame| {
+            (frame.return_address < self.program.len())
+                .then(|| frame.return_address.saturating_sub(1))
+        });
+        core::iter::once(Some(self.pc)).chain(callers).collect()
+    }
+
+    fn describe_site(&self, site: Option<usize>) -> String {
+        match site {
+            Some(addr) => match self.program.symbolize(addr) {
+                Some((name, offset)) => format!("in {} (+{})", name, offset),
+                None => format!("at {}", addr),
+            },
+            None => "<host>".to_string(),
+        }
+    }
 }
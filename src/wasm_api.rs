@@ -0,0 +1,95 @@
+//! A JavaScript-friendly facade for running programs in a browser,
+//! exported with `wasm-bindgen` when built for `wasm32-unknown-unknown`
+//!
+//! Programs are loaded from bytecode. Printed values are collected by the
+//! session rather than written to stdout, which a browser does not have.
+
+use wasm_bindgen::prelude::*;
+
+use crate::instruction::Instruction;
+use crate::program::Program;
+use crate::trace::Trace;
+use crate::vm::VM;
+
+/// A loaded program that can be run, stepped and inspected from JavaScript
+#[wasm_bindgen]
+pub struct Session {
+    vm: VM,
+    output: Vec<f64>,
+    trace: Option<Trace>,
+}
+
+#[wasm_bindgen]
+impl Session {
+    /// Decode `bytecode` and prepare a VM with `registers` registers
+    #[wasm_bindgen(constructor)]
+    pub fn new(bytecode: &[u8], registers: usize) -> Result<Session, String> {
+        let program = Program::from_bytecode(bytecode).map_err(|e| e.to_string())?;
+        Ok(Session {
+            vm: VM::new(program, registers),
+            output: Vec::new(),
+            trace: None,
+        })
+    }
+
+    /// Execute one instruction, returning whether the program has halted
+    pub fn step(&mut self) -> Result<bool, String> {
+        let printed = match self.vm.program.instructions.get(self.vm.pc) {
+            Some(Instruction::Print { src }) => self.vm.registers.get(*src).copied(),
+            _ => None,
+        };
+        let result = match &mut self.trace {
+            Some(trace) => trace.step(&mut self.vm),
+            None => self.vm.step(),
+        };
+        result.map_err(|e| e.to_string())?;
+        self.output.extend(printed);
+        Ok(self.vm.is_halted())
+    }
+
+    /// Execute at most `max_steps` instructions, returning whether the program has halted
+    ///
+    /// Bounding each call lets a page keep its UI responsive while a long program runs.
+    pub fn run(&mut self, max_steps: u32) -> Result<bool, String> {
+        for _ in 0..max_steps {
+            if self.vm.is_halted() {
+                break;
+            }
+            self.step()?;
+        }
+        Ok(self.vm.is_halted())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> usize {
+        self.vm.pc
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn halted(&self) -> bool {
+        self.vm.is_halted()
+    }
+
+    pub fn registers(&self) -> Vec<f64> {
+        self.vm.registers.clone()
+    }
+
+    /// Values printed since the last call
+    pub fn take_output(&mut self) -> Vec<f64> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Start or stop recording a trace of each executed instruction
+    pub fn set_tracing(&mut self, enabled: bool) {
+        match (enabled, self.trace.is_some()) {
+            (true, false) => self.trace = Some(Trace::new()),
+            (false, true) => self.trace = None,
+            _ => {}
+        }
+    }
+
+    /// The recorded trace as JSON, or an empty trace if tracing is off
+    pub fn trace_json(&self) -> String {
+        self.trace.clone().unwrap_or_default().to_json()
+    }
+}
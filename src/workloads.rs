@@ -0,0 +1,434 @@
+//! Canonical programs for benchmarking the interpreter and optimizer.
+//!
+//! Each workload needs at most `REGISTERS` registers and leaves its result in register 0.
+//! The `standard` ones use only arithmetic and control flow, so every backend runs them.
+
+use crate::instruction::Instruction::{self, *};
+use crate::prelude::*;
+use crate::program::Program;
+
+pub const REGISTERS: usize = 16;
+
+/// Every workload, by name, at a size that runs for a few milliseconds
+pub fn standard() -> Vec<(&'static str, Program)> {
+    vec![
+        ("fib", fib(1000)),
+        ("nested_loops", nested_loops(100, 100)),
+        ("calls", calls(5000)),
+        ("variables", variables(5000)),
+        ("spring", spring(5000)),
+        ("mandelbrot", mandelbrot(32, 50)),
+    ]
+}
+
+/// Workloads that allocate on the heap, which only the interpreters run
+pub fn allocating() -> Vec<(&'static str, Program)> {
+    vec![("strings", strings(5000))]
+}
+
+/// Iterative Fibonacci: r0 = fib(n)
+pub fn fib(n: u32) -> Program {
+    Program::new(vec![
+        imm(0, 0.0),
+        imm(1, 1.0),
+        imm(2, n as f64),
+        imm(3, 1.0),
+        imm(4, 0.0),
+        // loop (5): while i > 0
+        GreaterThan {
+            dest: 5,
+            src1: 2,
+            src2: 4,
+        },
+        ConditionalJump {
+            cond: 5,
+            target: 12,
+        },
+        Add {
+            dest: 6,
+            src1: 0,
+            src2: 1,
+        },
+        Mov { dest: 0, src: 1 },
+        Mov { dest: 1, src: 6 },
+        Sub {
+            dest: 2,
+            src1: 2,
+            src2: 3,
+        },
+        Jump(5),
+        Halt,
+    ])
+}
+
+/// r0 = sum of i * j over an `outer` x `inner` iteration space
+pub fn nested_loops(outer: u32, inner: u32) -> Program {
+    Program::new(vec![
+        imm(0, 0.0),
+        imm(1, 0.0),
+        imm(3, outer as f64),
+        imm(4, inner as f64),
+        imm(5, 1.0),
+        // outer (5): while i < outer
+        LessThan {
+            dest: 6,
+            src1: 1,
+            src2: 3,
+        },
+        ConditionalJump {
+            cond: 6,
+            target: 16,
+        },
+        imm(2, 0.0),
+        // inner (8): while j < inner
+        LessThan {
+            dest: 6,
+            src1: 2,
+            src2: 4,
+        },
+        ConditionalJump {
+            cond: 6,
+            target: 14,
+        },
+        Mul {
+            dest: 7,
+            src1: 1,
+            src2: 2,
+        },
+        Add {
+            dest: 0,
+            src1: 0,
+            src2: 7,
+        },
+        Add {
+            dest: 2,
+            src1: 2,
+            src2: 5,
+        },
+        Jump(8),
+        // inner done (14)
+        Add {
+            dest: 1,
+            src1: 1,
+            src2: 5,
+        },
+        Jump(5),
+        // done (16)
+        Halt,
+    ])
+}
+
+/// Calls a tiny increment function `n` times: r0 = n
+pub fn calls(n: u32) -> Program {
+    Program::new(vec![
+        imm(0, 0.0),
+        imm(1, n as f64),
+        imm(2, 1.0),
+        // loop (3): while r0 < n
+        LessThan {
+            dest: 3,
+            src1: 0,
+            src2: 1,
+        },
+        ConditionalJump { cond: 3, target: 7 },
+        Call { addr: 8 },
+        Jump(3),
+        Halt,
+        // increment (8)
+        Add {
+            dest: 0,
+            src1: 0,
+            src2: 2,
+        },
+        Return,
+    ])
+}
+
+/// Accumulates through named variables instead of registers: r0 = n
+pub fn variables(n: u32) -> Program {
+    Program::new(vec![
+        imm(0, 0.0),
+        imm(1, n as f64),
+        imm(2, 1.0),
+        Store {
+            src: 0,
+            var: "count".to_string(),
+        },
+        // loop (4): while count < n
+        Load {
+            dest: 0,
+            var: "count".to_string(),
+        },
+        LessThan {
+            dest: 3,
+            src1: 0,
+            src2: 1,
+        },
+        ConditionalJump {
+            cond: 3,
+            target: 10,
+        },
+        Add {
+            dest: 0,
+            src1: 0,
+            src2: 2,
+        },
+        Store {
+            src: 0,
+            var: "count".to_string(),
+        },
+        Jump(4),
+        Halt,
+    ])
+}
+
+/// Euler integration of a unit spring for `steps` steps: r0 = final position
+pub fn spring(steps: u32) -> Program {
+    Program::new(vec![
+        imm(0, 1.0),   // x
+        imm(1, 0.0),   // v
+        imm(2, -1.0),  // -k
+        imm(3, 0.001), // dt
+        imm(4, steps as f64),
+        imm(5, 1.0),
+        imm(6, 0.0),
+        // loop (7): while steps > 0
+        GreaterThan {
+            dest: 7,
+            src1: 4,
+            src2: 6,
+        },
+        ConditionalJump {
+            cond: 7,
+            target: 16,
+        },
+        Mul {
+            dest: 8,
+            src1: 2,
+            src2: 0,
+        },
+        Mul {
+            dest: 8,
+            src1: 8,
+            src2: 3,
+        },
+        Add {
+            dest: 1,
+            src1: 1,
+            src2: 8,
+        },
+        Mul {
+            dest: 9,
+            src1: 1,
+            src2: 3,
+        },
+        Add {
+            dest: 0,
+            src1: 0,
+            src2: 9,
+        },
+        Sub {
+            dest: 4,
+            src1: 4,
+            src2: 5,
+        },
+        Jump(7),
+        Halt,
+    ])
+}
+
+/// Counts the points of a `size` by `size` grid over [-2, 0.5) x [-1.25, 1.25)
+/// that stay bounded for `iterations` steps of z = z^2 + c: r0 = count
+pub fn mandelbrot(size: u32, iterations: u32) -> Program {
+    Program::new(vec![
+        imm(0, 0.0),
+        imm(6, 2.5 / size as f64), // grid step
+        imm(9, 4.0),
+        imm(10, 1.0),
+        imm(13, 0.0),
+        imm(1, -1.25), // cy
+        imm(11, size as f64),
+        // row (7): while rows > 0
+        GreaterThan {
+            dest: 14,
+            src1: 11,
+            src2: 13,
+        },
+        ConditionalJump {
+            cond: 14,
+            target: 38,
+        },
+        imm(2, -2.0), // cx
+        imm(12, size as f64),
+        // column (11): while columns > 0
+        GreaterThan {
+            dest: 14,
+            src1: 12,
+            src2: 13,
+        },
+        ConditionalJump {
+            cond: 14,
+            target: 35,
+        },
+        imm(3, 0.0), // zr
+        imm(4, 0.0), // zi
+        imm(5, iterations as f64),
+        // iterate (16): while iterations > 0
+        GreaterThan {
+            dest: 14,
+            src1: 5,
+            src2: 13,
+        },
+        ConditionalJump {
+            cond: 14,
+            target: 31,
+        },
+        Mul {
+            dest: 7,
+            src1: 3,
+            src2: 3,
+        },
+        Mul {
+            dest: 8,
+            src1: 4,
+            src2: 4,
+        },
+        Add {
+            dest: 14,
+            src1: 7,
+            src2: 8,
+        },
+        // escaped once |z|^2 > 4
+        GreaterThan {
+            dest: 14,
+            src1: 14,
+            src2: 9,
+        },
+        Not { dest: 14, src: 14 },
+        ConditionalJump {
+            cond: 14,
+            target: 32,
+        },
+        Mul {
+            dest: 4,
+            src1: 3,
+            src2: 4,
+        },
+        Add {
+            dest: 4,
+            src1: 4,
+            src2: 4,
+        },
+        Add {
+            dest: 4,
+            src1: 4,
+            src2: 1,
+        },
+        Sub {
+            dest: 3,
+            src1: 7,
+            src2: 8,
+        },
+        Add {
+            dest: 3,
+            src1: 3,
+            src2: 2,
+        },
+        Sub {
+            dest: 5,
+            src1: 5,
+            src2: 10,
+        },
+        Jump(16),
+        // bounded (31)
+        Add {
+            dest: 0,
+            src1: 0,
+            src2: 10,
+        },
+        // next column (32)
+        Add {
+            dest: 2,
+            src1: 2,
+            src2: 6,
+        },
+        Sub {
+            dest: 12,
+            src1: 12,
+            src2: 10,
+        },
+        Jump(11),
+        // next row (35)
+        Add {
+            dest: 1,
+            src1: 1,
+            src2: 6,
+        },
+        Sub {
+            dest: 11,
+            src1: 11,
+            src2: 10,
+        },
+        Jump(7),
+        // done (38)
+        Halt,
+    ])
+}
+
+/// Builds an `n`-character string, a to z over and over, as a map from
+/// position to character code: r0 = n, r1 = the map
+pub fn strings(n: u32) -> Program {
+    Program::new(vec![
+        imm(0, 0.0),
+        MapNew { dest: 1 },
+        imm(2, n as f64),
+        imm(3, 97.0), // 'a'
+        imm(4, 1.0),
+        imm(5, 123.0), // one past 'z'
+        imm(7, 97.0),
+        // loop (7): while length < n
+        LessThan {
+            dest: 6,
+            src1: 0,
+            src2: 2,
+        },
+        ConditionalJump {
+            cond: 6,
+            target: 17,
+        },
+        MapSet {
+            map: 1,
+            key: 0,
+            src: 3,
+        },
+        Add {
+            dest: 0,
+            src1: 0,
+            src2: 4,
+        },
+        Add {
+            dest: 3,
+            src1: 3,
+            src2: 4,
+        },
+        LessThan {
+            dest: 6,
+            src1: 3,
+            src2: 5,
+        },
+        ConditionalJump {
+            cond: 6,
+            target: 15,
+        },
+        Jump(7),
+        // wrap around (15)
+        Mov { dest: 3, src: 7 },
+        Jump(7),
+        // done (17)
+        Halt,
+    ])
+}
+
+fn imm(dest: usize, value: f64) -> Instruction {
+    LoadImm { dest, value }
+}
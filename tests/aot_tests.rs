@@ -0,0 +1,93 @@
+use std::fmt::Write;
+use std::process::Command;
+use zyde::aot;
+use zyde::instruction::Instruction;
+use zyde::passes::PassManager;
+use zyde::passes::fusion::Fusion;
+use zyde::program::{Program, ProgramError};
+use zyde::vm::VM;
+use zyde::workloads::{self, REGISTERS};
+
+#[test]
+fn test_to_rust_rejects_unverified_programs() {
+    let program = Program::new(vec![Instruction::Jump(3)]);
+    assert_eq!(
+        aot::to_rust(&program, 1, "f"),
+        Err(ProgramError::TargetOutOfBounds { addr: 0, target: 3 })
+    );
+
+    let program = Program::new(vec![Instruction::Print { src: 4 }]);
+    assert_eq!(
+        aot::to_rust(&program, 2, "f"),
+        Err(ProgramError::RegisterOutOfBounds { addr: 0, reg: 4 })
+    );
+
+    let program = Program::new(vec![Instruction::GetUpvalue { dest: 0, index: 0 }]);
+    assert_eq!(
+        aot::to_rust(&program, 1, "f"),
+        Err(ProgramError::Unsupported {
+            addr: 0,
+            mnemonic: "getupval"
+        })
+    );
+}
+
+#[test]
+fn test_to_rust_emits_one_arm_per_block() {
+    let source = aot::to_rust(&workloads::fib(5), REGISTERS, "fib").unwrap();
+    assert!(source.contains("pub fn fib(\n    registers: &mut [f64; 16],"));
+    assert!(source.contains("            5 => {\n"));
+    assert!(source.contains("// 6: jz r5, 12\n"));
+    assert!(source.contains("if r[5] == 0.0 { block = 12; continue; }"));
+}
+
+/// Compile every workload, plain and fused, with rustc and check the
+/// registers and variables match the interpreter's
+#[test]
+fn test_generated_rust_matches_interpreter() {
+    let mut source = String::new();
+    let mut main = String::from("fn main() {\n");
+    let mut expected = String::new();
+
+    for (name, plain) in workloads::standard() {
+        let mut fused = plain.clone();
+        PassManager::empty().add(Fusion).run(&mut fused);
+
+        for (mode, program) in [("plain", plain), ("fused", fused)] {
+            let function = format!("{}_{}", name, mode);
+            source.push_str(&aot::to_rust(&program, REGISTERS, &function).unwrap());
+
+            let mut vm = VM::new(program, REGISTERS);
+            vm.run().unwrap();
+            let mut variables: Vec<_> = vm.variables.into_iter().collect();
+            variables.sort_by(|a, b| a.0.cmp(&b.0));
+            let _ = writeln!(expected, "{:?} {:?}", vm.registers, variables);
+
+            let _ = writeln!(
+                main,
+                "    let mut r = [0.0; {}];\n    let mut v = std::collections::HashMap::new();\n    {}(&mut r, &mut v).unwrap();\n    let mut v: Vec<_> = v.into_iter().collect();\n    v.sort_by(|a, b| a.0.cmp(&b.0));\n    println!(\"{{:?}} {{:?}}\", r, v);",
+                REGISTERS, function
+            );
+        }
+    }
+    main.push_str("}\n");
+    source.push_str(&main);
+
+    let dir = std::env::temp_dir().join(format!("zyde-aot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("main.rs");
+    std::fs::write(&file, source).unwrap();
+
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let status = Command::new(rustc)
+        .args(["--edition", "2024", "-O", "-o"])
+        .arg(dir.join("main"))
+        .arg(&file)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(dir.join("main")).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
@@ -0,0 +1,422 @@
+use zyde::asm_reg::{
+    AsmError, AsmErrorKind, Level, Lints, MAX_ERRORS, Warning, WarningKind, assemble, assemble_all,
+    assemble_full, quote,
+};
+use zyde::instruction::Instruction;
+use zyde::program::{Program, ProgramError};
+use zyde::testing::Generator;
+use zyde::vm::VM;
+use zyde::workloads;
+
+/// One instruction per line, as `Instruction` displays it
+fn listing(program: &Program) -> String {
+    let lines: Vec<String> = program.instructions.iter().map(|i| i.to_string()).collect();
+    lines.join("\n")
+}
+
+#[test]
+fn test_displayed_instructions_assemble_back() {
+    let mut programs: Vec<Program> = workloads::standard()
+        .into_iter()
+        .chain(workloads::allocating())
+        .map(|(_, program)| program)
+        .collect();
+    programs.extend((0..50).map(|seed| Generator::default().seeded(seed)));
+    programs.push(Program::new(vec![
+        Instruction::Switch {
+            src: 0,
+            table: vec![1, 2],
+            default: 3,
+        },
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 3,
+            captures: vec![0, 2],
+        },
+        Instruction::CallHost {
+            dest: 0,
+            name: "clock".to_string(),
+            args: vec![],
+        },
+        Instruction::JumpRel(-2),
+        Instruction::Halt,
+    ]));
+    for program in programs {
+        let text = listing(&program);
+        let assembled = assemble(&text).unwrap();
+        // Compared as text, since NaN constants are never equal
+        assert_eq!(listing(&assembled), text);
+    }
+}
+
+#[test]
+fn test_labels_and_directives() {
+    let program = assemble(
+        "
+        .entry
+        main:
+            loadimm r0, 5
+            loadimm r1, 1
+            call double       ; r0 = 10
+        loop:
+            sub r0, r0, r1
+            gtjz r2, r0, r3, done
+            rjmp loop
+        done:
+            halt
+        .export double/1
+        double:
+            add r0, r0, r0
+            ret
+        ",
+    )
+    .unwrap();
+    assert_eq!(program.find_label("loop").unwrap().addr, 3);
+    assert_eq!(program.find_export("double").unwrap().arity, 1);
+    assert_eq!(program.instructions[5], Instruction::JumpRel(-2));
+
+    let mut vm = VM::new(program, 4);
+    vm.run().unwrap();
+    assert_eq!(vm.registers[0], 0.0);
+}
+
+#[test]
+fn test_operand_separators() {
+    let same = |a: &str, b: &str| {
+        assert_eq!(
+            assemble(a).unwrap().instructions,
+            assemble(b).unwrap().instructions
+        )
+    };
+    same("add r0 r1 r2", "add r0, r1, r2");
+    same("add r0, r1, r2,", "add r0, r1, r2");
+    same("callhost r0 print [r1 r2,]", "callhost r0, print, [r1, r2]");
+    same("store r0 \"a b\"", "store r0, \"a b\"");
+    // Spaces around a binary operator keep an expression together
+    same(
+        "loadimm r0 -1\njmp end - 1\nend: halt",
+        "loadimm r0, -1\njmp 1\nhalt",
+    );
+    same("loadimm r0 (2 * 3) / 2", "loadimm r0, 3");
+    assert_eq!(
+        assemble("mov r0 r1 r2").unwrap_err().kind,
+        AsmErrorKind::ExtraOperand("r2".to_string())
+    );
+}
+
+#[test]
+fn test_optional_operands() {
+    let program = assemble("call f\ncall f, 2\nf: arity r0, 1, 3\narity r0 1").unwrap();
+    assert_eq!(
+        program.instructions,
+        vec![
+            Instruction::Call { addr: 2 },
+            Instruction::CallArgs { addr: 2, argc: 2 },
+            Instruction::Arity {
+                dest: 0,
+                min: 1,
+                max: Some(3),
+            },
+            Instruction::Arity {
+                dest: 0,
+                min: 1,
+                max: None,
+            },
+        ]
+    );
+    assert_eq!(
+        assemble("arity r0, 1, 2, 3").unwrap_err().kind,
+        AsmErrorKind::ExtraOperand("3".to_string())
+    );
+}
+
+#[test]
+fn test_names_are_separate_from_keywords() {
+    // Keywords are read in any case, names are not
+    assert_eq!(
+        assemble("LoadImm R0, 2\nADD r1, r0, R0\n.ENTRY\nHALT").unwrap(),
+        assemble("loadimm r0, 2\nadd r1, r0, r0\n.entry\nhalt").unwrap()
+    );
+    let program = assemble("jmp Loop\nloop: halt\nLoop: jmp loop").unwrap();
+    assert_eq!(program.instructions[0], Instruction::Jump(2));
+    assert_eq!(program.instructions[2], Instruction::Jump(1));
+
+    let error = |source: &str| assemble(source).unwrap_err().kind;
+    assert_eq!(
+        error("ADD:\nhalt"),
+        AsmErrorKind::ReservedName("ADD".into())
+    );
+    assert_eq!(error("r1: halt"), AsmErrorKind::ReservedName("r1".into()));
+    assert_eq!(
+        error(".const Inf = 1"),
+        AsmErrorKind::ReservedName("Inf".into())
+    );
+    assert_eq!(error("top-1: halt"), AsmErrorKind::BadName("top-1".into()));
+    assert_eq!(
+        error(".export 2x/0\nhalt"),
+        AsmErrorKind::BadName("2x".into())
+    );
+
+    // Quoted, any name can be defined and used
+    let program = assemble(
+        r#"
+        .const "two words" = 2
+        "add": loadimm r0, ("two words" * 3)   ; a comment
+        "a \"b\"; c":
+            store r0, "x, y"
+            jmp "add"
+            rjmp "a \"b\"; c"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(program.find_label("add").unwrap().addr, 0);
+    assert_eq!(program.find_label(r#"a "b"; c"#).unwrap().addr, 1);
+    assert_eq!(
+        program.instructions,
+        [
+            Instruction::LoadImm {
+                dest: 0,
+                value: 6.0
+            },
+            Instruction::Store {
+                src: 0,
+                var: "x, y".into()
+            },
+            Instruction::Jump(0),
+            Instruction::JumpRel(-2),
+        ]
+    );
+    // Names that would not read back are quoted when displayed
+    assert_eq!(program.instructions[1].to_string(), r#"store r0, "x, y""#);
+    assert_eq!(quote("loop.1"), "loop.1");
+    assert_eq!(quote("Halt"), r#""Halt""#);
+    assert_eq!(quote(r#"a\"b"#), r#""a\\\"b""#);
+}
+
+#[test]
+fn test_errors_carry_their_line() {
+    let error = |source: &str| assemble(source).unwrap_err();
+    assert_eq!(
+        error("halt\nfrob r0"),
+        AsmError {
+            line: 2,
+            columns: 1..5,
+            kind: AsmErrorKind::UnknownMnemonic("frob".to_string()),
+        }
+    );
+    assert_eq!(error("add r0, r1").kind, AsmErrorKind::MissingOperand);
+    assert_eq!(
+        error("mov r0, r1, r2").kind,
+        AsmErrorKind::ExtraOperand("r2".to_string())
+    );
+    // Columns count from one and point at the operand at fault, or just
+    // past the last one when one is missing
+    assert_eq!(error("  mov r0, r1, r2").columns, 15..17);
+    assert_eq!(error("add r0, r1  ; sum").columns, 11..11);
+    assert_eq!(error("loadimm.q r0, 1").columns, 9..10);
+    assert_eq!(
+        error("print 3").kind,
+        AsmErrorKind::ExpectedRegister("3".to_string())
+    );
+    assert_eq!(
+        error("jmp nowhere").kind,
+        AsmErrorKind::Program(ProgramError::UnknownSymbol("nowhere".to_string()))
+    );
+    assert_eq!(
+        error("a:\nhalt\na:\nhalt"),
+        AsmError {
+            line: 3,
+            columns: 1..2,
+            kind: AsmErrorKind::Program(ProgramError::DuplicateLabel("a".to_string())),
+        }
+    );
+    // A label needs an instruction to name
+    assert_eq!(error("halt\nend:").line, 2);
+    assert_eq!(
+        error("halt\nend:").to_string(),
+        "Line 2: Label 'end' points outside the program (1)"
+    );
+}
+
+#[test]
+fn test_assembly_recovers_from_errors() {
+    let errors = |source: &str| -> Vec<(usize, AsmErrorKind)> {
+        assemble_all(source)
+            .unwrap_err()
+            .into_iter()
+            .map(|e| (e.line, e.kind))
+            .collect()
+    };
+    let unknown = |name: &str| AsmErrorKind::Program(ProgramError::UnknownSymbol(name.into()));
+    assert_eq!(
+        errors(
+            "
+            .const N = 1 / 0
+            .const M = N + 1
+            frob r0
+            loadimm r0, M
+            jmp nowhere
+            jmp nowhere
+            add r0, r1
+            halt"
+        ),
+        [
+            (2, AsmErrorKind::DivideByZero),
+            (4, AsmErrorKind::UnknownMnemonic("frob".into())),
+            (6, unknown("nowhere")),
+            (8, AsmErrorKind::MissingOperand),
+        ]
+    );
+    // A failed instruction still takes its address, so labels after it hold
+    assert_eq!(
+        errors(
+            "jmp end
+frob
+.func f(int, string)
+call f
+end:
+halt"
+        ),
+        [
+            (2, AsmErrorKind::UnknownMnemonic("frob".into())),
+            (3, AsmErrorKind::UnknownType("string".into())),
+        ]
+    );
+    let many = "frob\n".repeat(MAX_ERRORS * 2);
+    assert_eq!(assemble_all(&many).unwrap_err().len(), MAX_ERRORS);
+    assert_eq!(assemble_full(&many).unwrap_err().line, 1);
+}
+
+#[test]
+fn test_operand_expressions() {
+    let program = assemble(
+        "
+        .const WIDTH = 8
+        .const CELLS = WIDTH * WIDTH
+        .const LAST = CELLS - 1
+            loadimm r0, (WIDTH * 2 + 1)
+            loadimm r1, -(LAST % 10) / 2
+            record r2, (WIDTH / 4)
+            getfield r3, r2, (WIDTH / 4 - 1)
+            jmp (end - 1)
+            halt
+        end:
+            rjmp (0 - 5)
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        program.instructions[..5],
+        [
+            Instruction::LoadImm {
+                dest: 0,
+                value: 17.0,
+            },
+            Instruction::LoadImm {
+                dest: 1,
+                value: -1.5,
+            },
+            Instruction::NewRecord { dest: 2, fields: 2 },
+            Instruction::GetField {
+                dest: 3,
+                record: 2,
+                index: 1,
+            },
+            Instruction::Jump(5),
+        ]
+    );
+    assert_eq!(program.instructions[6], Instruction::JumpRel(-5));
+
+    let error = |source: &str| assemble(source).unwrap_err().kind;
+    assert_eq!(
+        error(".const N = 0\nloadimm r0, (1 / N)"),
+        AsmErrorKind::DivideByZero
+    );
+    assert_eq!(
+        error("record r0, (3 / 2)"),
+        AsmErrorKind::ExpectedNumber("(3 / 2)".to_string())
+    );
+    assert_eq!(
+        error("loadimm r0, (1 + )"),
+        AsmErrorKind::BadExpression(")".to_string())
+    );
+    assert_eq!(
+        error(".const N = 1\n.const N = 2"),
+        AsmErrorKind::DuplicateSymbol("N".to_string())
+    );
+    assert_eq!(
+        error(".const N = M\n.const M = 1"),
+        AsmErrorKind::Program(ProgramError::UnknownSymbol("M".to_string()))
+    );
+}
+
+#[test]
+fn test_warnings() {
+    let source = "
+.export main/0
+main:
+    loadimm r0, 9007199254740993
+    loadimm r1, 1e-400
+    loadimm r2, 0.1
+    store r0, x
+    store r0, x
+    store r1, y
+    load r3, y
+    call helper
+    halt
+    print r0
+helper:
+    ret
+spare:
+    ret
+";
+    let assembly = assemble_full(source).unwrap();
+    let kinds: Vec<(usize, WarningKind)> = assembly
+        .warnings
+        .into_iter()
+        .map(|w| (w.line, w.kind))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (
+                4,
+                WarningKind::InexactLiteral("9007199254740993".into(), 9007199254740992.0)
+            ),
+            (5, WarningKind::InexactLiteral("1e-400".into(), 0.0)),
+            (7, WarningKind::UnusedVariable("x".into())),
+            (13, WarningKind::Unreachable("halt")),
+            (16, WarningKind::UnusedLabel("spare".into())),
+        ]
+    );
+    // `spare` is labelled, so nothing after the `ret` before it is reported
+    let clean = assemble_full("loadimm r0, 1\nstore r0, x\nload r1, x\nhalt").unwrap();
+    assert!(clean.warnings.is_empty());
+}
+
+#[test]
+fn test_lints() {
+    let unused = Warning {
+        line: 1,
+        columns: 1..2,
+        kind: WarningKind::UnusedLabel("a".into()),
+    };
+    assert_eq!(
+        unused.to_string(),
+        "Line 1: Label 'a' is never used (unused-label)"
+    );
+    let literal = WarningKind::InexactLiteral("1e400".into(), f64::INFINITY);
+
+    let mut lints = Lints::new();
+    assert_eq!(lints.level(&unused.kind), Level::Warn);
+    lints.apply("deny").unwrap();
+    lints.apply("unused-label=allow").unwrap();
+    assert_eq!(lints.level(&unused.kind), Level::Allow);
+    assert_eq!(lints.level(&literal), Level::Deny);
+    // Setting every kind at once drops earlier settings for one kind
+    lints.apply("warn").unwrap();
+    assert_eq!(lints.level(&unused.kind), Level::Warn);
+
+    assert!(lints.apply("unused=deny").is_err());
+    assert!(lints.apply("loud").is_err());
+}
@@ -0,0 +1,82 @@
+#![cfg(feature = "rayon")]
+
+use std::collections::HashMap;
+use zyde::batch::{Inputs, run_batch, run_batch_with_config};
+use zyde::instruction::Instruction;
+use zyde::vm::{VmConfig, VmError};
+
+/// r2 = r0 * r1 + total, printed and stored back to total
+fn row_program() -> Vec<Instruction> {
+    vec![
+        Instruction::Mul {
+            dest: 2,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Load {
+            dest: 3,
+            var: "total".to_string(),
+        },
+        Instruction::Add {
+            dest: 2,
+            src1: 2,
+            src2: 3,
+        },
+        Instruction::Print { src: 2 },
+        Instruction::Store {
+            src: 2,
+            var: "total".to_string(),
+        },
+        Instruction::Halt,
+    ]
+}
+
+#[test]
+fn test_each_input_runs_on_its_own_vm_in_order() {
+    let inputs: Vec<Inputs> = (0..1000)
+        .map(|i| Inputs {
+            registers: vec![i as f64, 2.0],
+            variables: HashMap::from([("total".to_string(), 1.0)]),
+        })
+        .collect();
+    let outputs = run_batch(row_program(), 4, inputs);
+    assert_eq!(outputs.len(), 1000);
+    for (i, output) in outputs.into_iter().enumerate() {
+        let output = output.unwrap();
+        let expected = i as f64 * 2.0 + 1.0;
+        assert_eq!(output.printed, vec![expected]);
+        assert_eq!(output.registers[2], expected);
+        assert_eq!(output.variables["total"], expected);
+    }
+}
+
+#[test]
+fn test_one_failing_input_does_not_affect_the_others() {
+    let inputs = vec![
+        Inputs {
+            registers: vec![3.0, 4.0],
+            variables: HashMap::from([("total".to_string(), 0.0)]),
+        },
+        // `total` is unset, so the load fails
+        Inputs {
+            registers: vec![3.0, 4.0],
+            variables: HashMap::new(),
+        },
+    ];
+    let outputs = run_batch(row_program(), 4, inputs);
+    assert_eq!(outputs[0].as_ref().unwrap().printed, vec![12.0]);
+    assert!(matches!(&outputs[1], Err(VmError::VariableNotFound(name)) if name == "total"));
+}
+
+#[test]
+fn test_config_applies_to_every_run() {
+    let program = vec![Instruction::Jump(0)];
+    let config = VmConfig {
+        max_steps: Some(100),
+        ..VmConfig::default()
+    };
+    let outputs = run_batch_with_config(program, 1, &config, vec![Inputs::default(); 3]);
+    for output in outputs {
+        assert!(matches!(output, Err(VmError::StepLimitExceeded)));
+    }
+}
@@ -0,0 +1,227 @@
+use zyde::bytecode::{BytecodeView, DecodeError, EncodeError, Features, MAGIC, VERSION};
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+use zyde::rng::Rng;
+use zyde::vm::VM;
+
+fn sample_program() -> Program {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.5,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Load {
+            dest: 1,
+            var: "x".to_string(),
+        },
+        Instruction::Mul {
+            dest: 2,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::ConditionalJump { cond: 3, target: 6 },
+        Instruction::Print { src: 2 },
+        Instruction::Call { addr: 7 },
+        Instruction::Return,
+    ]);
+    program.export("main", 0, 0).unwrap();
+    program
+}
+
+#[test]
+fn test_bytecode_round_trip() {
+    let program = sample_program();
+    let bytes = program.to_bytecode().unwrap();
+
+    assert_eq!(&bytes[0..4], &MAGIC);
+    assert_eq!(Program::from_bytecode(&bytes).unwrap(), program);
+}
+
+#[test]
+fn test_bytecode_round_trips_loaded_addresses() {
+    let program = Program::new(vec![
+        Instruction::LoadAddr { dest: 1, addr: 2 },
+        Instruction::CallIndirect { src: 1 },
+        Instruction::Return,
+    ]);
+    let bytes = program.to_bytecode().unwrap();
+    assert_eq!(Program::from_bytecode(&bytes).unwrap(), program);
+}
+
+#[test]
+fn test_bytecode_view_is_lazy_and_borrowed() {
+    let bytes = sample_program().to_bytecode().unwrap();
+    let view = BytecodeView::parse(&bytes).unwrap();
+
+    assert_eq!(view.len(), 8);
+    assert_eq!(view.instruction(6).unwrap(), Instruction::Call { addr: 7 });
+
+    // Strings are handed out as slices of the input, not copies
+    let name = view.string(0).unwrap();
+    let range = bytes.as_ptr_range();
+    assert_eq!(name, "x");
+    assert!(range.contains(&name.as_ptr()));
+    assert_eq!(view.exports().unwrap(), vec![("main", 0, 0)]);
+}
+
+#[test]
+fn test_bytecode_executes() {
+    let bytes = sample_program().to_bytecode().unwrap();
+    let program = Program::from_bytecode(&bytes).unwrap();
+
+    let mut vm = VM::new(program, 4);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    vm.step().unwrap();
+    vm.step().unwrap();
+
+    assert_eq!(vm.registers[2], 6.25);
+}
+
+#[test]
+fn test_bytecode_rejects_bad_input() {
+    let bytes = sample_program().to_bytecode().unwrap();
+
+    assert_eq!(
+        BytecodeView::parse(b"NOPE and some more bytes").unwrap_err(),
+        DecodeError::BadMagic
+    );
+    assert_eq!(
+        BytecodeView::parse(&bytes[..30]).unwrap_err(),
+        DecodeError::Truncated
+    );
+
+    let mut future = bytes.clone();
+    future[4] = 99;
+    assert_eq!(
+        BytecodeView::parse(&future).unwrap_err(),
+        DecodeError::UnsupportedVersion(99)
+    );
+
+    let mut corrupt = bytes.clone();
+    corrupt[24] = 0xff;
+    let view = BytecodeView::parse(&corrupt).unwrap();
+    assert_eq!(
+        view.instruction(0).unwrap_err(),
+        DecodeError::UnknownOpcode {
+            index: 0,
+            opcode: 0xff
+        }
+    );
+    assert_eq!(
+        view.instruction(8).unwrap_err(),
+        DecodeError::InstructionOutOfBounds(8)
+    );
+}
+
+#[test]
+fn test_bytecode_features() {
+    let program = sample_program();
+    let bytes = program.to_bytecode().unwrap();
+    let view = BytecodeView::parse(&bytes).unwrap();
+    assert_eq!(view.version(), VERSION);
+    assert_eq!(view.features().unwrap(), Features::STRINGS);
+    assert_eq!(
+        Program::from_bytecode_with(&bytes, Features::HEAP).unwrap_err(),
+        DecodeError::UnsupportedFeatures(Features::STRINGS.bits())
+    );
+    assert_eq!(
+        Program::from_bytecode_with(&bytes, Features::STRINGS).unwrap(),
+        program
+    );
+
+    let mut unknown = bytes.clone();
+    unknown[7] = 0x80;
+    assert_eq!(
+        BytecodeView::parse(&unknown).unwrap_err(),
+        DecodeError::UnsupportedFeatures(0x8000)
+    );
+
+    // Version 1 files leave the features zero
+    let mut old = bytes.clone();
+    old[4..8].copy_from_slice(&[1, 0, 0, 0]);
+    let view = BytecodeView::parse(&old).unwrap();
+    assert_eq!(view.features().unwrap(), Features::STRINGS);
+    assert_eq!(view.to_program().unwrap(), program);
+}
+
+#[test]
+fn test_bytecode_round_trips_host_calls() {
+    let program = Program::new(vec![
+        Instruction::CallHost {
+            dest: 0,
+            name: "clock".to_string(),
+            args: vec![],
+        },
+        Instruction::CallHost {
+            dest: 1,
+            name: "max".to_string(),
+            args: vec![0, 2],
+        },
+    ]);
+    let bytes = program.to_bytecode().unwrap();
+    assert_eq!(Program::from_bytecode(&bytes).unwrap(), program);
+
+    let view = BytecodeView::parse(&bytes).unwrap();
+    assert_eq!(view.features().unwrap(), Features::HOST_CALLS);
+    assert_eq!(
+        Program::from_bytecode_with(&bytes, Features::STRINGS).unwrap_err(),
+        DecodeError::UnsupportedFeatures(Features::HOST_CALLS.bits())
+    );
+
+    // Point the first call's argument list at its five-byte name
+    let mut corrupt = bytes.clone();
+    corrupt[36..40].copy_from_slice(&0u32.to_le_bytes());
+    let view = BytecodeView::parse(&corrupt).unwrap();
+    assert_eq!(
+        view.instruction(0).unwrap_err(),
+        DecodeError::InvalidRegisterList(0)
+    );
+}
+
+/// Truncated and corrupted files must fail with a `DecodeError`, not panic
+#[test]
+fn test_decoder_survives_malformed_input() {
+    let bytes = sample_program().to_bytecode().unwrap();
+    for len in 0..bytes.len() {
+        assert!(Program::from_bytecode(&bytes[..len]).is_err());
+    }
+
+    let mut rng = Rng::new(7);
+    let mut random = |n: usize| (rng.next_f64() * n as f64) as usize;
+    for _ in 0..10_000 {
+        let mut corrupt = bytes.clone();
+        for _ in 0..1 + random(4) {
+            let at = random(corrupt.len());
+            corrupt[at] = random(256) as u8;
+        }
+        if let Ok(view) = BytecodeView::parse(&corrupt) {
+            for i in 0..view.len() {
+                let _ = view.instruction(i);
+            }
+            let _ = view.exports();
+            let _ = view.features();
+        }
+        let _ = Program::from_bytecode(&corrupt);
+    }
+
+    let record = |fields| Program::new(vec![Instruction::NewRecord { dest: 0, fields }]);
+    assert_eq!(
+        record(1 << 20).to_bytecode(),
+        Err(EncodeError::OperandTooLarge(1 << 20))
+    );
+    let mut bytes = record(1).to_bytecode().unwrap();
+    // Operand b of the first instruction
+    bytes[32..36].copy_from_slice(&(1u32 << 20).to_le_bytes());
+    assert_eq!(
+        Program::from_bytecode(&bytes).unwrap_err(),
+        DecodeError::TooManyFields {
+            index: 0,
+            fields: 1 << 20
+        }
+    );
+}
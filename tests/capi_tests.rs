@@ -0,0 +1,72 @@
+#![cfg(feature = "capi")]
+
+use std::ffi::CStr;
+
+use zyde::capi::*;
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+
+fn last_error(vm: *const ZydeVm) -> Option<String> {
+    let message = unsafe { zyde_vm_last_error(vm) };
+    (!message.is_null()).then(|| {
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+#[test]
+fn test_capi_loads_runs_and_reads_registers() {
+    let bytes = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::Mul {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Halt,
+    ])
+    .to_bytecode()
+    .unwrap();
+
+    let vm = zyde_vm_new(2);
+    unsafe {
+        assert_eq!(zyde_vm_load(vm, bytes.as_ptr(), bytes.len()), 0);
+        assert_eq!(zyde_vm_run(vm), 0);
+        let mut value = 0.0;
+        assert_eq!(zyde_vm_get_register(vm, 1, &mut value), 0);
+        assert_eq!(value, 4.0);
+        assert_eq!(last_error(vm), None);
+
+        assert_eq!(zyde_vm_get_register(vm, 5, &mut value), -1);
+        assert_eq!(last_error(vm).as_deref(), Some("Register r5 out of bounds"));
+        zyde_vm_free(vm);
+    }
+}
+
+#[test]
+fn test_capi_reports_errors() {
+    let vm = zyde_vm_new(1);
+    unsafe {
+        assert_eq!(zyde_vm_run(vm), -1);
+        assert_eq!(last_error(vm).as_deref(), Some("No program loaded"));
+
+        let garbage = b"garbage";
+        assert_eq!(zyde_vm_load(vm, garbage.as_ptr(), garbage.len()), -1);
+        assert!(last_error(vm).is_some());
+
+        let bytes = Program::new(vec![Instruction::Load {
+            dest: 0,
+            var: "x".to_string(),
+        }])
+        .to_bytecode()
+        .unwrap();
+        assert_eq!(zyde_vm_load(vm, bytes.as_ptr(), bytes.len()), 0);
+        assert_eq!(zyde_vm_run(vm), -1);
+        assert!(last_error(vm).unwrap().contains('x'));
+        zyde_vm_free(vm);
+    }
+}
@@ -0,0 +1,42 @@
+use zyde::conformance::{self, Failure, MAX_CALL_DEPTH};
+use zyde::sandbox::SandboxPolicy;
+use zyde::stack_vm::{StackInstruction, StackVM};
+use zyde::vm::{VmConfig, VmError};
+
+#[test]
+fn test_every_machine_passes_every_case() {
+    let cases = conformance::cases();
+    let names: Vec<&str> = cases.iter().map(|case| case.name).collect();
+    assert_eq!(names, ["fib", "ackermann", "sieve", "reverse"]);
+    for case in &cases {
+        if let Err(failure) = conformance::check(case) {
+            panic!("{}: {}", case.name, failure);
+        }
+    }
+}
+
+#[test]
+fn test_recursion_is_held_to_the_depth_limit() {
+    // A function that calls itself forever
+    let program = vec![StackInstruction::<i64>::Call(0)];
+    let mut vm = StackVM::with_config(program, conformance::config());
+    assert!(matches!(vm.run(), Err(VmError::PermissionDenied(_))));
+    assert_eq!(vm.call_stack.len(), MAX_CALL_DEPTH);
+
+    let config = VmConfig {
+        sandbox: SandboxPolicy {
+            max_call_depth: Some(2),
+            ..SandboxPolicy::default()
+        },
+        ..VmConfig::default()
+    };
+    let case = &conformance::cases()[1];
+    let mut vm = StackVM::with_config(case.stack_program::<f64>(), config);
+    assert!(matches!(vm.run(), Err(VmError::PermissionDenied(_))));
+
+    let failure = Failure::Output {
+        machine: "VM".to_string(),
+        printed: vec![1.0],
+    };
+    assert_eq!(failure.to_string(), "VM printed [1.0]");
+}
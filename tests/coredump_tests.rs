@@ -0,0 +1,52 @@
+use std::fs;
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+use zyde::vm::{VM, VmConfig, VmError};
+
+#[test]
+fn test_dump_on_trap() {
+    let path = std::env::temp_dir().join(format!("zyde-core-{}.json", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 4.0,
+        },
+        Instruction::Call { addr: 3 },
+        Instruction::Halt,
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Load {
+            dest: 1,
+            var: "y".to_string(),
+        },
+        Instruction::Return,
+    ]);
+    program.label("main", 0).unwrap();
+    program.label("lookup", 3).unwrap();
+    let config = VmConfig {
+        dump_on_trap: Some(path.clone()),
+        ..VmConfig::default()
+    };
+
+    let mut vm = VM::with_config(program, 2, config);
+    assert!(matches!(vm.run(), Err(VmError::VariableNotFound(name)) if name == "y"));
+    let dump = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        dump,
+        "{\"error\":\"Variable 'y' not found\",\"pc\":5,\
+         \"location\":{\"addr\":4,\"label\":\"lookup\",\"offset\":1,\"instruction\":\"load r1, y\"},\
+         \"steps\":4,\"registers\":[4,0],\
+         \"call_stack\":[{\"addr\":1,\"label\":\"main\",\"offset\":1,\"instruction\":\"call 3\"}],\
+         \"variables\":{\"x\":4},\
+         \"heap\":{\"objects\":0,\"cells\":0,\"allocated\":0,\"collections\":0}}\n"
+    );
+
+    // Runs that finish or only suspend leave no dump
+    vm.pc = 2;
+    vm.run().unwrap();
+    assert!(!path.exists());
+}
@@ -0,0 +1,49 @@
+use zyde::coverage::Coverage;
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+use zyde::vm::VM;
+
+#[test]
+fn test_coverage_counts_executed_instructions() {
+    // Loops twice, never reaching the instruction after the halt
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: -1.0,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 5 },
+        Instruction::Jump(2),
+        Instruction::Halt,
+        Instruction::Print { src: 0 },
+    ]);
+    program.label("main", 0).unwrap();
+    program.label("dead", 6).unwrap();
+
+    let mut vm = VM::new(program, 2);
+    vm.add_hook(Coverage::new());
+    vm.run().unwrap();
+    let coverage = vm.take_hook::<Coverage>().unwrap();
+
+    let hits: Vec<u64> = (0..7).map(|addr| coverage.hits(addr)).collect();
+    assert_eq!(hits, [1, 1, 2, 2, 1, 1, 0]);
+    assert_eq!(coverage.covered(&vm.program), (6, 7));
+
+    let lcov = coverage.to_lcov(&vm.program, "loop.zy");
+    assert!(lcov.starts_with("TN:\nSF:loop.zy\nFN:1,main\nFN:7,dead\n"));
+    assert!(lcov.contains("FNDA:1,main\nFNDA:0,dead\nFNF:2\nFNH:1\n"));
+    assert!(lcov.contains("DA:3,2\n"));
+    assert!(lcov.ends_with("DA:7,0\nLF:7\nLH:6\nend_of_record\n"));
+
+    let annotated = coverage.annotate(&vm.program);
+    assert!(annotated.contains("       2     2  add r0, r0, r1\n"));
+    assert!(annotated.ends_with("          dead:\n   #####     6  print r0\n"));
+}
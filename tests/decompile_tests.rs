@@ -0,0 +1,117 @@
+use pretty_assertions::assert_eq;
+use zyde::asm_reg::assemble;
+use zyde::decompile::to_structured;
+use zyde::lang::compile;
+
+#[test]
+fn test_loops_and_branches_are_recovered() {
+    let compiled = compile(
+        "
+        let a = 0;
+        let n = 10;
+        while n > 0 {
+            if a < 3 { a = a + 1; } else { print a; }
+            n = n - 1;
+        }
+        if a == 3 { print a; }
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        to_structured(&compiled.program),
+        "\
+loadimm r0, 0
+loadimm r1, 10
+loop {
+    loadimm r3, 0
+    gt r2, r1, r3
+    if !r2 { break }
+    loadimm r3, 3
+    lt r2, r0, r3
+    if r2 {
+        loadimm r2, 1
+        add r0, r0, r2
+    } else {
+        print r0
+    }
+    loadimm r2, 1
+    sub r1, r1, r2
+}
+loadimm r3, 3
+eq r2, r0, r3
+if r2 {
+    print r0
+}
+halt
+"
+    );
+}
+
+#[test]
+fn test_while_with_fused_condition() {
+    let program = assemble(
+        "
+        .const N = 5
+            loadimm r1, N
+        top:
+            ltjz r2, r0, r1, done
+            addimm r0, r0, r3, 1
+            jmp top
+        done:
+            call square
+            halt
+        square:
+            mul r0, r0, r0
+            ret
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        to_structured(&program),
+        "\
+loadimm r1, 5
+top:
+while (r2 = r0 < r1) {
+    addimm r0, r0, r3, 1
+}
+done:
+call 6
+halt
+square:
+6:
+mul r0, r0, r0
+ret
+"
+    );
+}
+
+#[test]
+fn test_irreducible_loop_falls_back_to_labels() {
+    // The loop over 1..=3 is entered at both 1 and 2
+    let program = assemble(
+        "
+            jz r0, b
+        a:
+            print r1
+        b:
+            print r2
+            jz r3, a
+            halt
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        to_structured(&program),
+        "\
+jz r0, 2
+a:
+1:
+print r1
+b:
+2:
+print r2
+jz r3, 1
+halt
+"
+    );
+}
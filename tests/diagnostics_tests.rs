@@ -0,0 +1,89 @@
+use zyde::asm_reg::{Lints, MAX_ERRORS};
+use zyde::diagnostics::{Diagnostic, Severity, check, to_json};
+
+fn codes(diagnostics: &[Diagnostic]) -> Vec<(usize, &str, Severity)> {
+    diagnostics
+        .iter()
+        .map(|d| (d.line, d.code, d.severity))
+        .collect()
+}
+
+#[test]
+fn test_errors_point_at_the_text_at_fault() {
+    let found = check("halt\n  ad r0, r0, r0 ; typo", &Lints::new());
+    assert_eq!(
+        found,
+        vec![Diagnostic {
+            severity: Severity::Error,
+            code: "unknown-mnemonic",
+            message: "Unknown instruction 'ad'".to_string(),
+            line: 2,
+            columns: 3..5,
+            suggestion: Some("did you mean `add`?".to_string()),
+        }]
+    );
+    assert_eq!(
+        found[0].to_string(),
+        "2:3: error[unknown-mnemonic]: Unknown instruction 'ad'\n  help: did you mean `add`?"
+    );
+
+    let found = check("halt: halt", &Lints::new());
+    assert_eq!(found[0].code, "reserved-name");
+    assert_eq!(found[0].suggestion.as_deref(), Some("quote it: `\"halt\"`"));
+
+    // Checks after assembly find the line of the instruction they name
+    let found = check("loadimm r0, 1\n  loadimm.i r1, 2.5\nhalt", &Lints::new());
+    assert_eq!(codes(&found), [(2, "bad-immediate", Severity::Error)]);
+    assert_eq!(found[0].columns, 3..20);
+}
+
+#[test]
+fn test_warnings_follow_their_lints() {
+    let source = "loadimm r0, 1e400\nstore r0, x\nhalt\nprint r0";
+    assert_eq!(
+        codes(&check(source, &Lints::new())),
+        [
+            (1, "inexact-literal", Severity::Warning),
+            (2, "unused-variable", Severity::Warning),
+            (4, "unreachable", Severity::Warning),
+        ]
+    );
+    let mut lints = Lints::new();
+    lints.apply("unreachable=deny").unwrap();
+    lints.apply("unused-variable=allow").unwrap();
+    let found = check(source, &lints);
+    assert_eq!(
+        codes(&found),
+        [
+            (1, "inexact-literal", Severity::Warning),
+            (4, "unreachable", Severity::Error),
+        ]
+    );
+    assert_eq!(found[0].columns, 13..18);
+
+    assert_eq!(
+        to_json(&found[..1], "a \"b\".asm"),
+        "[{\"file\":\"a \\\"b\\\".asm\",\"line\":1,\"column\":13,\"end_column\":18,\
+         \"severity\":\"warning\",\"code\":\"inexact-literal\",\
+         \"message\":\"Literal '1e400' becomes inf\",\
+         \"suggestion\":\"use a literal an `f64` can hold\"}]"
+    );
+    assert_eq!(to_json(&[], "a.asm"), "[]");
+}
+
+#[test]
+fn test_every_error_is_reported_up_to_a_cap() {
+    let found = check("frob\nhalt\nprint 3\njmp nowhere", &Lints::new());
+    assert_eq!(
+        codes(&found),
+        [
+            (1, "unknown-mnemonic", Severity::Error),
+            (3, "expected-register", Severity::Error),
+            (4, "unknown-symbol", Severity::Error),
+        ]
+    );
+    let found = check(&"frob\n".repeat(MAX_ERRORS + 1), &Lints::new());
+    assert_eq!(found.len(), MAX_ERRORS + 1);
+    assert_eq!(found[MAX_ERRORS].code, "too-many-errors");
+    assert_eq!(found[MAX_ERRORS].line, MAX_ERRORS);
+}
@@ -0,0 +1,112 @@
+use zyde::equiv::{self, Engine, Printed, Step};
+use zyde::instruction::Instruction;
+use zyde::passes::{OptLevel, Pass, PassManager};
+use zyde::program::Program;
+use zyde::workloads;
+
+/// Miscompiles every `mul` into an `add`
+struct MulToAdd;
+
+impl Pass for MulToAdd {
+    fn name(&self) -> &'static str {
+        "mul-to-add"
+    }
+
+    fn run(&self, program: &mut Program) {
+        for instr in &mut program.instructions {
+            if let Instruction::Mul { dest, src1, src2 } = *instr {
+                *instr = Instruction::Add { dest, src1, src2 };
+            }
+        }
+    }
+}
+
+#[test]
+fn test_optimized_workloads_agree() {
+    for level in [OptLevel::O1, OptLevel::O2, OptLevel::Os] {
+        for (name, program) in workloads::standard() {
+            let passes = PassManager::with_level(level);
+            if let Err(e) = equiv::verify(&program, workloads::REGISTERS, &passes) {
+                panic!("{} at {}: {}", name, level, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_reports_first_divergent_print() {
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Print { src: 0 },
+        Instruction::Mul {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Print { src: 1 },
+        Instruction::Halt,
+    ]);
+    let outcome = equiv::execute(&program, 2, Engine::Stepping);
+    assert_eq!(
+        outcome.output,
+        vec![
+            Printed {
+                value: 3.0,
+                step: Some(1),
+            },
+            Printed {
+                value: 9.0,
+                step: Some(3),
+            },
+        ]
+    );
+
+    let mut passes = PassManager::empty();
+    passes.add(MulToAdd);
+    let divergence = equiv::verify(&program, 2, &passes).unwrap_err();
+    assert_eq!(divergence.engine, Engine::Stepping);
+    assert_eq!(
+        divergence.to_string(),
+        "optimized stepping run diverged at r1: expected 9 at step 2 (pc 2), found 6 at step 2 (pc 2)"
+    );
+}
+
+#[test]
+fn test_blames_the_step_that_computed_an_overwritten_value() {
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Mul {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::LoadImm {
+            dest: 2,
+            value: 1.0,
+        },
+        Instruction::Add {
+            dest: 2,
+            src1: 1,
+            src2: 2,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 0.0,
+        },
+        Instruction::Print { src: 2 },
+        Instruction::Halt,
+    ]);
+    let mut passes = PassManager::empty();
+    passes.add(MulToAdd);
+    let divergence = equiv::verify(&program, 3, &passes).unwrap_err();
+    assert_eq!(divergence.step, Some(Step { index: 1, pc: 1 }));
+    assert_eq!(divergence.what, "r1");
+    assert_eq!(divergence.expected, "9 at step 1 (pc 1)");
+    assert_eq!(divergence.found, "6 at step 1 (pc 1)");
+}
@@ -0,0 +1,87 @@
+use zyde::history::History;
+use zyde::instruction::Instruction;
+use zyde::vm::{VM, VmError};
+
+fn counting_program() -> Vec<Instruction> {
+    vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Halt,
+    ]
+}
+
+#[test]
+fn test_step_back() {
+    let mut vm = VM::new(counting_program(), 2);
+    let mut history = History::new(16);
+    history.run(&mut vm).unwrap();
+
+    assert_eq!(vm.registers[0], 3.0);
+    assert_eq!(history.len(), 6);
+
+    assert_eq!(history.step_back(&mut vm, 2), 2);
+    assert_eq!(vm.pc, 4);
+    assert_eq!(vm.registers[0], 2.0);
+    assert_eq!(vm.variables.get("x"), Some(&2.0));
+
+    assert_eq!(history.step_back(&mut vm, 1), 1);
+    assert_eq!(vm.pc, 3);
+    assert!(vm.variables.is_empty());
+
+    // Re-executing from the rewound point reaches the same result
+    history.run(&mut vm).unwrap();
+    assert_eq!(vm.registers[0], 3.0);
+}
+
+#[test]
+fn test_history_is_bounded() {
+    let mut vm = VM::new(counting_program(), 2);
+    let mut history = History::new(2);
+    history.run(&mut vm).unwrap();
+
+    assert_eq!(history.len(), 2);
+    assert_eq!(history.step_back(&mut vm, 5), 2);
+    assert_eq!(vm.pc, 4);
+    assert_eq!(history.step_back(&mut vm, 1), 0);
+}
+
+#[test]
+fn test_step_back_from_failure() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 7.0,
+        },
+        Instruction::Mov { dest: 9, src: 0 },
+    ];
+
+    let mut vm = VM::new(program, 2);
+    let mut history = History::new(8);
+    let result = history.run(&mut vm);
+
+    assert!(matches!(result, Err(VmError::RegisterOutOfBounds(_))));
+    assert_eq!(history.step_back(&mut vm, 1), 1);
+    assert_eq!(vm.pc, 1);
+    assert_eq!(vm.registers[0], 7.0);
+}
@@ -0,0 +1,82 @@
+use zyde::hook::{Hook, HookAction};
+use zyde::instruction::Instruction;
+use zyde::vm::{VM, VmError};
+
+/// Counts executed instructions and pauses before `pc` the first time
+#[derive(Default)]
+struct Breakpoint {
+    pc: usize,
+    hit: bool,
+    executed: Vec<usize>,
+}
+
+impl Hook for Breakpoint {
+    fn before(&mut self, pc: usize, _: &Instruction) -> HookAction {
+        if pc == self.pc && !std::mem::replace(&mut self.hit, true) {
+            HookAction::Pause
+        } else {
+            HookAction::Continue
+        }
+    }
+
+    fn after(&mut self, pc: usize, _: &VM) -> HookAction {
+        self.executed.push(pc);
+        HookAction::Continue
+    }
+}
+
+/// Aborts once register 0 passes a limit
+struct Watch(f64);
+
+impl Hook for Watch {
+    fn after(&mut self, _: usize, vm: &VM) -> HookAction {
+        if vm.registers[0] > self.0 {
+            HookAction::Abort
+        } else {
+            HookAction::Continue
+        }
+    }
+}
+
+/// Counts r0 up by one forever
+fn counter() -> Vec<Instruction> {
+    vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Jump(1),
+    ]
+}
+
+#[test]
+fn test_hooks_pause_and_resume() {
+    let mut vm = VM::new(counter(), 2);
+    vm.add_hook(Breakpoint {
+        pc: 2,
+        ..Breakpoint::default()
+    });
+    vm.add_hook(Watch(2.0));
+
+    assert!(matches!(vm.run(), Err(VmError::Paused)));
+    assert_eq!((vm.pc, vm.registers[0]), (2, 1.0));
+    // The watch aborts after the add that takes r0 to 3
+    assert!(matches!(vm.run(), Err(VmError::Aborted)));
+    assert_eq!(vm.registers[0], 3.0);
+
+    assert_eq!(
+        vm.hook::<Breakpoint>().unwrap().executed,
+        [0, 1, 2, 1, 2, 1]
+    );
+    let breakpoint = vm.take_hook::<Breakpoint>().unwrap();
+    assert!(breakpoint.hit);
+    assert!(vm.take_hook::<Breakpoint>().is_none());
+    vm.clear_hooks();
+    vm.config.max_steps = Some(10);
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+}
@@ -0,0 +1,115 @@
+use zyde::heap::MemoryMode;
+use zyde::host::{Exit, HostCall};
+use zyde::instruction::Instruction;
+use zyde::vm::{VM, VmConfig, VmError};
+
+/// Adds its arguments, then asks the host for a value with no arguments
+fn program() -> Vec<Instruction> {
+    vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 2.0,
+        },
+        Instruction::LoadImm {
+            dest: 2,
+            value: 3.0,
+        },
+        Instruction::CallHost {
+            dest: 0,
+            name: "add".to_string(),
+            args: vec![1, 2],
+        },
+        Instruction::CallHost {
+            dest: 3,
+            name: "fetch".to_string(),
+            args: vec![],
+        },
+        Instruction::Halt,
+    ]
+}
+
+fn serve(call: &HostCall) -> f64 {
+    match call.name.as_str() {
+        "add" => call.args.iter().sum(),
+        _ => 42.0,
+    }
+}
+
+#[test]
+fn test_poll_suspends_on_host_calls() {
+    assert_eq!(program()[2].to_string(), "callhost r0, add, [r1, r2]");
+
+    // The reference-counted VM runs on the stepping interpreter
+    let stepping = VmConfig {
+        memory: MemoryMode::RefCounted,
+        ..VmConfig::default()
+    };
+    for config in [VmConfig::default(), stepping] {
+        let mut vm = VM::with_config(program(), 4, config);
+        let mut calls = Vec::new();
+        while let Exit::HostCall(call) = vm.poll().unwrap() {
+            assert_eq!(vm.pending_host_call(), Some(&call));
+            // Nothing runs until the call is completed
+            assert!(matches!(vm.run(), Err(VmError::HostCallPending(name)) if name == call.name));
+            vm.complete_host_call(serve(&call)).unwrap();
+            calls.push(call);
+        }
+        assert_eq!(
+            calls,
+            vec![
+                HostCall {
+                    name: "add".to_string(),
+                    args: vec![2.0, 3.0],
+                },
+                HostCall {
+                    name: "fetch".to_string(),
+                    args: vec![],
+                },
+            ]
+        );
+        assert_eq!(vm.registers, vec![5.0, 2.0, 3.0, 42.0]);
+        assert!(matches!(
+            vm.complete_host_call(0.0),
+            Err(VmError::NoHostCallPending)
+        ));
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_run_async_awaits_host_calls() {
+    use std::future::Future;
+    use std::pin::{Pin, pin};
+    use std::task::{Context, Poll, Waker};
+
+    /// Ready on its second poll, like a reply that has not arrived yet
+    struct Reply(Option<f64>, bool);
+
+    impl Future for Reply {
+        type Output = f64;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<f64> {
+            if std::mem::replace(&mut self.1, true) {
+                Poll::Ready(self.0.take().unwrap())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    let mut vm = VM::new(program(), 4);
+    let mut pending = 0;
+    {
+        let mut run = pin!(vm.run_async(|call| Reply(Some(serve(&call)), false)));
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            match run.as_mut().poll(&mut cx) {
+                Poll::Ready(result) => break result.unwrap(),
+                Poll::Pending => pending += 1,
+            }
+        }
+    }
+    assert_eq!(pending, 2);
+    assert_eq!(vm.registers, vec![5.0, 2.0, 3.0, 42.0]);
+}
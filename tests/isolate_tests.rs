@@ -0,0 +1,134 @@
+use zyde::instruction::Instruction;
+use zyde::isolate::{IsolateError, Isolates};
+use zyde::vm::{VM, VmConfig};
+
+/// Sends 1 to `count` to `to`, then a 0 to say it is done
+fn producer(count: f64, to: &str) -> VM {
+    let send = |src| Instruction::Send {
+        src,
+        to: to.to_string(),
+    };
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: count,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        // loop (2)
+        send(0),
+        Instruction::Sub {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 6 },
+        Instruction::Jump(2),
+        send(0),
+    ];
+    VM::new(program, 2)
+}
+
+/// Sums what it receives until a 0 arrives
+fn consumer() -> VM {
+    let program = vec![
+        Instruction::Recv { dest: 1 },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::ConditionalJump { cond: 1, target: 4 },
+        Instruction::Jump(0),
+        Instruction::Halt,
+    ];
+    VM::new(program, 2)
+}
+
+#[test]
+fn test_isolates_exchange_messages() {
+    assert_eq!(
+        producer(1.0, "sum").program.instructions[2].to_string(),
+        "send r0, sum"
+    );
+
+    for threads in [None, Some(2)] {
+        let mut isolates = Isolates::new(2);
+        isolates.spawn("sum", consumer()).unwrap();
+        isolates.spawn("count", producer(100.0, "sum")).unwrap();
+        match threads {
+            Some(threads) => isolates.run_parallel(threads).unwrap(),
+            None => isolates.run().unwrap(),
+        }
+        assert_eq!(isolates.get("sum").unwrap().registers[0], 5050.0);
+        assert!(isolates.get("count").unwrap().is_halted());
+    }
+}
+
+#[test]
+fn test_isolates_report_deadlock_and_bad_names() {
+    let mut isolates = Isolates::new(1);
+    isolates.spawn("a", consumer()).unwrap();
+    assert!(matches!(
+        isolates.spawn("a", consumer()),
+        Err(IsolateError::DuplicateName(name)) if name == "a"
+    ));
+    isolates.spawn("b", consumer()).unwrap();
+    assert!(matches!(
+        isolates.run(),
+        Err(IsolateError::Deadlock(names)) if names == ["a", "b"]
+    ));
+
+    let mut isolates = Isolates::new(1);
+    isolates.spawn("a", producer(1.0, "nobody")).unwrap();
+    assert!(matches!(
+        isolates.run(),
+        Err(IsolateError::UnknownIsolate(name)) if name == "nobody"
+    ));
+}
+
+#[test]
+fn test_isolate_step_budget_pauses_without_failing() {
+    // Counts down from 1000 without ever blocking, 50 steps per round
+    let spinner = VM::with_config(
+        vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: 1000.0,
+            },
+            Instruction::LoadImm {
+                dest: 1,
+                value: 1.0,
+            },
+            Instruction::Sub {
+                dest: 0,
+                src1: 0,
+                src2: 1,
+            },
+            Instruction::ConditionalJump { cond: 0, target: 5 },
+            Instruction::Jump(2),
+            Instruction::Halt,
+        ],
+        2,
+        VmConfig {
+            max_steps: Some(50),
+            ..VmConfig::default()
+        },
+    );
+    let mut isolates = Isolates::new(1);
+    isolates.spawn("spin", spinner).unwrap();
+    isolates.spawn("count", producer(3.0, "sum")).unwrap();
+    isolates.spawn("sum", consumer()).unwrap();
+    isolates
+        .get_mut("sum")
+        .unwrap()
+        .mailbox
+        .inbox
+        .push_back(10.0);
+    isolates.run().unwrap();
+
+    assert_eq!(isolates.get("sum").unwrap().registers[0], 16.0);
+    assert_eq!(isolates.get("spin").unwrap().steps, 3002);
+}
@@ -0,0 +1,198 @@
+#![cfg(feature = "jit")]
+
+use zyde::instruction::Instruction;
+use zyde::jit::{CompiledProgram, JitError};
+use zyde::passes::PassManager;
+use zyde::passes::fusion::Fusion;
+use zyde::program::Program;
+use zyde::vm::{VM, VmError};
+use zyde::workloads::{self, REGISTERS};
+
+/// Run `program` through the interpreter and the JIT and check they agree
+fn assert_same(program: Program, registers: usize) -> VM {
+    let mut interpreted = VM::new(program.clone(), registers);
+    let expected = interpreted.run();
+    let mut compiled = VM::new(program, registers);
+    let actual = compiled.run_jit();
+
+    assert_eq!(
+        actual.is_ok(),
+        expected.is_ok(),
+        "{:?} vs {:?}",
+        actual,
+        expected
+    );
+    assert_eq!(compiled.registers, interpreted.registers);
+    assert_eq!(compiled.variables, interpreted.variables);
+    assert_eq!(compiled.call_stack, interpreted.call_stack);
+    assert_eq!(
+        (compiled.pc, compiled.steps),
+        (interpreted.pc, interpreted.steps)
+    );
+    compiled
+}
+
+#[test]
+fn test_jit_matches_interpreter_on_workloads() {
+    for (_, program) in workloads::standard() {
+        let mut fused = program.clone();
+        PassManager::empty().add(Fusion).run(&mut fused);
+
+        assert_same(program, REGISTERS);
+        assert_same(fused, REGISTERS);
+    }
+}
+
+#[test]
+fn test_jit_keeps_variables_in_sync() {
+    let vm = assert_same(workloads::variables(100), REGISTERS);
+    assert_eq!(vm.variables["count"], 100.0);
+}
+
+#[test]
+fn test_jit_hands_unsupported_instructions_to_interpreter() {
+    // Print always runs on the interpreter; loading an unset variable exits
+    // so the interpreter can report it
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Print { src: 0 },
+        Instruction::Load {
+            dest: 1,
+            var: "x".to_string(),
+        },
+        Instruction::Load {
+            dest: 1,
+            var: "y".to_string(),
+        },
+    ]);
+    let mut vm = VM::new(program.clone(), 2);
+    assert!(matches!(vm.run_jit(), Err(VmError::VariableNotFound(_))));
+    assert_eq!(vm.variables["x"], 3.0);
+    assert_same(program, 2);
+}
+
+#[test]
+fn test_jit_deep_recursion_grows_call_stack() {
+    // Calls itself until r0 counts down to zero, then unwinds every frame
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 5000.0,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Call { addr: 4 },
+        Instruction::Halt,
+        // countdown (4)
+        Instruction::ConditionalJump { cond: 0, target: 7 },
+        Instruction::Sub {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Call { addr: 4 },
+        Instruction::Return,
+    ]);
+    assert_same(program, 2);
+}
+
+#[test]
+fn test_jit_reports_interpreter_errors() {
+    let empty_return = Program::new(vec![Instruction::Return]);
+    let mut vm = VM::new(empty_return.clone(), 1);
+    assert!(matches!(vm.run_jit(), Err(VmError::CallStackEmpty)));
+    assert_same(empty_return, 1);
+
+    let bad_jump = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 9 },
+    ]);
+    assert_same(bad_jump, 1);
+
+    // Unverified programs run on the interpreter
+    let bad_register = Program::new(vec![Instruction::Print { src: 3 }]);
+    let mut vm = VM::new(bad_register, 1);
+    assert!(matches!(vm.run_jit(), Err(VmError::RegisterOutOfBounds(_))));
+}
+
+#[test]
+fn test_compiled_program_is_reusable() {
+    let program = workloads::fib(20);
+    let compiled = CompiledProgram::compile(&program, REGISTERS).unwrap();
+    for _ in 0..3 {
+        let mut vm = VM::new(program.clone(), REGISTERS);
+        vm.run_compiled(&compiled).unwrap();
+        assert_eq!(vm.registers[0], 6765.0);
+    }
+
+    // A different program falls back to the interpreter rather than running stale code
+    let mut vm = VM::new(workloads::fib(10), REGISTERS);
+    vm.run_compiled(&compiled).unwrap();
+    assert_eq!(vm.registers[0], 55.0);
+
+    assert!(matches!(
+        CompiledProgram::compile(&program, 2),
+        Err(JitError::Unverified(_))
+    ));
+}
+
+#[test]
+fn test_jit_unwinds_to_handlers() {
+    let program = Program::new(vec![
+        Instruction::TryBegin {
+            handler: 4,
+            dest: 1,
+        },
+        Instruction::Call { addr: 5 },
+        Instruction::Call { addr: 6 },
+        Instruction::Halt,
+        // handler (4)
+        Instruction::Halt,
+        // returns (5)
+        Instruction::Return,
+        // fails inside the call (6)
+        Instruction::Load {
+            dest: 0,
+            var: "missing".to_string(),
+        },
+    ]);
+    let vm = assert_same(program, 2);
+    assert_eq!(
+        vm.registers[1],
+        VmError::VariableNotFound(String::new()).code().unwrap()
+    );
+}
+
+#[test]
+fn test_jit_leaves_heap_programs_to_interpreter() {
+    let program = Program::new(vec![
+        Instruction::MakeClosure {
+            dest: 0,
+            addr: 3,
+            captures: vec![],
+        },
+        Instruction::CallClosure { src: 0 },
+        Instruction::Halt,
+        Instruction::Return,
+    ]);
+    assert!(matches!(
+        CompiledProgram::compile(&program, 1),
+        Err(JitError::Unsupported(_))
+    ));
+
+    let mut vm = VM::new(program, 1);
+    vm.run_jit().unwrap();
+    assert_eq!(vm.heap.len(), 1);
+}
@@ -0,0 +1,133 @@
+use zyde::equiv;
+use zyde::lang::ast::{BinOp, Expr, Stmt};
+use zyde::lang::{LangError, LangErrorKind, compile, parse};
+use zyde::passes::{OptLevel, PassManager};
+use zyde::vm::VM;
+
+/// Compile and run `source`, returning what it printed and the VM
+fn run(source: &str) -> (Vec<f64>, VM) {
+    let compiled = compile(source).unwrap();
+    compiled.program.verify().unwrap();
+    compiled
+        .program
+        .verify_registers(compiled.registers)
+        .unwrap();
+    let mut vm = VM::new(compiled.program, compiled.registers);
+    vm.capture_output();
+    vm.run().unwrap();
+    (vm.take_output(), vm)
+}
+
+#[test]
+fn test_precedence() {
+    let stmts = parse("print 1 + 2 * 3 < 8;").unwrap();
+    let number = |n| Box::new(Expr::Number(n));
+    assert_eq!(
+        stmts,
+        vec![Stmt::Print(Expr::Binary(
+            BinOp::Less,
+            Box::new(Expr::Binary(
+                BinOp::Add,
+                number(1.0),
+                Box::new(Expr::Binary(BinOp::Mul, number(2.0), number(3.0))),
+            )),
+            number(8.0),
+        ))]
+    );
+    let (printed, _) =
+        run("print 1 + 2 * 3 < 8; print (1 + 2) * 3; print -2 - -3; print 8 / 2 / 2;");
+    assert_eq!(printed, vec![1.0, 9.0, 1.0, 2.0]);
+}
+
+#[test]
+fn test_comparisons() {
+    let (printed, _) = run("
+        print 1 == 1; print 1 != 1;
+        print 1 <= 1; print 2 <= 1;
+        print 1 >= 2; print 2 >= 2;
+        print !0; print !5;
+    ");
+    assert_eq!(printed, vec![1.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0]);
+}
+
+#[test]
+fn test_fibonacci_loop() {
+    let source = "
+        // the first ten Fibonacci numbers
+        let a = 0;
+        let b = 1;
+        let n = 10;
+        while n > 0 {
+            print a;
+            let next = a + b;
+            a = b;
+            b = next;
+            n = n - 1;
+        }
+    ";
+    let (printed, vm) = run(source);
+    assert_eq!(
+        printed,
+        vec![0.0, 1.0, 1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 34.0]
+    );
+    let compiled = compile(source).unwrap();
+    assert_eq!(vm.registers[compiled.variables["a"]], 55.0);
+    assert!(!compiled.variables.contains_key("next"));
+}
+
+#[test]
+fn test_if_else_chains_and_scopes() {
+    let (printed, _) = run("
+        let x = (2 + 3) * 3;
+        if x < 10 { print 1; } else if x < 20 { print 2; } else { print 3; }
+        if x == 15 { let x = 0; print x; }
+        print x;
+    ");
+    assert_eq!(printed, vec![2.0, 0.0, 15.0]);
+}
+
+#[test]
+fn test_optimized_programs_behave_the_same() {
+    let compiled = compile(
+        "
+        let total = 0;
+        let i = 0;
+        while i < 20 {
+            if i / 2 == 3 { total = total + 100; } else { total = total + i * 2; }
+            i = i + 1;
+        }
+        print total;
+    ",
+    )
+    .unwrap();
+    let passes = PassManager::with_level(OptLevel::O2);
+    equiv::verify(&compiled.program, compiled.registers, &passes).unwrap();
+}
+
+#[test]
+fn test_errors() {
+    let error = |source: &str| compile(source).unwrap_err();
+    assert_eq!(
+        error("let x = 1;\nprint y;"),
+        LangError {
+            line: 2,
+            kind: LangErrorKind::UndefinedVariable("y".to_string()),
+        }
+    );
+    assert_eq!(
+        error("if 1 { let y = 2; }\ny = 3;").kind,
+        LangErrorKind::UndefinedVariable("y".to_string())
+    );
+    assert_eq!(
+        error("print 1 # 2;").kind,
+        LangErrorKind::UnexpectedChar('#')
+    );
+    assert_eq!(
+        error("print (1 + 2;").to_string(),
+        "Line 1: Expected ')', found ';'"
+    );
+    assert_eq!(
+        error("while 1 {\nprint 1;").to_string(),
+        "Line 2: Expected '}', found end of input"
+    );
+}
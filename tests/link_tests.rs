@@ -0,0 +1,80 @@
+use zyde::instruction::Instruction;
+use zyde::link::{LinkError, Module, link};
+use zyde::program::{Program, ProgramError};
+use zyde::vm::VM;
+
+/// `square` multiplies r0 by itself
+fn math() -> Module {
+    let mut program = Program::new(vec![
+        Instruction::Mul {
+            dest: 0,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Return,
+    ]);
+    program.label("square", 0).unwrap();
+    Module::new("math", program)
+}
+
+/// Squares 3 by calling into `math`
+fn main_module() -> Module {
+    let mut program = Program::new(vec![
+        Instruction::Halt,
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Call { addr: 99 },
+        Instruction::Halt,
+    ]);
+    program.label("main", 1).unwrap();
+    program.set_entry("main").unwrap();
+    let mut module = Module::new("main", program);
+    module.import(2, "square").unwrap();
+    module
+}
+
+#[test]
+fn test_link_resolves_imports() {
+    let program = link(&[main_module(), math()]).unwrap();
+    assert_eq!(program.instructions[2], Instruction::Call { addr: 4 });
+    assert_eq!(program.entry(), 1);
+    assert_eq!(program.find_label("square").unwrap().addr, 4);
+
+    let mut vm = VM::new(program, 1);
+    vm.run().unwrap();
+    assert_eq!(vm.registers[0], 9.0);
+}
+
+#[test]
+fn test_link_errors() {
+    assert!(matches!(
+        main_module().import(1, "square"),
+        Err(LinkError::NotAReference { addr: 1, .. })
+    ));
+    assert_eq!(
+        link(&[main_module()]),
+        Err(LinkError::UnresolvedSymbol {
+            module: "main".to_string(),
+            symbol: "square".to_string(),
+        })
+    );
+    let mut other = math();
+    other.name = "other".to_string();
+    assert_eq!(
+        link(&[main_module(), math(), other]),
+        Err(LinkError::DuplicateSymbol {
+            symbol: "square".to_string(),
+            first: "math".to_string(),
+            second: "other".to_string(),
+        })
+    );
+
+    // A module's own references must still be valid once placed
+    let broken = Module::new("broken", Program::new(vec![Instruction::Jump(5)]));
+    assert!(matches!(
+        link(&[broken]),
+        Err(LinkError::Program(ProgramError::TargetOutOfBounds { .. }))
+    ));
+}
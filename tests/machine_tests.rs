@@ -0,0 +1,117 @@
+use std::fmt::Display;
+use zyde::history::History;
+use zyde::hook::{Hook, HookAction};
+use zyde::instruction::Instruction;
+use zyde::machine::Machine;
+use zyde::program::Program;
+use zyde::stack_vm::{StackInstruction, StackVM};
+use zyde::vm::{VM, VmError};
+
+/// Step `vm` until it reaches `breakpoint` or halts, listing each
+/// instruction run, as a debugger would for either machine
+fn run_to<M: Machine>(vm: &mut M, breakpoint: usize) -> Result<Vec<String>, VmError>
+where
+    M::Instruction: Display,
+{
+    let mut listing = Vec::new();
+    while !vm.is_halted() && vm.pc() != breakpoint {
+        listing.push(vm.instruction(vm.pc()).unwrap().to_string());
+        vm.step()?;
+    }
+    Ok(listing)
+}
+
+/// Remembers the values the machine held after each step
+struct Values<M: Machine>(Vec<Vec<M::Value>>);
+
+impl<M: Machine + 'static> Hook<M> for Values<M>
+where
+    M::Value: Send,
+{
+    fn after(&mut self, _: usize, vm: &M) -> HookAction {
+        self.0.push(vm.values().to_vec());
+        HookAction::Continue
+    }
+}
+
+fn register_vm() -> VM {
+    VM::new(
+        Program::new(vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: 2.0,
+            },
+            Instruction::LoadImm {
+                dest: 1,
+                value: 3.0,
+            },
+            Instruction::Mul {
+                dest: 0,
+                src1: 0,
+                src2: 1,
+            },
+            Instruction::Store {
+                src: 0,
+                var: "x".to_string(),
+            },
+        ]),
+        2,
+    )
+}
+
+fn stack_vm() -> StackVM<f64> {
+    use StackInstruction::*;
+    StackVM::new(vec![Push(2.0), Push(3.0), Mul, Store("x".to_string())])
+}
+
+#[test]
+fn test_tools_drive_either_machine() {
+    let mut vm = register_vm();
+    assert_eq!(
+        run_to(&mut vm, 2).unwrap(),
+        ["loadimm r0, 2", "loadimm r1, 3"]
+    );
+    assert_eq!(vm.values(), [2.0, 3.0]);
+    Machine::run(&mut vm).unwrap();
+    assert_eq!(vm.variable("x"), Some(6.0));
+
+    let mut vm = stack_vm();
+    assert_eq!(run_to(&mut vm, 2).unwrap(), ["push 2", "push 3"]);
+    assert_eq!(vm.values(), [2.0, 3.0]);
+    Machine::run(&mut vm).unwrap();
+    assert_eq!(vm.variable("x"), Some(6.0));
+    assert_eq!((vm.steps(), vm.return_addresses()), (4, vec![]));
+}
+
+#[test]
+fn test_hooks_inspect_either_machine() {
+    let mut vm = register_vm();
+    Machine::add_hook(&mut vm, Values::<VM>(Vec::new()));
+    vm.run().unwrap();
+    let values: Values<VM> = Machine::take_hook(&mut vm).unwrap();
+    assert_eq!(values.0[2], [6.0, 3.0]);
+
+    let mut vm = stack_vm();
+    vm.add_hook(Values::<StackVM<f64>>(Vec::new()));
+    vm.run().unwrap();
+    let values: &Values<StackVM<f64>> = vm.hook().unwrap();
+    assert_eq!(values.0, [vec![2.0], vec![2.0, 3.0], vec![6.0], vec![]]);
+}
+
+#[test]
+fn test_history_steps_back_on_either_machine() {
+    let mut vm = register_vm();
+    let mut history = History::new(8);
+    history.run(&mut vm).unwrap();
+    assert_eq!(history.step_back(&mut vm, 2), 2);
+    assert_eq!((vm.pc, vm.registers[0]), (2, 2.0));
+
+    let mut vm = stack_vm();
+    let mut history = History::new(8);
+    history.run(&mut vm).unwrap();
+    assert_eq!(history.step_back(&mut vm, 2), 2);
+    assert_eq!((vm.pc, vm.stack.as_slice()), (2, &[2.0, 3.0][..]));
+    assert!(vm.variables.is_empty());
+    history.run(&mut vm).unwrap();
+    assert_eq!(vm.variables["x"], 6.0);
+}
@@ -0,0 +1,1079 @@
+use zyde::asm_reg;
+use zyde::bytecode::EncodeError;
+use zyde::cfg::{self, Cfg};
+use zyde::coverage::Coverage;
+use zyde::equiv;
+use zyde::instruction::{Comparison, Instruction};
+use zyde::passes::compact::CodeCompaction;
+use zyde::passes::const_fold::ConstFold;
+use zyde::passes::dce::DeadCodeElim;
+use zyde::passes::fusion::Fusion;
+use zyde::passes::inline::Inline;
+use zyde::passes::layout::BlockLayout;
+use zyde::passes::loops::{LoopInvariantMotion, StrengthReduction};
+use zyde::passes::peephole::{Peephole, Rule};
+use zyde::passes::{OptLevel, Pass, PassManager};
+use zyde::program::Program;
+use zyde::vm::VM;
+
+#[test]
+fn test_const_fold_arithmetic() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 3.0,
+        },
+        Instruction::Mul {
+            dest: 2,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Add {
+            dest: 3,
+            src1: 2,
+            src2: 0,
+        },
+        Instruction::Halt,
+    ]);
+
+    PassManager::default().run(&mut program);
+
+    assert!(matches!(
+        program.instructions[2],
+        Instruction::LoadImm { dest: 2, value } if value == 6.0
+    ));
+    assert!(matches!(
+        program.instructions[3],
+        Instruction::LoadImm { dest: 3, value } if value == 8.0
+    ));
+}
+
+#[test]
+fn test_const_fold_propagates_variables() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 7.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Load {
+            dest: 1,
+            var: "x".to_string(),
+        },
+        Instruction::Halt,
+    ]);
+
+    PassManager::default().run(&mut program);
+
+    assert!(matches!(
+        program.instructions[2],
+        Instruction::LoadImm { dest: 1, value } if value == 7.0
+    ));
+}
+
+#[test]
+fn test_const_fold_stops_at_block_boundary() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Jump(2),
+        Instruction::Add {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Halt,
+    ]);
+
+    PassManager::empty().add(ConstFold).run(&mut program);
+
+    assert!(matches!(program.instructions[2], Instruction::Add { .. }));
+}
+
+#[test]
+fn test_const_fold_preserves_results() {
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 10.0,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 4.0,
+        },
+        Instruction::Sub {
+            dest: 2,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::LessThan {
+            dest: 3,
+            src1: 1,
+            src2: 2,
+        },
+        Instruction::Not { dest: 3, src: 3 },
+        Instruction::ConditionalJump { cond: 3, target: 7 },
+        Instruction::Halt,
+        Instruction::Mov { dest: 0, src: 2 },
+        Instruction::Halt,
+    ]);
+
+    let mut folded = program.clone();
+    PassManager::empty().add(ConstFold).run(&mut folded);
+    assert!(matches!(folded.instructions[5], Instruction::Jump(7)));
+
+    let mut vm = VM::new(program, 4);
+    vm.run().unwrap();
+    let mut folded_vm = VM::new(folded, 4);
+    folded_vm.run().unwrap();
+
+    assert_eq!(vm.registers, folded_vm.registers);
+}
+
+#[test]
+fn test_pipeline_toggle() {
+    let mut pipeline = PassManager::default();
+    assert!(pipeline.is_enabled("const-fold"));
+    assert!(pipeline.set_enabled("const-fold", false));
+    assert!(!pipeline.set_enabled("no-such-pass", false));
+
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::Add {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+    ]);
+    pipeline.run(&mut program);
+
+    assert!(matches!(program.instructions[1], Instruction::Add { .. }));
+}
+
+#[test]
+fn test_cfg_blocks() {
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 3 },
+        Instruction::Halt,
+        Instruction::Call { addr: 5 },
+        Instruction::Halt,
+        Instruction::Return,
+    ]);
+
+    let cfg = Cfg::build(&program.instructions);
+    let ranges: Vec<_> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+
+    assert_eq!(ranges, vec![(0, 2), (2, 3), (3, 4), (4, 5), (5, 6)]);
+    assert_eq!(cfg.blocks[0].successors, vec![2, 1]);
+    assert_eq!(cfg.blocks[2].successors, vec![4, 3]);
+    assert!(cfg.blocks[1].successors.is_empty());
+    assert_eq!(cfg.block_of(1), Some(0));
+    assert_eq!(cfg.reachable(), vec![true; 5]);
+}
+
+#[test]
+fn test_cfg_to_dot() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 3 },
+        Instruction::Halt,
+        Instruction::Call { addr: 5 },
+        Instruction::Halt,
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Return,
+    ]);
+    program.label("helper", 5).unwrap();
+
+    let dot = cfg::to_dot(&program, Some(6));
+
+    assert!(dot.starts_with("digraph cfg {"));
+    assert!(dot.contains("b0 -> b2 [label=zero];"));
+    assert!(dot.contains("b0 -> b1;"));
+    assert!(dot.contains("b2 -> b4 [style=dashed, label=call];"));
+    assert!(dot.contains("block 4: helper\\l"));
+    assert!(dot.contains("     5  store r0, x\\l"));
+    assert!(dot.contains(">    6  ret\\l"));
+    assert_eq!(dot.matches("fillcolor").count(), 1);
+}
+
+#[test]
+fn test_cfg_with_source_lines_and_mermaid() {
+    let (program, lines) = asm_reg::assemble_with_lines(
+        "
+        loadimm r0, 3
+    loop:
+        jz r0, done
+        addimm r0, r0, r1, -1
+        jmp loop
+    done:
+        halt
+        ",
+    )
+    .unwrap();
+    assert_eq!(lines, vec![2, 4, 5, 6, 8]);
+
+    let dot = cfg::to_dot_with_lines(&program, None, &lines);
+    assert!(dot.contains("block 1: loop\\laddresses 1..=1\\l     1  jz r0, 4  ; line 4\\l"));
+
+    let mermaid = cfg::to_mermaid(&program, &lines);
+    assert!(mermaid.starts_with("flowchart TD\n"));
+    assert!(
+        mermaid.contains("  b3[\"block 3: done<br/>addresses 4..=4<br/>4  halt  ; line 8\"]\n")
+    );
+    assert!(mermaid.contains("  b1 -->|zero| b3\n"));
+    assert!(mermaid.contains("  b2 --> b1\n"));
+}
+
+#[test]
+fn test_dce_removes_unreachable_blocks() {
+    let mut program = Program::new(vec![
+        Instruction::Jump(3),
+        Instruction::LoadImm {
+            dest: 0,
+            value: 999.0,
+        },
+        Instruction::Halt,
+        Instruction::Call { addr: 6 },
+        Instruction::Halt,
+        Instruction::Print { src: 0 },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 42.0,
+        },
+        Instruction::Return,
+    ]);
+
+    PassManager::empty().add(DeadCodeElim).run(&mut program);
+
+    assert_eq!(program.len(), 4);
+    assert!(matches!(
+        program.instructions[0],
+        Instruction::Call { addr: 2 }
+    ));
+
+    let mut vm = VM::new(program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(vm.registers[1], 42.0);
+}
+
+#[test]
+fn test_dce_keeps_out_of_bounds_targets_trapping() {
+    let mut program = Program::new(vec![
+        Instruction::Jump(2),
+        Instruction::Halt,
+        Instruction::Jump(100),
+    ]);
+
+    PassManager::empty().add(DeadCodeElim).run(&mut program);
+
+    assert_eq!(program.len(), 1);
+    assert!(matches!(program.instructions[0], Instruction::Jump(addr) if addr >= program.len()));
+}
+
+#[test]
+fn test_fold_then_dce_prunes_constant_branch() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 3 },
+        Instruction::Print { src: 0 },
+        Instruction::Halt,
+    ]);
+
+    PassManager::default().run(&mut program);
+
+    assert_eq!(program.len(), 2);
+    assert!(matches!(program.instructions[1], Instruction::Halt));
+}
+
+struct HaltEverything;
+
+impl Pass for HaltEverything {
+    fn name(&self) -> &'static str {
+        "halt-everything"
+    }
+
+    fn run(&self, program: &mut Program) {
+        program
+            .instructions
+            .iter_mut()
+            .for_each(|i| *i = Instruction::Halt);
+    }
+}
+
+#[test]
+fn test_opt_level_presets() {
+    assert!(
+        PassManager::with_level(OptLevel::O0)
+            .pass_names()
+            .is_empty()
+    );
+    assert_eq!(
+        PassManager::with_level(OptLevel::O1).pass_names(),
+        vec!["const-fold", "dce"]
+    );
+    assert_eq!("2".parse::<OptLevel>(), Ok(OptLevel::O2));
+    assert_eq!("O1".parse::<OptLevel>(), Ok(OptLevel::O1));
+    assert!("3".parse::<OptLevel>().is_err());
+}
+
+#[test]
+fn test_o2_runs_to_fixpoint() {
+    // Dropping the dead arm merges the add into the block that defines r0,
+    // which only a second round of folding can take advantage of
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 5.0,
+        },
+        Instruction::Jump(4),
+        Instruction::LoadImm {
+            dest: 0,
+            value: 6.0,
+        },
+        Instruction::Jump(4),
+        Instruction::Add {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Halt,
+    ]);
+
+    let mut o1 = program.clone();
+    PassManager::with_level(OptLevel::O1).run(&mut o1);
+    assert_eq!(o1.len(), 3);
+    assert!(matches!(o1.instructions[1], Instruction::Add { .. }));
+
+    let mut o2 = program;
+    PassManager::with_level(OptLevel::O2).run(&mut o2);
+    assert_eq!(o2.len(), 3);
+    assert!(matches!(
+        o2.instructions[1],
+        Instruction::LoadImm { dest: 1, value } if value == 10.0
+    ));
+}
+
+#[test]
+fn test_custom_pass() {
+    let mut manager = PassManager::with_level(OptLevel::O0);
+    manager.add(HaltEverything);
+
+    let mut program = Program::new(vec![Instruction::Print { src: 0 }]);
+    manager.run(&mut program);
+
+    assert_eq!(program.instructions, vec![Instruction::Halt]);
+}
+
+#[test]
+fn test_dce_removes_jump_to_next() {
+    let mut program = Program::new(vec![
+        Instruction::Jump(1),
+        Instruction::Jump(3),
+        Instruction::Halt,
+        Instruction::Halt,
+    ]);
+
+    PassManager::empty().add(DeadCodeElim).run(&mut program);
+
+    assert_eq!(program.instructions, vec![Instruction::Halt]);
+}
+
+#[test]
+fn test_dce_keeps_and_relocates_exports() {
+    let mut program = Program::new(vec![
+        Instruction::Halt,
+        Instruction::Print { src: 0 },
+        Instruction::Halt,
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Return,
+    ]);
+    program.export("three", 3, 0).unwrap();
+
+    PassManager::empty().add(DeadCodeElim).run(&mut program);
+
+    assert_eq!(program.len(), 3);
+    assert_eq!(program.find_export("three").unwrap().addr, 1);
+
+    let mut vm = VM::new(program, 2);
+    vm.call_export("three", &[]).unwrap();
+
+    assert_eq!(vm.registers[0], 3.0);
+}
+
+#[test]
+fn test_compact_merges_shared_tails() {
+    let tail = |value: f64| {
+        vec![
+            Instruction::LoadImm { dest: 1, value },
+            Instruction::Add {
+                dest: 2,
+                src1: 0,
+                src2: 1,
+            },
+            Instruction::Print { src: 2 },
+            Instruction::Halt,
+        ]
+    };
+    let mut code = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 7 },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 10.0,
+        },
+    ];
+    code.extend(tail(1.0));
+    code.push(Instruction::LoadImm {
+        dest: 0,
+        value: 20.0,
+    });
+    code.extend(tail(1.0));
+    let original = Program::new(code);
+
+    let mut program = original.clone();
+    PassManager::empty().add(CodeCompaction).run(&mut program);
+
+    assert_eq!(program.len(), original.len() - 3);
+    assert_eq!(program.instructions[8], Instruction::Jump(3));
+
+    let mut expected = VM::new(original, 4);
+    expected.run().unwrap();
+    let mut vm = VM::new(program, 4);
+    vm.run().unwrap();
+
+    assert_eq!(vm.registers, expected.registers);
+}
+
+#[test]
+fn test_compact_dedupes_whole_blocks() {
+    let mut program = Program::new(vec![
+        Instruction::Call { addr: 3 },
+        Instruction::Call { addr: 6 },
+        Instruction::Halt,
+        Instruction::Print { src: 0 },
+        Instruction::Mov { dest: 1, src: 0 },
+        Instruction::Return,
+        Instruction::Print { src: 0 },
+        Instruction::Mov { dest: 1, src: 0 },
+        Instruction::Return,
+    ]);
+
+    PassManager::empty().add(CodeCompaction).run(&mut program);
+
+    assert_eq!(program.len(), 7);
+    assert_eq!(program.instructions[1], Instruction::Call { addr: 6 });
+    assert_eq!(program.instructions[6], Instruction::Jump(3));
+}
+
+#[test]
+fn test_os_level() {
+    assert_eq!("s".parse::<OptLevel>(), Ok(OptLevel::Os));
+    assert_eq!(
+        PassManager::with_level(OptLevel::Os).pass_names(),
+        vec![
+            "const-fold",
+            "range-fold",
+            "licm",
+            "strength-reduce",
+            "peephole",
+            "dce",
+            "layout",
+            "compact"
+        ]
+    );
+}
+
+#[test]
+fn test_peephole_builtin_rules() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::Mov { dest: 1, src: 1 },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Load {
+            dest: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Mov { dest: 1, src: 0 },
+        Instruction::Mov { dest: 0, src: 1 },
+        Instruction::Halt,
+    ]);
+
+    PassManager::empty().add(Peephole::new()).run(&mut program);
+
+    assert_eq!(
+        program.instructions,
+        vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: 2.0,
+            },
+            Instruction::Store {
+                src: 0,
+                var: "x".to_string(),
+            },
+            Instruction::Mov { dest: 1, src: 0 },
+            Instruction::Halt,
+        ]
+    );
+}
+
+#[test]
+fn test_peephole_respects_jump_targets() {
+    let mut program = Program::new(vec![
+        Instruction::Jump(2),
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::Mov { dest: 3, src: 3 },
+        Instruction::Jump(5),
+        Instruction::Halt,
+    ]);
+
+    PassManager::empty().add(Peephole::new()).run(&mut program);
+
+    // The overwritten immediate stays because a jump lands between the pair,
+    // while the self-move goes and the final jump is relocated
+    assert_eq!(program.len(), 5);
+    assert_eq!(program.instructions[0], Instruction::Jump(2));
+    assert_eq!(program.instructions[3], Instruction::Jump(4));
+}
+
+#[test]
+fn test_peephole_custom_rule() {
+    // Collapse "add r, r, r" into "mul" by a constant two held in r15
+    let double = Rule::new("double", 1, |w| match &w[0] {
+        Instruction::Add { dest, src1, src2 } if src1 == src2 => Some(vec![
+            Instruction::LoadImm {
+                dest: 15,
+                value: 2.0,
+            },
+            Instruction::Mul {
+                dest: *dest,
+                src1: *src1,
+                src2: 15,
+            },
+        ]),
+        _ => None,
+    });
+    let peephole = Peephole::empty().with_rule(double);
+    assert_eq!(peephole.rule_names(), vec!["double"]);
+
+    let mut program = Program::new(vec![
+        Instruction::Jump(1),
+        Instruction::Add {
+            dest: 0,
+            src1: 1,
+            src2: 1,
+        },
+        Instruction::Jump(3),
+        Instruction::Halt,
+    ]);
+    PassManager::empty().add(peephole).run(&mut program);
+
+    assert_eq!(program.len(), 5);
+    assert_eq!(program.instructions[0], Instruction::Jump(1));
+    assert!(matches!(program.instructions[2], Instruction::Mul { .. }));
+    assert_eq!(program.instructions[3], Instruction::Jump(4));
+}
+
+#[test]
+fn test_fusion() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 2.0,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 1,
+            src2: 0,
+        },
+        Instruction::LessThan {
+            dest: 2,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::ConditionalJump { cond: 2, target: 5 },
+        Instruction::Halt,
+        Instruction::Print { src: 2 },
+        Instruction::Halt,
+    ]);
+    PassManager::empty().add(Fusion).run(&mut program);
+
+    assert_eq!(
+        program.instructions,
+        vec![
+            Instruction::AddImm {
+                dest: 0,
+                src: 0,
+                imm: 1,
+                value: 2.0,
+            },
+            Instruction::CompareJump {
+                cmp: Comparison::LessThan,
+                dest: 2,
+                src1: 0,
+                src2: 1,
+                target: 3,
+            },
+            Instruction::Halt,
+            Instruction::Print { src: 2 },
+            Instruction::Halt,
+        ]
+    );
+    assert_eq!(program.instructions[1].to_string(), "ltjz r2, r0, r1, 3");
+
+    let mut vm = VM::new(program.clone(), 4);
+    vm.run().unwrap();
+    // 0 + 2 is not less than 2, so the branch is taken with both writes visible
+    assert_eq!(vm.registers[..3], [2.0, 2.0, 0.0]);
+    assert_eq!(vm.pc, 5);
+
+    assert_eq!(
+        program.to_bytecode(),
+        Err(EncodeError::Unencodable("addimm"))
+    );
+}
+
+#[test]
+fn test_const_fold_resolves_known_switch() {
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Switch {
+            src: 0,
+            table: vec![2, 3],
+            default: 2,
+        },
+        Instruction::Halt,
+        Instruction::Halt,
+    ]);
+
+    PassManager::empty().add(ConstFold).run(&mut program);
+
+    assert_eq!(program.instructions[1], Instruction::Jump(3));
+}
+
+#[test]
+fn test_code_moving_passes_skip_computed_jumps() {
+    // The address in r0 is data, so nothing may move underneath it
+    let original = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 4.0,
+        },
+        Instruction::JumpIndirect { src: 0 },
+        Instruction::Jump(3),
+        Instruction::Jump(4),
+        Instruction::Halt,
+    ]);
+
+    let mut program = original.clone();
+    PassManager::empty()
+        .add(DeadCodeElim)
+        .add(Peephole::new())
+        .add(CodeCompaction)
+        .run(&mut program);
+
+    assert_eq!(program, original);
+}
+
+#[test]
+fn test_loaded_addresses_survive_dce_and_const_fold() {
+    // `callback` is only reachable through the address stored at 1
+    let mut program = Program::new(vec![
+        Instruction::Jump(1),
+        Instruction::LoadAddr { dest: 0, addr: 4 },
+        Instruction::Store {
+            src: 0,
+            var: "callback".to_string(),
+        },
+        Instruction::Halt,
+        // callback (4)
+        Instruction::Return,
+    ]);
+
+    PassManager::empty()
+        .add(ConstFold)
+        .add(DeadCodeElim)
+        .run(&mut program);
+
+    assert_eq!(
+        program.instructions,
+        vec![
+            Instruction::LoadAddr { dest: 0, addr: 3 },
+            Instruction::Store {
+                src: 0,
+                var: "callback".to_string(),
+            },
+            Instruction::Halt,
+            Instruction::Return,
+        ]
+    );
+}
+
+#[test]
+fn test_inline_small_functions() {
+    let source = "
+        loadimm r0, 5
+        call clamp
+        print r0
+        loadimm r0, -2
+        call clamp
+        print r0
+        call big
+        halt
+    clamp:
+        loadimm r1, 0
+        gtjz r2, r0, r1, zero
+        ret
+    zero:
+        mov r0, r1
+        ret
+    big:
+        addimm r0, r0, r1, 1
+        addimm r0, r0, r1, 1
+        addimm r0, r0, r1, 1
+        addimm r0, r0, r1, 1
+        addimm r0, r0, r1, 1
+        ret
+    ";
+    let (mut program, lines) = asm_reg::assemble_with_lines(source).unwrap();
+    let original = program.clone();
+    let origins = Inline::new(5).run_with_origins(&mut program);
+    program.verify().unwrap();
+
+    // Both calls to `clamp` are replaced; `big` is over the threshold
+    assert_eq!(program.len(), original.len() + 2 * 4);
+    assert!(
+        !program.instructions[..6]
+            .iter()
+            .any(|i| matches!(i, Instruction::Call { .. }))
+    );
+    assert_eq!(program.instructions[3], Instruction::Jump(6));
+    assert_eq!(program.instructions[5], Instruction::Jump(6));
+    assert_eq!(program.find_label("zero.1").unwrap().addr, 4);
+    assert_eq!(program.find_label("zero.2").unwrap().addr, 11);
+    assert_eq!(program.find_label("clamp").unwrap().addr, 16);
+
+    // Source lines follow the copies
+    let mapped: Vec<usize> = origins.iter().map(|&o| lines[o]).collect();
+    assert_eq!(&mapped[1..6], &[11, 12, 13, 15, 16]);
+
+    let mut vm = VM::new(program, 3);
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.take_output(), vec![5.0, 0.0]);
+    assert_eq!(vm.registers[0], 5.0);
+
+    equiv::verify(&original, 3, &PassManager::with_level(OptLevel::O2)).unwrap();
+}
+
+#[test]
+fn test_inline_skips_functions_that_call_or_stop() {
+    let source = "
+        call outer
+        call fails
+        halt
+    outer:
+        call inner
+        ret
+    inner:
+        print r0
+        ret
+    fails:
+        load r0, missing
+        ret
+    ";
+    let mut program = asm_reg::assemble(source).unwrap();
+    Inline::default().run(&mut program);
+    // Only the leaf is inlined; `outer` becomes one for the next round
+    assert_eq!(program.instructions[0], Instruction::Call { addr: 3 });
+    assert_eq!(program.instructions[1], Instruction::Call { addr: 8 });
+    assert_eq!(program.instructions[3], Instruction::Print { src: 0 });
+    Inline::default().run(&mut program);
+    assert_eq!(program.instructions[0], Instruction::Print { src: 0 });
+}
+
+#[test]
+fn test_licm_hoists_invariant_loads() {
+    let source = "
+        loadimm r0, 0
+        loadimm r1, 2
+        store r1, scale
+    top:
+        print r0
+        load r2, scale
+        mul r3, r0, r2
+        load r4, step
+        store r3, last
+        addimm r0, r0, r5, 1
+        gtjz r6, r0, r1, top
+        halt
+    ";
+    let original = asm_reg::assemble(source).unwrap();
+    let mut program = original.clone();
+    LoopInvariantMotion.run(&mut program);
+
+    // `scale` is stored on the way in, so its `load` moves in front of the
+    // loop; `step` may be missing, and that must still stop the program
+    // after the first `print`
+    assert_eq!(program.instructions[3], original.instructions[4]);
+    assert_eq!(program.instructions[4], Instruction::Print { src: 0 });
+    assert_eq!(program.instructions[6], original.instructions[6]);
+    assert_eq!(
+        program.instructions[9],
+        Instruction::CompareJump {
+            cmp: Comparison::GreaterThan,
+            dest: 6,
+            src1: 0,
+            src2: 1,
+            target: 4,
+        }
+    );
+
+    // `last` is stored in the loop, so its `load` stays
+    let mut stored = asm_reg::assemble(
+        "loadimm r0, 0\nstore r0, last\ntop:\nload r1, last\naddimm r1, r1, r2, 1\nstore r1, last\njmp top",
+    )
+    .unwrap();
+    let before = stored.clone();
+    LoopInvariantMotion.run(&mut stored);
+    assert_eq!(stored, before);
+
+    let mut level = PassManager::empty();
+    level.add(LoopInvariantMotion);
+    equiv::verify(&original, 7, &level).unwrap();
+}
+
+#[test]
+fn test_strength_reduction_of_counter_products() {
+    let source = "
+        loadimm r0, 0
+        loadimm r1, 9
+        loadimm r2, 3
+    top:
+        addimm r0, r0, r4, 1
+        mul r3, r0, r2
+        print r3
+        gtjz r5, r0, r1, top
+        halt
+    ";
+    let original = asm_reg::assemble(source).unwrap();
+    let mut program = original.clone();
+    StrengthReduction.run(&mut program);
+    assert_eq!(
+        program.instructions[3],
+        Instruction::Mul {
+            dest: 3,
+            src1: 0,
+            src2: 2,
+        }
+    );
+    assert_eq!(
+        program.instructions[5],
+        Instruction::Add {
+            dest: 3,
+            src1: 3,
+            src2: 2,
+        }
+    );
+
+    let mut vm = VM::new(program, 6);
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(
+        vm.take_output(),
+        (1..=10).map(|i| 3.0 * i as f64).collect::<Vec<_>>()
+    );
+
+    // A fractional factor could round differently when summed
+    let mut fractional =
+        asm_reg::assemble(&source.replace("loadimm r2, 3", "loadimm r2, 0.1")).unwrap();
+    let before = fractional.clone();
+    StrengthReduction.run(&mut fractional);
+    assert_eq!(fractional, before);
+
+    let mut level = PassManager::empty();
+    level.add(StrengthReduction);
+    equiv::verify(&original, 6, &level).unwrap();
+}
+
+#[test]
+fn test_layout_puts_jump_targets_next() {
+    let source = "
+        jmp main
+    helper:
+        addimm r0, r0, r1, 1
+        ret
+    main:
+        loadimm r0, 0
+        call helper
+        print r0
+        jmp done
+    unused:
+        print r1
+        halt
+    done:
+        halt
+    ";
+    let original = asm_reg::assemble(source).unwrap();
+    let mut program = original.clone();
+    BlockLayout::new().run(&mut program);
+
+    // `main` follows the entry, `done` follows `main`, and both jumps go
+    assert_eq!(program.len(), original.len() - 2);
+    assert!(
+        !program
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::Jump(_)))
+    );
+    assert_eq!(program.find_label("main").unwrap().addr, 0);
+    assert_eq!(program.find_label("done").unwrap().addr, 3);
+    assert_eq!(program.find_label("helper").unwrap().addr, 4);
+    assert_eq!(program.instructions[1], Instruction::Call { addr: 4 });
+
+    let mut level = PassManager::empty();
+    level.add(BlockLayout::new());
+    equiv::verify(&original, 2, &level).unwrap();
+}
+
+#[test]
+fn test_layout_inverts_negated_branches() {
+    let source = "
+        loadimm r0, 3
+    top:
+        not r2, r0
+        jz r2, body
+        jmp done
+    body:
+        print r0
+        addimm r0, r0, r1, -1
+        jmp top
+    done:
+        halt
+    ";
+    let original = asm_reg::assemble(source).unwrap();
+    let mut program = original.clone();
+    BlockLayout::new().run(&mut program);
+
+    // The loop body follows the test, which now jumps out when r0 is zero
+    assert_eq!(program.len(), original.len() - 1);
+    assert_eq!(
+        program.instructions[1..4],
+        [
+            Instruction::Not { dest: 2, src: 0 },
+            Instruction::ConditionalJump { cond: 0, target: 6 },
+            Instruction::Print { src: 0 },
+        ]
+    );
+    let mut level = PassManager::empty();
+    level.add(BlockLayout::new());
+    equiv::verify(&original, 3, &level).unwrap();
+
+    // Once `not` overwrites what it tested, the branch cannot be flipped
+    let source = source.replace("not r2, r0\n        jz r2", "not r0, r0\n        jz r0");
+    let mut program = asm_reg::assemble(&source).unwrap();
+    BlockLayout::new().run(&mut program);
+    let body = program.find_label("body").unwrap().addr;
+    assert_eq!(
+        program.instructions[2],
+        Instruction::ConditionalJump {
+            cond: 0,
+            target: body,
+        }
+    );
+}
+
+#[test]
+fn test_layout_follows_a_profile() {
+    // Mostly takes the `rare` branch's other side, which the guess keeps
+    // out of line
+    let source = "
+        loadimm r0, 0
+        loadimm r3, 100
+    top:
+        addimm r0, r0, r1, 1
+        eq r2, r0, r3
+        not r4, r2
+        jz r4, rare
+        jmp common
+    rare:
+        halt
+    common:
+        print r0
+        jmp top
+    ";
+    let original = asm_reg::assemble(source).unwrap();
+    let mut vm = VM::new(original.clone(), 5);
+    vm.capture_output();
+    vm.add_hook(Coverage::new());
+    vm.run().unwrap();
+    let coverage = vm.take_hook::<Coverage>().unwrap();
+
+    let mut program = original.clone();
+    let layout = BlockLayout::profiled(&original, &coverage);
+    layout.run(&mut program);
+    let common = program.find_label("common").unwrap().addr;
+    let jz = program
+        .instructions
+        .iter()
+        .position(|i| matches!(i, Instruction::ConditionalJump { .. }))
+        .unwrap();
+    assert_eq!(common, jz + 1);
+    assert_eq!(program.len(), original.len() - 1);
+
+    let mut level = PassManager::empty();
+    level.add(BlockLayout::profiled(&original, &coverage));
+    equiv::verify(&original, 5, &level).unwrap();
+}
@@ -0,0 +1,42 @@
+use zyde::instruction::Instruction;
+use zyde::profile::{Profiler, Unit};
+use zyde::program::Program;
+use zyde::vm::VM;
+
+#[test]
+fn test_profiler_charges_call_stacks() {
+    // main calls outer twice, and outer calls inner once per call
+    let mut program = Program::new(vec![
+        Instruction::Call { addr: 3 },
+        Instruction::Call { addr: 3 },
+        Instruction::Halt,
+        Instruction::Call { addr: 5 },
+        Instruction::Return,
+        Instruction::Print { src: 0 },
+        Instruction::Return,
+    ]);
+    program.label("main", 0).unwrap();
+    program.label("outer", 3).unwrap();
+    program.label("inner", 5).unwrap();
+
+    let mut vm = VM::new(program, 1);
+    vm.add_hook(Profiler::new());
+    vm.run().unwrap();
+    let profiler = vm.take_hook::<Profiler>().unwrap();
+
+    assert_eq!(
+        profiler.to_collapsed(Unit::Instructions),
+        "main 3\nmain;outer 4\nmain;outer;inner 4\n"
+    );
+    assert_eq!(profiler.inclusive("outer").instructions, 8);
+    assert_eq!(profiler.inclusive("main").instructions, 11);
+
+    let speedscope = profiler.to_speedscope("calls", Unit::Instructions);
+    assert!(
+        speedscope
+            .contains("\"frames\":[{\"name\":\"main\"},{\"name\":\"outer\"},{\"name\":\"inner\"}]")
+    );
+    assert!(
+        speedscope.contains("\"endValue\":11,\"samples\":[[0],[0,1],[0,1,2]],\"weights\":[3,4,4]")
+    );
+}
@@ -0,0 +1,524 @@
+use zyde::asm_reg;
+use zyde::instruction::Instruction;
+use zyde::program::{Program, ProgramError};
+use zyde::vm::{VM, VmError};
+
+/// init stores 0 into "count", update adds its argument, shutdown loads the total
+fn counter_program() -> Program {
+    let mut program = Program::new(vec![
+        Instruction::Halt,
+        // init
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "count".to_string(),
+        },
+        Instruction::Return,
+        // update(delta)
+        Instruction::Load {
+            dest: 1,
+            var: "count".to_string(),
+        },
+        Instruction::Add {
+            dest: 1,
+            src1: 1,
+            src2: 0,
+        },
+        Instruction::Store {
+            src: 1,
+            var: "count".to_string(),
+        },
+        Instruction::Return,
+        // shutdown
+        Instruction::Load {
+            dest: 2,
+            var: "count".to_string(),
+        },
+        Instruction::Halt,
+    ]);
+    program.export("init", 1, 0).unwrap();
+    program.export("update", 4, 1).unwrap();
+    program.export("shutdown", 8, 0).unwrap();
+    program
+}
+
+#[test]
+fn test_exports_catalog() {
+    let program = counter_program();
+    let names: Vec<_> = program.exports().iter().map(|e| e.name.as_str()).collect();
+
+    assert_eq!(names, vec!["init", "update", "shutdown"]);
+    assert_eq!(program.find_export("update").unwrap().arity, 1);
+    assert!(program.find_export("missing").is_none());
+}
+
+#[test]
+fn test_export_validation() {
+    let mut program = counter_program();
+
+    assert_eq!(
+        program.export("init", 2, 0),
+        Err(ProgramError::DuplicateExport("init".to_string()))
+    );
+    assert!(matches!(
+        program.export("far", 100, 0),
+        Err(ProgramError::ExportOutOfBounds { addr: 100, .. })
+    ));
+}
+
+#[test]
+fn test_call_exports_over_time() {
+    let mut vm = VM::new(counter_program(), 4);
+
+    vm.call_export("init", &[]).unwrap();
+    vm.call_export("update", &[5.0]).unwrap();
+    vm.call_export("update", &[10.0]).unwrap();
+    vm.call_export("shutdown", &[]).unwrap();
+
+    assert_eq!(vm.registers[2], 15.0);
+    assert_eq!(vm.variables.get("count"), Some(&15.0));
+}
+
+#[test]
+fn test_call_export_errors() {
+    let mut vm = VM::new(counter_program(), 4);
+
+    assert!(matches!(
+        vm.call_export("missing", &[]),
+        Err(VmError::UnknownExport(_))
+    ));
+    assert!(matches!(
+        vm.call_export("update", &[]),
+        Err(VmError::ArityMismatch {
+            expected: 1,
+            found: 0
+        })
+    ));
+}
+
+#[test]
+fn test_labels() {
+    let mut program = counter_program();
+    program.label("init", 1).unwrap();
+    assert_eq!(
+        program.label("init", 2),
+        Err(ProgramError::DuplicateLabel("init".to_string()))
+    );
+    assert!(matches!(
+        program.label("end", 100),
+        Err(ProgramError::LabelOutOfBounds { .. })
+    ));
+
+    assert_eq!(program.find_label("init").unwrap().addr, 1);
+    assert_eq!(program.symbolize(0), None);
+    assert_eq!(program.symbolize(3), Some(("init", 2)));
+    // Exports count as symbols too, and the nearest one wins
+    assert_eq!(program.symbolize(5), Some(("update", 1)));
+}
+
+#[test]
+fn test_append_relocates() {
+    let mut library = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 7.0,
+        },
+        Instruction::Jump(2),
+        Instruction::Return,
+    ]);
+    library.export("seven", 0, 0).unwrap();
+    library.label("seven_end", 2).unwrap();
+
+    let mut program = Program::new(vec![Instruction::Call { addr: 2 }, Instruction::Halt]);
+    program.append(library).unwrap();
+
+    assert_eq!(program.instructions[3], Instruction::Jump(4));
+    assert_eq!(program.find_export("seven").unwrap().addr, 2);
+    assert_eq!(program.find_label("seven_end").unwrap().addr, 4);
+
+    let mut vm = VM::new(program, 4);
+    vm.run().unwrap();
+    assert_eq!(vm.registers[0], 7.0);
+}
+
+#[test]
+fn test_splice_relocates() {
+    let mut program = Program::new(vec![
+        Instruction::Jump(3),
+        Instruction::Halt,
+        Instruction::Halt,
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Halt,
+    ]);
+    program.label("body", 3).unwrap();
+
+    // Replace the two dead halts with a three-instruction fragment
+    let fragment = Program::new(vec![
+        Instruction::Jump(2),
+        Instruction::Halt,
+        Instruction::Halt,
+    ]);
+    program.splice(1..3, fragment).unwrap();
+
+    assert_eq!(program.len(), 6);
+    assert_eq!(program.instructions[0], Instruction::Jump(4));
+    assert_eq!(program.instructions[1], Instruction::Jump(3));
+    assert_eq!(program.find_label("body").unwrap().addr, 4);
+}
+
+#[test]
+fn test_composition_is_verified() {
+    let mut program = counter_program();
+    let before = program.clone();
+
+    let mut clash = Program::new(vec![Instruction::Return]);
+    clash.export("init", 0, 0).unwrap();
+    assert_eq!(
+        program.append(clash),
+        Err(ProgramError::DuplicateExport("init".to_string()))
+    );
+
+    let dangling = Program::new(vec![Instruction::Jump(5)]);
+    assert_eq!(
+        program.append(dangling),
+        Err(ProgramError::TargetOutOfBounds {
+            addr: 10,
+            target: 15
+        })
+    );
+    assert_eq!(program, before);
+}
+
+#[test]
+fn test_instruction_display() {
+    let cases = [
+        (
+            Instruction::LoadImm {
+                dest: 0,
+                value: 1.5,
+            },
+            "loadimm r0, 1.5",
+        ),
+        (
+            Instruction::Add {
+                dest: 2,
+                src1: 0,
+                src2: 1,
+            },
+            "add r2, r0, r1",
+        ),
+        (
+            Instruction::ConditionalJump { cond: 3, target: 7 },
+            "jz r3, 7",
+        ),
+        (Instruction::Call { addr: 4 }, "call 4"),
+        (
+            Instruction::Store {
+                src: 1,
+                var: "count".to_string(),
+            },
+            "store r1, count",
+        ),
+        (Instruction::Not { dest: 1, src: 0 }, "not r1, r0"),
+        (Instruction::Return, "ret"),
+        (Instruction::JumpIndirect { src: 2 }, "jmpr r2"),
+        (
+            Instruction::Switch {
+                src: 0,
+                table: vec![3, 5],
+                default: 9,
+            },
+            "switch r0, [3, 5], 9",
+        ),
+    ];
+    for (instr, text) in cases {
+        assert_eq!(instr.to_string(), text);
+    }
+}
+
+#[test]
+fn test_disassembly_listing() {
+    let mut program = counter_program();
+    program.label("main", 0).unwrap();
+    let listing = program.to_string();
+
+    assert!(listing.starts_with("main:\n   0  halt\n; export init/0\n   1  loadimm r0, 0\n"));
+    assert!(listing.contains("; export update/1\n   4  load r1, count\n"));
+    assert_eq!(
+        listing
+            .lines()
+            .filter(|l| !l.ends_with(':') && !l.starts_with(';'))
+            .count(),
+        10
+    );
+}
+
+#[test]
+fn test_verify_registers() {
+    let program = counter_program();
+    assert_eq!(program.verify_registers(3), Ok(()));
+    assert_eq!(
+        program.verify_registers(2),
+        Err(ProgramError::RegisterOutOfBounds { addr: 8, reg: 2 })
+    );
+}
+
+#[test]
+fn test_switch_targets_are_verified_and_relocated() {
+    let switch = |default| Instruction::Switch {
+        src: 0,
+        table: vec![1, 2],
+        default,
+    };
+
+    let program = Program::new(vec![switch(3), Instruction::Halt, Instruction::Halt]);
+    assert_eq!(
+        program.verify(),
+        Err(ProgramError::TargetOutOfBounds { addr: 0, target: 3 })
+    );
+
+    let mut program = Program::new(vec![Instruction::Halt]);
+    program
+        .append(Program::new(vec![
+            switch(0),
+            Instruction::Halt,
+            Instruction::Halt,
+        ]))
+        .unwrap();
+    assert_eq!(
+        program.instructions[1],
+        Instruction::Switch {
+            src: 0,
+            table: vec![2, 3],
+            default: 1,
+        }
+    );
+}
+
+#[test]
+fn test_loaded_addresses_are_verified_and_relocated() {
+    let program = Program::new(vec![Instruction::LoadAddr { dest: 0, addr: 1 }]);
+    assert_eq!(
+        program.verify(),
+        Err(ProgramError::TargetOutOfBounds { addr: 0, target: 1 })
+    );
+
+    let mut program = Program::new(vec![Instruction::Halt]);
+    program
+        .append(Program::new(vec![
+            Instruction::LoadAddr { dest: 0, addr: 1 },
+            Instruction::Return,
+        ]))
+        .unwrap();
+    assert_eq!(
+        program.instructions[1],
+        Instruction::LoadAddr { dest: 0, addr: 2 }
+    );
+    assert_eq!(program.instructions[1].to_string(), "lea r0, 2");
+    assert_eq!(Instruction::CallIndirect { src: 3 }.to_string(), "callr r3");
+}
+
+#[test]
+fn test_closure_addresses_are_relocated() {
+    let mut program = Program::new(vec![Instruction::Halt]);
+    program
+        .append(Program::new(vec![
+            Instruction::MakeClosure {
+                dest: 0,
+                addr: 1,
+                captures: vec![1, 2],
+            },
+            Instruction::Return,
+        ]))
+        .unwrap();
+    assert_eq!(
+        program.instructions[1].to_string(),
+        "closure r0, 2, [r1, r2]"
+    );
+    assert_eq!(program.entry_points(), vec![0, 2]);
+}
+
+#[test]
+fn test_verify_checks_fields_of_known_records() {
+    let get = |index| Instruction::GetField {
+        dest: 1,
+        record: 0,
+        index,
+    };
+    let known = Program::new(vec![
+        Instruction::NewRecord { dest: 0, fields: 2 },
+        get(1),
+        get(2),
+    ]);
+    assert_eq!(
+        known.verify(),
+        Err(ProgramError::FieldOutOfBounds {
+            addr: 2,
+            index: 2,
+            fields: 2
+        })
+    );
+    assert_eq!(known.instructions[2].to_string(), "getfield r1, r0, 2");
+
+    // The shape is forgotten across a jump target and once r0 is overwritten
+    let unknown = Program::new(vec![
+        Instruction::NewRecord { dest: 0, fields: 2 },
+        Instruction::Jump(2),
+        get(5),
+        Instruction::NewRecord { dest: 0, fields: 2 },
+        Instruction::Mov { dest: 0, src: 1 },
+        get(5),
+    ]);
+    assert_eq!(unknown.verify(), Ok(()));
+}
+
+#[test]
+fn test_entry_point() {
+    // A function defined ahead of the code that calls it
+    let mut program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 7.0,
+        },
+        Instruction::Return,
+        Instruction::Call { addr: 0 },
+        Instruction::Halt,
+    ]);
+    program.label("seven", 0).unwrap();
+    program.label("main", 2).unwrap();
+    assert_eq!(
+        program.set_entry("start"),
+        Err(ProgramError::UnknownSymbol("start".to_string()))
+    );
+    assert_eq!(
+        program.set_entry_addr(4),
+        Err(ProgramError::EntryOutOfBounds(4))
+    );
+    program.set_entry("main").unwrap();
+    assert_eq!(program.entry(), 2);
+    assert!(
+        program
+            .to_string()
+            .contains("main:\n; entry\n   2  call 0\n")
+    );
+
+    let decoded = Program::from_bytecode(&program.to_bytecode().unwrap()).unwrap();
+    assert_eq!(decoded.entry(), 2);
+
+    let mut vm = VM::new(decoded, 1);
+    assert_eq!(vm.pc, 2);
+    vm.run().unwrap();
+    assert_eq!(vm.registers[0], 7.0);
+    assert_eq!(
+        zyde::aot::to_rust(&program, 1, "f"),
+        Err(ProgramError::UnsupportedEntry(2))
+    );
+}
+
+#[test]
+fn test_relative_jumps() {
+    let mut program = Program::new(vec![
+        Instruction::CallRel(3),
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Halt,
+        Instruction::LoadImm {
+            dest: 0,
+            value: 7.0,
+        },
+        Instruction::JumpRel(-3),
+    ]);
+    assert!(program.to_string().contains("   0  rcall +3  ; 3\n"));
+    assert!(program.to_string().contains("   4  rjmp -3  ; 1\n"));
+
+    // Code placed in front moves the targets without rewriting them
+    let prologue = Program::new(vec![Instruction::LoadImm {
+        dest: 2,
+        value: 2.0,
+    }]);
+    program.splice(0..0, prologue).unwrap();
+    assert_eq!(program.instructions[1], Instruction::CallRel(3));
+    assert_eq!(program.instructions[5], Instruction::JumpRel(-3));
+
+    let decoded = Program::from_bytecode(&program.to_bytecode().unwrap()).unwrap();
+    assert_eq!(decoded, program);
+    let mut vm = VM::new(decoded, 3);
+    vm.run().unwrap();
+    assert_eq!(vm.registers, vec![7.0, 1.0, 2.0]);
+
+    let mut absolute = program.clone();
+    absolute.make_absolute();
+    assert_eq!(absolute.instructions[1], Instruction::Call { addr: 4 });
+    assert_eq!(absolute.instructions[5], Instruction::Jump(2));
+    absolute.make_relative();
+    assert_eq!(absolute, program);
+
+    assert_eq!(
+        Program::new(vec![Instruction::JumpRel(1)]).verify(),
+        Err(ProgramError::TargetOutOfBounds { addr: 0, target: 1 })
+    );
+}
+
+#[test]
+fn test_handler_depths() {
+    let program = asm_reg::assemble(
+        "
+            try r0, caught
+            call helper
+            endtry
+            halt
+        caught:
+            print r0
+            halt
+        helper:
+            try r1, done
+            throw r1
+        done:
+            ret
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        program.handler_depths().unwrap(),
+        vec![
+            Some(0),
+            Some(1),
+            Some(1),
+            Some(0),
+            Some(0),
+            Some(0),
+            Some(0),
+            Some(1),
+            Some(0),
+        ]
+    );
+
+    let error = |source: &str| {
+        asm_reg::assemble(source)
+            .unwrap()
+            .verify_handlers()
+            .unwrap_err()
+    };
+    // A function cannot remove its caller's handler
+    assert_eq!(
+        error("try r0, 3\ncall 4\nendtry\nhalt\nendtry\nret"),
+        ProgramError::HandlerUnderflow(4)
+    );
+    // Only one branch installs a handler
+    assert_eq!(
+        error("jz r0, 2\ntry r1, 3\nhalt\nhalt"),
+        ProgramError::HandlerMismatch {
+            addr: 2,
+            expected: 1,
+            found: 0,
+        }
+    );
+}
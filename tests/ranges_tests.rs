@@ -0,0 +1,134 @@
+use zyde::asm_reg::assemble;
+use zyde::equiv;
+use zyde::instruction::Instruction;
+use zyde::passes::range_fold::RangeFold;
+use zyde::passes::{OptLevel, PassManager};
+use zyde::program::ProgramError;
+use zyde::ranges::{Range, analyze};
+
+/// Prints 10, 20 and 30 through a jump table indexed by a loop counter,
+/// checking the index against 10 before each lookup
+const TABLE_LOOP: &str = "
+        loadimm r0, 0
+        loadimm r1, 3
+        loadimm r5, 10
+    top:
+        ltjz r2, r0, r1, done
+        ltjz r4, r0, r5, done
+        switch r0, [a, b, c], done
+    a:
+        loadimm r3, 10
+        rjmp next
+    b:
+        loadimm r3, 20
+        rjmp next
+    c:
+        loadimm r3, 30
+    next:
+        print r3
+        addimm r0, r0, r6, 1
+        rjmp top
+    done:
+        halt
+";
+
+#[test]
+fn test_loop_counter_is_bounded() {
+    let program = assemble(TABLE_LOOP).unwrap();
+    let ranges = analyze(&program);
+    let index = Range {
+        lo: 0.0,
+        hi: 2.0,
+        integral: true,
+    };
+    assert_eq!(ranges.get(5, 0), Some(index));
+    assert!(ranges.in_bounds(&program, 5));
+    assert_eq!(ranges.get(9, 3), Range::point(20.0));
+    // The counter reaches 3 before the exit test stops it
+    assert_eq!(ranges.get(3, 0).map(|r| r.hi), Some(3.0));
+}
+
+#[test]
+fn test_widened_loop_is_narrowed_by_its_exit_test() {
+    let program = assemble(
+        "loadimm r0, 0\nloadimm r1, 99\ntop:\naddimm r0, r0, r2, 1\ngtjz r3, r0, r1, top\nhalt",
+    )
+    .unwrap();
+    let counter = analyze(&program).get(3, 0).unwrap();
+    assert_eq!((counter.lo, counter.hi), (1.0, 100.0));
+}
+
+#[test]
+fn test_unknown_values_are_not_bounded() {
+    let program = assemble("load r0, i\nswitch r0, [1, 1], 2\nhalt").unwrap();
+    let ranges = analyze(&program);
+    assert_eq!(ranges.get(1, 0), None);
+    assert!(!ranges.in_bounds(&program, 1));
+    // A loop with no known bound widens to an open one
+    let program = assemble(
+        "loadimm r0, 0\nload r1, n\nltjz r2, r0, r1, 5\naddimm r0, r0, r3, 1\nrjmp -2\nhalt",
+    )
+    .unwrap();
+    let counter = analyze(&program).get(2, 0).unwrap();
+    assert_eq!((counter.lo, counter.hi), (0.0, f64::INFINITY));
+    // Dividing may make NaN
+    let program = assemble("loadimm r0, 1\ndiv r1, r0, r0\nhalt").unwrap();
+    assert_eq!(analyze(&program).get(2, 1), None);
+}
+
+#[test]
+fn test_verify_ranges() {
+    let error = |source: &str| assemble(source).unwrap().verify_ranges();
+    assert_eq!(
+        error("loadimm r0, 5\nloadimm r1, 3\nadd r0, r0, r1\njmpr r0\nhalt"),
+        Err(ProgramError::AddressOutOfBounds { addr: 3, reg: 0 })
+    );
+    assert_eq!(
+        error("loadimm r0, 1.5\ncallr r0\nhalt"),
+        Err(ProgramError::AddressOutOfBounds { addr: 1, reg: 0 })
+    );
+    assert_eq!(error("lea r0, end\njmpr r0\nend:\nhalt"), Ok(()));
+    assert_eq!(error("load r0, f\njmpr r0\nhalt"), Ok(()));
+}
+
+#[test]
+fn test_range_fold_drops_proven_checks() {
+    let mut program = assemble(TABLE_LOOP).unwrap();
+    PassManager::empty().add(RangeFold).run(&mut program);
+    // The index is always below 10, and the switch is left alone
+    assert_eq!(
+        program.instructions[4],
+        Instruction::LoadImm {
+            dest: 4,
+            value: 1.0,
+        }
+    );
+    assert!(matches!(
+        program.instructions[3],
+        Instruction::CompareJump { .. }
+    ));
+    assert!(matches!(
+        program.instructions[5],
+        Instruction::Switch { .. }
+    ));
+
+    let mut program = assemble(
+        "
+            lea r0, f
+            callr r0
+            loadimm r1, 1
+            switch r1, [2, 4, 4], 0
+            halt
+        f:
+            ret
+        ",
+    )
+    .unwrap();
+    PassManager::empty().add(RangeFold).run(&mut program);
+    assert_eq!(program.instructions[1], Instruction::Call { addr: 5 });
+    assert_eq!(program.instructions[3], Instruction::Jump(4));
+
+    let program = assemble(TABLE_LOOP).unwrap();
+    let passes = PassManager::with_level(OptLevel::O2);
+    equiv::verify(&program, 7, &passes).unwrap();
+}
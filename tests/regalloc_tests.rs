@@ -0,0 +1,125 @@
+use zyde::asm_reg::assemble;
+use zyde::instruction::Instruction;
+use zyde::lang::{Compiled, compile};
+use zyde::regalloc::{AllocError, Home, allocate, liveness};
+use zyde::vm::VM;
+
+/// Run a compiled script, returning what it printed and the VM
+fn run(compiled: &Compiled) -> (Vec<f64>, VM) {
+    let mut vm = VM::new(compiled.program.clone(), compiled.registers);
+    vm.capture_output();
+    vm.run().unwrap();
+    (vm.take_output(), vm)
+}
+
+#[test]
+fn test_liveness() {
+    let program = assemble(
+        "
+            loadimm r1, 1
+            add r2, r0, r1
+            loadimm r0, 5
+            print r2
+            print r0
+        ",
+    )
+    .unwrap();
+    let live = liveness(&program, &[]);
+    assert_eq!(live.live_at(0), vec![0]);
+    assert_eq!(live.live_at(1), vec![0, 1]);
+    assert_eq!(live.live_at(2), vec![2]);
+    assert_eq!(live.live_at(4), vec![0]);
+    assert!(!live.is_live(4, 2));
+    assert_eq!(liveness(&program, &[1]).live_at(4), vec![0, 1]);
+}
+
+#[test]
+fn test_long_expressions_fit_few_registers() {
+    let source = "
+        let a = 2;
+        let b = 3;
+        print ((a + 1) * (b + 2) - (a * b + 4)) * ((a - b) * (b - 7) + (a + b) * (a + 1));
+        print a + (b + (a + (b + (a + (b + (a + b))))));
+    ";
+    let compiled = compile(source).unwrap();
+    assert!(compiled.registers > 4);
+    let (expected, _) = run(&compiled);
+
+    let fitted = compiled.fit(4).unwrap();
+    assert!(fitted.registers <= 4);
+    assert!(
+        fitted
+            .program
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::Store { .. }))
+    );
+    assert_eq!(run(&fitted).0, expected);
+    // Without spilling, the same registers are reused
+    let packed = compiled.fit(compiled.registers).unwrap();
+    assert!(packed.registers < compiled.registers);
+    assert_eq!(run(&packed).0, expected);
+}
+
+#[test]
+fn test_variables_keep_their_values() {
+    let compiled = compile(
+        "
+        let a = 0;
+        let b = 1;
+        let n = 10;
+        while n > 0 {
+            let next = a + b;
+            a = b;
+            b = next;
+            n = n - 1;
+        }
+    ",
+    )
+    .unwrap();
+    let fitted = compiled.fit(3).unwrap();
+    let (_, vm) = run(&fitted);
+    let value = |name: &str| match fitted.variables.get(name) {
+        Some(&reg) => vm.registers[reg],
+        None => vm.variables[&fitted.spilled[name]],
+    };
+    assert_eq!(value("a"), 55.0);
+    assert_eq!(value("b"), 89.0);
+    assert_eq!(value("n"), 0.0);
+}
+
+#[test]
+fn test_inputs_keep_their_registers() {
+    let program = assemble("loadimm r5, 2\nmul r7, r3, r5\nprint r7\nhalt").unwrap();
+    let allocation = allocate(&program, 4, &[]).unwrap();
+    assert_eq!(allocation.homes[3], Some(Home::Register(3)));
+    assert_eq!(allocation.homes[1], None);
+    assert_eq!(allocation.registers, 4);
+
+    let mut vm = VM::new(allocation.program, allocation.registers);
+    vm.registers[3] = 21.0;
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.take_output(), vec![42.0]);
+}
+
+#[test]
+fn test_errors() {
+    let program = assemble("loadimm r0, 1\ncall f\nhalt\nf:\nret").unwrap();
+    assert_eq!(
+        allocate(&program, 8, &[]),
+        Err(AllocError::Unsupported {
+            addr: 1,
+            mnemonic: "call",
+        })
+    );
+    let program = assemble("loadimm r0, 1\nloadimm r1, 2\nadd r2, r0, r1\nprint r2").unwrap();
+    assert_eq!(
+        allocate(&program, 2, &[]),
+        Err(AllocError::TooFewRegisters(2))
+    );
+    assert_eq!(
+        AllocError::TooFewRegisters(2).to_string(),
+        "The program does not fit in 2 registers"
+    );
+}
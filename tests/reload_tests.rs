@@ -0,0 +1,79 @@
+use zyde::instruction::Instruction;
+use zyde::program::{Program, ProgramError};
+use zyde::reload::{AddressMap, ReloadError};
+use zyde::vm::VM;
+
+/// Adds `step` to r0 forever, storing the total in `x`
+fn counter(step: f64, padding: usize) -> Program {
+    let mut code = vec![Instruction::LoadImm {
+        dest: 0,
+        value: 0.0,
+    }];
+    code.extend((0..padding).map(|_| Instruction::Mov { dest: 2, src: 2 }));
+    let top = code.len();
+    code.extend([
+        Instruction::LoadImm {
+            dest: 1,
+            value: step,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Jump(top),
+    ]);
+    let mut program = Program::new(code);
+    program.label("start", 0).unwrap();
+    program.label("loop", top).unwrap();
+    program
+}
+
+#[test]
+fn test_replace_program_remaps_the_pc() {
+    let old = counter(1.0, 0);
+    let mut vm = VM::new(old.clone(), 3);
+    for _ in 0..7 {
+        vm.step().unwrap();
+    }
+    assert_eq!((vm.pc, vm.registers[0]), (3, 2.0));
+
+    let new = counter(10.0, 1);
+    vm.replace_program(new.clone(), AddressMap::by_symbol(&old, &new))
+        .unwrap();
+    assert_eq!(vm.pc, 4);
+    for _ in 0..5 {
+        vm.step().unwrap();
+    }
+    assert_eq!(vm.variables["x"], 12.0);
+}
+
+#[test]
+fn test_replace_program_failures_leave_the_vm_unchanged() {
+    let old = counter(1.0, 0);
+    let mut vm = VM::new(old.clone(), 3);
+    vm.step().unwrap();
+
+    assert_eq!(
+        vm.replace_program(counter(10.0, 0), AddressMap::new()),
+        Err(ReloadError::Unmapped(1))
+    );
+    let mut remap = AddressMap::new();
+    remap.insert(1, 9);
+    assert_eq!(
+        vm.replace_program(counter(10.0, 0), remap),
+        Err(ReloadError::Unmapped(1))
+    );
+    assert_eq!(
+        vm.replace_program(Program::new(vec![Instruction::Jump(3)]), AddressMap::new()),
+        Err(ReloadError::Program(ProgramError::TargetOutOfBounds {
+            addr: 0,
+            target: 3
+        }))
+    );
+    assert_eq!((vm.pc, &*vm.program), (1, &old));
+}
@@ -0,0 +1,89 @@
+use zyde::host::{Exit, HostCall};
+use zyde::instruction::Instruction;
+use zyde::replay::Input;
+use zyde::vm::{VM, VmError};
+
+/// Reads a clock, asks the host for a value, draws a random number and
+/// takes a message, adding them all up
+fn program() -> Vec<Instruction> {
+    vec![
+        Instruction::CallHost {
+            dest: 0,
+            name: "clock".to_string(),
+            args: vec![],
+        },
+        Instruction::CallHost {
+            dest: 1,
+            name: "fetch".to_string(),
+            args: vec![0],
+        },
+        Instruction::Rand { dest: 2 },
+        Instruction::Recv { dest: 3 },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 2,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 3,
+        },
+        Instruction::Halt,
+    ]
+}
+
+#[test]
+fn test_replay_reproduces_recorded_run() {
+    let mut vm = VM::new(program(), 4);
+    vm.register_host_function("clock", |_| Ok(1234.5));
+    vm.start_recording();
+    loop {
+        match vm.poll() {
+            Ok(Exit::Halted) => break,
+            Ok(Exit::HostCall(call)) => vm.complete_host_call(call.args[0] * 2.0).unwrap(),
+            Err(VmError::Blocked) => vm.mailbox.inbox.push_back(7.0),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let log = vm.take_recording().unwrap();
+    assert_eq!(
+        log.inputs,
+        vec![
+            Input::HostCall {
+                call: HostCall {
+                    name: "clock".to_string(),
+                    args: vec![],
+                },
+                result: Ok(1234.5),
+            },
+            Input::HostCall {
+                call: HostCall {
+                    name: "fetch".to_string(),
+                    args: vec![1234.5],
+                },
+                result: Ok(2469.0),
+            },
+            Input::Message(7.0),
+        ]
+    );
+
+    // No host functions and an empty inbox: every input comes from the log
+    let mut replayed = VM::new(program(), 4);
+    replayed.replay(&log).unwrap();
+    assert_eq!(replayed.snapshot(), vm.snapshot());
+
+    // A different program asks for inputs in another order
+    let mut swapped = program();
+    swapped.swap(0, 3);
+    let mut diverged = VM::new(swapped, 4);
+    assert!(matches!(
+        diverged.replay(&log),
+        Err(VmError::ReplayDiverged(_))
+    ));
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use zyde::instruction::Instruction;
+use zyde::sandbox::SandboxPolicy;
+use zyde::stdlib;
+use zyde::vm::{VM, VmConfig, VmError};
+
+fn sandboxed(program: Vec<Instruction>, registers: usize, sandbox: SandboxPolicy) -> VM {
+    let mut vm = VM::with_config(
+        program,
+        registers,
+        VmConfig {
+            sandbox,
+            ..VmConfig::default()
+        },
+    );
+    stdlib::install(&mut vm, true);
+    vm
+}
+
+fn denied(result: Result<(), VmError>, what: &str) -> bool {
+    matches!(result, Err(VmError::PermissionDenied(w)) if w == what)
+}
+
+#[test]
+fn test_sandbox_denies_io_and_host_functions() {
+    let program = || {
+        vec![
+            Instruction::TryBegin {
+                handler: 4,
+                dest: 0,
+            },
+            Instruction::Print { src: 0 },
+            Instruction::TryEnd,
+            Instruction::Halt,
+            Instruction::Halt,
+        ]
+    };
+    // Denials bypass the program's handlers
+    let mut vm = sandboxed(program(), 1, SandboxPolicy::deny_all());
+    assert!(denied(vm.run(), "stdout"));
+    assert_eq!(vm.handlers.len(), 1);
+
+    let call = |name: &str| {
+        vec![
+            Instruction::CallHost {
+                dest: 0,
+                name: name.to_string(),
+                args: vec![],
+            },
+            Instruction::Halt,
+        ]
+    };
+    let policy = SandboxPolicy {
+        stdin: false,
+        host_functions: Some(HashSet::from([
+            "random".to_string(),
+            "read_line".to_string(),
+        ])),
+        ..SandboxPolicy::default()
+    };
+    assert!(sandboxed(call("random"), 1, policy.clone()).run().is_ok());
+    assert!(denied(
+        sandboxed(call("time_ms"), 1, policy.clone()).run(),
+        "host function 'time_ms'"
+    ));
+    assert!(denied(
+        sandboxed(call("read_line"), 1, policy).run(),
+        "stdin"
+    ));
+    assert!(SandboxPolicy::default().is_unrestricted());
+}
+
+#[test]
+fn test_sandbox_caps_heap_and_call_depth() {
+    // Allocates records forever, keeping only the latest
+    let program = vec![
+        Instruction::NewRecord { dest: 0, fields: 1 },
+        Instruction::Jump(0),
+    ];
+    let policy = SandboxPolicy {
+        max_heap_objects: Some(4),
+        ..SandboxPolicy::default()
+    };
+    let mut vm = sandboxed(program, 1, policy);
+    vm.config.max_steps = Some(100);
+    // Garbage is collected before an allocation is refused
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+
+    let program = vec![Instruction::Call { addr: 0 }];
+    let policy = SandboxPolicy {
+        max_call_depth: Some(8),
+        ..SandboxPolicy::default()
+    };
+    let mut vm = sandboxed(program, 1, policy);
+    assert!(denied(vm.run(), "more than 8 call frames"));
+    assert_eq!(vm.call_stack.len(), 8);
+
+    let program = vec![
+        Instruction::NewRecord { dest: 0, fields: 1 },
+        Instruction::Store {
+            src: 0,
+            var: "kept".to_string(),
+        },
+        Instruction::NewRecord { dest: 0, fields: 1 },
+        Instruction::Halt,
+    ];
+    let policy = SandboxPolicy {
+        max_heap_objects: Some(1),
+        ..SandboxPolicy::default()
+    };
+    let mut vm = sandboxed(program, 1, policy);
+    assert!(denied(vm.run(), "more than 1 heap objects"));
+}
@@ -0,0 +1,81 @@
+use zyde::instruction::Instruction;
+use zyde::scheduler::{Scheduler, SchedulerError, TaskState};
+use zyde::vm::{VM, VmError};
+
+/// Counts r0 down from `n`, three instructions per iteration
+fn countdown(n: f64) -> VM {
+    let program = vec![
+        Instruction::LoadImm { dest: 0, value: n },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Sub {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 5 },
+        Instruction::Jump(2),
+        Instruction::Halt,
+    ];
+    VM::new(program, 2)
+}
+
+#[test]
+fn test_scheduler_interleaves_with_budget() {
+    let mut scheduler = Scheduler::new(10);
+    let short = scheduler.spawn(countdown(2.0));
+    let long = scheduler.spawn(countdown(100.0));
+    let forever = scheduler.spawn(VM::new(vec![Instruction::Jump(0)], 1));
+
+    assert_eq!(scheduler.tick(), 3);
+    for id in [long, forever] {
+        let stats = scheduler.stats(id).unwrap();
+        assert_eq!((stats.steps, stats.slices, stats.preempted), (10, 1, 1));
+    }
+    assert_eq!(scheduler.stats(short).unwrap().steps, 8);
+    assert!(matches!(scheduler.state(short), Some(TaskState::Halted)));
+    assert!(scheduler.fairness() < 1.0);
+
+    // Paused tasks keep their place and fall behind
+    scheduler.pause(long).unwrap();
+    scheduler.tick();
+    assert_eq!(scheduler.stats(long).unwrap().steps, 10);
+    assert_eq!(scheduler.stats(forever).unwrap().steps, 20);
+    scheduler.resume(long).unwrap();
+
+    scheduler.kill(forever).unwrap();
+    assert!(matches!(
+        scheduler.pause(forever),
+        Err(SchedulerError::Finished(id)) if id == forever
+    ));
+    assert!(matches!(
+        scheduler.kill(7),
+        Err(SchedulerError::UnknownTask(7))
+    ));
+
+    scheduler.run();
+    assert!(scheduler.vm(long).unwrap().is_halted());
+    assert_eq!(scheduler.stats(long).unwrap().steps, 302);
+    assert_eq!(scheduler.stats(forever).unwrap().steps, 20);
+}
+
+#[test]
+fn test_scheduler_records_failures_and_fairness() {
+    let mut scheduler = Scheduler::new(4);
+    let a = scheduler.spawn(countdown(10.0));
+    let b = scheduler.spawn(countdown(10.0));
+    scheduler.tick();
+    assert_eq!(scheduler.fairness(), 1.0);
+
+    let failing = scheduler.spawn(VM::new(vec![Instruction::Return], 1));
+    scheduler.run();
+    assert!(matches!(
+        scheduler.state(failing),
+        Some(TaskState::Failed(VmError::CallStackEmpty))
+    ));
+    for id in [a, b] {
+        assert!(matches!(scheduler.state(id), Some(TaskState::Halted)));
+    }
+}
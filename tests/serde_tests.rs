@@ -0,0 +1,60 @@
+#![cfg(feature = "serde")]
+
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+use zyde::vm::{VM, VmSnapshot};
+
+#[test]
+fn test_instruction_round_trip() {
+    let instr = Instruction::Store {
+        src: 1,
+        var: "x".to_string(),
+    };
+
+    let json = serde_json::to_string(&instr).unwrap();
+    let back: Instruction = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(back, instr);
+}
+
+#[test]
+fn test_program_round_trip() {
+    let mut program = Program::new(vec![Instruction::Jump(1), Instruction::Return]);
+    program.export("f", 1, 2).unwrap();
+
+    let json = serde_json::to_string(&program).unwrap();
+    let back: Program = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(back, program);
+    assert_eq!(back.find_export("f").unwrap().arity, 2);
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Call { addr: 3 },
+        Instruction::Halt,
+    ];
+
+    let mut vm = VM::new(program, 2);
+    for _ in 0..3 {
+        vm.step().unwrap();
+    }
+    let snapshot = vm.snapshot();
+
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let back: VmSnapshot = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(back, snapshot);
+    assert_eq!(back.pc, 3);
+    assert_eq!(back.call_stack.len(), 1);
+    assert_eq!(back.variables.get("x"), Some(&3.0));
+}
@@ -0,0 +1,45 @@
+use std::thread;
+use zyde::instruction::Instruction;
+use zyde::program::{Program, SharedProgram};
+use zyde::vm::{CancellationToken, VM, VmSnapshot};
+use zyde::workloads::{self, REGISTERS};
+
+fn assert_send<T: Send>() {}
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_thread_safety_contract() {
+    assert_send::<VM>();
+    assert_send_sync::<Program>();
+    assert_send_sync::<SharedProgram>();
+    assert_send_sync::<VmSnapshot>();
+    assert_send_sync::<CancellationToken>();
+}
+
+#[test]
+fn test_vms_share_one_program_across_threads() {
+    let program = SharedProgram::new(workloads::fib(30));
+    let vms: Vec<VM> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let program = program.clone();
+                scope.spawn(move || {
+                    let mut vm = VM::new(program, REGISTERS);
+                    vm.run().unwrap();
+                    vm
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+    for vm in &vms {
+        assert_eq!(vm.registers[0], 832040.0);
+        assert!(SharedProgram::ptr_eq(&vm.program, &program));
+    }
+
+    // Changing one VM's program gives it its own copy
+    let mut vm = vms.into_iter().next().unwrap();
+    vm.program.instructions.push(Instruction::Halt);
+    assert!(!SharedProgram::ptr_eq(&vm.program, &program));
+    assert_eq!(program.len() + 1, vm.program.len());
+}
@@ -0,0 +1,199 @@
+use zyde::hook::{Hook, HookAction};
+use zyde::instruction::Instruction;
+use zyde::machine::Machine;
+use zyde::program::Program;
+use zyde::stack_vm::{StackInstruction, StackVM};
+use zyde::vm::{VM, VmConfig, VmError};
+
+use StackInstruction::*;
+
+/// Sum of 1..=n, printed
+fn sum_to<T: zyde::stack_vm::Number>(n: T) -> Vec<StackInstruction<T>> {
+    vec![
+        Push(n),
+        Store("n".to_string()),
+        Push(T::ZERO),
+        Store("sum".to_string()),
+        // loop: 4
+        Load("n".to_string()),
+        JumpIfZero(15),
+        Load("sum".to_string()),
+        Load("n".to_string()),
+        Add,
+        Store("sum".to_string()),
+        Load("n".to_string()),
+        Push(T::ONE),
+        Sub,
+        Store("n".to_string()),
+        Jump(4),
+        // done: 15
+        Load("sum".to_string()),
+        Print,
+    ]
+}
+
+/// Counts the instructions it sees, for any machine
+#[derive(Default)]
+struct Counter {
+    before: usize,
+    after: usize,
+}
+
+impl<M: Machine> Hook<M> for Counter {
+    fn before(&mut self, _: usize, _: &M::Instruction) -> HookAction {
+        self.before += 1;
+        HookAction::Continue
+    }
+
+    fn after(&mut self, _: usize, _: &M) -> HookAction {
+        self.after += 1;
+        HookAction::Continue
+    }
+}
+
+#[test]
+fn test_stack_vm_runs_over_floats_and_integers() {
+    let mut vm = StackVM::new(sum_to(10.0));
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.take_output(), vec![55.0]);
+    assert!(vm.stack.is_empty());
+
+    let mut vm = StackVM::new(sum_to(100i64));
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.take_output(), vec![5050.0]);
+    assert_eq!(vm.variables["sum"], 5050);
+
+    // Integers divide exactly, and wrap rather than overflow
+    let mut vm = StackVM::new(vec![Push(7i32), Push(2), Div, Push(i32::MAX), Push(1), Add]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, vec![3, i32::MIN]);
+}
+
+#[test]
+fn test_stack_vm_calls_and_compares() {
+    let program = vec![
+        Push(3.0),
+        Call(4),
+        Print,
+        Halt,
+        // square: 4
+        Dup,
+        Mul,
+        Return,
+    ];
+    let mut vm = StackVM::new(program);
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.take_output(), vec![9.0]);
+
+    let mut vm = StackVM::new(vec![
+        Push(1.0),
+        Push(2.0),
+        Swap,
+        LessThan,
+        Push(2.0),
+        Push(2.0),
+        Equal,
+        Not,
+    ]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, vec![0.0, 0.0]);
+}
+
+#[test]
+fn test_stack_vm_errors() {
+    let mut vm = StackVM::new(vec![Push(1.0), Add]);
+    assert!(matches!(vm.run(), Err(VmError::StackUnderflow)));
+    // Nothing is taken from a stack too short for the instruction
+    assert_eq!(vm.stack, vec![1.0]);
+
+    let mut vm = StackVM::new(vec![Push(1i64), Push(0), Div]);
+    assert!(matches!(vm.run(), Err(VmError::DivisionByZero)));
+    assert_eq!(vm.stack, vec![1, 0]);
+    // Floats divide by zero as IEEE 754 does
+    let mut vm = StackVM::new(vec![Push(1.0), Push(0.0), Div]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, vec![f64::INFINITY]);
+
+    let mut vm = StackVM::<f64>::new(vec![Return]);
+    assert!(matches!(vm.run(), Err(VmError::CallStackEmpty)));
+    let mut vm = StackVM::<f64>::new(vec![Load("x".to_string())]);
+    assert!(matches!(vm.run(), Err(VmError::VariableNotFound(name)) if name == "x"));
+    let mut vm = StackVM::<f64>::new(vec![Jump(5)]);
+    assert!(matches!(vm.run(), Err(VmError::ProgramCounterOutOfBounds)));
+
+    assert_eq!(VmError::StackUnderflow.code(), Some(-13.0));
+    assert_eq!(VmError::DivisionByZero.code(), Some(-14.0));
+}
+
+#[test]
+fn test_stack_vm_shares_the_step_limit_and_stepping() {
+    let config = VmConfig {
+        max_steps: Some(5),
+        ..VmConfig::default()
+    };
+    let mut vm = StackVM::<f64>::with_config(vec![Jump(0)], config);
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+    assert_eq!(vm.steps, 5);
+    // The limit is per run, so the loop can be resumed
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+    assert_eq!(vm.steps, 10);
+
+    let mut vm = StackVM::new(vec![Push(2i64), Push(3), Mul]);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    assert_eq!((vm.pc, vm.stack.as_slice()), (2, &[2, 3][..]));
+    vm.step().unwrap();
+    assert!(vm.is_halted());
+    assert_eq!(vm.stack, vec![6]);
+}
+
+#[test]
+fn test_one_hook_observes_both_machines() {
+    let mut stack_vm = StackVM::new(vec![Push(1.0), Push(2.0), Add]);
+    stack_vm.add_hook(Counter::default());
+    stack_vm.run().unwrap();
+    let counter: &Counter = stack_vm.hook().unwrap();
+    assert_eq!((counter.before, counter.after), (3, 3));
+
+    let mut vm = VM::new(
+        Program::new(vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: 1.0,
+            },
+            Instruction::Halt,
+        ]),
+        1,
+    );
+    vm.add_hook(Counter::default());
+    vm.run().unwrap();
+    let counter: Counter = vm.take_hook().unwrap();
+    assert_eq!((counter.before, counter.after), (2, 2));
+}
+
+#[test]
+fn test_stack_vm_hooks_can_pause() {
+    struct PauseAt(usize);
+
+    impl Hook<StackVM<f64>> for PauseAt {
+        fn before(&mut self, pc: usize, instruction: &StackInstruction<f64>) -> HookAction {
+            if pc == self.0 {
+                assert_eq!(instruction.to_string(), "add");
+                self.0 = usize::MAX;
+                return HookAction::Pause;
+            }
+            HookAction::Continue
+        }
+    }
+
+    let mut vm = StackVM::new(vec![Push(1.0), Push(2.0), Add]);
+    vm.add_hook(PauseAt(2));
+    assert!(matches!(vm.run(), Err(VmError::Paused)));
+    assert_eq!(vm.stack, vec![1.0, 2.0]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack, vec![3.0]);
+    assert!(vm.take_hook::<PauseAt>().is_some());
+}
@@ -0,0 +1,70 @@
+use zyde::host::{Exit, HostCall};
+use zyde::instruction::Instruction;
+use zyde::stdlib;
+use zyde::vm::{VM, VmError};
+
+fn call(dest: usize, name: &str, args: Vec<usize>) -> Instruction {
+    Instruction::CallHost {
+        dest,
+        name: name.to_string(),
+        args,
+    }
+}
+
+fn load(dest: usize, value: f64) -> Instruction {
+    Instruction::LoadImm { dest, value }
+}
+
+#[test]
+fn test_stdlib_functions_run_in_place() {
+    let program = vec![
+        load(0, 2.0),
+        load(1, 10.0),
+        load(2, -1.0),
+        call(3, "pow", vec![0, 1]),
+        call(4, "clamp", vec![3, 2, 1]),
+        call(5, "max", vec![0, 2]),
+        call(6, "random", vec![]),
+        call(7, "cos", vec![2]),
+    ];
+    let mut vm = VM::new(program, 8);
+    stdlib::install(&mut vm, false);
+    vm.run().unwrap();
+    assert_eq!(vm.registers[3..6], [1024.0, 10.0, 2.0]);
+    assert!((0.0..1.0).contains(&vm.registers[6]));
+    assert_eq!(vm.registers[7], (-1.0f64).cos());
+}
+
+#[test]
+fn test_stdlib_errors_and_sandbox() {
+    // Argument errors are catchable like any other trap
+    let program = vec![
+        Instruction::TryBegin {
+            handler: 3,
+            dest: 1,
+        },
+        call(0, "sin", vec![]),
+        Instruction::Halt,
+        call(2, "read_line", vec![]),
+    ];
+    let mut vm = VM::new(program, 3);
+    stdlib::install(&mut vm, false);
+    assert!(!vm.has_host_function("read_line"));
+    assert_eq!(
+        vm.poll().unwrap(),
+        Exit::HostCall(HostCall {
+            name: "read_line".to_string(),
+            args: vec![],
+        })
+    );
+    assert_eq!(vm.registers[1], -12.0);
+
+    let mut vm = VM::new(vec![call(0, "log", vec![0, 0])], 1);
+    stdlib::install(&mut vm, true);
+    assert!(vm.has_host_function("read_line"));
+    assert!(matches!(
+        vm.run(),
+        Err(VmError::HostFunction { name, message })
+            if name == "log" && message == "expected 1 arguments, found 2"
+    ));
+}
@@ -0,0 +1,47 @@
+use zyde::testing::Generator;
+use zyde::vm::{VM, VmConfig, VmError};
+
+#[test]
+fn test_generated_programs_verify_and_terminate() {
+    let generator = Generator::default();
+    assert_eq!(generator.program(&[]).instructions.len(), 1);
+    assert_eq!(generator.seeded(7), generator.seeded(7));
+
+    for seed in 0..2000 {
+        let program = generator.seeded(seed);
+        program.verify().unwrap();
+        program.verify_registers(generator.registers).unwrap();
+
+        let config = VmConfig {
+            max_steps: Some(100_000),
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(program, generator.registers, config);
+        let result = vm.run();
+        assert!(
+            !matches!(result, Err(VmError::StepLimitExceeded)),
+            "seed {} did not terminate",
+            seed
+        );
+    }
+}
+
+#[test]
+fn test_looping_programs_stay_in_bounds() {
+    let generator = Generator {
+        registers: 4,
+        max_len: 32,
+        loops: true,
+    };
+    for seed in 0..2000 {
+        let program = generator.seeded(seed);
+        program.verify().unwrap();
+        program.verify_registers(generator.registers).unwrap();
+
+        let config = VmConfig {
+            max_steps: Some(10_000),
+            ..VmConfig::default()
+        };
+        let _ = VM::with_config(program, generator.registers, config).run();
+    }
+}
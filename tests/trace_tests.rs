@@ -0,0 +1,85 @@
+use zyde::instruction::Instruction;
+use zyde::trace::{RegisterWrite, Trace};
+use zyde::vm::{VM, VmError};
+
+#[test]
+fn test_trace_records_steps() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 10.0,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 20.0,
+        },
+        Instruction::Add {
+            dest: 2,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Store {
+            src: 2,
+            var: "sum".to_string(),
+        },
+        Instruction::Halt,
+    ];
+
+    let mut vm = VM::new(program, 4);
+    let mut trace = Trace::new();
+    vm.run_traced(&mut trace).unwrap();
+
+    assert_eq!(trace.steps.len(), 5);
+    let add = &trace.steps[2];
+    assert_eq!(add.pc, 2);
+    assert_eq!(add.opcode, "add");
+    assert_eq!(add.reads, vec![(0, 10.0), (1, 20.0)]);
+    assert_eq!(
+        add.writes,
+        vec![RegisterWrite {
+            reg: 2,
+            old: 0.0,
+            new: 30.0
+        }]
+    );
+    assert_eq!(trace.steps[3].store, Some(("sum".to_string(), 30.0)));
+    assert_eq!(trace.steps[4].next_pc, 5);
+}
+
+#[test]
+fn test_trace_records_failing_step() {
+    let program = vec![Instruction::Jump(1), Instruction::Jump(100)];
+
+    let mut vm = VM::new(program, 4);
+    let mut trace = Trace::new();
+    let result = vm.run_traced(&mut trace);
+
+    assert!(matches!(result, Err(VmError::ProgramCounterOutOfBounds)));
+    assert_eq!(trace.steps.len(), 2);
+    assert!(trace.steps[1].error.is_some());
+}
+
+#[test]
+fn test_trace_json() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.5,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "a\"b".to_string(),
+        },
+    ];
+
+    let mut vm = VM::new(program, 1);
+    let mut trace = Trace::new();
+    vm.run_traced(&mut trace).unwrap();
+    let json = trace.to_json();
+
+    assert!(json.starts_with("{\"steps\":["));
+    assert!(json.contains(
+        "{\"pc\":0,\"opcode\":\"loadimm\",\"text\":\"loadimm r0, 1.5\",\"reads\":[],\"writes\":[{\"reg\":0,\"old\":0,\"new\":1.5}],\"next_pc\":1}"
+    ));
+    assert!(json.contains("\"store\":{\"var\":\"a\\\"b\",\"value\":1.5}"));
+}
@@ -0,0 +1,91 @@
+#![cfg(feature = "tracing")]
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+use zyde::vm::VM;
+
+/// Logs each span as it opens and each event, as `name field=value ...`
+#[derive(Clone, Default)]
+struct Recorder {
+    log: Arc<Mutex<Vec<String>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push_str(&format!(" {}={:?}", field.name(), value));
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = Fields(span.metadata().name().to_string());
+        span.record(&mut fields);
+        self.log.lock().unwrap().push(fields.0);
+        Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields("event".to_string());
+        event.record(&mut fields);
+        self.log.lock().unwrap().push(fields.0);
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn test_run_emits_spans_and_trap_events() {
+    let mut program = Program::new(vec![
+        Instruction::Call { addr: 2 },
+        Instruction::Halt,
+        Instruction::TryBegin {
+            handler: 5,
+            dest: 0,
+        },
+        Instruction::Throw { src: 1 },
+        Instruction::Halt,
+        Instruction::CallHost {
+            dest: 0,
+            name: "double".to_string(),
+            args: vec![1],
+        },
+        Instruction::Return,
+    ]);
+    program.label("main", 0).unwrap();
+    program.label("thrower", 2).unwrap();
+    let mut vm = VM::new(program, 2);
+    vm.registers[1] = 4.0;
+    vm.register_host_function("double", |args| Ok(args[0] * 2.0));
+
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || vm.run().unwrap());
+    assert_eq!(vm.registers[0], 8.0);
+    assert_eq!(
+        *recorder.log.lock().unwrap(),
+        [
+            "run pc=0 label=\"main\"",
+            "call pc=2 label=\"thrower\"",
+            "event message=trap pc=3 label=\"thrower\" caught=true error=Uncaught error with code 4",
+            "host_call name=double",
+        ]
+    );
+}
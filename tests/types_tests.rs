@@ -0,0 +1,138 @@
+use zyde::asm_reg::{AsmErrorKind, assemble_full};
+use zyde::types::{Signature, Type, TypeError, check};
+
+/// Assemble `source` and type check it
+fn check_source(source: &str) -> Result<(), TypeError> {
+    let assembly = assemble_full(source).unwrap();
+    check(&assembly.program, &assembly.types)
+}
+
+#[test]
+fn test_annotations_are_collected() {
+    let assembly = assemble_full(
+        "
+            loadimm.i r0, 3
+            loadimm.f r1, 2.5
+            call add2
+            halt
+        .func add2(int, float) -> float
+            add r0, r0, r1
+            ret
+        ",
+    )
+    .unwrap();
+    assert_eq!(assembly.types.immediates[&0], Type::Int);
+    assert_eq!(assembly.types.immediates[&1], Type::Float);
+    assert_eq!(
+        assembly.types.functions[&4],
+        Signature {
+            params: vec![Type::Int, Type::Float],
+            ret: Some(Type::Float),
+        }
+    );
+    assert_eq!(assembly.program.find_export("add2").unwrap().arity, 2);
+    check(&assembly.program, &assembly.types).unwrap();
+}
+
+#[test]
+fn test_inferred_types_are_checked() {
+    assert_eq!(
+        check_source("record r0, 2\nmap r1\ngetfield r2, r1, 0\nhalt"),
+        Err(TypeError::Mismatch {
+            addr: 2,
+            reg: 1,
+            expected: Type::Record,
+            found: Type::Map,
+        })
+    );
+    // Int arithmetic stays int, so a record is never mistaken for one
+    assert_eq!(
+        check_source(
+            "loadimm r0, 2\nloadimm r1, 3\nmul r2, r0, r1\nrecord r3, 1\nadd r4, r2, r3\nhalt"
+        ),
+        Err(TypeError::Mismatch {
+            addr: 4,
+            reg: 3,
+            expected: Type::Float,
+            found: Type::Record,
+        })
+    );
+    // Paths that disagree leave a register unknown, and unknowns pass
+    check_source(
+        "
+            rand r0
+            jz r0, other
+            record r1, 1
+            rjmp done
+        other:
+            map r1
+        done:
+            getfield r2, r1, 0
+            halt
+        ",
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_signatures_are_checked() {
+    assert_eq!(
+        check_source(
+            "record r0, 1\ncall inc\nhalt\n.func inc(int) -> int\naddimm r0, r0, r1, 1\nret"
+        ),
+        Err(TypeError::Mismatch {
+            addr: 1,
+            reg: 0,
+            expected: Type::Int,
+            found: Type::Record,
+        })
+    );
+    assert_eq!(
+        check_source(
+            "loadimm r0, 1\ncall half\nhalt\n.func half(int) -> int\nloadimm r1, 2\ndiv r0, r0, r1\nret"
+        ),
+        Err(TypeError::Mismatch {
+            addr: 5,
+            reg: 0,
+            expected: Type::Int,
+            found: Type::Float,
+        })
+    );
+    // Only the parameters a call passes are checked
+    check_source("loadimm r0, 1\nmap r1\ncall inc, 1\nhalt\n.func inc(int, int) -> int\nret")
+        .unwrap();
+    // The declared return type flows back to the caller
+    assert!(matches!(
+        check_source("call make\ngetfield r1, r0, 0\nhalt\n.func make() -> map\nmap r0\nret"),
+        Err(TypeError::Mismatch {
+            addr: 1,
+            reg: 0,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_annotation_errors() {
+    assert_eq!(
+        check_source("loadimm.i r0, 2.5\nhalt"),
+        Err(TypeError::BadImmediate {
+            addr: 0,
+            value: 2.5,
+            expected: Type::Int,
+        })
+    );
+    let error = |source: &str| assemble_full(source).unwrap_err().kind;
+    assert_eq!(
+        error("loadimm.s r0, 1"),
+        AsmErrorKind::UnknownType("s".to_string())
+    );
+    assert_eq!(
+        error(".func f(int, string)\nret"),
+        AsmErrorKind::UnknownType("string".to_string())
+    );
+    assert_eq!(
+        error(".func f int\nret"),
+        AsmErrorKind::BadSignature("f int".to_string())
+    );
+}
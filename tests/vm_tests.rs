@@ -1,5 +1,8 @@
+use std::time::{Duration, Instant};
+use zyde::heap::{MemoryMode, Object};
 use zyde::instruction::Instruction;
-use zyde::vm::{VM, VmError};
+use zyde::program::Program;
+use zyde::vm::{CancellationToken, VM, VmConfig, VmError};
 
 #[test]
 fn test_loadimm() {
@@ -504,3 +507,1450 @@ fn test_mov() {
 
     assert_eq!(vm.registers[1], 123.0);
 }
+
+#[test]
+fn test_step() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Halt,
+    ];
+
+    let mut vm = VM::new(program, 4);
+    vm.step().unwrap();
+
+    assert_eq!(vm.pc, 1);
+    assert_eq!(vm.registers[0], 1.0);
+    assert!(!vm.is_halted());
+
+    vm.step().unwrap();
+
+    assert!(vm.is_halted());
+    assert!(matches!(vm.step(), Err(VmError::ProgramCounterOutOfBounds)));
+}
+
+#[test]
+fn test_max_steps() {
+    let program = vec![Instruction::Jump(0)];
+    let config = VmConfig {
+        max_steps: Some(100),
+        ..VmConfig::default()
+    };
+
+    let mut vm = VM::with_config(program, 4, config);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::StepLimitExceeded)));
+    assert_eq!(vm.steps, 100);
+
+    // The limit applies per run, so the VM can be resumed for another slice
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+    assert_eq!(vm.steps, 200);
+}
+
+#[test]
+fn test_max_steps_not_hit() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Halt,
+    ];
+    let config = VmConfig {
+        max_steps: Some(2),
+        ..VmConfig::default()
+    };
+
+    let mut vm = VM::with_config(program, 4, config);
+
+    assert!(vm.run().is_ok());
+}
+
+#[test]
+fn test_timeout() {
+    let program = vec![Instruction::Jump(0)];
+    let config = VmConfig {
+        timeout: Some(Duration::from_millis(20)),
+        ..VmConfig::default()
+    };
+
+    let mut vm = VM::with_config(program, 4, config);
+    let result = vm.run();
+
+    assert!(matches!(result, Err(VmError::Timeout)));
+    assert_eq!(vm.pc, 0);
+}
+
+#[test]
+fn test_snapshot() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 5.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Halt,
+    ];
+
+    let mut vm = VM::new(program, 2);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    let snapshot = vm.snapshot();
+
+    assert_eq!(snapshot.pc, 2);
+    assert_eq!(snapshot.registers, vec![5.0, 0.0]);
+    assert_eq!(snapshot.variables.get("x"), Some(&5.0));
+    assert_eq!(snapshot.steps, 2);
+}
+
+#[test]
+fn test_run_with_deadline() {
+    let program = vec![Instruction::Jump(0)];
+
+    let mut vm = VM::new(program, 4);
+    let result = vm.run_with_deadline(Instant::now() + Duration::from_millis(20));
+
+    assert!(matches!(result, Err(VmError::Timeout)));
+}
+
+#[test]
+fn test_cancellation() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Add {
+            dest: 1,
+            src1: 1,
+            src2: 0,
+        },
+        Instruction::Jump(1),
+    ];
+    let token = CancellationToken::new();
+    let config = VmConfig {
+        cancellation: Some(token.clone()),
+        ..VmConfig::default()
+    };
+
+    let mut vm = VM::with_config(program, 4, config);
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        token.cancel();
+    });
+    let result = vm.run();
+    canceller.join().unwrap();
+
+    assert!(matches!(result, Err(VmError::Cancelled)));
+    // The VM stopped between instructions, so its state is still consistent
+    assert!(vm.pc < 3);
+    assert!(vm.registers[1] > 0.0);
+
+    // Dropping the token lets the same VM carry on from where it stopped
+    vm.config.cancellation = None;
+    vm.config.max_steps = Some(10);
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+}
+
+#[test]
+fn test_snapshot_restore() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.0,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "x".to_string(),
+        },
+        Instruction::Halt,
+    ];
+
+    let mut vm = VM::new(program, 2);
+    vm.step().unwrap();
+    vm.step().unwrap();
+    let checkpoint = vm.snapshot();
+    vm.run().unwrap();
+
+    assert_eq!(vm.variables.get("x"), Some(&2.0));
+
+    vm.restore(&checkpoint);
+
+    assert_eq!(vm.pc, 2);
+    assert_eq!(vm.registers[0], 1.0);
+    assert_eq!(vm.variables.get("x"), Some(&1.0));
+    assert_eq!(vm.steps, 2);
+
+    vm.run().unwrap();
+
+    assert_eq!(vm.variables.get("x"), Some(&2.0));
+}
+
+#[test]
+fn test_snapshot_json() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 2.5,
+        },
+        Instruction::Store {
+            src: 1,
+            var: "b".to_string(),
+        },
+        Instruction::Store {
+            src: 1,
+            var: "a".to_string(),
+        },
+        Instruction::Call { addr: 4 },
+        Instruction::Return,
+    ];
+
+    let mut vm = VM::new(program, 2);
+    for _ in 0..4 {
+        vm.step().unwrap();
+    }
+
+    assert_eq!(
+        vm.snapshot().to_json(),
+        "{\"pc\":4,\"steps\":4,\"registers\":[0,2.5],\"variables\":{\"a\":2.5,\"b\":2.5},\"call_stack\":[4]}\n"
+    );
+}
+
+#[test]
+fn test_backtrace_names_frames() {
+    let mut program = Program::new(vec![
+        Instruction::Call { addr: 2 },
+        Instruction::Halt,
+        // outer
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Call { addr: 4 },
+        // inner
+        Instruction::Print { src: 0 },
+        Instruction::Print { src: 9 },
+        Instruction::Return,
+    ]);
+    program.label("main", 0).unwrap();
+    program.label("outer", 2).unwrap();
+    program.label("inner", 4).unwrap();
+
+    let mut vm = VM::new(program, 4);
+    assert!(matches!(vm.run(), Err(VmError::RegisterOutOfBounds(_))));
+
+    assert_eq!(
+        vm.backtrace(),
+        "backtrace:\n  frame 0: in inner (+2)\n  frame 1: in outer (+1)\n  frame 2: in main (+0)\n"
+    );
+}
+
+#[test]
+fn test_export_callstack_dot() {
+    let mut program = Program::new(vec![
+        Instruction::Call { addr: 2 },
+        Instruction::Halt,
+        Instruction::Print { src: 9 },
+        Instruction::Return,
+    ]);
+    program.label("helper", 2).unwrap();
+
+    let mut vm = VM::new(program, 4);
+    assert!(vm.run().is_err());
+
+    let dot = vm.export_callstack_dot();
+    assert!(dot.contains("f0 [label=\"frame 0\\nin helper (+1)\\npc 3\", style=filled"));
+    assert!(dot.contains("f1 [label=\"frame 1\\nat 0\\npc 0\"];"));
+    assert!(dot.contains("f0 -> f1 [label=\"returns to\"];"));
+}
+
+#[test]
+fn test_visualize_state() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 2.5,
+        },
+        Instruction::Store {
+            src: 1,
+            var: "b".to_string(),
+        },
+        Instruction::Store {
+            src: 0,
+            var: "a".to_string(),
+        },
+        Instruction::Halt,
+    ];
+    let mut vm = VM::new(program, 2);
+    vm.run().unwrap();
+
+    assert_eq!(
+        vm.visualize(),
+        "pc: 4 (steps: 4)\nregisters:\n  r0 = 0\n  r1 = 2.5\nvariables:\n  a = 0\n  b = 2.5\n(empty call stack)\n"
+    );
+}
+
+#[test]
+fn test_unverified_program_runs_checked() {
+    // Register 7 doesn't exist, which keeps the run on the checked path
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 4 },
+        Instruction::Print { src: 3 },
+        Instruction::Halt,
+        Instruction::Print { src: 7 },
+    ];
+    let mut vm = VM::new(program, 4);
+    vm.run().unwrap();
+    assert!(vm.is_halted());
+
+    vm.registers[0] = 0.0;
+    vm.pc = 1;
+    assert!(matches!(vm.run(), Err(VmError::RegisterOutOfBounds(_))));
+    assert_eq!(vm.pc, 5);
+}
+
+/// Run `program` on the pre-decoded path and by single-stepping, check both
+/// end in the same state, and return the first VM with its result
+fn run_both_ways(program: Vec<Instruction>, registers: usize) -> (VM, Result<(), VmError>) {
+    let mut fast = VM::new(program.clone(), registers);
+    let fast_result = fast.run();
+
+    let mut stepped = VM::new(program, registers);
+    let stepped_result = (|| -> Result<(), VmError> {
+        while !stepped.is_halted() {
+            stepped.step()?;
+        }
+        Ok(())
+    })();
+
+    // Compared as text, since registers holding heap handles are NaNs
+    assert_eq!(
+        format!("{:?}", fast.snapshot()),
+        format!("{:?}", stepped.snapshot())
+    );
+    assert_eq!(
+        format!("{:?}", fast_result),
+        format!("{:?}", stepped_result)
+    );
+    (fast, fast_result)
+}
+
+#[test]
+fn test_try_catches_throw() {
+    let program = vec![
+        Instruction::TryBegin {
+            handler: 5,
+            dest: 1,
+        },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 7.0,
+        },
+        Instruction::Throw { src: 0 },
+        Instruction::LoadImm {
+            dest: 2,
+            value: 1.0,
+        },
+        Instruction::Halt,
+        // handler (5)
+        Instruction::LoadImm {
+            dest: 2,
+            value: 2.0,
+        },
+        Instruction::Halt,
+    ];
+
+    let (vm, result) = run_both_ways(program, 3);
+    result.unwrap();
+    assert_eq!(vm.registers, vec![7.0, 7.0, 2.0]);
+    assert!(vm.handlers.is_empty());
+}
+
+#[test]
+fn test_try_unwinds_frames_to_catch_traps() {
+    let program = vec![
+        Instruction::TryBegin {
+            handler: 4,
+            dest: 1,
+        },
+        Instruction::Call { addr: 5 },
+        Instruction::TryEnd,
+        Instruction::Halt,
+        // handler (4)
+        Instruction::Halt,
+        // outer (5)
+        Instruction::Call { addr: 6 },
+        // inner (6)
+        Instruction::Load {
+            dest: 0,
+            var: "missing".to_string(),
+        },
+    ];
+
+    let (vm, result) = run_both_ways(program, 2);
+    result.unwrap();
+    assert_eq!(
+        vm.registers[1],
+        VmError::VariableNotFound(String::new()).code().unwrap()
+    );
+    assert!(vm.call_stack.is_empty());
+}
+
+#[test]
+fn test_handlers_do_not_outlive_their_frame() {
+    let program = vec![
+        Instruction::Call { addr: 4 },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Throw { src: 0 },
+        Instruction::Halt,
+        // installs a handler, then returns without removing it (4)
+        Instruction::TryBegin {
+            handler: 3,
+            dest: 1,
+        },
+        Instruction::Return,
+    ];
+
+    let (vm, result) = run_both_ways(program, 2);
+    assert!(matches!(result, Err(VmError::Uncaught(code)) if code == 3.0));
+    assert!(vm.handlers.is_empty());
+}
+
+#[test]
+fn test_host_limits_are_not_catchable() {
+    let (_, result) = run_both_ways(vec![Instruction::TryEnd], 1);
+    assert!(matches!(result, Err(VmError::HandlerStackEmpty)));
+
+    let program = vec![
+        Instruction::TryBegin {
+            handler: 0,
+            dest: 0,
+        },
+        Instruction::Jump(1),
+    ];
+    let config = VmConfig {
+        max_steps: Some(100),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(program, 1, config);
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+    assert_eq!(vm.handlers.len(), 1);
+}
+
+#[test]
+fn test_stale_handlers_do_not_write_past_the_registers() {
+    let program = vec![
+        Instruction::TryBegin {
+            handler: 2,
+            dest: 7,
+        },
+        Instruction::Jump(1),
+        Instruction::Halt,
+    ];
+    let config = VmConfig {
+        max_steps: Some(10),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(program, 8, config);
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+
+    vm.registers.truncate(1);
+    vm.program = Program::new(vec![Instruction::Throw { src: 0 }]).into();
+    vm.pc = 0;
+    assert!(matches!(vm.run(), Err(VmError::RegisterOutOfBounds(_))));
+    assert_eq!(vm.registers.len(), 1);
+}
+
+#[test]
+fn test_stale_resumers_do_not_write_past_the_registers() {
+    let program = vec![
+        Instruction::Spawn { dest: 0, addr: 3 },
+        Instruction::Resume { dest: 7, id: 0 },
+        Instruction::Halt,
+        Instruction::Jump(3),
+    ];
+    let config = VmConfig {
+        max_steps: Some(10),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(program, 8, config);
+    assert!(matches!(vm.run(), Err(VmError::StepLimitExceeded)));
+
+    vm.registers.truncate(1);
+    vm.program = Program::new(vec![Instruction::Yield]).into();
+    vm.pc = 0;
+    assert!(matches!(vm.run(), Err(VmError::RegisterOutOfBounds(_))));
+    assert_eq!(vm.registers.len(), 1);
+}
+
+#[test]
+fn test_push_and_pop_use_the_operand_stack() {
+    let load = |dest, value| Instruction::LoadImm { dest, value };
+    let program = vec![
+        load(0, 1.0),
+        load(1, 2.0),
+        Instruction::Push { src: 0 },
+        Instruction::Push { src: 1 },
+        Instruction::Pop { dest: 0 },
+        Instruction::Pop { dest: 1 },
+        Instruction::Push { src: 0 },
+        Instruction::TryBegin {
+            handler: 11,
+            dest: 2,
+        },
+        Instruction::Push { src: 1 },
+        Instruction::Push { src: 1 },
+        // Traps, so the handler drops what the try block pushed
+        Instruction::Load {
+            dest: 3,
+            var: "missing".to_string(),
+        },
+        // handler (11)
+        Instruction::Halt,
+    ];
+    assert_eq!(program[2].to_string(), "push r0");
+    assert_eq!(program[4].to_string(), "pop r0");
+    let (vm, result) = run_both_ways(program, 4);
+    result.unwrap();
+    assert_eq!(vm.registers[..2], [2.0, 1.0]);
+    assert_eq!(
+        vm.registers[2],
+        VmError::VariableNotFound(String::new()).code().unwrap()
+    );
+    assert_eq!(vm.stack, vec![2.0]);
+
+    let (vm, result) = run_both_ways(vec![Instruction::Pop { dest: 0 }], 1);
+    assert!(matches!(result, Err(VmError::StackUnderflow)));
+    assert_eq!(vm.pc, 1);
+}
+
+#[test]
+fn test_max_stack_limits_pushes() {
+    let program = vec![Instruction::Push { src: 0 }, Instruction::Jump(0)];
+    let config = VmConfig {
+        max_stack: Some(3),
+        ..VmConfig::default()
+    };
+    let mut fast = VM::with_config(program.clone(), 1, config.clone());
+    let mut stepped = VM::with_config(program, 1, config);
+    assert!(matches!(
+        fast.run(),
+        Err(VmError::MemoryLimitExceeded("stack"))
+    ));
+    let result = (|| loop {
+        stepped.step()?;
+    })();
+    assert!(matches!(
+        result,
+        Err::<(), _>(VmError::MemoryLimitExceeded("stack"))
+    ));
+    for vm in [fast, stepped] {
+        assert_eq!((vm.pc, vm.stack.len()), (1, 3));
+        assert_eq!(vm.memory_usage().stack, 3);
+    }
+}
+
+#[test]
+fn test_coroutines_have_their_own_operand_stack() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 5.0,
+        },
+        Instruction::Push { src: 0 },
+        Instruction::Spawn { dest: 1, addr: 6 },
+        Instruction::Resume { dest: 2, id: 1 },
+        Instruction::Pop { dest: 3 },
+        Instruction::Halt,
+        // pushes onto its own stack, then fails to pop twice (6)
+        Instruction::Push { src: 0 },
+        Instruction::Pop { dest: 0 },
+        Instruction::Yield,
+        Instruction::Pop { dest: 0 },
+    ];
+    let (vm, result) = run_both_ways(program, 4);
+    result.unwrap();
+    assert_eq!(vm.registers[3], 5.0);
+    assert!(vm.stack.is_empty());
+}
+
+#[test]
+fn test_arity_checks_the_argument_count() {
+    let call = |argc| Instruction::CallArgs { addr: 8, argc };
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 5.0,
+        },
+        call(1),
+        Instruction::LoadImm {
+            dest: 1,
+            value: 2.0,
+        },
+        call(2),
+        Instruction::TryBegin {
+            handler: 7,
+            dest: 5,
+        },
+        call(3),
+        Instruction::Halt,
+        // handler (7)
+        Instruction::Halt,
+        // adds r1 to r0, r1 being 10 if it was not passed (8)
+        Instruction::Arity {
+            dest: 3,
+            min: 1,
+            max: Some(2),
+        },
+        Instruction::LoadImm {
+            dest: 4,
+            value: 2.0,
+        },
+        Instruction::LessThan {
+            dest: 4,
+            src1: 3,
+            src2: 4,
+        },
+        Instruction::ConditionalJump {
+            cond: 4,
+            target: 13,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 10.0,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Return,
+    ];
+    assert_eq!(program[1].to_string(), "call 8, 1");
+    assert_eq!(program[8].to_string(), "arity r3, 1, 2");
+    let (vm, result) = run_both_ways(program, 6);
+    result.unwrap();
+    assert_eq!(vm.registers[0], 17.0);
+    assert_eq!(vm.registers[5], -15.0);
+    assert!(vm.stack.is_empty() && vm.call_stack.is_empty());
+
+    // A plain call passes no count to check
+    let at_least_one = Instruction::Arity {
+        dest: 0,
+        min: 1,
+        max: None,
+    };
+    assert_eq!(at_least_one.to_string(), "arity r0, 1");
+    let (_, result) = run_both_ways(vec![Instruction::Call { addr: 1 }, at_least_one.clone()], 1);
+    assert!(matches!(result, Err(VmError::StackUnderflow)));
+    let program = vec![Instruction::CallArgs { addr: 1, argc: 0 }, at_least_one];
+    let (vm, result) = run_both_ways(program, 1);
+    assert!(matches!(
+        result,
+        Err(VmError::ArityMismatch {
+            expected: 1,
+            found: 0
+        })
+    ));
+    assert_eq!(vm.stack, vec![0.0]);
+}
+
+#[test]
+fn test_switch_selects_table_entry_or_default() {
+    // r1 = 10 + case number, where the default case is 9
+    let program = |selector| {
+        vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: selector,
+            },
+            Instruction::Switch {
+                src: 0,
+                table: vec![4, 6],
+                default: 8,
+            },
+            Instruction::Halt,
+            Instruction::Halt,
+            Instruction::LoadImm {
+                dest: 1,
+                value: 10.0,
+            },
+            Instruction::Halt,
+            Instruction::LoadImm {
+                dest: 1,
+                value: 11.0,
+            },
+            Instruction::Halt,
+            Instruction::LoadImm {
+                dest: 1,
+                value: 19.0,
+            },
+        ]
+    };
+
+    for (selector, expected) in [
+        (0.0, 10.0),
+        (1.0, 11.0),
+        (2.0, 19.0),
+        (-1.0, 19.0),
+        (0.5, 19.0),
+    ] {
+        let (vm, result) = run_both_ways(program(selector), 2);
+        assert!(result.is_ok());
+        assert_eq!(vm.registers[1], expected, "selector {}", selector);
+    }
+}
+
+#[test]
+fn test_jump_indirect_follows_register() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::JumpIndirect { src: 0 },
+        Instruction::Halt,
+        Instruction::LoadImm {
+            dest: 1,
+            value: 7.0,
+        },
+    ];
+    let (vm, result) = run_both_ways(program, 2);
+    assert!(result.is_ok());
+    assert_eq!(vm.registers[1], 7.0);
+
+    for bad in [4.5, -1.0, 1e12] {
+        let program = vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: bad,
+            },
+            Instruction::JumpIndirect { src: 0 },
+        ];
+        let (_, result) = run_both_ways(program, 1);
+        assert!(matches!(result, Err(VmError::ProgramCounterOutOfBounds)));
+    }
+}
+
+#[test]
+fn test_call_indirect_through_stored_address() {
+    // Store the address of `double` in a variable, then call it through r1
+    let program = vec![
+        Instruction::LoadAddr { dest: 1, addr: 7 },
+        Instruction::Store {
+            src: 1,
+            var: "callback".to_string(),
+        },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 21.0,
+        },
+        Instruction::Load {
+            dest: 2,
+            var: "callback".to_string(),
+        },
+        Instruction::CallIndirect { src: 2 },
+        Instruction::CallIndirect { src: 2 },
+        Instruction::Halt,
+        // double (7)
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Return,
+    ];
+    let (vm, result) = run_both_ways(program, 3);
+    assert!(result.is_ok());
+    assert_eq!(vm.registers[0], 84.0);
+
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 9.0,
+        },
+        Instruction::CallIndirect { src: 0 },
+    ];
+    let (vm, result) = run_both_ways(program, 1);
+    assert!(matches!(result, Err(VmError::ProgramCounterOutOfBounds)));
+    assert!(vm.call_stack.is_empty());
+}
+
+#[test]
+fn test_closures_keep_their_own_upvalues() {
+    // Two closures over a counter that increments its upvalue into r1
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 10.0,
+        },
+        Instruction::MakeClosure {
+            dest: 2,
+            addr: 9,
+            captures: vec![0],
+        },
+        Instruction::MakeClosure {
+            dest: 3,
+            addr: 9,
+            captures: vec![0],
+        },
+        Instruction::CallClosure { src: 2 },
+        Instruction::CallClosure { src: 2 },
+        Instruction::CallClosure { src: 3 },
+        Instruction::Mov { dest: 4, src: 1 },
+        Instruction::CallClosure { src: 2 },
+        Instruction::Halt,
+        // counter (9)
+        Instruction::GetUpvalue { dest: 1, index: 0 },
+        Instruction::LoadImm {
+            dest: 5,
+            value: 1.0,
+        },
+        Instruction::Add {
+            dest: 1,
+            src1: 1,
+            src2: 5,
+        },
+        Instruction::SetUpvalue { src: 1, index: 0 },
+        Instruction::Return,
+    ];
+
+    let (vm, result) = run_both_ways(program, 6);
+    assert!(result.is_ok());
+    assert_eq!(vm.registers[4], 11.0);
+    assert_eq!(vm.registers[1], 13.0);
+    assert_eq!(vm.heap.len(), 2);
+    assert!(matches!(
+        vm.heap.get(vm.registers[3]),
+        Some(Object::Closure { upvalues, .. }) if upvalues == &[11.0]
+    ));
+}
+
+#[test]
+fn test_closure_errors_are_catchable() {
+    let program = vec![Instruction::CallClosure { src: 0 }];
+    let (_, result) = run_both_ways(program, 1);
+    assert!(matches!(result, Err(VmError::WrongType("closure"))));
+
+    let program = vec![
+        Instruction::TryBegin {
+            handler: 2,
+            dest: 0,
+        },
+        Instruction::GetUpvalue { dest: 1, index: 0 },
+        Instruction::Halt,
+    ];
+    let (vm, result) = run_both_ways(program, 2);
+    assert!(result.is_ok());
+    assert_eq!(
+        vm.registers[0],
+        VmError::UpvalueOutOfBounds(0).code().unwrap()
+    );
+}
+
+#[test]
+fn test_gc_reclaims_closures_as_the_heap_grows() {
+    // Allocate 5000 closures, keeping only the latest in r1
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 5000.0,
+        },
+        Instruction::LoadImm {
+            dest: 2,
+            value: 1.0,
+        },
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 6,
+            captures: vec![0],
+        },
+        Instruction::Sub {
+            dest: 0,
+            src1: 0,
+            src2: 2,
+        },
+        Instruction::ConditionalJump { cond: 0, target: 6 },
+        Instruction::Jump(2),
+        Instruction::Halt,
+    ];
+    let (vm, result) = run_both_ways(program, 3);
+    assert!(result.is_ok());
+
+    let stats = vm.gc_stats();
+    assert!(stats.collections >= 4, "{:?}", stats);
+    assert_eq!(stats.allocated, 5000);
+    assert_eq!(stats.freed + stats.live as u64, 5000);
+    assert!(stats.live <= 1024, "{:?}", stats);
+    assert!(matches!(
+        vm.heap.get(vm.registers[1]),
+        Some(Object::Closure { upvalues, .. }) if upvalues == &[1.0]
+    ));
+}
+
+#[test]
+fn test_gc_keeps_reachable_objects() {
+    let program = vec![
+        Instruction::MakeClosure {
+            dest: 0,
+            addr: 5,
+            captures: vec![],
+        },
+        Instruction::Store {
+            src: 0,
+            var: "kept".to_string(),
+        },
+        // Reachable only through the upvalue of the closure in r1
+        Instruction::MakeClosure {
+            dest: 0,
+            addr: 5,
+            captures: vec![],
+        },
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 5,
+            captures: vec![0],
+        },
+        // Unreachable once overwritten
+        Instruction::MakeClosure {
+            dest: 0,
+            addr: 5,
+            captures: vec![],
+        },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+    ];
+    let mut vm = VM::new(program, 2);
+    vm.run().unwrap();
+    assert_eq!(vm.heap.len(), 4);
+
+    assert_eq!(vm.collect_garbage(), 1);
+    assert_eq!(vm.heap.len(), 3);
+    assert!(vm.heap.get(vm.variables["kept"]).is_some());
+    let Some(Object::Closure { upvalues, .. }) = vm.heap.get(vm.registers[1]) else {
+        panic!("r1 is not a closure");
+    };
+    assert!(vm.heap.get(upvalues[0]).is_some());
+    assert_eq!(vm.collect_garbage(), 0);
+    assert_eq!(vm.gc_stats().collections, 2);
+}
+
+fn ref_counted() -> VmConfig {
+    VmConfig {
+        memory: MemoryMode::RefCounted,
+        ..VmConfig::default()
+    }
+}
+
+#[test]
+fn test_ref_counting_frees_objects_immediately() {
+    // r1 is overwritten by a new closure three times; the closure running in
+    // the call keeps itself alive after r1 drops it
+    let program = vec![
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 6,
+            captures: vec![],
+        },
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 6,
+            captures: vec![],
+        },
+        Instruction::CallClosure { src: 1 },
+        Instruction::Store {
+            src: 1,
+            var: "last".to_string(),
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 0.0,
+        },
+        Instruction::Halt,
+        // overwrites r1 with a fresh closure (6)
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 6,
+            captures: vec![],
+        },
+        Instruction::Return,
+    ];
+    let mut vm = VM::with_config(program, 2, ref_counted());
+    let mut live = Vec::new();
+    while !vm.is_halted() {
+        vm.step().unwrap();
+        live.push(vm.heap.len());
+    }
+    // The first closure goes when r1 is overwritten, the called one when
+    // its frame returns; the last survives in a variable
+    assert_eq!(live, vec![1, 1, 1, 2, 1, 1, 1, 1]);
+    assert!(vm.heap.get(vm.variables["last"]).is_some());
+    assert_eq!(vm.gc_stats().freed, 2);
+    assert_eq!(vm.gc_stats().collections, 0);
+}
+
+#[test]
+fn test_ref_counting_leaks_cycles_until_collected() {
+    // The closure stores its own handle in its upvalue
+    let program = vec![
+        Instruction::MakeClosure {
+            dest: 1,
+            addr: 4,
+            captures: vec![0],
+        },
+        Instruction::CallClosure { src: 1 },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 0.0,
+        },
+        Instruction::Halt,
+        // (4)
+        Instruction::SetUpvalue { src: 1, index: 0 },
+        Instruction::Return,
+    ];
+    let mut vm = VM::with_config(program, 2, ref_counted());
+    vm.run().unwrap();
+    assert_eq!(vm.heap.len(), 1);
+
+    assert_eq!(vm.collect_garbage(), 1);
+    assert!(vm.heap.is_empty());
+}
+
+#[test]
+fn test_record_fields() {
+    // Build a point, then have a function swap its fields through the handle
+    let program = vec![
+        Instruction::NewRecord { dest: 0, fields: 2 },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 3.0,
+        },
+        Instruction::SetField {
+            record: 0,
+            index: 0,
+            src: 1,
+        },
+        Instruction::Call { addr: 7 },
+        Instruction::GetField {
+            dest: 1,
+            record: 0,
+            index: 0,
+        },
+        Instruction::GetField {
+            dest: 2,
+            record: 0,
+            index: 1,
+        },
+        Instruction::Halt,
+        // swap (7)
+        Instruction::GetField {
+            dest: 1,
+            record: 0,
+            index: 0,
+        },
+        Instruction::GetField {
+            dest: 2,
+            record: 0,
+            index: 1,
+        },
+        Instruction::SetField {
+            record: 0,
+            index: 0,
+            src: 2,
+        },
+        Instruction::SetField {
+            record: 0,
+            index: 1,
+            src: 1,
+        },
+        Instruction::Return,
+    ];
+    let (vm, result) = run_both_ways(program, 3);
+    assert!(result.is_ok());
+    assert_eq!(vm.registers[1..], [0.0, 3.0]);
+    assert!(matches!(
+        vm.heap.get(vm.registers[0]),
+        Some(Object::Record { fields }) if fields == &[0.0, 3.0]
+    ));
+
+    let program = vec![
+        Instruction::NewRecord { dest: 0, fields: 1 },
+        Instruction::Jump(2),
+        Instruction::GetField {
+            dest: 1,
+            record: 0,
+            index: 1,
+        },
+    ];
+    let (_, result) = run_both_ways(program, 2);
+    assert!(matches!(result, Err(VmError::FieldOutOfBounds(1))));
+
+    let program = vec![Instruction::SetField {
+        record: 0,
+        index: 0,
+        src: 0,
+    }];
+    let (_, result) = run_both_ways(program, 1);
+    assert!(matches!(result, Err(VmError::WrongType("record"))));
+}
+
+#[test]
+fn test_map_entries() {
+    // Keys are numbers or object identities; -0 finds the entry stored at 0
+    let program = vec![
+        Instruction::MapNew { dest: 0 },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 0.0,
+        },
+        Instruction::LoadImm {
+            dest: 2,
+            value: 5.0,
+        },
+        Instruction::MapSet {
+            map: 0,
+            key: 1,
+            src: 2,
+        },
+        Instruction::NewRecord { dest: 3, fields: 0 },
+        Instruction::MapSet {
+            map: 0,
+            key: 3,
+            src: 3,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: -0.0,
+        },
+        Instruction::MapGet {
+            dest: 4,
+            map: 0,
+            key: 1,
+        },
+        Instruction::MapDelete { map: 0, key: 1 },
+        Instruction::MapHas {
+            dest: 5,
+            map: 0,
+            key: 1,
+        },
+        Instruction::MapHas {
+            dest: 6,
+            map: 0,
+            key: 3,
+        },
+        Instruction::MapGet {
+            dest: 4,
+            map: 0,
+            key: 2,
+        },
+    ];
+    assert_eq!(program[3].to_string(), "mapset r0, r1, r2");
+    assert_eq!(program[8].to_string(), "mapdel r0, r1");
+    let (vm, result) = run_both_ways(program, 7);
+    assert!(matches!(result, Err(VmError::KeyNotFound(5.0))));
+    assert_eq!(vm.registers[4..], [5.0, 0.0, 1.0]);
+    assert!(matches!(
+        vm.heap.get(vm.registers[0]),
+        Some(Object::Map { entries }) if entries.len() == 1
+    ));
+
+    let program = vec![
+        Instruction::NewRecord { dest: 0, fields: 0 },
+        Instruction::MapHas {
+            dest: 1,
+            map: 0,
+            key: 0,
+        },
+    ];
+    let (_, result) = run_both_ways(program, 2);
+    assert!(matches!(result, Err(VmError::WrongType("map"))));
+}
+
+#[test]
+fn test_ref_counting_map_entries() {
+    // The map keeps the record in r1 alive until its entry is deleted
+    let program = vec![
+        Instruction::MapNew { dest: 0 },
+        Instruction::NewRecord { dest: 1, fields: 0 },
+        Instruction::MapSet {
+            map: 0,
+            key: 2,
+            src: 1,
+        },
+        Instruction::NewRecord { dest: 1, fields: 0 },
+        Instruction::MapDelete { map: 0, key: 2 },
+    ];
+    let mut vm = VM::with_config(program, 3, ref_counted());
+    let mut live = Vec::new();
+    while !vm.is_halted() {
+        vm.step().unwrap();
+        live.push(vm.heap.len());
+    }
+    assert_eq!(live, vec![1, 2, 2, 3, 2]);
+}
+
+#[test]
+fn test_coroutines_yield_and_resume() {
+    // The coroutine keeps its own copy of r0 and reports through "n"
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 10.0,
+        },
+        Instruction::Spawn { dest: 1, addr: 9 },
+        Instruction::LoadImm {
+            dest: 0,
+            value: 0.0,
+        },
+        Instruction::Resume { dest: 2, id: 1 },
+        Instruction::Resume { dest: 2, id: 1 },
+        Instruction::Resume { dest: 2, id: 1 },
+        Instruction::Load {
+            dest: 3,
+            var: "n".to_string(),
+        },
+        Instruction::Resume { dest: 2, id: 1 },
+        Instruction::Halt,
+        // counter (9)
+        Instruction::Store {
+            src: 0,
+            var: "n".to_string(),
+        },
+        Instruction::Yield,
+        Instruction::LoadImm {
+            dest: 3,
+            value: 1.0,
+        },
+        Instruction::Add {
+            dest: 0,
+            src1: 0,
+            src2: 3,
+        },
+        Instruction::Store {
+            src: 0,
+            var: "n".to_string(),
+        },
+        Instruction::Yield,
+        Instruction::Return,
+    ];
+    assert_eq!(program[1].to_string(), "spawn r1, 9");
+    assert_eq!(program[3].to_string(), "resume r2, r1");
+    let (vm, result) = run_both_ways(program, 4);
+    // The third resume finishes it, so the fourth fails
+    assert!(matches!(result, Err(VmError::NotResumable(0.0))));
+    assert_eq!(vm.registers, vec![0.0, 0.0, 0.0, 11.0]);
+    assert_eq!(vm.pc, 8);
+
+    let (_, result) = run_both_ways(vec![Instruction::Yield], 1);
+    assert!(matches!(result, Err(VmError::YieldOutsideCoroutine)));
+}
+
+#[test]
+fn test_host_resumes_coroutine_each_frame() {
+    // The script bumps "frames" once per resume and finishes after two
+    let bump = |dest| {
+        vec![
+            Instruction::Load {
+                dest,
+                var: "frames".to_string(),
+            },
+            Instruction::Add {
+                dest,
+                src1: dest,
+                src2: 1,
+            },
+            Instruction::Store {
+                src: dest,
+                var: "frames".to_string(),
+            },
+        ]
+    };
+    let mut program = vec![
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Store {
+            src: 2,
+            var: "frames".to_string(),
+        },
+        Instruction::Spawn { dest: 0, addr: 5 },
+        Instruction::Store {
+            src: 0,
+            var: "script".to_string(),
+        },
+        Instruction::Halt,
+    ];
+    program.extend(bump(2));
+    program.push(Instruction::Yield);
+    program.extend(bump(2));
+    program.push(Instruction::Return);
+
+    let mut vm = VM::new(program, 3);
+    vm.run().unwrap();
+    let script = vm.variables["script"];
+    assert!(vm.resume(script).unwrap());
+    assert_eq!(vm.variables["frames"], 1.0);
+    assert!(!vm.resume(script).unwrap());
+    assert_eq!(vm.variables["frames"], 2.0);
+    assert!(vm.is_halted());
+    assert!(matches!(
+        vm.resume(script),
+        Err(VmError::NotResumable(id)) if id == script
+    ));
+}
+
+#[test]
+fn test_rand_is_repeatable_for_a_seed() {
+    let program = || {
+        vec![
+            Instruction::Rand { dest: 0 },
+            Instruction::Rand { dest: 1 },
+            Instruction::Rand { dest: 2 },
+            Instruction::Halt,
+        ]
+    };
+    let seeded = |seed| {
+        let mut vm = VM::with_config(
+            program(),
+            3,
+            VmConfig {
+                seed,
+                ..VmConfig::default()
+            },
+        );
+        vm.run().unwrap();
+        vm
+    };
+
+    let (vm, _) = run_both_ways(program(), 3);
+    assert!(vm.registers.iter().all(|v| (0.0..1.0).contains(v)));
+    assert_eq!(vm.rng.draws(), 3);
+    assert_eq!(seeded(7).registers, seeded(7).registers);
+    assert_ne!(seeded(7).registers, seeded(8).registers);
+
+    // Restoring a snapshot replays the same draws
+    let mut vm = VM::with_config(
+        program(),
+        3,
+        VmConfig {
+            seed: 7,
+            ..VmConfig::default()
+        },
+    );
+    vm.step().unwrap();
+    let snapshot = vm.snapshot();
+    vm.run().unwrap();
+    let first = vm.registers.clone();
+    vm.restore(&snapshot);
+    vm.run().unwrap();
+    assert_eq!(vm.registers, first);
+    assert_eq!(Instruction::Rand { dest: 2 }.to_string(), "rand r2");
+}
+
+#[test]
+fn test_memory_limits() {
+    // Adds entries under counting keys until the map outgrows the limit
+    let program = vec![
+        Instruction::MapNew { dest: 0 },
+        Instruction::MapSet {
+            map: 0,
+            key: 1,
+            src: 1,
+        },
+        Instruction::AddImm {
+            dest: 1,
+            src: 1,
+            imm: 2,
+            value: 1.0,
+        },
+        Instruction::Jump(1),
+    ];
+    let config = VmConfig {
+        max_heap_cells: Some(10),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(program, 3, config);
+    assert!(matches!(
+        vm.run(),
+        Err(VmError::MemoryLimitExceeded("heap cells"))
+    ));
+    assert_eq!(vm.memory_usage().heap_cells, 10);
+    assert_eq!(vm.registers[1], 5.0);
+
+    let store = |var: &str| Instruction::Store {
+        src: 0,
+        var: var.to_string(),
+    };
+    let program = vec![store("ab"), store("cd"), store("ab"), store("e")];
+    let config = VmConfig {
+        max_variables: Some(2),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(program.clone(), 1, config);
+    assert!(matches!(
+        vm.run(),
+        Err(VmError::MemoryLimitExceeded("variables"))
+    ));
+    let usage = vm.memory_usage();
+    assert_eq!((usage.variables, usage.string_bytes), (2, 4));
+
+    let config = VmConfig {
+        max_string_bytes: Some(4),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(program, 1, config);
+    assert!(matches!(
+        vm.run(),
+        Err(VmError::MemoryLimitExceeded("string bytes"))
+    ));
+    assert_eq!(vm.pc, 4);
+}
+
+#[test]
+fn test_print_goes_to_output_sink() {
+    let program = vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 4.0,
+        },
+        Instruction::Print { src: 0 },
+        Instruction::Print { src: 0 },
+        Instruction::Halt,
+    ];
+    let printed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = printed.clone();
+    let mut vm = VM::new(program.clone(), 1);
+    vm.set_output(move |v| sink.lock().unwrap().push(v));
+    vm.run().unwrap();
+    assert_eq!(*printed.lock().unwrap(), vec![4.0, 4.0]);
+
+    let mut vm = VM::new(program, 1);
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.take_output(), vec![4.0, 4.0]);
+    assert_eq!(vm.take_output(), Vec::<f64>::new());
+}
@@ -0,0 +1,61 @@
+#![cfg(feature = "wasm-api")]
+
+use zyde::instruction::Instruction;
+use zyde::program::Program;
+use zyde::wasm_api::Session;
+
+fn bytecode(instructions: Vec<Instruction>) -> Vec<u8> {
+    Program::new(instructions).to_bytecode().unwrap()
+}
+
+#[test]
+fn test_session_runs_and_collects_output() {
+    let bytes = bytecode(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 3.0,
+        },
+        Instruction::Print { src: 0 },
+        Instruction::Add {
+            dest: 1,
+            src1: 0,
+            src2: 0,
+        },
+        Instruction::Print { src: 1 },
+        Instruction::Halt,
+    ]);
+    let mut session = Session::new(&bytes, 2).unwrap();
+
+    assert!(!session.run(2).unwrap());
+    assert_eq!(session.pc(), 2);
+    assert_eq!(session.take_output(), vec![3.0]);
+
+    assert!(session.run(100).unwrap());
+    assert!(session.halted());
+    assert_eq!(session.registers(), vec![3.0, 6.0]);
+    assert_eq!(session.take_output(), vec![6.0]);
+    assert!(session.take_output().is_empty());
+}
+
+#[test]
+fn test_session_traces_and_reports_errors() {
+    let bytes = bytecode(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 1.0,
+        },
+        Instruction::Load {
+            dest: 0,
+            var: "missing".to_string(),
+        },
+    ]);
+    let mut session = Session::new(&bytes, 1).unwrap();
+    session.set_tracing(true);
+
+    assert!(session.run(10).is_err());
+    let json = session.trace_json();
+    assert!(json.contains("\"opcode\":\"loadimm\""), "{json}");
+    assert!(json.contains("\"error\":"), "{json}");
+
+    assert!(Session::new(b"not bytecode", 1).is_err());
+}
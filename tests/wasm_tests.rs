@@ -0,0 +1,212 @@
+#![cfg(feature = "wasm-backend")]
+
+use wasmi::{Caller, Engine, Linker, Module, Store};
+use zyde::backend::wasm::{self, WasmModule};
+use zyde::instruction::Instruction;
+use zyde::passes::PassManager;
+use zyde::passes::fusion::Fusion;
+use zyde::program::{Program, ProgramError};
+use zyde::vm::{VM, VmError};
+use zyde::workloads::{self, REGISTERS};
+
+/// Registers, variables and printed values after running a compiled module
+struct Outcome {
+    result: Result<(), VmError>,
+    registers: Vec<f64>,
+    variables: Vec<Option<f64>>,
+    printed: Vec<f64>,
+}
+
+fn run(module: &WasmModule, registers: usize) -> Outcome {
+    let engine = Engine::default();
+    let wasm = Module::new(&engine, module.bytes()).unwrap();
+    let mut store = Store::new(&engine, Vec::new());
+    let mut linker = <Linker<Vec<f64>>>::new(&engine);
+    linker
+        .func_wrap("env", "print", |mut caller: Caller<Vec<f64>>, v: f64| {
+            caller.data_mut().push(v)
+        })
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &wasm)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+
+    let status = instance
+        .get_typed_func::<(), i32>(&store, "run")
+        .unwrap()
+        .call(&mut store, ())
+        .unwrap();
+    let memory = instance.get_memory(&store, "memory").unwrap();
+    let data = memory.data(&store);
+    let read = |offset: usize| f64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+    Outcome {
+        result: module.status(status),
+        registers: (0..registers)
+            .map(|r| read(module.register_offset(r)))
+            .collect(),
+        variables: (0..module.variables().len())
+            .map(|slot| {
+                (data[module.variable_set_offset(slot)] != 0)
+                    .then(|| read(module.variable_offset(slot)))
+            })
+            .collect(),
+        printed: store.data().clone(),
+    }
+}
+
+/// Compile and run `program` and check it agrees with the interpreter
+fn assert_same(program: Program, registers: usize) -> Outcome {
+    let module = wasm::compile(&program, registers).unwrap();
+    let outcome = run(&module, registers);
+
+    let mut vm = VM::new(program, registers);
+    let expected = vm.run();
+    assert_eq!(
+        outcome.result.is_ok(),
+        expected.is_ok(),
+        "{:?} vs {:?}",
+        outcome.result,
+        expected
+    );
+    assert_eq!(outcome.registers, vm.registers);
+    for (slot, name) in module.variables().iter().enumerate() {
+        assert_eq!(outcome.variables[slot], vm.variables.get(name).copied());
+    }
+    outcome
+}
+
+#[test]
+fn test_wasm_matches_interpreter_on_workloads() {
+    for (_, program) in workloads::standard() {
+        let mut fused = program.clone();
+        PassManager::empty().add(Fusion).run(&mut fused);
+
+        assert_same(program, REGISTERS);
+        assert_same(fused, REGISTERS);
+    }
+}
+
+#[test]
+fn test_wasm_call_stack_grows_memory() {
+    // Recurses 100000 deep, past the initially reserved stack
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 100000.0,
+        },
+        Instruction::LoadImm {
+            dest: 1,
+            value: 1.0,
+        },
+        Instruction::Call { addr: 4 },
+        Instruction::Halt,
+        // countdown (4)
+        Instruction::ConditionalJump { cond: 0, target: 7 },
+        Instruction::Sub {
+            dest: 0,
+            src1: 0,
+            src2: 1,
+        },
+        Instruction::Call { addr: 4 },
+        Instruction::Return,
+    ]);
+    assert_same(program, 2);
+}
+
+#[test]
+fn test_wasm_prints_and_reports_errors() {
+    let program = Program::new(vec![
+        Instruction::LoadImm {
+            dest: 0,
+            value: 2.5,
+        },
+        Instruction::Print { src: 0 },
+        Instruction::Load {
+            dest: 1,
+            var: "missing".to_string(),
+        },
+    ]);
+    let outcome = assert_same(program, 2);
+    assert_eq!(outcome.printed, vec![2.5]);
+    assert!(matches!(outcome.result, Err(VmError::VariableNotFound(name)) if name == "missing"));
+
+    let outcome = assert_same(Program::new(vec![Instruction::Return]), 1);
+    assert!(matches!(outcome.result, Err(VmError::CallStackEmpty)));
+
+    assert_eq!(
+        wasm::compile(&Program::new(vec![Instruction::Jump(5)]), 1).unwrap_err(),
+        ProgramError::TargetOutOfBounds { addr: 0, target: 5 }
+    );
+}
+
+#[test]
+fn test_wasm_computed_jumps() {
+    for selector in [0.0, 1.0, 2.0, 0.5, 3.0, 9.0] {
+        // Switch on r0, then jmpr to 8 + r0
+        let program = Program::new(vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: selector,
+            },
+            Instruction::LoadImm {
+                dest: 3,
+                value: 8.0,
+            },
+            Instruction::Switch {
+                src: 0,
+                table: vec![4, 5],
+                default: 6,
+            },
+            Instruction::Halt,
+            Instruction::LoadImm {
+                dest: 1,
+                value: 10.0,
+            },
+            Instruction::LoadImm {
+                dest: 2,
+                value: 20.0,
+            },
+            Instruction::Add {
+                dest: 4,
+                src1: 0,
+                src2: 3,
+            },
+            Instruction::JumpIndirect { src: 4 },
+            Instruction::LoadImm {
+                dest: 1,
+                value: 30.0,
+            },
+            Instruction::Halt,
+        ]);
+        assert_same(program, 5);
+    }
+}
+
+#[test]
+fn test_wasm_indirect_calls() {
+    for callee in [4.0, 6.0, 8.0, 2.5] {
+        let program = Program::new(vec![
+            Instruction::LoadImm {
+                dest: 0,
+                value: callee,
+            },
+            Instruction::LoadAddr { dest: 2, addr: 6 },
+            Instruction::CallIndirect { src: 0 },
+            Instruction::Halt,
+            // 4
+            Instruction::Mov { dest: 1, src: 2 },
+            Instruction::Return,
+            // 6
+            Instruction::Add {
+                dest: 1,
+                src1: 2,
+                src2: 2,
+            },
+            Instruction::Return,
+        ]);
+        assert_same(program, 3);
+    }
+}
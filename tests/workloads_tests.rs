@@ -0,0 +1,117 @@
+use zyde::passes::PassManager;
+use zyde::passes::fusion::Fusion;
+use zyde::vm::VM;
+use zyde::workloads::{self, REGISTERS};
+
+fn run(program: zyde::program::Program) -> VM {
+    let mut vm = VM::new(program, REGISTERS);
+    vm.run().unwrap();
+    vm
+}
+
+#[test]
+fn test_fib_workload() {
+    assert_eq!(run(workloads::fib(10)).registers[0], 55.0);
+    assert_eq!(run(workloads::fib(50)).registers[0], 12586269025.0);
+}
+
+#[test]
+fn test_nested_loops_workload() {
+    // (0 + 1 + 2) * (0 + 1 + 2 + 3)
+    assert_eq!(run(workloads::nested_loops(3, 4)).registers[0], 18.0);
+}
+
+#[test]
+fn test_calls_and_variables_workloads() {
+    assert_eq!(run(workloads::calls(25)).registers[0], 25.0);
+    assert_eq!(run(workloads::variables(25)).registers[0], 25.0);
+}
+
+#[test]
+fn test_spring_workload() {
+    let x = run(workloads::spring(1000)).registers[0];
+
+    // One time unit into a unit spring starting at 1, x is roughly cos(1)
+    assert!((x - 1f64.cos()).abs() < 0.01);
+}
+
+#[test]
+fn test_mandelbrot_workload() {
+    let (size, iterations) = (16, 30);
+    let step = 2.5 / size as f64;
+    let mut expected = 0.0;
+    for row in 0..size {
+        for column in 0..size {
+            let (cx, cy) = (-2.0 + column as f64 * step, -1.25 + row as f64 * step);
+            let (mut zr, mut zi) = (0.0f64, 0.0f64);
+            let bounded = (0..iterations).all(|_| {
+                let (zr2, zi2) = (zr * zr, zi * zi);
+                if zr2 + zi2 > 4.0 {
+                    return false;
+                }
+                zi = zr * zi * 2.0 + cy;
+                zr = zr2 - zi2 + cx;
+                true
+            });
+            if bounded {
+                expected += 1.0;
+            }
+        }
+    }
+    let count = run(workloads::mandelbrot(size, iterations)).registers[0];
+    assert!(count > 0.0 && count < (size * size) as f64);
+    assert_eq!(count, expected);
+}
+
+#[test]
+fn test_strings_workload() {
+    let vm = run(workloads::strings(30));
+    assert_eq!(vm.registers[0], 30.0);
+    // 30 characters run through the alphabet once, ending on 'd'
+    assert_eq!(vm.registers[3], 101.0);
+}
+
+#[test]
+fn test_standard_workloads_halt() {
+    for (name, program) in workloads::standard()
+        .into_iter()
+        .chain(workloads::allocating())
+    {
+        let mut vm = VM::new(program, REGISTERS);
+        assert!(vm.run().is_ok(), "workload {} failed", name);
+    }
+}
+
+#[test]
+fn test_fused_workloads_match() {
+    for (name, program) in workloads::standard() {
+        let mut fused = program.clone();
+        PassManager::empty().add(Fusion).run(&mut fused);
+        assert!(fused.len() < program.len(), "nothing fused in {}", name);
+
+        let (plain, fused) = (run(program), run(fused));
+        assert_eq!(plain.registers, fused.registers, "workload {}", name);
+        assert_eq!(plain.variables, fused.variables, "workload {}", name);
+    }
+}
+
+#[test]
+fn test_run_matches_stepping() {
+    // `run` takes the pre-decoded path; `step` always executes the enum directly
+    for (name, program) in workloads::standard() {
+        let mut stepped = VM::new(program.clone(), REGISTERS);
+        while !stepped.is_halted() {
+            stepped.step().unwrap();
+        }
+        let ran = run(program);
+
+        assert_eq!(ran.registers, stepped.registers, "workload {}", name);
+        assert_eq!(ran.variables, stepped.variables, "workload {}", name);
+        assert_eq!(
+            (ran.pc, ran.steps),
+            (stepped.pc, stepped.steps),
+            "workload {}",
+            name
+        );
+    }
+}